@@ -1,2 +1,15 @@
-= Introduction
-This is a basic Typst template, converted to PDF
\ No newline at end of file
+// Reads `name` and `pages` from `sys.inputs`, which `GET /hello_typst.pdf` fills from its query.
+#let name = sys.inputs.at("name", default: "World")
+#let pages = int(sys.inputs.at("pages", default: "1"))
+#let logo = image.decode(
+  `<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 40 40"><circle cx="20" cy="20" r="18" fill="#239dad"/><path d="M12 21l6 6 11-13" stroke="white" stroke-width="4" fill="none"/></svg>`.text,
+  format: "svg",
+  width: 2cm,
+)
+
+#for page in range(1, pages + 1) [
+  #if page > 1 { pagebreak() }
+  #logo
+  = Hello #name!
+  This is a basic Typst template, converted to PDF. This is page #page of #pages.
+]
@@ -0,0 +1,86 @@
+//! Allow and deny lists of client networks, checked before anything else looks at a request.
+//!
+//! The client is the address `forwarded` resolved, so behind a proxy a request the proxy
+//! didn't annotate is turned away rather than judged by the proxy's own address. Requests
+//! over the Unix socket have no address and are left to the socket's file permissions.
+
+use std::fmt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use crate::config::{Config, ConfigHandle};
+use crate::errors::Code;
+use crate::forwarded::{ClientIp, Unresolved};
+
+/// Turned away, without saying why.
+#[derive(Debug)]
+struct Denied;
+
+/// Whether the lists let `request` through, and the rule that decided it.
+fn decide(request: &ServiceRequest, config: &Config) -> (bool, String) {
+    let extensions = request.extensions();
+    let ip = match (extensions.get::<ClientIp>(), extensions.get::<Unresolved>()) {
+        (Some(ClientIp(ip)), _) => { *ip }
+        (None, Some(Unresolved(problem))) => { return (false, problem.clone()) }
+        (None, None) => { return (true, "no address".into()) }
+    };
+    if let Some(network) = config.ip_denylist.iter().find(|network| network.contains(&ip)) {
+        return (false, format!("{ip} is in denied {network}"));
+    }
+    if config.ip_allowlist.is_empty() {
+        return (true, "no allowlist".into());
+    }
+    match config.ip_allowlist.iter().find(|network| network.contains(&ip)) {
+        Some(network) => { (true, format!("{ip} is in allowed {network}")) }
+        None => { (false, format!("{ip} is in no allowed network")) }
+    }
+}
+
+/// Middleware answering clients the lists exclude with a bare 403, for `App::wrap_fn`.
+pub fn filter<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let decision = request.app_data::<web::Data<ConfigHandle>>()
+        .map(|config| config.current())
+        .filter(|config| !config.ip_allowlist.is_empty() || !config.ip_denylist.is_empty())
+        .map(|config| decide(&request, &config));
+
+    match decision {
+        Some((false, rule)) => {
+            tracing::warn!(rule, "client denied");
+            let response = request.error_response(Denied).map_into_right_body();
+            Box::pin(async { Ok(response) })
+        }
+        decision => {
+            if let Some((_, rule)) = decision {
+                tracing::debug!(rule, "client allowed");
+            }
+            let call = service.call(request);
+            Box::pin(async move { Ok(call.await?.map_into_left_body()) })
+        }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Forbidden")
+    }
+}
+
+impl ResponseError for Denied {
+    fn status_code(&self) -> StatusCode {
+        Code::ClientDenied.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(Code::ClientDenied.body(self))
+    }
+}
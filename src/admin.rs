@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+use actix_web::http::header;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::auth::secrets_match;
+use crate::blobs::Blobs;
+use crate::cache::CompileCache;
+use crate::concurrency::{self, ClientCompiles, ClientUsage};
+use crate::config::{Config, CurrentConfig};
+use crate::docker_world::{ErrorOutput, FontLibrary};
+use crate::errors::Code;
+use crate::health::Health;
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::packages::PackageStore;
+use crate::projects::Projects;
+use crate::quotas::Quotas;
+use crate::slots::CompileSlots;
+use crate::uploads::Uploads;
+
+/// Snapshot returned by `GET /admin/stats`.
+#[derive(Serialize, ToSchema)]
+pub struct Stats {
+    uptime_seconds: u64,
+    compiles: Compiles,
+    queue: Queue,
+    result_cache: ResultCache,
+    fonts: Fonts,
+    package_cache: PackageCache,
+    /// Responses per key from the keys file since startup.
+    requests_by_key: BTreeMap<String, u64>,
+    /// Compiles running per client with its `MAX_CONCURRENT_COMPILES_PER_CLIENT` limit, as
+    /// `key:NAME` or its address; clients with none running are left out.
+    compiles_by_client: BTreeMap<String, ClientUsage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Compiles {
+    total: u64,
+    failed: u64,
+    timed_out: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Queue {
+    in_flight: usize,
+    queued: usize,
+    limit: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ResultCache {
+    entries: usize,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    /// Share of lookups answered from the cache, `null` before the first.
+    hit_rate: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Fonts {
+    count: usize,
+    loaded: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PackageCache {
+    /// Measured every ten minutes, so up to that old.
+    bytes: u64,
+}
+
+/// Reject the request unless it carries the configured admin token.
+pub fn require_admin(request: &HttpRequest, config: &Config) -> Result<(), Error> {
+    let expected = match &config.admin_token {
+        None => { return Err(Code::AdminDisabled.error("Admin endpoints are disabled")) }
+        Some(token) => { token }
+    };
+
+    let given = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match given {
+        Some(token) if secrets_match(token, expected) => { Ok(()) }
+        _ => { Err(Code::InvalidAdminToken.error("Invalid admin token")) }
+    }
+}
+
+/// Drop everything comemo has memoized, regardless of age.
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 204, description = "The memoized results were dropped"),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[post("/admin/evict")]
+async fn evict_cache(request: HttpRequest, config: CurrentConfig) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    comemo::evict(0);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Counters from all over the server, read without waiting on running compiles.
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "A snapshot of the server", body = Stats),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[get("/admin/stats")]
+async fn stats(
+    request: HttpRequest,
+    config: CurrentConfig,
+    (health, metrics, slots): (web::Data<Health>, web::Data<Metrics>, web::Data<CompileSlots>),
+    (cache, fonts, packages): (web::Data<CompileCache>, web::Data<FontLibrary>, web::Data<PackageStore>),
+    client_compiles: web::Data<ClientCompiles>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    let (hits, misses) = cache.hits_and_misses();
+    let fonts = fonts.current();
+    Ok(HttpResponse::Ok().json(Stats {
+        uptime_seconds: health.uptime().as_secs(),
+        compiles: Compiles {
+            total: metrics.compiles.load(Ordering::Relaxed),
+            failed: metrics.failed_compiles.load(Ordering::Relaxed),
+            timed_out: metrics.timed_out_compiles.load(Ordering::Relaxed),
+        },
+        queue: Queue { in_flight: slots.in_use(), queued: slots.queued(), limit: slots.limit() },
+        result_cache: ResultCache {
+            entries: cache.memory_entries(),
+            bytes: cache.memory_size(),
+            hits,
+            misses,
+            hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        },
+        fonts: Fonts { count: fonts.count(), loaded: fonts.loaded() },
+        package_cache: PackageCache { bytes: packages.size() },
+        requests_by_key: metrics.requests_by_key(),
+        compiles_by_client: client_compiles.usage(|client| concurrency::limit_of(&config, client)),
+    }))
+}
+
+/// Delete everything a tenant has: its projects, blobs, uploads and jobs, stored ones too,
+/// and its quota usage.
+///
+/// Its cached results can't be told apart from others', but no other tenant can hit them,
+/// and they age out like any. Keys of the tenant still authenticate until the keys file drops them.
+#[utoipa::path(
+    tag = "admin",
+    params(("tenant" = String, Path, description = "The tenant, or the key's name for keys without one")),
+    responses(
+        (status = 204, description = "The tenant's state is deleted"),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[delete("/admin/tenants/{tenant}")]
+#[allow(clippy::too_many_arguments)]
+async fn delete_tenant(
+    request: HttpRequest,
+    tenant: web::Path<String>,
+    config: CurrentConfig,
+    projects: web::Data<Projects>,
+    blobs: web::Data<Blobs>,
+    uploads: web::Data<Uploads>,
+    jobs: web::Data<Jobs>,
+    quotas: web::Data<Quotas>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    blobs.remove_tenant(&tenant);
+    uploads.remove_tenant(&tenant);
+    quotas.remove_tenant(&tenant);
+    let removed = tenant.clone();
+    web::block(move || {
+        projects.remove_tenant(&removed);
+        jobs.remove_tenant(&removed);
+    }).await?;
+    tracing::info!(tenant = %tenant, "deleted the tenant's state");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(evict_cache).service(stats).service(delete_tenant);
+}
@@ -0,0 +1,163 @@
+use actix_multipart::Multipart;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use typst::{World, WorldExt};
+use typst::syntax::{Side, Span};
+use typst_ide::{Completion, Definition, Tooltip};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{file_id, DockerWorld, ErrorOutput, FontLibrary};
+use crate::errors::{unbuildable, Code};
+use crate::multipart::read_documents;
+use crate::openapi::Upload;
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Cursor {
+    /// File the cursor is in.
+    path: String,
+    /// Byte offset of the cursor in that file.
+    offset: usize,
+}
+
+/// Editor assistance for one cursor position.
+#[derive(Serialize, ToSchema)]
+pub struct Analysis {
+    completions: Option<Completions>,
+    tooltip: Option<TooltipJson>,
+    definition: Option<DefinitionJson>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Completions {
+    /// Byte offset the completions replace text from.
+    from: usize,
+    items: Vec<CompletionJson>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CompletionJson {
+    kind: String,
+    label: String,
+    apply: Option<String>,
+    detail: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum TooltipJson {
+    Text(String),
+    Code(String),
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum DefinitionJson {
+    /// Defined in one of the project's files.
+    Source { path: String, start: usize, end: usize },
+    /// Defined in typst's standard library.
+    Std { std: Option<String> },
+}
+
+impl From<Completion> for CompletionJson {
+    fn from(completion: Completion) -> Self {
+        // `Symbol('x')` and friends carry their payload in parentheses, keep only the variant.
+        let kind = format!("{:?}", completion.kind);
+        let kind = kind.split('(').next().unwrap_or_default().to_lowercase();
+        Self {
+            kind,
+            label: completion.label.to_string(),
+            apply: completion.apply.map(|apply| apply.to_string()),
+            detail: completion.detail.map(|detail| detail.to_string()),
+        }
+    }
+}
+
+fn locate(world: &DockerWorld, span: Span) -> Option<DefinitionJson> {
+    let path = span.id()?.vpath().as_rootless_path().to_string_lossy().into_owned();
+    let range = world.range(span)?;
+    Some(DefinitionJson::Source { path, start: range.start, end: range.end })
+}
+
+/// Autocompletion, hover tooltip and jump-to-definition for a cursor in an uploaded project.
+///
+/// The project is uploaded like for `/compile`, the cursor is given as `?path=&offset=`.
+#[utoipa::path(
+    tag = "compile",
+    params(Cursor, OptionsQuery),
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "What the editor may offer at the cursor", body = Analysis),
+        (status = 400, description = "The upload or the cursor is invalid", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+    ),
+)]
+#[post("/analyze")]
+async fn analyze(
+    request: HttpRequest,
+    cursor: web::Query<Cursor>,
+    config: CurrentConfig,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    slots: web::Data<CompileSlots>,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let options = config.compile_options(&request)?;
+    let mut documents = read_documents(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the file to analyze"));
+    }
+
+    let world = options.world()
+        .main(documents.remove(0))
+        .files(documents)
+        .font_db(fonts.current())
+        .packages(packages.into_inner())
+        .build()
+        .map_err(unbuildable)?;
+
+    let cursor = cursor.into_inner();
+    let slot = slots.acquire(options.priority).await?;
+    let analysis = web::block(move || {
+        let analysis = analyze_at(&world, &cursor);
+        drop(slot);
+        analysis
+    }).await?;
+    match analysis {
+        Ok(analysis) => { Ok(HttpResponse::Ok().json(analysis)) }
+        Err(problem) => { Err(Code::AnalysisFailed.error(problem)) }
+    }
+}
+
+fn analyze_at(world: &DockerWorld, cursor: &Cursor) -> Result<Analysis, String> {
+    let source = world.source(file_id(&cursor.path)).map_err(|problem| problem.to_string())?;
+    if cursor.offset > source.text().len() || !source.text().is_char_boundary(cursor.offset) {
+        return Err("offset is not a character boundary in the file".into());
+    }
+
+    let completions = typst_ide::autocomplete(world, None, &source, cursor.offset, true)
+        .map(|(from, items)| Completions {
+            from,
+            items: items.into_iter().map(CompletionJson::from).collect(),
+        });
+
+    let tooltip = typst_ide::tooltip(world, None, &source, cursor.offset, Side::After)
+        .map(|tooltip| match tooltip {
+            Tooltip::Text(text) => { TooltipJson::Text(text.to_string()) }
+            Tooltip::Code(code) => { TooltipJson::Code(code.to_string()) }
+        });
+
+    let definition = typst_ide::definition(world, None, &source, cursor.offset, Side::After)
+        .and_then(|definition| match definition {
+            Definition::Span(span) => { locate(world, span) }
+            Definition::Std(value) => { Some(DefinitionJson::Std { std: value.name().map(String::from) }) }
+        });
+
+    Ok(Analysis { completions, tooltip, definition })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(analyze);
+}
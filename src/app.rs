@@ -0,0 +1,188 @@
+//! The application every worker serves, assembled in one place so it can be built outside
+//! `HttpServer` as well, with `actix_web::test::init_service(app(state))`. `AppState::new`
+//! sets up its state from a `Config` without starting anything, which is left to `main`.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::{web, App, Error};
+use crate::audit::{self, AuditLog};
+use crate::blobs::{self, Blobs};
+use crate::cache::CompileCache;
+use crate::compilers::Compilers;
+use crate::concurrency::{self, ClientCompiles};
+use crate::config::{Config, ConfigHandle};
+use crate::docker_world::FontLibrary;
+use crate::health::Health;
+use crate::jobs::Jobs;
+use crate::metrics::{self, Metrics};
+use crate::packages::PackageStore;
+use crate::projects::{self, Projects};
+use crate::quotas::{self, Quotas};
+use crate::rate_limit::{self, RateLimiter};
+use crate::shadow::Shadow;
+use crate::slots::CompileSlots;
+use crate::uploads::{self, Uploads};
+use crate::version::Build;
+use crate::webhooks::Webhooks;
+use crate::{access, admin, analyze, ast, auth, bibliography, cors, documents, element, errors, events, exports, fonts, formatter, forwarded, highlight, jobs, limits, logging, openapi, package_admin, pages, preview, selftest, stats, ui, version};
+use crate::{compile_endpoint, example, health, storage, store};
+
+/// The state shared by every worker, each getting a clone.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: web::Data<ConfigHandle>,
+    pub fonts: web::Data<FontLibrary>,
+    pub packages: web::Data<PackageStore>,
+    pub compilers: web::Data<Compilers>,
+    pub projects: web::Data<Projects>,
+    pub metrics: web::Data<Metrics>,
+    pub slots: web::Data<CompileSlots>,
+    pub client_compiles: web::Data<ClientCompiles>,
+    pub cache: web::Data<CompileCache>,
+    pub rate_limiter: web::Data<RateLimiter>,
+    pub quotas: web::Data<Quotas>,
+    pub audit_log: web::Data<AuditLog>,
+    pub jobs: web::Data<Jobs>,
+    pub webhooks: web::Data<Webhooks>,
+    pub uploads: web::Data<Uploads>,
+    pub blobs: web::Data<Blobs>,
+    pub health: web::Data<Health>,
+    pub build: web::Data<Build>,
+    pub shadow: web::Data<Shadow>,
+    /// Whether `/metrics` is served here rather than on `METRICS_LISTEN`.
+    pub serve_metrics: bool,
+}
+
+impl AppState {
+    /// The state a server with `config` starts with, or why it can't start.
+    pub fn new(config: Config) -> Result<Self, String> {
+        let serve_metrics = config.metrics_listen.is_none();
+        let config = web::Data::new(ConfigHandle::new(config));
+        let current = config.current();
+        let fonts = web::Data::new(FontLibrary::new(current.font_dir.clone()));
+        let packages = PackageStore::new(current.package_settings())
+            .map_err(|problem| format!("cannot set up the package store: {problem}"))?;
+        let packages = web::Data::new(packages);
+        let compilers = web::Data::new(Compilers::new(
+            &current,
+            fonts.clone().into_inner(),
+            packages.clone().into_inner(),
+        ));
+        let shadow = Shadow::new(&current, &compilers)
+            .map_err(|problem| format!("cannot set up shadow compiles: {problem}"))?;
+        let build = web::Data::new(Build::new(&compilers, &current));
+        let storage = storage::open(&current)?;
+        let projects = Projects::restore(storage.clone(), &fonts, packages.clone().into_inner(), &current)?;
+        let shared = store::open(&current)?;
+        let audit_log = AuditLog::open(&current)
+            .map_err(|problem| format!("cannot write the audit log: {problem}"))?;
+        let cache = CompileCache::new(&current, shared.clone())?;
+        let jobs = Jobs::new(&current, shared.clone(), storage);
+        jobs.restore(&compilers.versions())?;
+        Ok(Self {
+            fonts,
+            packages,
+            projects: web::Data::new(projects),
+            metrics: web::Data::new(Metrics::default()),
+            slots: web::Data::new(CompileSlots::new(&current)),
+            client_compiles: web::Data::new(ClientCompiles::default()),
+            cache: web::Data::new(cache),
+            rate_limiter: web::Data::new(RateLimiter::new(&current)),
+            quotas: web::Data::new(Quotas::new(shared)),
+            audit_log: web::Data::new(audit_log),
+            jobs: web::Data::new(jobs),
+            webhooks: web::Data::new(Webhooks::default()),
+            uploads: web::Data::new(Uploads::default()),
+            blobs: web::Data::new(Blobs::new(&current)),
+            health: web::Data::new(Health::new(&current)),
+            shadow: web::Data::new(shadow),
+            compilers,
+            build,
+            config,
+            serve_metrics,
+        })
+    }
+}
+
+/// Every endpoint with its state and middleware.
+pub fn app(state: AppState) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let server_header = state.build.server_header();
+    let cors = cors::cors(&state.config.current());
+    let serve_metrics = state.serve_metrics;
+    App::new()
+        .app_data(state.config)
+        .app_data(state.fonts)
+        .app_data(state.packages)
+        .app_data(state.compilers)
+        .app_data(state.projects)
+        .app_data(state.metrics)
+        .app_data(state.slots)
+        .app_data(state.client_compiles)
+        .app_data(state.cache)
+        .app_data(state.rate_limiter)
+        .app_data(state.quotas)
+        .app_data(state.audit_log)
+        .app_data(state.jobs)
+        .app_data(state.webhooks)
+        .app_data(state.uploads)
+        .app_data(state.blobs)
+        .app_data(state.health)
+        .app_data(state.build)
+        .app_data(state.shadow)
+        .wrap_fn(errors::codes)
+        .wrap_fn(audit::record)
+        .wrap_fn(concurrency::limit)
+        .wrap_fn(quotas::enforce)
+        .wrap_fn(auth::signature::verify)
+        .wrap_fn(auth::authenticate)
+        .wrap_fn(rate_limit::limit)
+        .wrap_fn(metrics::track)
+        .wrap(DefaultHeaders::new().add((header::SERVER, server_header)))
+        .wrap(cors)
+        .wrap_fn(access::filter)
+        .wrap_fn(forwarded::identify)
+        .wrap_fn(logging::trace)
+        .configure(example::configure)
+        .configure(compile_endpoint::configure)
+        .configure(events::configure)
+        .configure(pages::configure)
+        .configure(exports::configure)
+        .configure(element::configure)
+        .configure(stats::configure)
+        .configure(documents::configure)
+        .configure(projects::configure)
+        .configure(admin::configure)
+        .configure(package_admin::configure)
+        .configure(analyze::configure)
+        .configure(ast::configure)
+        .configure(formatter::configure)
+        .configure(highlight::configure)
+        .configure(fonts::configure)
+        .configure(bibliography::configure)
+        .configure(preview::configure)
+        .configure(jobs::configure)
+        .configure(uploads::configure)
+        .configure(blobs::configure)
+        .configure(limits::configure)
+        .configure(quotas::configure)
+        .configure(health::configure)
+        .configure(selftest::configure)
+        .configure(version::configure)
+        .configure(openapi::configure)
+        .configure(ui::configure)
+        .configure(|cfg| {
+            if serve_metrics {
+                metrics::configure(cfg);
+            }
+        })
+}
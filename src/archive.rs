@@ -0,0 +1,118 @@
+//! A zip archive written one entry at a time, each entry's bytes handed out as soon as it was
+//! added so the archive can be streamed without holding more than one entry.
+//!
+//! Entries are whole before they are written, so their local headers carry the sizes and CRC
+//! and no data descriptors are needed. The archive stays below the limits of a zip without
+//! the zip64 extensions, which every unzip reads.
+
+use std::io::Write;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+/// Name of the entry a failed archive ends with.
+const ERROR_ENTRY: &str = "error.txt";
+
+/// Bytes the entries may take, leaving room for the error entry and the central directory
+/// below the 4 GiB a zip without zip64 addresses.
+const MAX_OFFSET: u64 = u32::MAX as u64 - (16 << 20);
+
+/// Entries there may be, one short of the limit for the error entry.
+const MAX_ENTRIES: u16 = u16::MAX - 1;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+pub struct ZipWriter {
+    /// Bytes handed out so far, where the next entry starts.
+    offset: u64,
+    /// The central directory headers of the entries so far.
+    central: Vec<u8>,
+    entries: u16,
+    /// MS-DOS time and date every entry is stamped with.
+    time: u16,
+    date: u16,
+}
+
+impl ZipWriter {
+    pub fn new(modified: DateTime<Utc>) -> Self {
+        let time = (modified.hour() << 11 | modified.minute() << 5 | modified.second() / 2) as u16;
+        let date = ((modified.year().clamp(1980, 2107) - 1980) as u32) << 9 | modified.month() << 5 | modified.day();
+        Self { offset: 0, central: Vec::new(), entries: 0, time, date: date as u16 }
+    }
+
+    /// The bytes of entry `name` holding `data`, deflated if `compress` and that makes it smaller,
+    /// or `None` if the archive can't hold it.
+    pub fn entry(&mut self, name: &str, data: &[u8], compress: bool) -> Option<Vec<u8>> {
+        if self.entries >= MAX_ENTRIES || self.offset + (data.len() + name.len()) as u64 > MAX_OFFSET {
+            return None;
+        }
+        Some(self.add(name, data, compress))
+    }
+
+    /// The central directory that ends the archive.
+    pub fn finish(self) -> Vec<u8> {
+        let mut end = self.central;
+        let size = end.len() as u32;
+        end.extend(0x06054b50u32.to_le_bytes());
+        end.extend([0u8; 4]);
+        end.extend(self.entries.to_le_bytes());
+        end.extend(self.entries.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend((self.offset as u32).to_le_bytes());
+        end.extend([0u8; 2]);
+        end
+    }
+
+    /// An entry `error.txt` with `message`, followed by the central directory.
+    pub fn fail(mut self, message: &str) -> Vec<u8> {
+        let mut tail = self.add(ERROR_ENTRY, message.as_bytes(), false);
+        tail.extend(self.finish());
+        tail
+    }
+
+    fn add(&mut self, name: &str, data: &[u8], compress: bool) -> Vec<u8> {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let deflated = compress.then(|| {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).expect("writing to memory succeeds");
+            encoder.finish().expect("writing to memory succeeds")
+        });
+        let (method, contents) = match &deflated {
+            Some(deflated) if deflated.len() < data.len() => { (DEFLATED, deflated.as_slice()) }
+            _ => { (STORED, data) }
+        };
+
+        // Shared by the local and the central header, from the version needed to the name's length.
+        let mut fields = Vec::with_capacity(26);
+        fields.extend(20u16.to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+        fields.extend(method.to_le_bytes());
+        fields.extend(self.time.to_le_bytes());
+        fields.extend(self.date.to_le_bytes());
+        fields.extend(crc.sum().to_le_bytes());
+        fields.extend((contents.len() as u32).to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((name.len() as u16).to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+
+        let mut local = Vec::with_capacity(30 + name.len() + contents.len());
+        local.extend(0x04034b50u32.to_le_bytes());
+        local.extend(&fields);
+        local.extend(name.as_bytes());
+        local.extend(contents);
+
+        self.central.extend(0x02014b50u32.to_le_bytes());
+        self.central.extend(20u16.to_le_bytes());
+        self.central.extend(&fields);
+        // No comment, disk 0, no attributes.
+        self.central.extend([0u8; 10]);
+        self.central.extend((self.offset as u32).to_le_bytes());
+        self.central.extend(name.as_bytes());
+
+        self.offset += local.len() as u64;
+        self.entries += 1;
+        local
+    }
+}
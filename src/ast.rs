@@ -0,0 +1,193 @@
+//! `POST /ast`, the concrete syntax tree of a typst source file as JSON.
+//!
+//! The file comes like for `/format`, up to `MAX_AST_SIZE_KB`. Every node has its kind,
+//! named like typst's `SyntaxKind`, `LetBinding` or `Ident`, and the byte range it spans;
+//! leaves have their text and inner nodes their children, so the leaves' texts in order
+//! give back the file. `trivia=false` leaves out spaces and comments, and `errors=true`
+//! adds the message and hints to each `Error` node. Files with syntax errors are answered
+//! like any other, the parser recovering around `Error` nodes.
+
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use typst::syntax::SyntaxNode;
+use utoipa::{IntoParams, ToSchema};
+use crate::config::CurrentConfig;
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::multipart::read_source;
+use crate::openapi::Upload;
+
+#[derive(Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AstQuery {
+    /// Include spaces and comments, true by default.
+    trivia: Option<bool>,
+    /// Add the message and hints to `Error` nodes.
+    errors: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Tree {
+    /// Whether the file has syntax errors, so `Error` nodes.
+    erroneous: bool,
+    root: Node,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Node {
+    /// The node's `SyntaxKind`, like `Markup`, `LetBinding` or `Ident`.
+    kind: String,
+    /// Byte offset the node starts at.
+    start: usize,
+    /// Byte offset after the node.
+    end: usize,
+    /// What a leaf stands for in the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    /// The nodes of an inner node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<Vec<Node>>,
+    /// Why an `Error` node doesn't parse, with `errors=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<SyntaxMessage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SyntaxMessage {
+    message: String,
+    hints: Vec<String>,
+}
+
+/// Parse a typst file into its syntax tree, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    params(AstQuery),
+    request_body(
+        content((String = "text/plain"), (Upload = "multipart/form-data")),
+        description = "The file as the body, or as the only part of an upload",
+    ),
+    responses(
+        (status = 200, description = "The syntax tree", body = Tree),
+        (status = 400, description = "The file isn't UTF-8", body = ErrorOutput),
+        (status = 413, description = "The file is larger than `MAX_AST_SIZE_KB`, or more than one was uploaded", body = ErrorOutput),
+    ),
+)]
+#[post("/ast")]
+async fn ast(request: HttpRequest, payload: web::Payload, config: CurrentConfig) -> Result<HttpResponse, Error> {
+    let query = web::Query::<AstQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?
+        .into_inner();
+    let text = read_source(&request, payload, &config).await?;
+    if text.len() > config.max_ast_size {
+        return Err(Code::FileTooLarge.error(format!("The file is larger than the {} bytes /ast parses", config.max_ast_size)));
+    }
+    let tree = web::block(move || tree(&text, query)).await?;
+    Ok(HttpResponse::Ok().json(tree))
+}
+
+fn tree(text: &str, query: AstQuery) -> Tree {
+    let root = typst::syntax::parse(text);
+    Tree { erroneous: root.erroneous(), root: node(&root, 0, query) }
+}
+
+/// `syntax` starting at byte `start`, with what `query` asks for.
+fn node(syntax: &SyntaxNode, start: usize, query: AstQuery) -> Node {
+    let (text, children) = match syntax.children().len() {
+        0 => { (Some(syntax.text().to_string()), None) }
+        _ => {
+            let mut offset = start;
+            let mut nodes = Vec::new();
+            for child in syntax.children() {
+                if query.trivia.unwrap_or(true) || !child.kind().is_trivia() {
+                    nodes.push(node(child, offset, query));
+                }
+                offset += child.len();
+            }
+            (None, Some(nodes))
+        }
+    };
+    let error = match syntax.kind().is_error() && query.errors.unwrap_or(false) {
+        true => {
+            syntax.errors().into_iter().next().map(|error| SyntaxMessage {
+                message: error.message.to_string(),
+                hints: error.hints.iter().map(ToString::to_string).collect(),
+            })
+        }
+        false => { None }
+    };
+    Node { kind: format!("{:?}", syntax.kind()), start, end: start + syntax.len(), text, children, error }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ast);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs};
+    use serde_json::Value;
+    use super::{tree, AstQuery};
+
+    fn json(text: &str, trivia: bool, errors: bool) -> Value {
+        serde_json::to_value(tree(text, AstQuery { trivia: Some(trivia), errors: Some(errors) })).unwrap()
+    }
+
+    /// Compare `tree` with the snapshot `name` in `tests/fixtures/ast`, or write it there
+    /// when `UPDATE_SNAPSHOTS` is set, for a change of the tree that is meant.
+    fn snapshot(name: &str, tree: &Value) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ast").join(format!("{name}.json"));
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(&path, serde_json::to_string_pretty(tree).unwrap() + "\n").unwrap();
+            return;
+        }
+        let expected: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(tree, &expected, "the tree of {name} changed, downstream tools rely on its shape");
+    }
+
+    /// The texts of the leaves under `node`, in order.
+    fn leaves(node: &Value, texts: &mut String) {
+        match node["children"].as_array() {
+            Some(children) => {
+                for child in children {
+                    leaves(child, texts);
+                }
+            }
+            None => { texts.push_str(node["text"].as_str().unwrap()) }
+        }
+    }
+
+    #[test]
+    fn snapshots() {
+        snapshot("let", &json("#let x = 1\n", true, false));
+        snapshot("let-without-trivia", &json("#let x = 1\n", false, false));
+        snapshot("heading", &json("= Hi *there*\n", true, false));
+    }
+
+    #[test]
+    fn the_leaves_give_back_the_file() {
+        let text = "= Title\n#let f(x, y: 2) = x * y // product\n$ f(1) $ and `raw` and #f(3)\n";
+        let mut texts = String::new();
+        leaves(&json(text, true, false)["root"], &mut texts);
+        assert_eq!(texts, text);
+    }
+
+    #[test]
+    fn error_nodes_say_what_is_wrong_if_asked() {
+        let tree = json("#let = 1\n", true, true);
+        assert_eq!(tree["erroneous"], true);
+        let mut errors = Vec::new();
+        let mut pending = vec![&tree["root"]];
+        while let Some(node) = pending.pop() {
+            if node["kind"] == "Error" {
+                errors.push(node);
+            }
+            pending.extend(node["children"].as_array().into_iter().flatten());
+        }
+        assert!(!errors.is_empty());
+        assert!(errors.iter().all(|node| node["error"]["message"].as_str().is_some_and(|message| !message.is_empty())));
+
+        let plain = json("#let = 1\n", true, false);
+        assert!(!plain.to_string().contains("\"message\""));
+    }
+}
@@ -0,0 +1,172 @@
+//! Attaching a document's sources to its PDF, for `?embed_sources=true`, so any copy can be
+//! compiled again and audited.
+//!
+//! `true` attaches the main file, `all` every uploaded file with one of
+//! `EMBED_SOURCE_EXTENSIONS` too, text sources and data but no fonts or images by default.
+//! They are attached under their upload paths with a MIME type by extension, all together
+//! at most `EMBED_SOURCES_MAX_KB`, which is checked before compiling. qpdf writes them into
+//! the exported PDF, which has to pass `qpdf --check` after, and the compile fails if
+//! either step does rather than answer a PDF without them. The response lists what was
+//! attached in `X-Typst-Embedded-Sources`, percent-encoded paths separated by commas.
+//!
+//! A pinned `date` is used for the attachments' dates, so the PDF stays deterministic.
+//! PDF/A-2b doesn't allow attachments that aren't PDF/A themselves, so `standard=pdfa-2b`
+//! refuses the option.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::{web, Error, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use typst::foundations::Bytes;
+use utoipa::{IntoParams, ToSchema};
+use crate::compilers::CompileOptions;
+use crate::config::Config;
+use crate::docker_world::{CompileError, DocumentFile};
+use crate::errors::Code;
+use crate::optimize::succeeded;
+use crate::sarif::uri;
+
+pub const EMBEDDED_HEADER: &str = "X-Typst-Embedded-Sources";
+
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+pub enum EmbedSources {
+    #[serde(rename = "false")]
+    None,
+    #[serde(rename = "true")]
+    Main,
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EmbedQuery {
+    /// `true` attaches the main file to the PDF, `all` the other text sources and data too.
+    #[param(inline)]
+    embed_sources: Option<EmbedSources>,
+}
+
+/// The sources a compile attaches to its PDF.
+#[derive(Clone)]
+pub struct Embedding {
+    pub qpdf: PathBuf,
+    /// Upload paths of the files, the main file first.
+    pub paths: Vec<String>,
+}
+
+/// What the request asked to attach of `documents`, the main file first, refused if it
+/// exceeds the limit.
+pub fn requested(
+    request: &HttpRequest,
+    config: &Config,
+    documents: &[DocumentFile],
+    options: &CompileOptions,
+) -> Result<Option<Embedding>, Error> {
+    let query = web::Query::<EmbedQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let all = match query.embed_sources {
+        None | Some(EmbedSources::None) => { return Ok(None) }
+        Some(EmbedSources::Main) => { false }
+        Some(EmbedSources::All) => { true }
+    };
+    if options.pdf_a {
+        return Err(Code::InvalidParameter.error("PDF/A-2b doesn't allow attaching sources"));
+    }
+
+    let mut size = 0;
+    let mut paths = Vec::new();
+    for (index, document) in documents.iter().enumerate() {
+        let path = path(document);
+        if index > 0 && !(all && embeddable(&path, &config.embed_extensions)) {
+            continue;
+        }
+        size += document.data.size().map_err(|problem| Code::Internal.error(problem))?;
+        if size > config.embed_sources_max_size as u64 {
+            return Err(Code::UploadTooLarge.error(format!(
+                "The sources to attach are larger than {} bytes",
+                config.embed_sources_max_size
+            )));
+        }
+        paths.push(path);
+    }
+    Ok(Some(Embedding { qpdf: config.qpdf.clone(), paths }))
+}
+
+/// The contents of the files `embedding` attaches, read before the compile takes them.
+pub fn sources(embedding: &Embedding, main: &DocumentFile, files: &[DocumentFile]) -> Result<Vec<(String, Bytes)>, CompileError> {
+    std::iter::once(main).chain(files)
+        .filter(|file| embedding.paths.contains(&path(file)))
+        .map(|file| {
+            let data = file.data.load().map_err(|problem| CompileError::Failed(problem.to_string().into()))?;
+            Ok((path(file), data))
+        })
+        .collect()
+}
+
+/// `pdf` with `sources` attached, dated `now` if it is pinned.
+pub fn attach(pdf: Vec<u8>, qpdf: &Path, sources: &[(String, Bytes)], now: Option<DateTime<Utc>>) -> Result<Vec<u8>, CompileError> {
+    let _span = tracing::info_span!("attach", files = sources.len()).entered();
+    rewrite(&pdf, qpdf, sources, now)
+        .map_err(|problem| CompileError::Failed(format!("The sources could not be attached to the PDF: {problem}").into()))
+}
+
+fn rewrite(pdf: &[u8], qpdf: &Path, sources: &[(String, Bytes)], now: Option<DateTime<Utc>>) -> io::Result<Vec<u8>> {
+    let dir = tempfile::tempdir()?;
+    let (input, output) = (dir.path().join("input.pdf"), dir.path().join("output.pdf"));
+    fs::write(&input, pdf)?;
+    let date = now.map(|now| now.format("D:%Y%m%d%H%M%SZ").to_string());
+    let mut command = Command::new(qpdf);
+    for (index, (path, data)) in sources.iter().enumerate() {
+        let file = dir.path().join(format!("source-{index}"));
+        fs::write(&file, data)?;
+        command.arg("--add-attachment")
+            .arg(&file)
+            .arg(format!("--key={path}"))
+            .arg(format!("--filename={path}"))
+            .arg(format!("--mimetype={}", mime_type(path)));
+        if let Some(date) = &date {
+            command.arg(format!("--creationdate={date}")).arg(format!("--moddate={date}"));
+        }
+        command.arg("--");
+    }
+    succeeded(command.arg(&input).arg(&output).stdin(Stdio::null()).output()?)?;
+    // What qpdf wrote must parse again, so a bug there can't serve a broken file.
+    succeeded(Command::new(qpdf).arg("--check").arg(&output).stdin(Stdio::null()).output()?)?;
+    fs::read(&output)
+}
+
+/// Note the attached paths on a response.
+pub fn list(headers: &mut HeaderMap, embedding: &Embedding) {
+    let paths: Vec<_> = embedding.paths.iter().map(|path| uri(path)).collect();
+    headers.insert(
+        EMBEDDED_HEADER.parse().expect("the header name is valid"),
+        HeaderValue::from_str(&paths.join(",")).expect("percent-encoded paths are valid header values"),
+    );
+}
+
+fn path(document: &DocumentFile) -> String {
+    document.name.vpath().as_rootless_path().to_string_lossy().into_owned()
+}
+
+fn embeddable(path: &str, extensions: &[String]) -> bool {
+    Path::new(path).extension()
+        .is_some_and(|extension| extensions.iter().any(|allowed| extension.eq_ignore_ascii_case(allowed.as_str())))
+}
+
+fn mime_type(path: &str) -> &'static str {
+    let extension = Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("bib") => { "application/x-bibtex" }
+        Some("json") => { "application/json" }
+        Some("yaml" | "yml") => { "application/yaml" }
+        Some("toml") => { "application/toml" }
+        Some("xml") => { "application/xml" }
+        Some("csv") => { "text/csv" }
+        Some("typ" | "txt") => { "text/plain" }
+        _ => { "application/octet-stream" }
+    }
+}
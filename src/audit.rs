@@ -0,0 +1,269 @@
+//! A JSON lines record of every compile request, for compliance rather than debugging.
+//!
+//! Each request gets exactly one line once it finished, whatever the outcome, and a job's
+//! line is written when its compile finished or the job was dropped. Document contents are
+//! never logged, and of the file names only the main file's unless `AUDIT_FILENAMES` is set.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use chrono::{DateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::auth::Principal;
+use crate::cache::hash_files;
+use crate::checksum::Checksum;
+use crate::compilers::Paged;
+use crate::config::{Config, ConfigHandle};
+use crate::docker_world::{CompileError, Compiled, DocumentFile};
+use crate::quotas::starts_compile;
+
+/// Where the records go, nowhere unless `AUDIT_LOG` is set.
+pub struct AuditLog {
+    sink: Option<Mutex<Sink>>,
+}
+
+enum Sink {
+    /// Apart from the logs, which go to stderr.
+    Stdout,
+    File { path: PathBuf, file: File, size: u64, max_size: u64, keep: usize },
+}
+
+#[derive(Serialize)]
+struct Entry {
+    at: DateTime<Utc>,
+    /// Name of the key from the keys file, if the request used one.
+    key: Option<String>,
+    path: String,
+    job: Option<String>,
+    /// SHA-256 over every file's path and contents, main file first.
+    input_sha256: Option<String>,
+    files: Option<usize>,
+    input_bytes: Option<u64>,
+    main_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filenames: Option<Vec<String>>,
+    format: Option<&'static str>,
+    pages: Option<usize>,
+    /// SHA-256 of the output, the PDF or a streamed zip, as `X-Content-SHA256` sends it.
+    output_sha256: Option<String>,
+    duration_ms: u64,
+    /// `success`, the kind of compile error, or how the request ended without a compile
+    /// result: `queued`, `rejected`, `error` or `abandoned`.
+    outcome: &'static str,
+    status: Option<u16>,
+}
+
+/// The record of one request, written when it is dropped.
+pub struct Record {
+    log: web::Data<AuditLog>,
+    entry: Entry,
+    started: Instant,
+    log_filenames: bool,
+    outcome: Option<&'static str>,
+}
+
+impl AuditLog {
+    pub fn open(config: &Config) -> io::Result<Self> {
+        let sink = match config.audit_log.as_deref() {
+            None => { None }
+            Some(path) if path == Path::new("-") => { Some(Sink::Stdout) }
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let size = file.metadata()?.len();
+                Some(Sink::File {
+                    path: path.to_path_buf(),
+                    file,
+                    size,
+                    max_size: config.audit_log_size,
+                    keep: config.audit_log_files,
+                })
+            }
+        };
+        Ok(Self { sink: sink.map(Mutex::new) })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let Some(sink) = &self.sink else { return };
+        let mut line = serde_json::to_vec(entry).expect("audit entries serialize");
+        line.push(b'\n');
+        if let Err(problem) = sink.lock().unwrap().write(&line) {
+            tracing::error!("could not write the audit log: {problem}");
+        }
+    }
+}
+
+impl Sink {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(line)?;
+                stdout.flush()
+            }
+            Sink::File { path, file, size, max_size, keep } => {
+                if *size > 0 && *size + line.len() as u64 > *max_size {
+                    *file = rotate(path, *keep)?;
+                    *size = 0;
+                }
+                file.write_all(line)?;
+                *size += line.len() as u64;
+                file.flush()
+            }
+        }
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, dropping the one beyond `keep`,
+/// and start a new file.
+fn rotate(path: &Path, keep: usize) -> io::Result<File> {
+    let numbered = |index: usize| PathBuf::from(format!("{}.{index}", path.display()));
+    for index in (1..keep).rev() {
+        if numbered(index).exists() {
+            fs::rename(numbered(index), numbered(index + 1))?;
+        }
+    }
+    fs::rename(path, numbered(1))?;
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Record {
+    fn inputs(&mut self, documents: &[DocumentFile]) {
+        let Some((main, files)) = documents.split_first() else { return };
+        let name = |file: &DocumentFile| file.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+        self.entry.input_sha256 = input_sha256(main, files);
+        self.entry.files = Some(documents.len());
+        self.entry.input_bytes = Some(documents.iter().map(|file| file.data.size().unwrap_or(0)).sum());
+        self.entry.main_file = Some(name(main));
+        if self.log_filenames {
+            self.entry.filenames = Some(documents.iter().map(name).collect());
+        }
+    }
+
+    /// Note how the compile went, in whatever shape it was output in.
+    pub fn compiled(&mut self, format: &'static str, compiled: &Result<Result<Compiled, CompileError>, Error>) {
+        if let Ok(Ok(compiled)) = compiled {
+            self.entry.output_sha256 = Some(Checksum::of(&compiled.pdf).hex());
+        }
+        self.finished(format, compiled);
+    }
+
+    /// Note how a compile went whose output was streamed, with the SHA-256 of what was sent.
+    pub fn streamed<T: Paged>(&mut self, format: &'static str, compiled: &Result<Result<T, CompileError>, Error>, sha256: Checksum) {
+        self.entry.output_sha256 = Some(sha256.hex());
+        self.finished(format, compiled);
+    }
+
+    /// Note how a compile went that produced something other than a `Compiled`.
+    pub fn finished<T: Paged>(&mut self, format: &'static str, compiled: &Result<Result<T, CompileError>, Error>) {
+        self.entry.format = Some(format);
+        self.outcome = Some(match compiled {
+            Ok(Ok(compiled)) => {
+                self.entry.pages = Some(compiled.pages());
+                "success"
+            }
+            Ok(Err(problem)) => { problem.kind() }
+            Err(_) => { "error" }
+        });
+    }
+
+    pub fn job(&mut self, id: &str) {
+        self.entry.job = Some(id.to_string());
+    }
+
+    /// Note that the job went to the shared queue, for another replica to compile.
+    pub fn queued(&mut self) {
+        self.outcome = Some("queued");
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        self.entry.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.entry.outcome = match (self.outcome, self.entry.status) {
+            (Some(outcome), _) => { outcome }
+            (None, Some(status)) if status < 500 => { "rejected" }
+            (None, Some(_)) => { "error" }
+            (None, None) => { "abandoned" }
+        };
+        self.log.write(&self.entry);
+    }
+}
+
+fn with_record(request: &HttpRequest, note: impl FnOnce(&mut Record)) {
+    if let Some(record) = request.extensions_mut().get_mut::<Record>() {
+        note(record);
+    }
+}
+
+/// Note the uploaded files of a request, the first being the main file.
+/// The `input_sha256` of a record, over every file's path and contents, main file first.
+pub fn input_sha256(main: &DocumentFile, files: &[DocumentFile]) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hash_files(&mut hasher, main, files).map(|()| hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+pub fn inputs(request: &HttpRequest, documents: &[DocumentFile]) {
+    with_record(request, |record| record.inputs(documents));
+}
+
+pub fn compiled(request: &HttpRequest, format: &'static str, compiled: &Result<Result<Compiled, CompileError>, Error>) {
+    with_record(request, |record| record.compiled(format, compiled));
+}
+
+/// Take the record of a request whose compile outlives it, like a job's.
+pub fn detach(request: &HttpRequest) -> Option<Record> {
+    request.extensions_mut().remove::<Record>()
+}
+
+/// Middleware starting a record for every compile request, for `App::wrap_fn`.
+///
+/// Runs after `authenticate`, which names the key.
+pub fn record<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let log = request.app_data::<web::Data<AuditLog>>().filter(|log| log.sink.is_some()).cloned();
+    let config = request.app_data::<web::Data<ConfigHandle>>().map(|config| config.current());
+    if let (Some(log), Some(config), true) = (log, config, starts_compile(&request)) {
+        let key = request.extensions().get::<Principal>().map(|principal| principal.0.clone());
+        let entry = Entry {
+            at: Utc::now(),
+            key,
+            path: request.path().to_string(),
+            job: None,
+            input_sha256: None,
+            files: None,
+            input_bytes: None,
+            main_file: None,
+            filenames: None,
+            format: None,
+            pages: None,
+            output_sha256: None,
+            duration_ms: 0,
+            outcome: "abandoned",
+            status: None,
+        };
+        let record = Record { log, entry, started: Instant::now(), log_filenames: config.audit_filenames, outcome: None };
+        request.extensions_mut().insert(record);
+    }
+
+    let call = service.call(request);
+    Box::pin(async move {
+        let response = call.await?;
+        let record = response.request().extensions_mut().remove::<Record>();
+        if let Some(mut record) = record {
+            record.entry.status = Some(response.status().as_u16());
+        }
+        Ok(response)
+    })
+}
@@ -0,0 +1,201 @@
+//! API keys guarding the endpoints that compile or expose documents, once any are configured.
+//!
+//! Keys come as `Authorization: Bearer <key>` or `X-Api-Key: <key>`, and if enabled as the
+//! password of Basic credentials for clients that can't send other headers. The probes,
+//! metrics, version, API docs and web UI stay open, and the admin token is accepted wherever
+//! a key is.
+//!
+//! Keys from the keys file authenticate as their name, which the logs, the metrics and the
+//! rate limiter then go by, and act for their tenant, which everything stored is kept apart by.
+
+pub mod keys;
+pub mod signature;
+
+use std::fmt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+use crate::auth::keys::ApiKey;
+use crate::config::{Config, ConfigHandle};
+use crate::errors::Code;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+const EXEMPT_PATHS: [&str; 7] = ["/healthz", "/readyz", "/metrics", "/version", "/openapi.json", "/docs", "/ui"];
+
+/// Name of the keys file entry a request authenticated with, in its extensions.
+pub struct Principal(pub String);
+
+/// The tenant of the key a request authenticated with, in its extensions.
+pub struct Tenant(pub String);
+
+#[derive(Debug)]
+enum Rejected {
+    /// No key, answered with the challenges of the accepted schemes so browsers prompt.
+    Missing { challenge: String },
+    Invalid,
+    Disabled,
+    Expired { at: chrono::DateTime<chrono::Utc> },
+    /// The key is restricted to other endpoints.
+    NotAllowed,
+}
+
+/// Whether `given` equals `expected`, taking the same time wherever they differ.
+///
+/// Both are hashed first, so not even their lengths show in the timing.
+pub fn secrets_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given), Sha256::digest(expected));
+    given.iter().zip(expected.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// The key a request carries, from `X-Api-Key` or else the `Authorization` header.
+pub fn credential(headers: &HeaderMap, config: &Config) -> Option<String> {
+    if let Some(key) = headers.get(API_KEY_HEADER) {
+        return key.to_str().ok().map(|key| key.trim().to_string());
+    }
+    let authorization = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return Some(token.trim().to_string());
+    }
+    let encoded = authorization.strip_prefix("Basic ").filter(|_| config.basic_auth)?;
+    basic_key(encoded.trim())
+}
+
+/// The key in Basic credentials: the password, or the user for `curl -u <key>:` style
+/// credentials with an empty password.
+fn basic_key(encoded: &str) -> Option<String> {
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    match password.is_empty() {
+        true => { Some(user.to_string()) }
+        false => { Some(password.to_string()) }
+    }
+}
+
+/// The entry of the keys file `given` belongs to.
+fn issued<'a>(given: &str, config: &'a Config) -> Option<&'a ApiKey> {
+    // Every key is compared, so the timing doesn't tell which one matched.
+    let digest: [u8; 32] = Sha256::digest(given).into();
+    config.keys.iter().fold(None, |found, key| match key.matches(&digest) {
+        true => { Some(key) }
+        false => { found }
+    })
+}
+
+/// The name of the keys file entry a request's key belongs to, whether or not the key is
+/// still enabled, for limits that apply before `authenticate` ran.
+pub fn key_name(headers: &HeaderMap, config: &Config) -> Option<String> {
+    issued(&credential(headers, config)?, config).map(|key| key.name.clone())
+}
+
+/// The principal a request authenticates as and its tenant, `None` for the shared keys and open servers.
+fn check(request: &ServiceRequest, config: &Config) -> Result<Option<(Principal, Tenant)>, Rejected> {
+    if (config.api_keys.is_empty() && config.keys.is_empty()) || EXEMPT_PATHS.contains(&request.path()) {
+        return Ok(None);
+    }
+    let given = credential(request.headers(), config).ok_or_else(|| {
+        let mut challenge = format!("Bearer realm=\"{}\"", config.auth_realm);
+        if config.basic_auth {
+            challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\", {challenge}", config.auth_realm);
+        }
+        Rejected::Missing { challenge }
+    })?;
+
+    let issued = issued(&given, config);
+    let shared = config.api_keys.iter().chain(&config.admin_token)
+        .fold(false, |found, key| secrets_match(&given, key) | found);
+
+    let key = match issued {
+        Some(key) => { key }
+        None if shared => { return Ok(None) }
+        None => { return Err(Rejected::Invalid) }
+    };
+    if !key.enabled {
+        return Err(Rejected::Disabled);
+    }
+    if let Some(at) = key.expired_at() {
+        return Err(Rejected::Expired { at });
+    }
+    if !key.allows(request.path()) {
+        return Err(Rejected::NotAllowed);
+    }
+    Ok(Some((Principal(key.name.clone()), Tenant(key.tenant.clone()))))
+}
+
+/// Middleware turning away requests without a valid key, for `App::wrap_fn`.
+pub fn authenticate<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let checked = match request.app_data::<web::Data<ConfigHandle>>() {
+        None => { Ok(None) }
+        Some(config) => { check(&request, &config.current()) }
+    };
+
+    match checked {
+        Err(rejected) => {
+            let response = request.error_response(rejected).map_into_right_body();
+            Box::pin(async { Ok(response) })
+        }
+        Ok(principal) => {
+            if let Some((principal, tenant)) = principal {
+                tracing::Span::current().record("principal", principal.0.as_str());
+                request.extensions_mut().insert(principal);
+                request.extensions_mut().insert(tenant);
+            }
+            let call = service.call(request);
+            Box::pin(async move { Ok(call.await?.map_into_left_body()) })
+        }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Missing { .. } => {
+                write!(f, "An API key is required, send it as a bearer token or in {API_KEY_HEADER}")
+            }
+            Rejected::Invalid => { write!(f, "The API key is not valid") }
+            Rejected::Disabled => { write!(f, "The API key is disabled") }
+            Rejected::Expired { at } => { write!(f, "The API key expired at {}", at.to_rfc3339()) }
+            Rejected::NotAllowed => { write!(f, "The API key may not use this endpoint") }
+        }
+    }
+}
+
+impl Rejected {
+    fn code(&self) -> Code {
+        match self {
+            Rejected::Missing { .. } => { Code::ApiKeyRequired }
+            Rejected::Invalid => { Code::InvalidApiKey }
+            Rejected::Disabled => { Code::ApiKeyDisabled }
+            Rejected::Expired { .. } => { Code::ApiKeyExpired }
+            Rejected::NotAllowed => { Code::EndpointNotAllowed }
+        }
+    }
+}
+
+impl ResponseError for Rejected {
+    fn status_code(&self) -> StatusCode {
+        self.code().status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Rejected::Missing { challenge } = self {
+            response.insert_header((WWW_AUTHENTICATE, challenge.as_str()));
+        }
+        response.json(self.code().body(self))
+    }
+}
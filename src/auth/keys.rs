@@ -0,0 +1,195 @@
+//! The keys file, issuing each team its own key that can be restricted, disabled or left to expire.
+//!
+//! TOML, or JSON when the file name ends in `.json`:
+//!
+//! ```toml
+//! [[keys]]
+//! name = "billing"
+//! tenant = "finance"
+//! sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! endpoints = ["/compile", "/jobs/*"]
+//! expires = "2027-01-01"
+//! compiles_per_hour = 600
+//! output_bytes_per_day = 10_000_000_000
+//! signing_secret = "shared-with-billing"
+//! fail_on_warnings = true
+//! max_concurrent_compiles = 4
+//! priorities = ["high", "normal", "low"]
+//!
+//! [[keys]]
+//! name = "reports"
+//! key = "plaintext-key"
+//! enabled = false
+//! ```
+//!
+//! A key belongs to the tenant it names, or else to a tenant of its own name. Keys of one
+//! tenant share its projects, blobs, uploads, jobs, cached results and quotas.
+
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::slots::Priority;
+
+/// A key from the keys file, naming whoever authenticates with it.
+pub struct ApiKey {
+    pub name: String,
+    pub tenant: String,
+    /// SHA-256 of the key, so plain and hashed entries compare the same way.
+    digest: [u8; 32],
+    pub enabled: bool,
+    /// Paths the key may use, a trailing `*` matching any rest. Empty allows all.
+    endpoints: Vec<String>,
+    expires: Option<DateTime<Utc>>,
+    pub limits: Limits,
+    /// Secret the key's requests must be signed with, see `signature`.
+    pub signing_secret: Option<String>,
+    /// Whether the key's compiles fail on warnings when they don't say, the server's default if unset.
+    pub fail_on_warnings: Option<bool>,
+    /// Compiles the key may run at once, `MAX_CONCURRENT_COMPILES_PER_CLIENT` if unset.
+    pub max_concurrent_compiles: Option<usize>,
+    /// The `X-Priority` values the key may send, if only these. Unset, it may send any but
+    /// `high`, which needs one of `HIGH_PRIORITY_TOKENS`.
+    pub priorities: Option<Vec<Priority>>,
+}
+
+/// Usage a key is allowed per hour or day, unlimited where `None`.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Limits {
+    pub compiles_per_hour: Option<u64>,
+    pub compiles_per_day: Option<u64>,
+    pub output_bytes_per_day: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    name: String,
+    tenant: Option<String>,
+    key: Option<String>,
+    sha256: Option<String>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+    #[serde(default)]
+    endpoints: Vec<String>,
+    expires: Option<String>,
+    compiles_per_hour: Option<u64>,
+    compiles_per_day: Option<u64>,
+    output_bytes_per_day: Option<u64>,
+    signing_secret: Option<String>,
+    fail_on_warnings: Option<bool>,
+    max_concurrent_compiles: Option<usize>,
+    priorities: Option<Vec<Priority>>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl ApiKey {
+    /// Whether `given` is this key, taking the same time wherever they differ.
+    pub fn matches(&self, given: &[u8; 32]) -> bool {
+        given.iter().zip(self.digest.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+
+    pub fn allows(&self, path: &str) -> bool {
+        self.endpoints.is_empty() || self.endpoints.iter().any(|endpoint| match endpoint.strip_suffix('*') {
+            Some(prefix) => { path.starts_with(prefix) }
+            None => { path == endpoint }
+        })
+    }
+
+    /// When the key expired, if it has.
+    pub fn expired_at(&self) -> Option<DateTime<Utc>> {
+        self.expires.filter(|expires| *expires <= Utc::now())
+    }
+}
+
+/// Read the keys in `path`, or describe what is wrong with it.
+pub fn load(path: &Path) -> Result<Vec<ApiKey>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|problem| format!("Could not read the keys file {}: {problem}", path.display()))?;
+    let file: KeysFile = match path.extension().is_some_and(|extension| extension == "json") {
+        true => { serde_json::from_str(&contents).map_err(|problem| problem.to_string()) }
+        false => { toml::from_str(&contents).map_err(|problem| problem.to_string()) }
+    }.map_err(|problem| format!("The keys file {} is invalid: {problem}", path.display()))?;
+
+    let mut keys: Vec<ApiKey> = Vec::with_capacity(file.keys.len());
+    for entry in file.keys {
+        let key = entry.parse().map_err(|problem| format!("Key {} in {}: {problem}", entry.name, path.display()))?;
+        if keys.iter().any(|known| known.name == entry.name) {
+            return Err(format!("The keys file {} names {} twice", path.display(), entry.name));
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+impl Entry {
+    fn parse(&self) -> Result<ApiKey, String> {
+        let digest = match (&self.key, &self.sha256) {
+            (Some(key), None) => { Sha256::digest(key).into() }
+            (None, Some(hash)) => { parse_digest(hash).ok_or("sha256 must be 64 hex digits")? }
+            _ => { return Err("needs exactly one of key and sha256".into()) }
+        };
+        let tenant = self.tenant.clone().unwrap_or_else(|| self.name.clone());
+        if tenant.is_empty() || tenant.chars().any(char::is_control) {
+            return Err("the tenant must be a name without control characters".into());
+        }
+        let expires = match &self.expires {
+            None => { None }
+            Some(expires) => { Some(parse_expiry(expires).ok_or("expires must be a date like 2027-01-01 or an RFC 3339 time")?) }
+        };
+        if self.max_concurrent_compiles == Some(0) {
+            return Err("max_concurrent_compiles must be at least 1".into());
+        }
+        if self.priorities.as_ref().is_some_and(Vec::is_empty) {
+            return Err("priorities must list at least one priority".into());
+        }
+        Ok(ApiKey {
+            name: self.name.clone(),
+            tenant,
+            digest,
+            enabled: self.enabled,
+            endpoints: self.endpoints.clone(),
+            expires,
+            limits: Limits {
+                compiles_per_hour: self.compiles_per_hour,
+                compiles_per_day: self.compiles_per_day,
+                output_bytes_per_day: self.output_bytes_per_day,
+            },
+            signing_secret: self.signing_secret.clone(),
+            fail_on_warnings: self.fail_on_warnings,
+            max_concurrent_compiles: self.max_concurrent_compiles,
+            priorities: self.priorities.clone(),
+        })
+    }
+}
+
+pub fn parse_digest(hash: &str) -> Option<[u8; 32]> {
+    let mut digest = [0; 32];
+    if hash.len() != 64 || !hash.is_ascii() {
+        return None;
+    }
+    for (byte, pair) in digest.iter_mut().zip(hash.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// A date expires at its start in UTC.
+fn parse_expiry(expires: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(expires) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(expires, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
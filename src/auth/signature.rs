@@ -0,0 +1,198 @@
+//! HMAC request signatures, required of keys from the keys file that have a `signing_secret`.
+//!
+//! `X-Signature` carries the hex HMAC-SHA256 of `<X-Signature-Timestamp>.<body>` under the
+//! secret, the timestamp being in unix seconds, as in our own job notifications. The body
+//! is hashed while the handler streams it, and the last chunk is withheld until it matched,
+//! so a handler never sees a complete body that wasn't signed. That needs its length up front.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::auth::keys::parse_digest;
+use crate::auth::Principal;
+use crate::config::{Config, ConfigHandle};
+use crate::errors::Code;
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+#[derive(Debug)]
+enum Rejected {
+    Missing,
+    Stale { skew: u64 },
+    Invalid,
+    LengthRequired,
+}
+
+/// How far a signed body got, shared between the payload stream and the middleware.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Verified {
+    Pending,
+    Matched,
+    Mismatched,
+}
+
+/// The signature a request carries and its body length, once the timestamp is recent enough.
+fn signed(request: &ServiceRequest, config: &Config) -> Result<(Hmac<Sha256>, [u8; 32], u64), Rejected> {
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+    let (Some(signature), Some(timestamp)) = (header(SIGNATURE_HEADER), header(TIMESTAMP_HEADER)) else {
+        return Err(Rejected::Missing);
+    };
+    let expected = parse_digest(signature).ok_or(Rejected::Invalid)?;
+    let length = body_length(request)?;
+    let sent: i64 = timestamp.parse().map_err(|_| Rejected::Invalid)?;
+    let skew = config.signature_max_skew.as_secs();
+    if sent.abs_diff(Utc::now().timestamp()) > skew {
+        return Err(Rejected::Stale { skew });
+    }
+
+    let principal = request.extensions().get::<Principal>().map(|principal| principal.0.clone());
+    let secret = config.keys.iter()
+        .find(|key| Some(&key.name) == principal.as_ref())
+        .and_then(|key| key.signing_secret.as_deref())
+        .unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC keys can have any length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    Ok((mac, expected, length))
+}
+
+/// The declared body length, zero for requests without a body.
+fn body_length(request: &ServiceRequest) -> Result<u64, Rejected> {
+    match request.headers().get(CONTENT_LENGTH) {
+        Some(length) => { length.to_str().ok().and_then(|length| length.parse().ok()).ok_or(Rejected::LengthRequired) }
+        None if request.headers().contains_key(TRANSFER_ENCODING) => { Err(Rejected::LengthRequired) }
+        None => { Ok(0) }
+    }
+}
+
+fn outcome(mac: Hmac<Sha256>, expected: &[u8; 32]) -> Verified {
+    match mac.verify_slice(expected) {
+        Ok(()) => { Verified::Matched }
+        Err(_) => { Verified::Mismatched }
+    }
+}
+
+/// Replace the payload of `request` with one checking its signature as it is read.
+fn verify_body(request: &mut ServiceRequest, mac: Hmac<Sha256>, expected: [u8; 32], length: u64) -> Rc<Cell<Verified>> {
+    if length == 0 {
+        return Rc::new(Cell::new(outcome(mac, &expected)));
+    }
+
+    let verified = Rc::new(Cell::new(Verified::Pending));
+    let state = verified.clone();
+    let mut read = 0;
+    let mut mac = Some(mac);
+    let payload = request.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+        let Some(current) = mac.as_mut() else { return Ok(chunk) };
+        current.update(&chunk);
+        if read < length {
+            return Ok(chunk);
+        }
+        if let Some(mac) = mac.take() {
+            state.set(outcome(mac, &expected));
+        }
+        match state.get() {
+            Verified::Matched => { Ok(chunk) }
+            _ => { Err(PayloadError::Io(io::Error::new(io::ErrorKind::InvalidData, "the signature does not match the body"))) }
+        }
+    });
+    request.set_payload(Payload::from(payload.boxed_local()));
+    verified
+}
+
+/// Middleware checking the signatures of keys that require them, for `App::wrap_fn`.
+///
+/// Runs after `authenticate`, which names the key.
+pub fn verify<S, B>(
+    mut request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let config = request.app_data::<web::Data<ConfigHandle>>().map(|config| config.current());
+    let principal = request.extensions().get::<Principal>().map(|principal| principal.0.clone());
+    let required = config.as_ref().zip(principal).is_some_and(|(config, name)| {
+        config.keys.iter().any(|key| key.name == name && key.signing_secret.is_some())
+    });
+    let checked = match (config, required) {
+        (Some(config), true) => { signed(&request, &config).map(Some) }
+        _ => { Ok(None) }
+    };
+
+    let verified = match checked {
+        Err(rejected) => {
+            let response = request.error_response(rejected).map_into_right_body();
+            return Box::pin(async { Ok(response) });
+        }
+        Ok(None) => { None }
+        Ok(Some((mac, expected, length))) => { Some(verify_body(&mut request, mac, expected, length)) }
+    };
+    if verified.as_ref().is_some_and(|verified| verified.get() == Verified::Mismatched) {
+        let response = request.error_response(Rejected::Invalid).map_into_right_body();
+        return Box::pin(async { Ok(response) });
+    }
+
+    let call = service.call(request);
+    Box::pin(async move {
+        let response = call.await?;
+        match verified.map(|verified| verified.get()) {
+            None | Some(Verified::Matched) => { Ok(response.map_into_left_body()) }
+            // Also when the handler answered without reading the whole body.
+            Some(_) => {
+                let (request, _) = response.into_parts();
+                Ok(ServiceResponse::new(request, Rejected::Invalid.error_response()).map_into_right_body())
+            }
+        }
+    })
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Missing => {
+                write!(f, "This key must sign its requests, send {SIGNATURE_HEADER} and {TIMESTAMP_HEADER}")
+            }
+            Rejected::Stale { skew } => { write!(f, "{TIMESTAMP_HEADER} is more than {skew} seconds off") }
+            Rejected::Invalid => { write!(f, "The request signature is not valid") }
+            Rejected::LengthRequired => { write!(f, "Signed requests with a body need a Content-Length") }
+        }
+    }
+}
+
+impl Rejected {
+    fn code(&self) -> Code {
+        match self {
+            Rejected::Missing => { Code::SignatureRequired }
+            Rejected::Stale { .. } => { Code::SignatureStale }
+            Rejected::Invalid => { Code::InvalidSignature }
+            Rejected::LengthRequired => { Code::LengthRequired }
+        }
+    }
+}
+
+impl ResponseError for Rejected {
+    fn status_code(&self) -> StatusCode {
+        self.code().status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.code().body(self))
+    }
+}
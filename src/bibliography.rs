@@ -0,0 +1,272 @@
+//! `POST /preflight/bibliography`, checking bibliography files on their own before a document
+//! cites them, so a broken entry is found at upload rather than as an error at
+//! `#bibliography`.
+//!
+//! The upload has one or more `.bib` or `.yml`/`.yaml` files, parsed with hayagriva like
+//! typst parses them. The report lists for each file what doesn't parse, with line, column
+//! and the key of the entry it is in, and the entries lacking what common styles print:
+//! a title, an author or editor and a date, and a journal for articles. Keys more than one
+//! entry has, within a file or across the files, are listed with where each is, since typst
+//! refuses those once the files make up one bibliography. A report with problems is still a
+//! 200, `valid` says whether there are any.
+
+use actix_multipart::Multipart;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use hayagriva::io::BibLaTeXError;
+use hayagriva::types::EntryType;
+use hayagriva::{Entry, Library};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::config::CurrentConfig;
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::multipart::read_documents;
+use crate::openapi::Upload;
+
+#[derive(Serialize, ToSchema)]
+pub struct Preflight {
+    /// Whether no file has any problem below.
+    valid: bool,
+    files: Vec<FileReport>,
+    /// Keys more than one entry has.
+    duplicates: Vec<Duplicate>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FileReport {
+    file: String,
+    /// Entries the file has, as far as it parses.
+    entries: usize,
+    /// What doesn't parse.
+    errors: Vec<ParseProblem>,
+    /// Entries lacking fields common styles need.
+    incomplete: Vec<Incomplete>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ParseProblem {
+    message: String,
+    /// Counting from 1, if the parser says where.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+    /// The entry the problem is in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Incomplete {
+    key: String,
+    /// Where the entry starts, counting from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    /// The entry's type, like `article` or `book`.
+    kind: String,
+    missing: Vec<&'static str>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Duplicate {
+    key: String,
+    places: Vec<Place>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Place {
+    file: String,
+    line: usize,
+}
+
+/// A bibliography format typst reads.
+#[derive(Clone, Copy)]
+enum Format {
+    BibLatex,
+    Yaml,
+}
+
+/// An entry's key where the file has it.
+struct KeyAt {
+    key: String,
+    /// Byte offset of the entry.
+    offset: usize,
+    line: usize,
+}
+
+/// Check bibliography files, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    request_body(content = Upload, content_type = "multipart/form-data", description = "The `.bib`, `.yml` or `.yaml` files"),
+    responses(
+        (status = 200, description = "What was found", body = Preflight),
+        (status = 400, description = "The upload is invalid, a file isn't UTF-8 or isn't a bibliography", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+    ),
+)]
+#[post("/preflight/bibliography")]
+async fn preflight(request: HttpRequest, payload: Multipart, config: CurrentConfig) -> Result<HttpResponse, Error> {
+    let documents = read_documents(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least one bibliography"));
+    }
+    let mut sources = Vec::new();
+    for document in documents {
+        let name = document.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+        let format = match name.rsplit_once('.').map(|(_, extension)| extension.to_lowercase()).as_deref() {
+            Some("bib") => { Format::BibLatex }
+            Some("yml" | "yaml") => { Format::Yaml }
+            _ => { return Err(Code::InvalidParameter.error(format!("{name} is no .bib, .yml or .yaml file"))) }
+        };
+        let data = document.data.load().map_err(|problem| Code::Internal.error(problem))?;
+        let text = String::from_utf8(data.to_vec()).map_err(|_| Code::InvalidBody.error(format!("{name} isn't UTF-8")))?;
+        sources.push((name, format, text));
+    }
+    let preflight = web::block(move || check(&sources)).await?;
+    Ok(HttpResponse::Ok().json(preflight))
+}
+
+fn check(sources: &[(String, Format, String)]) -> Preflight {
+    let mut files = Vec::new();
+    let mut duplicates: Vec<Duplicate> = Vec::new();
+    for (name, format, text) in sources {
+        let keys = match format {
+            Format::BibLatex => { biblatex_keys(text) }
+            Format::Yaml => { yaml_keys(text) }
+        };
+        for key in &keys {
+            let place = Place { file: name.clone(), line: key.line };
+            match duplicates.iter_mut().find(|duplicate| duplicate.key == key.key) {
+                Some(duplicate) => { duplicate.places.push(place) }
+                None => { duplicates.push(Duplicate { key: key.key.clone(), places: vec![place] }) }
+            }
+        }
+        files.push(check_file(name, *format, text, &keys));
+    }
+    duplicates.retain(|duplicate| duplicate.places.len() > 1);
+    let valid = duplicates.is_empty() && files.iter().all(|file| file.errors.is_empty() && file.incomplete.is_empty());
+    Preflight { valid, files, duplicates }
+}
+
+fn check_file(name: &str, format: Format, text: &str, keys: &[KeyAt]) -> FileReport {
+    let parsed = match format {
+        Format::BibLatex => {
+            hayagriva::io::from_biblatex_str(text).map_err(|errors| {
+                errors.iter()
+                    .map(|error| {
+                        let span = match error {
+                            BibLaTeXError::Parse(error) => { error.span.clone() }
+                            BibLaTeXError::Type(error) => { error.span.clone() }
+                        };
+                        problem(error.to_string(), text, Some(span.start), keys)
+                    })
+                    .collect()
+            })
+        }
+        Format::Yaml => {
+            hayagriva::io::from_yaml_str(text).map_err(|error| {
+                let offset = error.location().map(|location| location.index());
+                vec![problem(error.to_string(), text, offset, keys)]
+            })
+        }
+    };
+    match parsed {
+        Ok(library) => {
+            FileReport { file: name.to_string(), entries: library.len(), errors: Vec::new(), incomplete: incomplete(&library, keys) }
+        }
+        Err(errors) => { FileReport { file: name.to_string(), entries: keys.len(), errors, incomplete: Vec::new() } }
+    }
+}
+
+/// A parse problem at byte `offset` of `text`, in the last entry starting before it.
+fn problem(message: String, text: &str, offset: Option<usize>, keys: &[KeyAt]) -> ParseProblem {
+    let Some(offset) = offset.map(|offset| offset.min(text.len())) else {
+        return ParseProblem { message, line: None, column: None, key: None };
+    };
+    let before = &text.as_bytes()[..offset];
+    let line = before.iter().filter(|&&byte| byte == b'\n').count() + 1;
+    let line_start = before.iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+    let column = String::from_utf8_lossy(&before[line_start..]).chars().count() + 1;
+    let key = keys.iter().rev().find(|key| key.offset <= offset).map(|key| key.key.clone());
+    ParseProblem { message, line: Some(line), column: Some(column), key }
+}
+
+fn incomplete(library: &Library, keys: &[KeyAt]) -> Vec<Incomplete> {
+    library.iter()
+        .filter_map(|entry| {
+            let missing = missing(entry);
+            (!missing.is_empty()).then(|| Incomplete {
+                key: entry.key().to_string(),
+                line: keys.iter().find(|key| key.key == entry.key()).map(|key| key.line),
+                kind: format!("{:?}", entry.entry_type()).to_lowercase(),
+                missing,
+            })
+        })
+        .collect()
+}
+
+/// The fields common styles print that `entry` lacks, see the module docs.
+fn missing(entry: &Entry) -> Vec<&'static str> {
+    let mut missing = Vec::new();
+    if entry.title().is_none() {
+        missing.push("title");
+    }
+    if entry.authors().is_none() && entry.editors().is_none() {
+        missing.push("author");
+    }
+    if entry.date().is_none() {
+        missing.push("date");
+    }
+    let journal = entry.parents().iter().any(|parent| parent.title().is_some());
+    if matches!(entry.entry_type(), EntryType::Article) && !journal {
+        missing.push("journal");
+    }
+    missing
+}
+
+/// The keys of the entries of a BibLaTeX file, `@type{key,` at the start of a line, leaving
+/// out `@string`, `@preamble` and `@comment`.
+fn biblatex_keys(text: &str) -> Vec<KeyAt> {
+    lines(text)
+        .filter_map(|(offset, line, content)| {
+            let indent = content.len() - content.trim_start().len();
+            let rest = content.trim_start().strip_prefix('@')?;
+            let (kind, rest) = rest.split_once(['{', '('])?;
+            let kind = kind.trim().to_lowercase();
+            if matches!(kind.as_str(), "string" | "preamble" | "comment") || kind.is_empty() {
+                return None;
+            }
+            let key = rest.split(',').next()?.trim();
+            (!key.is_empty()).then(|| KeyAt { key: key.to_string(), offset: offset + indent, line })
+        })
+        .collect()
+}
+
+/// The keys of the entries of a hayagriva YAML file, the mapping keys without indentation.
+fn yaml_keys(text: &str) -> Vec<KeyAt> {
+    lines(text)
+        .filter_map(|(offset, line, content)| {
+            if content.starts_with([' ', '\t', '#', '-']) {
+                return None;
+            }
+            let (key, _) = content.split_once(':')?;
+            let key = key.trim().trim_matches(['"', '\'']);
+            (!key.is_empty()).then(|| KeyAt { key: key.to_string(), offset, line })
+        })
+        .collect()
+}
+
+/// The lines of `text` with the byte offset they start at and their number from 1.
+fn lines(text: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    text.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line.trim_end_matches(['\r', '\n'])))
+    })
+    .enumerate()
+    .map(|(index, (offset, line))| (offset, index + 1, line))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(preflight);
+}
@@ -0,0 +1,317 @@
+//! Files kept by their SHA-256, so clients only upload what the server hasn't seen yet.
+//!
+//! A client first sends its manifest to `POST /blobs/negotiate`, as
+//! `{"files": [{"path": "main.typ", "sha256": "..."}]}` with the main file first, and gets
+//! back the hashes the server lacks. It uploads each with `PUT /blobs/{sha256}` and then
+//! compiles with `POST /compile/manifest`, sending the same manifest. A blob evicted in
+//! between fails the compile with a 409 listing exactly which paths and hashes are missing.
+//!
+//! Blobs are kept per tenant, so nobody can learn which files another tenant uploaded, and the
+//! least recently used are evicted beyond `BLOB_STORE_MB`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use actix_web::{post, put, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use utoipa::ToSchema;
+use crate::auth::keys::parse_digest;
+use crate::cache::CompileCache;
+use crate::compilers::{compile_blocking, respond, Compilers, JsonOutput, VersionQuery};
+use crate::config::{Config, CurrentConfig, OptionsQuery};
+use crate::docker_world::{file_id, DocumentFile, ErrorOutput, FileData, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::multipart::{allowed, next, valid_name};
+use crate::slots::CompileSlots;
+use crate::uploads::owner;
+use crate::{audit, sniff};
+
+/// A blob is stored per owner, the key's tenant or `None` for shared keys and open servers.
+type BlobKey = (Option<String>, [u8; 32]);
+
+/// The blobs of every owner, least recently used evicted first.
+pub struct Blobs {
+    capacity: u64,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<BlobKey, (FileData, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    by_use: BTreeMap<u64, BlobKey>,
+    tick: u64,
+    size: u64,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct Manifest {
+    /// Every file of the document, the main file first.
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct ManifestEntry {
+    path: String,
+    /// Hex SHA-256 of the contents.
+    sha256: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Missing {
+    /// Hashes to upload before compiling, each once.
+    missing: Vec<String>,
+}
+
+/// Response body of a compile whose manifest refers to blobs the server doesn't have.
+#[derive(Serialize, ToSchema)]
+pub struct MissingBlobs {
+    /// Always `missing_blobs`.
+    error: &'static str,
+    missing: Vec<ManifestEntry>,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &BlobKey) -> Option<FileData> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, used) = self.by_key.get_mut(key)?;
+        self.by_use.remove(used);
+        *used = tick;
+        self.by_use.insert(tick, key.clone());
+        Some(data.clone())
+    }
+}
+
+impl Blobs {
+    pub fn new(config: &Config) -> Self {
+        Self { capacity: config.blob_store_size, entries: Mutex::new(Entries::default()) }
+    }
+
+    /// Drop the blobs of `tenant`.
+    pub fn remove_tenant(&self, tenant: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let (by_key, by_use) = (&mut entries.by_key, &mut entries.by_use);
+        let mut freed = 0;
+        by_key.retain(|(owner, _), (data, used)| {
+            let keep = owner.as_deref() != Some(tenant);
+            if !keep {
+                by_use.remove(used);
+                freed += data.size().unwrap_or(0);
+            }
+            keep
+        });
+        entries.size = entries.size.saturating_sub(freed);
+    }
+
+    fn get(&self, owner: &Option<String>, digest: [u8; 32]) -> Option<FileData> {
+        self.entries.lock().unwrap().touch(&(owner.clone(), digest))
+    }
+
+    fn insert(&self, owner: Option<String>, digest: [u8; 32], data: FileData, size: u64) {
+        if size > self.capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let key = (owner, digest);
+        if entries.touch(&key).is_some() {
+            return;
+        }
+        entries.by_key.insert(key.clone(), (data, entries.tick));
+        entries.by_use.insert(entries.tick, key);
+        entries.size += size;
+
+        while entries.size > self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else { break };
+            if let Some((evicted, _)) = entries.by_key.remove(&oldest) {
+                entries.size -= evicted.size().unwrap_or(0);
+            }
+        }
+    }
+}
+
+fn digest(entry: &ManifestEntry) -> Result<[u8; 32], Error> {
+    parse_digest(&entry.sha256.to_lowercase())
+        .ok_or_else(|| Code::InvalidDigest.error(format!("The sha256 of {} must be 64 hex digits", entry.path)))
+}
+
+/// Check the manifest against the upload limits, as if its files were uploaded.
+fn check(manifest: &Manifest, config: &Config) -> Result<(), Error> {
+    if manifest.files.is_empty() {
+        return Err(Code::MissingMain.error("The manifest needs at least the main file"));
+    }
+    if let Some(max) = config.max_files.filter(|max| manifest.files.len() > *max) {
+        return Err(Code::TooManyFiles.error(format!("An upload may have at most {max} files")));
+    }
+    for entry in &manifest.files {
+        valid_name(&entry.path, config.max_filename_length).map_err(|problem| Code::InvalidFilename.error(problem))?;
+        allowed(&entry.path, &config.allowed_extensions, config.allow_extensionless).map_err(|problem| Code::FileTypeNotAllowed.error(problem))?;
+        digest(entry)?;
+    }
+    Ok(())
+}
+
+/// Tell which blobs of a manifest the server lacks.
+#[utoipa::path(
+    tag = "compile",
+    request_body = Manifest,
+    responses(
+        (status = 200, description = "The hashes to upload", body = Missing),
+        (status = 400, description = "The manifest is invalid", body = ErrorOutput),
+    ),
+)]
+#[post("/blobs/negotiate")]
+async fn negotiate(
+    request: HttpRequest,
+    manifest: web::Json<Manifest>,
+    config: CurrentConfig,
+    blobs: web::Data<Blobs>,
+) -> Result<HttpResponse, Error> {
+    check(&manifest, &config)?;
+    let owner = owner(&request);
+    let mut missing = BTreeSet::new();
+    for entry in &manifest.files {
+        if blobs.get(&owner, digest(entry)?).is_none() {
+            missing.insert(entry.sha256.to_lowercase());
+        }
+    }
+    Ok(HttpResponse::Ok().json(Missing { missing: missing.into_iter().collect() }))
+}
+
+/// Upload a blob, refused unless its contents hash to the path.
+#[utoipa::path(
+    tag = "compile",
+    params(("sha256" = String, Path, description = "Hex SHA-256 of the body")),
+    request_body(content = [u8], content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The blob is stored"),
+        (status = 400, description = "The body doesn't hash to the path", body = ErrorOutput),
+        (status = 413, description = "The blob is larger than `MAX_FILE_SIZE_KB`", body = ErrorOutput),
+    ),
+)]
+#[put("/blobs/{sha256}")]
+async fn upload_blob(
+    request: HttpRequest,
+    sha256: web::Path<String>,
+    mut payload: web::Payload,
+    config: CurrentConfig,
+    blobs: web::Data<Blobs>,
+) -> Result<HttpResponse, Error> {
+    let expected = parse_digest(&sha256.to_lowercase())
+        .ok_or_else(|| Code::InvalidDigest.error("The path must be the 64 hex digit SHA-256 of the body"))?;
+
+    let mut hasher = Sha256::new();
+    let (mut data, mut spooled): (Vec<u8>, Option<NamedTempFile>) = (vec![], None);
+    let mut size = 0;
+    while let Some(chunk) = next(&mut payload, config.upload_idle_timeout).await? {
+        let bytes = chunk.map_err(|problem| Code::InvalidBody.error(problem))?;
+        size += bytes.len();
+        if let Some(max) = config.max_file_size.filter(|max| size > *max) {
+            return Err(Code::FileTooLarge.error(format!("A blob may have at most {max} bytes")));
+        }
+        hasher.update(&bytes);
+        if spooled.is_none() && data.len() + bytes.len() > config.spool_threshold {
+            let mut file = NamedTempFile::new_in(&config.spool_dir).map_err(|problem| Code::Internal.error(problem))?;
+            file.write_all(&data).map_err(|problem| Code::Internal.error(problem))?;
+            data = vec![];
+            spooled = Some(file);
+        }
+        match &mut spooled {
+            Some(file) => { file.write_all(&bytes).map_err(|problem| Code::Internal.error(problem))?; }
+            None => { data.extend_from_slice(&bytes); }
+        }
+    }
+    if <[u8; 32]>::from(hasher.finalize()) != expected {
+        return Err(Code::DigestMismatch.error("The body doesn't hash to the SHA-256 in the path"));
+    }
+
+    let data = match spooled {
+        Some(file) => { FileData::Spooled(Arc::new(file)) }
+        None => { FileData::Memory(data.into()) }
+    };
+    blobs.insert(owner(&request), expected, data, size as u64);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Compile the files of a manifest from the blobs uploaded before.
+#[utoipa::path(
+    tag = "compile",
+    params(VersionQuery, OptionsQuery),
+    request_body = Manifest,
+    responses(
+        (status = 200, description = "The PDF, or with `include=deps` the PDF and what the compile read", content(
+            ([u8] = "application/octet-stream"),
+            (JsonOutput = "application/json"),
+        )),
+        (status = 400, description = "The manifest is invalid or the document has errors", body = ErrorOutput),
+        (status = 409, description = "Blobs the manifest refers to are missing, upload them and compile again", body = MissingBlobs),
+        (status = 422, description = "The document exceeded the page or resource limits, or with `fail_on_warnings` compiled with warnings", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "The compile timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/compile/manifest")]
+#[allow(clippy::too_many_arguments)]
+async fn compile_manifest(
+    request: HttpRequest,
+    manifest: web::Json<Manifest>,
+    config: CurrentConfig,
+    blobs: web::Data<Blobs>,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    let options = config.compile_options(&request)?;
+    check(&manifest, &config)?;
+
+    let owner = owner(&request);
+    let (mut documents, mut missing, mut total) = (vec![], vec![], 0);
+    for entry in &manifest.files {
+        match blobs.get(&owner, digest(entry)?) {
+            Some(data) => {
+                total += data.size().unwrap_or(0);
+                documents.push(DocumentFile { name: file_id(&entry.path), data });
+            }
+            None => { missing.push(entry.clone()) }
+        }
+    }
+    if !missing.is_empty() {
+        return Ok(HttpResponse::build(Code::MissingBlobs.status()).json(MissingBlobs { error: Code::MissingBlobs.as_str(), missing }));
+    }
+    if let Some(max) = config.max_upload_size.filter(|max| total > *max as u64) {
+        return Err(Code::UploadTooLarge.error(format!("The upload is larger than {max} bytes")));
+    }
+    for document in &documents {
+        let path = document.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+        if sniff::applies(&path) {
+            let data = document.data.load().map_err(|problem| Code::Internal.error(problem))?;
+            sniff::check(&path, &data).map_err(|problem| Code::InvalidFileContent.error(problem))?;
+        }
+    }
+    audit::inputs(&request, &documents);
+
+    let compiled = compile_blocking(
+        &slots,
+        &metrics,
+        &cache,
+        compiler.clone(),
+        documents,
+        options.clone(),
+        config.cache_max_age,
+    ).await;
+    audit::compiled(&request, options.output_format(), &compiled);
+    respond(compiler.version(), compiled?, &options, &metrics)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(negotiate)
+        .service(upload_blob)
+        .service(compile_manifest);
+}
@@ -0,0 +1,257 @@
+mod disk;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use crate::compilers::CompileOptions;
+use crate::config::Config;
+use crate::docker_world::{Compiled, DocumentFile, FileData};
+use crate::store::SharedStore;
+use self::disk::DiskCache;
+
+/// Hash of everything a stateless compile depends on.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey([u8; 32]);
+
+/// Feed the paths and contents of `main` and then the other files in path order to `hasher`,
+/// `None` if a spooled one couldn't be read.
+pub fn hash_files(hasher: &mut Sha256, main: &DocumentFile, files: &[DocumentFile]) -> Option<()> {
+    let mut files: Vec<_> = files.iter().collect();
+    files.sort_by_key(|file| file.name.vpath().as_rootless_path().to_path_buf());
+    for file in std::iter::once(main).chain(files) {
+        let path = file.name.vpath().as_rootless_path().to_string_lossy();
+        hasher.update((path.len() as u64).to_le_bytes());
+        hasher.update(path.as_bytes());
+        match &file.data {
+            FileData::Memory(bytes) => {
+                hasher.update((bytes.len() as u64).to_le_bytes());
+                hasher.update(&**bytes);
+            }
+            FileData::Spooled(spooled) => {
+                let mut reader = fs::File::open(spooled.path()).ok()?;
+                hasher.update(reader.metadata().ok()?.len().to_le_bytes());
+                io::copy(&mut reader, hasher).ok()?;
+            }
+        }
+    }
+    Some(())
+}
+
+impl CacheKey {
+    /// Hash the compiler version, the options that change the output, `sys.inputs` included,
+    /// the tenant, and every file.
+    ///
+    /// `main` is hashed first, the other files in path order, so upload order doesn't matter.
+    /// Documents reading the clock are only cached with a pinned time, so the timezone only
+    /// matters for those, but hashing it keeps the key honest if that ever changes.
+    /// Spooled files are streamed through the hash, `None` means one of them couldn't be read.
+    pub fn new(version: &str, main: &DocumentFile, files: &[DocumentFile], options: &CompileOptions) -> Option<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(version.as_bytes());
+        hasher.update(options.max_pages.to_le_bytes());
+        hasher.update(options.timezone.name().as_bytes());
+        for (key, value) in &options.inputs {
+            for part in [key, value] {
+                hasher.update((part.len() as u64).to_le_bytes());
+                hasher.update(part.as_bytes());
+            }
+        }
+        if let Some(max_size) = options.max_image_size {
+            hasher.update(b"max_image_size");
+            hasher.update(max_size.to_le_bytes());
+        }
+        if options.optimizer.is_some() {
+            hasher.update(b"optimize");
+        }
+        if options.pdf_a {
+            hasher.update(b"pdf_a");
+        }
+        if let Some(embedding) = &options.embedding {
+            hasher.update(b"embedding");
+            for path in &embedding.paths {
+                hasher.update((path.len() as u64).to_le_bytes());
+                hasher.update(path.as_bytes());
+            }
+        }
+        if let Some(now) = options.now {
+            hasher.update(b"now");
+            hasher.update(now.to_rfc3339().as_bytes());
+        }
+        // So no tenant can tell from the cache's timing which documents another compiled.
+        if let Some(tenant) = &options.tenant {
+            hasher.update(b"tenant");
+            hasher.update((tenant.len() as u64).to_le_bytes());
+            hasher.update(tenant.as_bytes());
+        }
+        hash_files(&mut hasher, main, files)?;
+        Some(Self(hasher.finalize().into()))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+/// Compiled documents kept in memory, evicting the least recently used beyond a total size,
+/// optionally backed by a directory that survives restarts and a store shared by replicas.
+pub struct CompileCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    disk: Option<DiskCache>,
+    shared: Option<Arc<dyn SharedStore>>,
+    shared_max_age: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Copies of the in-memory entry count and size, so reporting them doesn't wait for compiles.
+    memory_entries: AtomicUsize,
+    memory_size: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<CacheKey, (Compiled, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    by_use: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl CompileCache {
+    pub fn new(config: &Config, shared: Option<Arc<dyn SharedStore>>) -> Result<Self, String> {
+        let disk = match config.compile_cache_dir.clone() {
+            Some(dir) => {
+                let disk = DiskCache::new(dir.clone(), config.compile_cache_disk_size, config.compile_cache_disk_max_age)
+                    .map_err(|problem| format!("cannot create the compile cache {}: {problem}", dir.display()))?;
+                Some(disk)
+            }
+            None => { None }
+        };
+        Ok(Self {
+            capacity: config.compile_cache_size,
+            entries: Mutex::new(Entries::default()),
+            disk,
+            shared,
+            shared_max_age: config.redis_cache_max_age,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            memory_entries: AtomicUsize::new(0),
+            memory_size: AtomicUsize::new(0),
+        })
+    }
+
+    /// The disk cache directory and whether entries can be written to it, if there is one.
+    pub fn check_disk(&self) -> Option<(PathBuf, io::Result<()>)> {
+        self.disk.as_ref().map(|disk| (disk.dir().to_path_buf(), disk.check_writable()))
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Compiled> {
+        let found = self.lookup(key);
+        let counter = match found.is_some() {
+            true => { &self.hits }
+            false => { &self.misses }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Lookups that found an entry and ones that didn't, since startup.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    /// Bytes of PDFs held in memory.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size.load(Ordering::Relaxed)
+    }
+
+    /// PDFs held in memory.
+    pub fn memory_entries(&self) -> usize {
+        self.memory_entries.load(Ordering::Relaxed)
+    }
+
+    fn lookup(&self, key: &CacheKey) -> Option<Compiled> {
+        if let Some(compiled) = self.get_in_memory(key) {
+            return Some(compiled);
+        }
+
+        let compiled = match self.disk.as_ref().and_then(|disk| disk.get(key)) {
+            Some(compiled) => { compiled }
+            None => {
+                let contents = self.shared.as_ref()?.get(&Self::shared_key(key))?;
+                disk::decode(&contents)?
+            }
+        };
+        self.insert_in_memory(*key, &compiled);
+        Some(compiled)
+    }
+
+    /// Remember `compiled` unless it is not reproducible.
+    pub fn insert(&self, key: CacheKey, compiled: &Compiled) {
+        if !compiled.cacheable {
+            return;
+        }
+
+        self.insert_in_memory(key, compiled);
+        if let Some(disk) = &self.disk {
+            disk.insert(&key, compiled);
+        }
+        if let Some(shared) = &self.shared {
+            match disk::encode(compiled) {
+                Ok(contents) => { shared.set(&Self::shared_key(&key), &contents, self.shared_max_age) }
+                Err(problem) => { tracing::warn!("could not encode compile cache entry: {problem}") }
+            }
+        }
+    }
+
+    /// Trim the disk cache to its size and age limits.
+    pub fn prune(&self) {
+        if let Some(disk) = &self.disk {
+            disk.prune();
+        }
+    }
+
+    fn shared_key(key: &CacheKey) -> String {
+        format!("typstapi:compile:{}", key.to_hex())
+    }
+
+    fn get_in_memory(&self, key: &CacheKey) -> Option<Compiled> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.tick += 1;
+        let (compiled, used) = entries.by_key.get_mut(key)?;
+        entries.by_use.remove(used);
+        *used = entries.tick;
+        entries.by_use.insert(entries.tick, *key);
+        Some(compiled.clone())
+    }
+
+    fn insert_in_memory(&self, key: CacheKey, compiled: &Compiled) {
+        if compiled.pdf.len() > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.tick += 1;
+        if let Some((previous, used)) = entries.by_key.insert(key, (compiled.clone(), entries.tick)) {
+            entries.by_use.remove(&used);
+            entries.size -= previous.pdf.len();
+        }
+        entries.by_use.insert(entries.tick, key);
+        entries.size += compiled.pdf.len();
+
+        while entries.size > self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else { break };
+            if let Some((evicted, _)) = entries.by_key.remove(&oldest) {
+                entries.size -= evicted.pdf.len();
+            }
+        }
+        self.memory_entries.store(entries.by_key.len(), Ordering::Relaxed);
+        self.memory_size.store(entries.size, Ordering::Relaxed);
+    }
+}
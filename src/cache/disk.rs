@@ -0,0 +1,200 @@
+//! Compile results persisted across restarts.
+//!
+//! Each entry is one file named after its key: a magic number, the length of a JSON header
+//! with the warnings and dependencies, the header, the PDF, and a SHA-256 over all of that,
+//! so truncated or corrupt files are recognized and treated as misses. Shared stores keep
+//! entries in the same format.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::cache::CacheKey;
+use crate::docker_world::{Compiled, Dependencies, Warning};
+
+const MAGIC: &[u8; 8] = b"TYPSTPDF";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// Missing from entries written before page counts were recorded.
+    #[serde(default)]
+    pages: usize,
+    /// Missing from entries written before page hashes were recorded.
+    #[serde(default)]
+    page_hashes: Vec<String>,
+    warnings: Vec<Warning>,
+    dependencies: Dependencies,
+}
+
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+}
+
+impl DiskCache {
+    /// Open the cache in `dir`, creating the directory if need be.
+    pub fn new(dir: PathBuf, max_size: u64, max_age: Duration) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_size, max_age })
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Compiled> {
+        let path = self.path(key);
+        let contents = fs::read(&path).ok()?;
+        match decode(&contents) {
+            Some(compiled) => {
+                // The modification time doubles as the last use for pruning.
+                if let Ok(file) = fs::File::options().write(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(compiled)
+            }
+            None => {
+                tracing::warn!("discarding corrupt compile cache entry {}", path.display());
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Write the entry to a temporary file first, so concurrent writers of one key never interleave.
+    pub fn insert(&self, key: &CacheKey, compiled: &Compiled) {
+        let written = encode(compiled).and_then(|contents| {
+            let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+            file.write_all(&contents)?;
+            file.persist(self.path(key))?;
+            Ok(())
+        });
+        if let Err(problem) = written {
+            tracing::warn!("could not write compile cache entry: {problem}");
+        }
+    }
+
+    /// Delete entries unused for longer than the maximum age, then the least recently
+    /// used ones until the cache fits its size limit.
+    pub fn prune(&self) {
+        let now = SystemTime::now();
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir).into_iter().flatten().flatten() {
+            let Ok(metadata) = entry.metadata() else { continue };
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(now);
+            if now.duration_since(modified).unwrap_or_default() > self.max_age {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            entries.push((modified, metadata.len(), entry.path()));
+        }
+
+        entries.sort();
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= len;
+            }
+        }
+    }
+
+    /// Whether entries can be written, by creating and deleting a temporary file.
+    pub fn check_writable(&self) -> std::io::Result<()> {
+        tempfile::NamedTempFile::new_in(&self.dir).map(drop)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(key.to_hex())
+    }
+}
+
+pub fn encode(compiled: &Compiled) -> std::io::Result<Vec<u8>> {
+    let header = serde_json::to_vec(&Header {
+        pages: compiled.pages,
+        page_hashes: compiled.page_hashes.clone(),
+        warnings: compiled.warnings.clone(),
+        dependencies: compiled.dependencies.clone(),
+    })?;
+
+    let mut contents = Vec::with_capacity(MAGIC.len() + 4 + header.len() + compiled.pdf.len() + 32);
+    contents.extend_from_slice(MAGIC);
+    contents.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    contents.extend_from_slice(&header);
+    contents.extend_from_slice(&compiled.pdf);
+    let checksum = Sha256::digest(&contents);
+    contents.extend_from_slice(&checksum);
+    Ok(contents)
+}
+
+pub fn decode(contents: &[u8]) -> Option<Compiled> {
+    let (body, checksum) = contents.split_at(contents.len().checked_sub(32)?);
+    if Sha256::digest(body).as_slice() != checksum {
+        return None;
+    }
+
+    let body = body.strip_prefix(MAGIC)?;
+    let (length, body) = body.split_at_checked(4)?;
+    let length = u32::from_le_bytes(length.try_into().ok()?) as usize;
+    let (header, pdf) = body.split_at_checked(length)?;
+    let header: Header = serde_json::from_slice(header).ok()?;
+
+    Some(Compiled {
+        pdf: pdf.to_vec(),
+        pages: header.pages,
+        page_hashes: header.page_hashes,
+        warnings: header.warnings,
+        dependencies: header.dependencies,
+        cacheable: true,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use crate::cache::CacheKey;
+    use crate::docker_world::{Compiled, Dependencies};
+    use super::DiskCache;
+
+    #[test]
+    fn a_truncated_entry_is_a_miss_and_deleted() {
+        let directory = TempDir::new().unwrap();
+        let cache = DiskCache::new(directory.path().join("cache"), u64::MAX, Duration::from_secs(3600)).unwrap();
+        let key = CacheKey([7; 32]);
+        let compiled = Compiled {
+            pdf: b"%PDF-1.7 not really".to_vec(),
+            pages: 1,
+            page_hashes: vec!["abc".into()],
+            warnings: vec![],
+            dependencies: Dependencies::default(),
+            cacheable: true,
+        };
+        cache.insert(&key, &compiled);
+        assert_eq!(cache.get(&key).map(|found| found.pdf), Some(compiled.pdf.clone()));
+
+        let path = cache.path(&key);
+        let contents = fs::read(&path).unwrap();
+        for length in [contents.len() - 1, 40, 3, 0] {
+            fs::write(&path, &contents[..length]).unwrap();
+            assert!(cache.get(&key).is_none(), "a {length} byte entry was found");
+            assert!(!path.exists(), "the {length} byte entry was kept");
+        }
+
+        let mut flipped = contents.clone();
+        flipped[20] ^= 1;
+        fs::write(&path, flipped).unwrap();
+        assert!(cache.get(&key).is_none());
+        assert!(!path.exists());
+    }
+}
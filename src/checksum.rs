@@ -0,0 +1,57 @@
+//! The SHA-256 of compiled output, so downstream systems can verify what they stored.
+//!
+//! Successful compile responses send the SHA-256 of their body in hex as `X-Content-SHA256`,
+//! and as `Repr-Digest` and the older `Digest` in their standard forms. In the JSON mode that
+//! is of the JSON, otherwise of the PDF or image. Jobs and the audit log keep the PDF's, which
+//! is what `/compile` and `GET /jobs/{id}/result` send, so the three can be compared.
+//!
+//! A zip from `/compile/pages` is hashed chunk by chunk as it goes out, after its headers, so
+//! its SHA-256 is only in the audit log.
+
+use actix_web::HttpResponseBuilder;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+
+/// Response header with the hex SHA-256 of the body.
+pub const SHA256_HEADER: &str = "X-Content-SHA256";
+
+#[derive(Clone, Copy)]
+pub struct Checksum([u8; 32]);
+
+impl Checksum {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// The checksum `hex` shows, if it is one.
+    pub fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
+
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Send the checksum as the SHA-256 of the body of `response`.
+    pub fn insert(&self, response: &mut HttpResponseBuilder) {
+        let base64 = STANDARD.encode(self.0);
+        response
+            .insert_header((SHA256_HEADER, self.hex()))
+            .insert_header(("Repr-Digest", format!("sha-256=:{base64}:")))
+            .insert_header(("Digest", format!("sha-256={base64}")));
+    }
+}
+
+impl From<Sha256> for Checksum {
+    fn from(hasher: Sha256) -> Self {
+        Self(hasher.finalize().into())
+    }
+}
@@ -0,0 +1,188 @@
+//! `POST /compile`, compiling an upload to a PDF, the server's main endpoint.
+
+use actix_web::http::header;
+use actix_web::{error, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::attachments::{self, EmbedQuery};
+use crate::audit;
+use crate::cache::CompileCache;
+use crate::compilers::{compile_blocking, respond, CompileOptions, Compilers, JsonOutput, VersionQuery, VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::debug::{self, Recorder};
+use crate::docker_world::{CompileError, Compiled, Dependencies, ErrorOutput, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::etag;
+use crate::metrics::Metrics;
+use crate::multipart::{read_submission, JsonUpload, Submission};
+use crate::openapi::{SourceForm, Upload};
+use crate::profile::{self, Profiler, ProfileQuery};
+use crate::sarif::{self, DiagnosticsQuery};
+use crate::shadow::Shadow;
+use crate::slots::CompileSlots;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DryRunQuery {
+    /// Lay the document out and report its pages and warnings without exporting the PDF,
+    /// a cheap check for CI, failing with `fail_on_warnings` like a full compile.
+    dry_run: Option<bool>,
+}
+
+/// What a dry run answers with instead of the PDF.
+#[derive(Serialize, ToSchema)]
+pub struct DryRunOutput<'a> {
+    pages: usize,
+    warnings: Vec<&'a str>,
+    /// What the compile read, with `include=deps`.
+    #[schema(value_type = Option<Dependencies>)]
+    dependencies: Option<&'a Dependencies>,
+}
+
+#[utoipa::path(
+    tag = "compile",
+    params(VersionQuery, OptionsQuery, EmbedQuery, DiagnosticsQuery, ProfileQuery, DryRunQuery),
+    request_body(content(
+        (Upload = "multipart/form-data"),
+        (SourceForm = "application/x-www-form-urlencoded"),
+        (JsonUpload = "application/json"),
+        (String = "text/plain"),
+        ([u8] = "application/gzip"),
+    )),
+    responses(
+        (status = 200, description = "The PDF, or with `include=deps` the PDF and what the compile read, or with `diagnostics=sarif` the errors and warnings as SARIF, or with `profile=true` the compile's timeline, or with `dry_run=true` the pages and warnings", content(
+            ([u8] = "application/octet-stream"),
+            (JsonOutput = "application/json"),
+            (DryRunOutput = "application/json"),
+            (Object = "application/sarif+json"),
+        )),
+        (status = 304, description = "`If-None-Match` has the `ETag` of these inputs, see `etag`"),
+        (status = 400, description = "The upload is invalid or the document has errors", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 415, description = "The body is neither multipart, a form, JSON, a tar archive nor a document", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits, or with `fail_on_warnings` compiled with warnings", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "The compile timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/compile")]
+#[allow(clippy::too_many_arguments)]
+async fn typst_compile(
+    request: HttpRequest,
+    payload: web::Payload,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+    shadow: web::Data<Shadow>,
+) -> Result<HttpResponse, error::Error> {
+    let compiler = compilers.select(&request)?;
+    let mut options = config.compile_options(&request)?;
+    let debug = debug::requested(&request, &config)?;
+    let sarif = sarif::requested(&request)?;
+    let profile = profile::requested(&request, &config, compiler.version())?;
+    if [debug, sarif, profile].into_iter().filter(|&asked| asked).count() > 1 {
+        return Err(Code::InvalidParameter.error("Ask for one of the debug bundle, diagnostics=sarif and profile=true"));
+    }
+    let dry_run = web::Query::<DryRunQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?
+        .dry_run
+        .unwrap_or(false);
+    if dry_run && debug {
+        return Err(Code::InvalidParameter.error("A dry run has no PDF for the debug bundle"));
+    }
+    // Without a PDF there is nothing to optimize.
+    options.dry_run = dry_run;
+    options.optimizer = options.optimizer.filter(|_| !dry_run);
+    // A cached result would leave nothing to profile.
+    options.no_cache |= profile;
+    // The log says whether warnings would have failed the compile, with the warnings in it.
+    let fail_on_warnings = options.fail_on_warnings;
+    options.fail_on_warnings &= !sarif;
+
+    let Submission { documents, inputs, warnings } = read_submission(&request, payload, &config).await?;
+    audit::inputs(&request, &documents);
+    options.inputs.extend(inputs);
+    options.embedding = attachments::requested(&request, &config, &documents, &options)?;
+    if dry_run && options.embedding.is_some() {
+        return Err(Code::InvalidParameter.error("A dry run has no PDF to attach the sources to"));
+    }
+    let recorder = debug.then(|| Recorder::start(&mut options));
+    // The ignored fields warnings are about aren't hashed, so they would change the JSON under the same tag.
+    let tag = match debug || sarif || profile || dry_run || !warnings.is_empty() {
+        true => { None }
+        false => { etag::of(compiler.version(), &documents, &options) }
+    };
+    if let Some(tag) = tag.as_ref().filter(|tag| etag::matches(&request, tag)) {
+        return Ok(HttpResponse::NotModified().insert_header(header::ETag(tag.clone())).finish());
+    }
+
+    let profiler = match profile {
+        true => { Some(Profiler::start(&documents).await) }
+        false => { None }
+    };
+    let shadowed = (!debug && !sarif && !profile && !dry_run && shadow.sample(&config, compiler.version()))
+        .then(|| documents.clone());
+    let compiled = compile_blocking(
+        &slots,
+        &metrics,
+        &cache,
+        compiler.clone(),
+        documents,
+        options.clone(),
+        config.cache_max_age,
+    ).await;
+    let trace = profiler.map(Profiler::finish);
+    audit::compiled(&request, options.output_format(), &compiled);
+    let mut compiled = compiled?;
+    if let (Some(documents), Ok(primary)) = (shadowed, &compiled) {
+        shadow.spawn(metrics.clone(), &config, documents, &options, primary, compiler.version());
+    }
+    if let Ok(compiled) = &mut compiled {
+        compiled.warnings.extend(warnings);
+    }
+    if sarif {
+        return sarif::respond(compiler.version(), compiled, fail_on_warnings, &metrics);
+    }
+
+    let tag = compiled.as_ref().ok().and_then(|compiled| etag::deterministic(tag, compiled));
+    let embedding = options.embedding.as_ref().filter(|_| compiled.is_ok());
+    let mut response = match (recorder, trace, compiled) {
+        (_, None, compiled) if dry_run => { respond_dry_run(compiler.version(), compiled, &options, &metrics)? }
+        (Some(recorder), _, Ok(compiled)) => { debug::respond(compiler.version(), compiled, &options, &config, recorder, &metrics)? }
+        (_, Some(trace), Ok(compiled)) => { profile::respond(compiler.version(), compiled, options.include_dependencies, trace, &metrics)? }
+        (_, _, compiled) => { respond(compiler.version(), compiled, &options, &metrics)? }
+    };
+    if let Some(tag) = tag {
+        response.headers_mut().insert(header::ETAG, tag.to_string().parse().expect("entity tags are valid headers"));
+    }
+    if let Some(embedding) = embedding {
+        attachments::list(response.headers_mut(), embedding);
+    }
+    Ok(response)
+}
+
+/// Answer a dry run with the pages and warnings of the document.
+fn respond_dry_run(
+    version: &str,
+    compiled: Result<Compiled, CompileError>,
+    options: &CompileOptions,
+    metrics: &Metrics,
+) -> Result<HttpResponse, error::Error> {
+    let compiled = compiled?;
+    metrics.record_warnings(&compiled.warnings);
+    let output = DryRunOutput {
+        pages: compiled.pages,
+        warnings: compiled.warnings.iter().map(|warning| warning.message.as_str()).collect(),
+        dependencies: options.include_dependencies.then_some(&compiled.dependencies),
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, version))
+        .insert_header((WARNING_COUNT_HEADER, compiled.warnings.len().to_string()))
+        .json(output))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(typst_compile);
+}
@@ -0,0 +1,555 @@
+#[cfg(unix)]
+pub mod isolated;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::http::header::ContentType;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::attachments::{self, Embedding};
+use crate::cache::{CacheKey, CompileCache};
+use crate::checksum::Checksum;
+use crate::config::Config;
+use crate::docker_world::{
+    Cancellation, CompileError, Compiled, Dependencies, DockerWorld, DockerWorldBuilder, DocumentFile, FontLibrary,
+    PdfExport, Warning,
+};
+use crate::errors::Code;
+use crate::images;
+use crate::metrics::Metrics;
+use crate::optimize;
+use crate::packages::PackageStore;
+use crate::slots::{CompileSlots, Priority};
+
+/// Request and response header naming the typst version a document is compiled with.
+pub const VERSION_HEADER: &str = "X-Typst-Version";
+
+/// Response header counting the warnings a compile produced.
+pub const WARNING_COUNT_HEADER: &str = "X-Typst-Warning-Count";
+
+/// The typst release `DockerWorld` is built against.
+pub const CURRENT_VERSION: &str = "0.12.0";
+
+/// Per-request settings every compiler honours.
+#[derive(Clone)]
+pub struct CompileOptions {
+    /// Where `datetime.today()` is evaluated without an explicit offset.
+    pub timezone: Tz,
+    /// Most pages the document may lay out to.
+    pub max_pages: usize,
+    /// Answer with JSON including the dependency manifest instead of the bare PDF.
+    pub include_dependencies: bool,
+    /// How long the compile may run before it is cancelled and answered with a 504.
+    pub timeout: Duration,
+    /// Skip the compile cache, both for lookup and for storing the result.
+    pub no_cache: bool,
+    /// Where the compile queues while all compile slots are taken.
+    pub priority: Priority,
+    /// Values the document reads from `sys.inputs`.
+    pub inputs: BTreeMap<String, String>,
+    /// Where the compile reports how far it got.
+    pub progress: Progress,
+    /// Fail a compile that produced warnings, like `-D warnings` does for rustc.
+    pub fail_on_warnings: bool,
+    /// Longest side in pixels uploaded rasters are downscaled to before compiling, see `images`.
+    pub max_image_size: Option<u32>,
+    /// The qpdf binary the PDF is optimized for the web with, if the request wants it, see `optimize`.
+    pub optimizer: Option<PathBuf>,
+    /// The tenant the compile is for, whose results are cached apart from every other's.
+    pub tenant: Option<String>,
+    /// The time the document sees and the PDF is stamped with instead of the compile's, see `etag`.
+    pub now: Option<DateTime<Utc>>,
+    /// Export a PDF/A-2b document, see `?standard=`.
+    pub pdf_a: bool,
+    /// The sources attached to the PDF, see `attachments`.
+    pub embedding: Option<Embedding>,
+    /// Lay the document out without exporting it, leaving the PDF of the result empty.
+    pub dry_run: bool,
+}
+
+/// A point a compile reached, as `/compile/events` reports it.
+pub enum Stage {
+    /// The compile got its slot and is running.
+    Started,
+    /// The document is laid out, with these warnings.
+    Warnings(Vec<Warning>),
+    /// The PDF export started.
+    Exporting,
+}
+
+/// Where a compile reports its stages, nowhere unless a client is listening.
+///
+/// Only the compiler in this process reports every stage, isolated and older ones only start.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<dyn Fn(Stage) + Send + Sync>>);
+
+impl Progress {
+    pub fn new(report: impl Fn(Stage) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(report)))
+    }
+
+    pub fn report(&self, stage: Stage) {
+        if let Some(report) = &self.0 {
+            report(stage);
+        }
+    }
+}
+
+impl CompileOptions {
+    /// What the response carries, as the compile metrics label it.
+    pub fn output_format(&self) -> &'static str {
+        match (self.dry_run, self.include_dependencies) {
+            (true, _) => { "dry_run" }
+            (false, true) => { "json" }
+            (false, false) => { "pdf" }
+        }
+    }
+
+    /// How the PDF is exported, the time it is stamped with left to the world.
+    pub fn pdf_export(&self) -> PdfExport {
+        PdfExport { pdf_a: self.pdf_a, ..PdfExport::default() }
+    }
+
+    /// A world builder with the options that shape the world, its files and fonts still
+    /// to be added.
+    pub fn world(&self) -> DockerWorldBuilder {
+        let world = DockerWorld::builder().timezone(self.timezone).inputs(self.inputs.clone());
+        match self.now {
+            Some(now) => { world.now(now) }
+            None => { world }
+        }
+    }
+}
+
+/// Response body of the JSON response mode.
+#[derive(Serialize, ToSchema)]
+pub struct JsonOutput<'a> {
+    /// The PDF, base64 encoded.
+    pdf: String,
+    warnings: Vec<&'a str>,
+    #[schema(value_type = Dependencies)]
+    dependencies: &'a Dependencies,
+}
+
+impl<'a> JsonOutput<'a> {
+    pub fn new(compiled: &'a Compiled) -> Self {
+        Self {
+            pdf: STANDARD.encode(&compiled.pdf),
+            warnings: compiled.warnings.iter().map(|warning| warning.message.as_str()).collect(),
+            dependencies: &compiled.dependencies,
+        }
+    }
+}
+
+/// Entry points of one bundled typst compiler.
+pub trait Compiler: Send + Sync {
+    /// The typst release this compiler was built from, like `0.12.0`.
+    fn version(&self) -> &'static str;
+
+    /// Compile `main`, which may read `files`, into a PDF, giving up once `cancellation` triggers.
+    fn compile(
+        &self,
+        main: DocumentFile,
+        files: Vec<DocumentFile>,
+        options: &CompileOptions,
+        cancellation: Cancellation,
+    ) -> Result<Compiled, CompileError>;
+
+    /// Evict memoized results that went unused for `max_age` compiles.
+    fn evict(&self, max_age: usize);
+}
+
+/// The typst version the rest of the server is built against.
+struct Current {
+    fonts: Arc<FontLibrary>,
+    packages: Arc<PackageStore>,
+}
+
+impl Compiler for Current {
+    fn version(&self) -> &'static str {
+        CURRENT_VERSION
+    }
+
+    fn compile(
+        &self,
+        main: DocumentFile,
+        files: Vec<DocumentFile>,
+        options: &CompileOptions,
+        cancellation: Cancellation,
+    ) -> Result<Compiled, CompileError> {
+        let world = options.world()
+            .main(main)
+            .files(files)
+            .font_db(self.fonts.current())
+            .packages(self.packages.clone());
+        let mut world = tracing::info_span!("world").in_scope(|| world.build())?;
+        let document = world.typeset(options.max_pages, cancellation)?;
+        options.progress.report(Stage::Warnings(document.warnings.clone()));
+        let pdf = match options.dry_run {
+            true => { Vec::new() }
+            false => {
+                options.progress.report(Stage::Exporting);
+                tracing::info_span!("export").in_scope(|| document.to_pdf(&options.pdf_export()))?
+            }
+        };
+        Ok(document.into_compiled(pdf))
+    }
+
+    fn evict(&self, max_age: usize) {
+        comemo::evict(max_age);
+    }
+}
+
+/// Every compiler built into this binary, newest first.
+pub struct Compilers {
+    compilers: Vec<Arc<dyn Compiler>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VersionQuery {
+    /// Typst release to compile with, like `0.12` or `0.12.0`. Overrides `X-Typst-Version`.
+    typst_version: Option<String>,
+}
+
+impl Compilers {
+    pub fn new(config: &Config, fonts: Arc<FontLibrary>, packages: Arc<PackageStore>) -> Self {
+        let current: Arc<dyn Compiler> = match config.isolate_compiles {
+            #[cfg(unix)]
+            true => { Arc::new(isolated::Isolated::new(config)) }
+            // Off Unix the configuration refuses ISOLATE_COMPILES, compiles stay in process.
+            _ => { Arc::new(Current { fonts, packages }) }
+        };
+        let compilers: Vec<Arc<dyn Compiler>> = vec![current];
+        Self { compilers }
+    }
+
+    /// Versions of the bundled compilers, newest first.
+    pub fn versions(&self) -> Vec<&'static str> {
+        self.compilers.iter().map(|compiler| compiler.version()).collect()
+    }
+
+    /// The newest bundled compiler, which requests get unless they ask for another.
+    pub fn newest(&self) -> Arc<dyn Compiler> {
+        self.compilers[0].clone()
+    }
+
+    /// The compiler of `version`, where `0.12` matches any `0.12.x`.
+    pub fn find(&self, version: &str) -> Option<Arc<dyn Compiler>> {
+        let prefix = format!("{version}.");
+        self.compilers.iter()
+            .find(|compiler| compiler.version() == version || compiler.version().starts_with(&prefix))
+            .cloned()
+    }
+
+    /// The compiler asked for with `?typst_version=` or the version header.
+    ///
+    /// A requested version like `0.12` matches any `0.12.x`, and no request means the newest.
+    pub fn select(&self, request: &HttpRequest) -> Result<Arc<dyn Compiler>, Error> {
+        let query = web::Query::<VersionQuery>::from_query(request.query_string())
+            .map_err(|problem| Code::InvalidQuery.error(problem))?
+            .into_inner();
+        let requested = match query.typst_version {
+            Some(version) => { Some(version) }
+            None => {
+                request.headers().get(VERSION_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            }
+        };
+
+        let requested = match requested {
+            None => { return Ok(self.newest()) }
+            Some(requested) => { requested }
+        };
+
+        match self.find(&requested) {
+            Some(compiler) => { Ok(compiler) }
+            None => {
+                Err(Code::UnknownVersion.error(format!(
+                    "typst {requested} is not available, this server bundles {}",
+                    self.versions().join(", ")
+                )))
+            }
+        }
+    }
+}
+
+/// Compile `documents`, the first of which is the main file, with `compiler` through
+/// `run_compile`, unless an identical request is still in `cache`.
+pub async fn compile_blocking(
+    slots: &CompileSlots,
+    metrics: &Metrics,
+    cache: &CompileCache,
+    compiler: Arc<dyn Compiler>,
+    mut documents: Vec<DocumentFile>,
+    options: CompileOptions,
+    cache_max_age: usize,
+) -> Result<Result<Compiled, CompileError>, Error> {
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    let main = documents.remove(0);
+    record_inputs(std::iter::once(&main).chain(&documents));
+
+    let key = cache_key(compiler.as_ref(), &main, &documents, &options);
+    let cached = key.as_ref().and_then(|key| cache.get(key));
+    tracing::Span::current().record("cache_hit", cached.is_some());
+    let fail_on_warnings = options.fail_on_warnings;
+    if let Some(compiled) = cached {
+        return Ok(strict(Ok(compiled), fail_on_warnings));
+    }
+
+    let (timeout, priority, dry_run) = (options.timeout, options.priority, options.dry_run);
+    let format = options.output_format();
+    let compiled = run_compile(slots, metrics, timeout, priority, format, move |cancellation| {
+        options.progress.report(Stage::Started);
+        let compiled = compile_processed(compiler.as_ref(), main, documents, &options, cancellation);
+        compiler.evict(cache_max_age);
+        compiled
+    }).await?;
+
+    // A dry run's result has no PDF, while a cached compile serves a dry run as well.
+    if let (Some(key), Ok(compiled), false) = (key, &compiled, dry_run) {
+        cache.insert(key, compiled);
+    }
+    Ok(strict(compiled, fail_on_warnings))
+}
+
+/// Fail a compile with warnings if the request asked for it. The cache keeps the document
+/// either way, since the policy doesn't change what typst produces.
+pub fn strict(compiled: Result<Compiled, CompileError>, fail_on_warnings: bool) -> Result<Compiled, CompileError> {
+    match compiled {
+        Ok(compiled) if fail_on_warnings && !compiled.warnings.is_empty() => {
+            let warnings = compiled.warnings.iter().map(|warning| format!("{}\n", warning.message)).collect::<String>();
+            Err(CompileError::Warnings { warnings: warnings.into(), count: compiled.warnings.len() })
+        }
+        compiled => { compiled }
+    }
+}
+
+/// Compile with `compiler` and the processing the request asked for around it: downscaling
+/// oversized rasters before, each reported among the warnings, and attaching the sources to
+/// and optimizing the PDF after.
+pub fn compile_processed(
+    compiler: &dyn Compiler,
+    main: DocumentFile,
+    files: Vec<DocumentFile>,
+    options: &CompileOptions,
+    cancellation: Cancellation,
+) -> Result<Compiled, CompileError> {
+    let sources = match &options.embedding {
+        Some(embedding) => { Some((embedding, attachments::sources(embedding, &main, &files)?)) }
+        None => { None }
+    };
+    let (files, downscaled) = match options.max_image_size {
+        None => { (files, Vec::new()) }
+        Some(max_size) => { images::downscale_all(files, max_size) }
+    };
+    let mut compiled = compiler.compile(main, files, options, cancellation)?;
+    compiled.warnings.extend(downscaled);
+    if let Some((embedding, sources)) = sources {
+        compiled.pdf = attachments::attach(std::mem::take(&mut compiled.pdf), &embedding.qpdf, &sources, options.now)?;
+    }
+    if let Some(qpdf) = &options.optimizer {
+        compiled.pdf = optimize::optimize(std::mem::take(&mut compiled.pdf), qpdf);
+    }
+    Ok(compiled)
+}
+
+/// Note how many files and bytes a compile got on the request's span, never their contents.
+/// Returns the bytes.
+pub fn record_inputs<'a>(files: impl Iterator<Item = &'a DocumentFile>) -> u64 {
+    let (count, bytes) = files.fold((0, 0), |(count, bytes), file| (count + 1, bytes + file.data.size().unwrap_or(0)));
+    let span = tracing::Span::current();
+    span.record("files", count);
+    span.record("input_bytes", bytes);
+    bytes
+}
+
+/// The key a compile's result is cached under, unless the request opted out of caching.
+pub fn cache_key(
+    compiler: &dyn Compiler,
+    main: &DocumentFile,
+    files: &[DocumentFile],
+    options: &CompileOptions,
+) -> Option<CacheKey> {
+    match options.no_cache {
+        true => { None }
+        false => { CacheKey::new(compiler.version(), main, files, options) }
+    }
+}
+
+/// Run `work` on the blocking thread pool once one of the compile slots is free,
+/// so a slow document doesn't stall an actix worker.
+///
+/// Past `timeout` the request is answered right away and `work` is cancelled, its slot going
+/// to the next request once `work` returned. typst only sees the cancellation when the compile
+/// reads a file or a font, so a document stuck in layout or introspection keeps its slot until
+/// it ends, which holds the slots to the compiles really running. Isolated compiles are killed
+/// at once.
+///
+/// actix drops the handler future when the client disconnects, which cancels `work` too,
+/// as does aborting the task of a job.
+pub async fn run_compile<T: Paged>(
+    slots: &CompileSlots,
+    metrics: &Metrics,
+    timeout: Duration,
+    priority: Priority,
+    format: &'static str,
+    work: impl FnOnce(Cancellation) -> Result<T, CompileError> + Send + 'static,
+) -> Result<Result<T, CompileError>, Error> {
+    let cancellation = Cancellation::default();
+    let abandoned = CancelOnDrop { cancellation: Some(cancellation.clone()), metrics };
+    let slot = slots.acquire(priority).await?;
+    let started = Instant::now();
+    // The blocking thread doesn't inherit the request's span by itself.
+    let span = tracing::info_span!("compile", pages = tracing::field::Empty);
+    let running = web::block({
+        let (cancellation, span) = (cancellation.clone(), span.clone());
+        move || {
+            let _entered = span.enter();
+            let compiled = work(cancellation);
+            if let Ok(compiled) = &compiled {
+                span.record("pages", compiled.pages());
+            }
+            drop(slot);
+            compiled
+        }
+    });
+
+    let finished = tokio::time::timeout(timeout, running).await;
+    abandoned.disarm();
+    let compiled = match finished {
+        Ok(compiled) => { compiled? }
+        Err(_) => {
+            cancellation.cancel();
+            Err(CompileError::TimedOut { after: timeout })
+        }
+    };
+    let elapsed = started.elapsed();
+    metrics.record_compile(format, &compiled, elapsed);
+    let milliseconds = elapsed.as_millis() as u64;
+    match &compiled {
+        Ok(compiled) => { tracing::info!(milliseconds, pages = compiled.pages(), outcome = "success", "compiled") }
+        Err(problem) => { tracing::info!(milliseconds, outcome = problem.kind(), "compile failed") }
+    }
+    Ok(compiled)
+}
+
+/// What `run_compile` runs produce, a PDF or otherwise.
+pub trait Paged: Send + 'static {
+    fn pages(&self) -> usize;
+}
+
+impl Paged for Compiled {
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// Cancels a compile whose request future is dropped before the compile finished,
+/// because its client disconnected or its job was cancelled.
+struct CancelOnDrop<'a> {
+    cancellation: Option<Cancellation>,
+    metrics: &'a Metrics,
+}
+
+impl CancelOnDrop<'_> {
+    fn disarm(mut self) {
+        self.cancellation = None;
+    }
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(cancellation) = self.cancellation.take() {
+            tracing::info!("request dropped before its compile finished, cancelling it");
+            cancellation.cancel();
+            self.metrics.record_abandoned();
+        }
+    }
+}
+
+/// Turn a compile result into a response naming the typst version that produced it.
+pub fn respond(
+    version: &str,
+    compiled: Result<Compiled, CompileError>,
+    options: &CompileOptions,
+    metrics: &Metrics,
+) -> Result<HttpResponse, Error> {
+    let _span = tracing::info_span!("respond").entered();
+    let compiled = compiled?;
+    metrics.record_warnings(&compiled.warnings);
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((VERSION_HEADER, version))
+        .insert_header((WARNING_COUNT_HEADER, compiled.warnings.len().to_string()));
+
+    if !options.include_dependencies {
+        Checksum::of(&compiled.pdf).insert(&mut response);
+        return Ok(response.content_type(ContentType::octet_stream()).body(compiled.pdf));
+    }
+
+    let body = serde_json::to_vec(&JsonOutput::new(&compiled)).expect("the JSON output serializes");
+    Checksum::of(&body).insert(&mut response);
+    Ok(response.content_type(ContentType::json()).body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use crate::config::Config;
+    use crate::docker_world::{Cancellation, CompileError};
+    use crate::metrics::Metrics;
+    use crate::slots::{CompileSlots, Priority};
+    use super::{run_compile, Paged};
+
+    struct Done;
+
+    impl Paged for Done {
+        fn pages(&self) -> usize {
+            1
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_timed_out_compile_frees_its_slot_once_it_stopped() {
+        let config = Config::from_args(["typstapi", "--max-concurrent-compiles", "1"]).unwrap();
+        let (slots, metrics) = (CompileSlots::new(&config), Metrics::default());
+        let stopped = Arc::new(AtomicBool::new(false));
+        // Like a document looping in layout, it never looks at the cancellation.
+        let slow = {
+            let stopped = stopped.clone();
+            move |_: Cancellation| {
+                std::thread::sleep(Duration::from_secs(1));
+                stopped.store(true, Ordering::SeqCst);
+                Ok::<_, CompileError>(Done)
+            }
+        };
+        let timed_out = run_compile(&slots, &metrics, Duration::from_millis(100), Priority::Normal, "pdf", slow).await;
+        assert!(matches!(timed_out, Ok(Err(CompileError::TimedOut { .. }))));
+        assert!(!stopped.load(Ordering::SeqCst));
+        assert_eq!(slots.in_use(), 1, "the slow compile still runs and keeps its slot");
+
+        let next = run_compile(&slots, &metrics, Duration::from_secs(1), Priority::Normal, "pdf", {
+            let stopped = stopped.clone();
+            move |_| {
+                assert!(stopped.load(Ordering::SeqCst), "the next compile ran next to the slow one");
+                Ok(Done)
+            }
+        }).await;
+        assert!(matches!(next, Ok(Ok(Done))));
+        assert_eq!(slots.in_use(), 0);
+    }
+}
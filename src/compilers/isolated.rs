@@ -0,0 +1,355 @@
+//! Compiles in a child process, so a document exhausting memory only takes down its own process.
+//!
+//! The child is this same binary started with `WORKER_FLAG`, or with `ISOLATION_IMAGE` the
+//! image's `typstapi` started that way in a container without network access, seeing only the
+//! fonts and packages. It reads a JSON header line and the file contents from stdin and
+//! answers with a JSON header line and the PDF on stdout.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use ecow::EcoString;
+use serde::{Deserialize, Serialize};
+use crate::compilers::{CompileOptions, Compiler, CURRENT_VERSION};
+use crate::config::Config;
+use crate::docker_world::{Cancellation, CompileError, Compiled, Dependencies, DockerWorld, DocumentFile, FileData, FontDb, PdfExport, Warning};
+use crate::packages::PackageStore;
+
+/// Argument starting the binary as a compile worker instead of a server.
+pub const WORKER_FLAG: &str = "--compile-worker";
+
+/// How often the parent checks whether the child exited or the compile was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where a container sees the fonts, the package cache and the local packages.
+const CONTAINER_FONTS: &str = "/typstapi/fonts";
+const CONTAINER_PACKAGES: &str = "/typstapi/packages";
+const CONTAINER_LOCAL_PACKAGES: &str = "/typstapi/local";
+
+/// Numbers the containers of this process, so each can be killed by name.
+static CONTAINERS: AtomicU64 = AtomicU64::new(0);
+
+pub struct Isolated {
+    sandbox: Sandbox,
+    /// Address space the child may use, in bytes.
+    memory_limit: u64,
+}
+
+/// What a worker runs as.
+enum Sandbox {
+    /// A child process of this binary, limited with `setrlimit`.
+    Process(PathBuf),
+    /// A container of `image`, limited by `runtime`, with the host directories mounted
+    /// read-only next to the flags pointing the worker at them.
+    Container { runtime: PathBuf, image: String, mounts: Vec<(PathBuf, &'static str)>, args: Vec<String> },
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorkerRequest {
+    timezone: String,
+    max_pages: usize,
+    /// Values for `sys.inputs`.
+    inputs: BTreeMap<String, String>,
+    now: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pdf_a: bool,
+    #[serde(default)]
+    dry_run: bool,
+    /// Path and length of every file, the main file first, their contents follow the header.
+    files: Vec<(String, u64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum WorkerResponse {
+    /// The PDF of `pdf_len` bytes follows the header.
+    Compiled {
+        pages: usize,
+        page_hashes: Vec<String>,
+        warnings: Vec<Warning>,
+        dependencies: Dependencies,
+        cacheable: bool,
+        pdf_len: usize,
+    },
+    Failed(String),
+    PackageUnavailable(String),
+    TooManyPages { pages: usize, limit: usize },
+}
+
+impl Isolated {
+    pub fn new(config: &Config) -> Self {
+        let sandbox = match &config.isolation_image {
+            Some(image) => {
+                // Mounting needs the cache to exist, the server would only create it on the first download.
+                let _ = fs::create_dir_all(&config.package_cache_dir);
+                let mut mounts = vec![(config.package_cache_dir.clone(), CONTAINER_PACKAGES)];
+                let mut args = vec!["--offline".to_string(), "--package-cache-dir".to_string(), CONTAINER_PACKAGES.to_string()];
+                if let Some(fonts) = &config.font_dir {
+                    mounts.push((fonts.clone(), CONTAINER_FONTS));
+                    args.extend(["--font-dir".to_string(), CONTAINER_FONTS.to_string()]);
+                }
+                if let Some(local) = &config.local_package_dir {
+                    mounts.push((local.clone(), CONTAINER_LOCAL_PACKAGES));
+                    args.extend(["--local-package-dir".to_string(), CONTAINER_LOCAL_PACKAGES.to_string()]);
+                }
+                Sandbox::Container { runtime: config.isolation_runtime.clone(), image: image.clone(), mounts, args }
+            }
+            None => {
+                Sandbox::Process(std::env::current_exe().expect("Could not find the server binary for compile workers"))
+            }
+        };
+        Self { sandbox, memory_limit: config.isolation_memory_limit }
+    }
+
+    /// Start a worker, returning the name of its container if it runs in one.
+    fn spawn(&self, options: &CompileOptions) -> io::Result<(Child, Option<String>)> {
+        // One second of slack, the parent's timeout normally kills the child first.
+        let cpu_limit = options.timeout.as_secs() + 1;
+        match &self.sandbox {
+            Sandbox::Process(executable) => { self.spawn_process(executable, cpu_limit).map(|child| (child, None)) }
+            Sandbox::Container { runtime, image, mounts, args } => {
+                let name = format!("typstapi-compile-{}-{}", std::process::id(), CONTAINERS.fetch_add(1, Ordering::Relaxed));
+                self.spawn_container(runtime, image, mounts, args, &name, cpu_limit).map(|child| (child, Some(name)))
+            }
+        }
+    }
+
+    fn spawn_container(
+        &self,
+        runtime: &Path,
+        image: &str,
+        mounts: &[(PathBuf, &'static str)],
+        args: &[String],
+        name: &str,
+        cpu_limit: u64,
+    ) -> io::Result<Child> {
+        let mut command = Command::new(runtime);
+        command.args(["run", "--rm", "--interactive", "--network=none", "--read-only", "--tmpfs=/tmp"])
+            .args(["--cap-drop=ALL", "--security-opt=no-new-privileges", "--pids-limit=256"])
+            .arg(format!("--name={name}"))
+            // Without swap the limit holds like the address space limit of a child process.
+            .arg(format!("--memory={}b", self.memory_limit))
+            .arg(format!("--memory-swap={}b", self.memory_limit))
+            .arg(format!("--ulimit=cpu={cpu_limit}:{cpu_limit}"));
+        for (source, target) in mounts {
+            command.arg(format!("--mount=type=bind,source={},target={target},readonly", source.display()));
+        }
+        command.args(["--entrypoint", "typstapi", image, WORKER_FLAG])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        command.spawn()
+    }
+
+    fn spawn_process(&self, executable: &Path, cpu_limit: u64) -> io::Result<Child> {
+        let memory_limit = self.memory_limit;
+        let mut command = Command::new(executable);
+        // The worker reads the same flags to arrive at the same configuration.
+        command.arg(WORKER_FLAG)
+            .args(std::env::args_os().skip(1))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // SAFETY: setrlimit is async-signal-safe and nothing else runs between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                let memory = libc::rlimit { rlim_cur: memory_limit, rlim_max: memory_limit };
+                let cpu = libc::rlimit { rlim_cur: cpu_limit, rlim_max: cpu_limit };
+                if libc::setrlimit(libc::RLIMIT_AS, &memory) != 0 || libc::setrlimit(libc::RLIMIT_CPU, &cpu) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command.spawn()
+    }
+}
+
+impl Compiler for Isolated {
+    fn version(&self) -> &'static str {
+        CURRENT_VERSION
+    }
+
+    fn compile(
+        &self,
+        main: DocumentFile,
+        files: Vec<DocumentFile>,
+        options: &CompileOptions,
+        cancellation: Cancellation,
+    ) -> Result<Compiled, CompileError> {
+        let crashed = |problem: io::Error| CompileError::WorkerCrashed(problem.to_string());
+        let (mut child, container) = self.spawn(options).map_err(crashed)?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = thread::spawn(move || {
+            let mut output = vec![];
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+
+        // A child dying halfway through reading shows up in its exit status below.
+        let files: Vec<_> = std::iter::once(main).chain(files).collect();
+        let _ = send(&mut stdin, &files, options);
+        drop(stdin);
+
+        let status = loop {
+            if cancellation.is_cancelled() {
+                if let (Sandbox::Container { runtime, .. }, Some(name)) = (&self.sandbox, &container) {
+                    // Killing the runtime's client would leave the container running.
+                    let _ = Command::new(runtime).args(["kill", name.as_str()]).stdout(Stdio::null()).stderr(Stdio::null()).status();
+                }
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CompileError::Cancelled);
+            }
+            match child.try_wait().map_err(crashed)? {
+                Some(status) => { break status }
+                None => { thread::sleep(POLL_INTERVAL) }
+            }
+        };
+        let output = reader.join().expect("The worker output reader panicked").map_err(crashed)?;
+
+        let contained = matches!(self.sandbox, Sandbox::Container { .. });
+        // A container runtime exits with 128 plus the signal that ended the worker.
+        let signal = match (contained, status.code()) {
+            (true, Some(code)) if code > 128 => { Some(code - 128) }
+            _ => { status.signal() }
+        };
+        if let Some(signal) = signal {
+            return Err(match signal {
+                libc::SIGXCPU => { CompileError::ResourceLimit("the compile used too much CPU time".into()) }
+                // Allocation failures abort the process.
+                libc::SIGABRT | libc::SIGSEGV => { CompileError::ResourceLimit("the compile ran out of memory".into()) }
+                // The runtime kills a container outright once it reaches its memory limit.
+                libc::SIGKILL if contained => { CompileError::ResourceLimit("the compile ran out of memory".into()) }
+                _ => { CompileError::WorkerCrashed(format!("the compile worker was killed by signal {signal}")) }
+            });
+        }
+        if !status.success() {
+            return Err(CompileError::WorkerCrashed(format!("the compile worker exited with {status}")));
+        }
+
+        receive(&output).ok_or_else(|| CompileError::WorkerCrashed("the compile worker answered garbage".into()))?
+    }
+
+    fn evict(&self, _max_age: usize) {
+        // Every compile starts with an empty cache in a fresh process.
+    }
+}
+
+fn send(stdin: &mut impl Write, files: &[DocumentFile], options: &CompileOptions) -> io::Result<()> {
+    let mut header = WorkerRequest {
+        timezone: options.timezone.name().into(),
+        max_pages: options.max_pages,
+        inputs: options.inputs.clone(),
+        now: options.now,
+        pdf_a: options.pdf_a,
+        dry_run: options.dry_run,
+        files: vec![],
+    };
+    for file in files {
+        let length = file.data.size()?;
+        header.files.push((file.name.vpath().as_rootless_path().to_string_lossy().into_owned(), length));
+    }
+
+    serde_json::to_writer(&mut *stdin, &header)?;
+    stdin.write_all(b"\n")?;
+    for file in files {
+        match &file.data {
+            FileData::Memory(bytes) => { stdin.write_all(bytes)?; }
+            FileData::Spooled(spooled) => { io::copy(&mut fs::File::open(spooled.path())?, stdin)?; }
+        }
+    }
+    stdin.flush()
+}
+
+fn receive(output: &[u8]) -> Option<Result<Compiled, CompileError>> {
+    let newline = output.iter().position(|&byte| byte == b'\n')?;
+    let (header, pdf) = (&output[..newline], &output[newline + 1..]);
+    Some(match serde_json::from_slice(header).ok()? {
+        WorkerResponse::Compiled { pages, page_hashes, warnings, dependencies, cacheable, pdf_len } => {
+            if pdf.len() != pdf_len {
+                return None;
+            }
+            Ok(Compiled { pdf: pdf.to_vec(), pages, page_hashes, warnings, dependencies, cacheable })
+        }
+        WorkerResponse::Failed(errors) => { Err(CompileError::Failed(EcoString::from(errors))) }
+        WorkerResponse::PackageUnavailable(errors) => { Err(CompileError::PackageUnavailable(EcoString::from(errors))) }
+        WorkerResponse::TooManyPages { pages, limit } => { Err(CompileError::TooManyPages { pages, limit }) }
+    })
+}
+
+/// Entry point of a compile worker: compile the request on stdin and answer on stdout.
+pub fn run_worker() -> io::Result<()> {
+    let mut stdin = BufReader::new(io::stdin().lock());
+    let mut header = String::new();
+    stdin.read_line(&mut header)?;
+    let request: WorkerRequest = serde_json::from_str(&header)?;
+
+    let mut files = vec![];
+    for (path, length) in &request.files {
+        let mut data = vec![0; *length as usize];
+        stdin.read_exact(&mut data)?;
+        files.push(DocumentFile::new(path, data));
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no main file"));
+    }
+    let timezone = request.timezone.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "unknown timezone"))?;
+
+    let config = Config::load();
+    let fonts = std::sync::Arc::new(FontDb::new(config.font_dir.clone()));
+    let packages = std::sync::Arc::new(PackageStore::new(config.package_settings())?);
+    let main = files.remove(0);
+    let world = DockerWorld::builder()
+        .main(main)
+        .files(files)
+        .font_db(fonts)
+        .packages(packages)
+        .timezone(timezone)
+        .inputs(request.inputs);
+    let world = match request.now {
+        Some(now) => { world.now(now) }
+        None => { world }
+    };
+    let export = PdfExport { pdf_a: request.pdf_a, ..PdfExport::default() };
+    let compiled = world.build().map_err(CompileError::from).and_then(|mut world| {
+        let document = world.typeset(request.max_pages, Cancellation::default())?;
+        let pdf = match request.dry_run {
+            true => { Vec::new() }
+            false => { document.to_pdf(&export)? }
+        };
+        Ok(document.into_compiled(pdf))
+    });
+
+    let (response, pdf) = match compiled {
+        Ok(compiled) => {
+            let response = WorkerResponse::Compiled {
+                pages: compiled.pages,
+                page_hashes: compiled.page_hashes,
+                warnings: compiled.warnings,
+                dependencies: compiled.dependencies,
+                cacheable: compiled.cacheable,
+                pdf_len: compiled.pdf.len(),
+            };
+            (response, compiled.pdf)
+        }
+        Err(CompileError::TooManyPages { pages, limit }) => { (WorkerResponse::TooManyPages { pages, limit }, vec![]) }
+        Err(CompileError::PackageUnavailable(errors)) => { (WorkerResponse::PackageUnavailable(errors.to_string()), vec![]) }
+        Err(problem) => { (WorkerResponse::Failed(problem.to_string()), vec![]) }
+    };
+
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, &response)?;
+    stdout.write_all(b"\n")?;
+    stdout.write_all(&pdf)?;
+    stdout.flush()
+}
@@ -0,0 +1,207 @@
+//! A cap on the compiles each client runs at once, below the server's compile slots, so a
+//! client sending many compiles in parallel can't take every slot while others wait.
+//!
+//! Clients are told apart like for rate limiting, by key or else by address. Each may run
+//! `MAX_CONCURRENT_COMPILES_PER_CLIENT` compile requests at once, or what its key's
+//! `max_concurrent_compiles` says, and one more is answered with a 429 `client_busy` before
+//! it takes a compile slot or a place in the queue, while everyone else's go on. A request
+//! counts from when it arrives until its response is ready, reading the upload included.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{web, Error, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::config::{Config, ConfigHandle};
+use crate::errors::Code;
+use crate::quotas::starts_compile;
+use crate::rate_limit::client;
+
+/// Compiles running per client, for `/admin/stats`.
+#[derive(Default)]
+pub struct ClientCompiles {
+    running: Mutex<HashMap<String, usize>>,
+}
+
+/// Held while one of a client's compiles runs, dropping it frees its place.
+struct ClientSlot {
+    compiles: web::Data<ClientCompiles>,
+    client: String,
+}
+
+#[derive(Debug)]
+struct ClientBusy {
+    limit: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ClientUsage {
+    /// Compiles the client runs right now.
+    running: usize,
+    /// How many it may run at once.
+    limit: Option<usize>,
+}
+
+impl ClientCompiles {
+    /// A place for one more compile of `client`, unless it runs `limit` already.
+    fn admit(compiles: &web::Data<ClientCompiles>, client: String, limit: usize) -> Result<ClientSlot, ClientBusy> {
+        let mut running = compiles.running.lock().unwrap();
+        let count = running.entry(client.clone()).or_insert(0);
+        if *count >= limit {
+            return Err(ClientBusy { limit });
+        }
+        *count += 1;
+        Ok(ClientSlot { compiles: compiles.clone(), client })
+    }
+
+    /// What each client with a running compile uses, with the limit `limit_of` gives it.
+    pub fn usage(&self, limit_of: impl Fn(&str) -> Option<usize>) -> BTreeMap<String, ClientUsage> {
+        self.running.lock().unwrap().iter()
+            .map(|(client, &running)| (client.clone(), ClientUsage { running, limit: limit_of(client) }))
+            .collect()
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut running = self.compiles.running.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// The limit of the client named like `client` names it, by its key's setting or else the
+/// server's, `None` if unlimited.
+pub fn limit_of(config: &Config, client: &str) -> Option<usize> {
+    let key = client.strip_prefix("key:")
+        .and_then(|name| config.keys.iter().find(|key| key.name == name))
+        .and_then(|key| key.max_concurrent_compiles);
+    key.or(config.max_concurrent_compiles_per_client)
+}
+
+impl fmt::Display for ClientBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "This client already runs {} compiles at once, the most it may", self.limit)
+    }
+}
+
+impl ResponseError for ClientBusy {
+    fn status_code(&self) -> StatusCode {
+        Code::ClientBusy.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header((RETRY_AFTER, "1"))
+            .json(Code::ClientBusy.body(self))
+    }
+}
+
+/// Middleware answering compiles beyond their client's cap with a 429, for `App::wrap_fn`.
+pub fn limit<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let compiles = request.app_data::<web::Data<ClientCompiles>>().cloned();
+    let config = request.app_data::<web::Data<ConfigHandle>>().cloned();
+    let client = client(&request);
+    let (Some(compiles), Some(config), Some(client), true) = (compiles, config, client, starts_compile(&request)) else {
+        let call = service.call(request);
+        return Box::pin(async move { Ok(call.await?.map_into_left_body()) });
+    };
+    let Some(limit) = limit_of(&config.current(), &client) else {
+        let call = service.call(request);
+        return Box::pin(async move { Ok(call.await?.map_into_left_body()) });
+    };
+
+    match ClientCompiles::admit(&compiles, client.clone(), limit) {
+        Err(busy) => {
+            tracing::info!(client = client.as_str(), limit, "client runs too many compiles");
+            let response = request.error_response(busy).map_into_right_body();
+            Box::pin(async { Ok(response) })
+        }
+        Ok(slot) => {
+            let call = service.call(request);
+            Box::pin(async move {
+                let response = call.await;
+                drop(slot);
+                Ok(response?.map_into_left_body())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::Value;
+    use crate::config::{Config, ConfigHandle};
+    use super::{limit, ClientCompiles};
+
+    fn compile(client: &str) -> test::TestRequest {
+        let address: SocketAddr = format!("{client}:40000").parse().unwrap();
+        test::TestRequest::post().uri("/compile").peer_addr(address)
+    }
+
+    #[actix_web::test]
+    async fn a_client_at_its_cap_does_not_hold_up_another() {
+        let config = Config::from_args(["typstapi", "--max-concurrent-compiles-per-client", "2"]).unwrap();
+        let compiles = web::Data::new(ClientCompiles::default());
+        let service = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ConfigHandle::new(config)))
+                .app_data(compiles.clone())
+                .wrap_fn(limit)
+                .route("/compile", web::post().to(|| async { HttpResponse::Ok().finish() })),
+        ).await;
+
+        // A compile holds its place from the call until its response is ready, so these run
+        // on while they aren't awaited.
+        let flooding: Vec<_> = (0..10).map(|_| service.call(compile("10.0.0.1").to_request())).collect();
+        let usage = compiles.usage(|_| Some(2));
+        assert_eq!(usage["10.0.0.1"].running, 2);
+
+        let response = test::call_service(&service, compile("10.0.0.2").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let other = service.call(compile("10.0.0.2").to_request());
+        assert_eq!(compiles.usage(|_| Some(2))["10.0.0.2"].running, 1);
+
+        let response = test::call_service(&service, compile("10.0.0.1").to_request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        let failure: Value = test::read_body_json(response).await;
+        assert_eq!(failure["error"], "client_busy");
+
+        let mut statuses = Vec::new();
+        for call in flooding {
+            statuses.push(call.await.unwrap().status());
+        }
+        assert_eq!(statuses.iter().filter(|status| **status == StatusCode::OK).count(), 2);
+        assert_eq!(statuses.iter().filter(|status| **status == StatusCode::TOO_MANY_REQUESTS).count(), 8);
+        assert_eq!(other.await.unwrap().status(), StatusCode::OK);
+
+        // Once its compiles are done the client may start more.
+        assert!(compiles.usage(|_| Some(2)).is_empty());
+        let response = test::call_service(&service, compile("10.0.0.1").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,958 @@
+mod settings;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderName;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use serde::Deserialize;
+use typst::syntax::package::PackageSpec;
+use utoipa::{IntoParams, ToSchema};
+use crate::auth::{self, Principal, Tenant};
+use crate::auth::keys::{self, ApiKey};
+use crate::compilers::{CompileOptions, Progress};
+use crate::errors::Code;
+use crate::packages::{Credentials, PackageSettings, DEFAULT_REGISTRY_URL};
+use crate::slots::{Priority, PRIORITY_HEADER};
+use self::settings::{describe, Settings};
+
+/// Server settings, read from the command line and the environment at startup.
+pub struct Config {
+    /// Directory searched for fonts in addition to the system fonts.
+    pub font_dir: Option<PathBuf>,
+    /// Number of compiles a memoized result may go unused before comemo evicts it.
+    pub cache_max_age: usize,
+    /// Bearer token guarding the admin endpoints. They are disabled when unset.
+    pub admin_token: Option<String>,
+    /// Keys required on every endpoint but the probes, metrics and version. Without any,
+    /// here or in the keys file, the server is open.
+    pub api_keys: Vec<String>,
+    /// Keys issued from the keys file, each authenticating as its name.
+    pub keys: Vec<ApiKey>,
+    /// Also accept a key as the password of HTTP Basic credentials.
+    pub basic_auth: bool,
+    /// Realm named in authentication challenges.
+    pub auth_realm: String,
+    /// How far the timestamp of a signed request may be off the server's clock.
+    pub signature_max_skew: Duration,
+    /// Serve the OpenAPI document on `/openapi.json` and a Swagger UI on `/docs`.
+    pub api_docs: bool,
+    /// Serve the page for trying compiles out on `/ui`.
+    pub web_ui: bool,
+    /// Where downloaded typst packages are unpacked.
+    pub package_cache_dir: PathBuf,
+    /// Root of the `@local` package namespace, laid out as `<root>/<name>/<version>/`.
+    pub local_package_dir: Option<PathBuf>,
+    /// Disable every outbound fetch, such as package downloads.
+    pub offline: bool,
+    /// Package archive URL with `{namespace}`, `{name}` and `{version}` placeholders.
+    pub registry_url: String,
+    /// Credentials sent to the registry keyed by namespace, `*` applies to all others.
+    pub registry_credentials: HashMap<String, Credentials>,
+    /// Extra root certificate (PEM) trusted for registry connections.
+    pub registry_ca_cert: Option<PathBuf>,
+    /// Packages fetched into the cache at startup, so no request pays for the download.
+    pub preload_packages: Vec<PackageSpec>,
+    /// Zone documents see as "today", unless a request overrides it.
+    pub timezone: Tz,
+    /// Page limit for requests that don't ask for one.
+    pub max_pages: usize,
+    /// Highest page limit a request may ask for with `?max_pages=`.
+    pub max_pages_ceiling: usize,
+    /// Compiles allowed to run at the same time.
+    pub max_concurrent_compiles: usize,
+    /// Compiles one client may run at the same time unless its key says, see `concurrency`.
+    pub max_concurrent_compiles_per_client: Option<usize>,
+    /// Requests allowed to wait for a compile slot before new ones are turned away.
+    pub compile_queue_length: usize,
+    /// How long a request waits for a compile slot before it gets a 503.
+    pub compile_queue_timeout: Duration,
+    /// How long a compile may run for requests that don't ask otherwise.
+    pub compile_timeout: Duration,
+    /// Longest compile timeout a request may ask for with `?timeout=`.
+    pub compile_timeout_ceiling: Duration,
+    /// Bytes of compiled PDFs kept in memory for repeated requests, zero disables the cache.
+    pub compile_cache_size: usize,
+    /// Directory compiled PDFs are also kept in, so they survive restarts.
+    pub compile_cache_dir: Option<PathBuf>,
+    /// Bytes the disk cache may grow to before its least recently used entries are deleted.
+    pub compile_cache_disk_size: u64,
+    /// How long a disk cache entry may go unused before it is deleted.
+    pub compile_cache_disk_max_age: Duration,
+    /// Uploaded parts larger than this many bytes are written to `spool_dir` instead of memory.
+    pub spool_threshold: usize,
+    /// Where large uploads are spooled, ideally fast local storage.
+    pub spool_dir: PathBuf,
+    /// Uploads are aborted when no bytes arrived for this long, zero waits forever.
+    pub upload_idle_timeout: Duration,
+    /// Uploaded files larger than this many bytes are refused with a 413.
+    pub max_file_size: Option<usize>,
+    /// Uploads larger than this many bytes in total are refused with a 413.
+    pub max_upload_size: Option<usize>,
+    /// Uploads of more files than this are refused with a 413.
+    pub max_files: Option<usize>,
+    /// Uploaded file paths longer than this many bytes are refused with a 400.
+    pub max_filename_length: usize,
+    /// `/ast` refuses files larger than this many bytes with a 413.
+    pub max_ast_size: usize,
+    /// Extensions of the files `?embed_sources=all` attaches besides the main file, lowercase
+    /// and without the dot.
+    pub embed_extensions: Vec<String>,
+    /// The files `?embed_sources=` attaches may have this many bytes in total, more are refused
+    /// with a 413.
+    pub embed_sources_max_size: usize,
+    /// Extensions uploaded files may have, lowercase and without the dot. `*` allows any.
+    pub allowed_extensions: Vec<String>,
+    /// Whether uploaded files may have no extension at all.
+    pub allow_extensionless: bool,
+    /// Bytes a resumable upload may have.
+    pub upload_session_max_size: u64,
+    /// How long a resumable upload is kept after it was opened.
+    pub upload_session_ttl: Duration,
+    /// Bytes the blobs uploaded for `/compile/manifest` may take, together.
+    pub blob_store_size: u64,
+    /// Addresses the server listens on, like `0.0.0.0:8080` or `[::]:8080`.
+    pub listen: Vec<String>,
+    /// Write logs as JSON lines instead of text.
+    pub log_json: bool,
+    /// OTLP collector spans are exported to, like `http://collector:4318/v1/traces`.
+    pub otlp_endpoint: Option<String>,
+    /// File the audit log of compile requests is appended to, `-` for stdout.
+    pub audit_log: Option<PathBuf>,
+    /// Bytes the audit log file may grow to before it is rotated.
+    pub audit_log_size: u64,
+    /// Rotated audit log files kept, as `<file>.1` and on.
+    pub audit_log_files: usize,
+    /// Also log the names of every uploaded file, not only the main file's.
+    pub audit_filenames: bool,
+    /// Separate address serving only `/metrics`, which is then not served on the others.
+    pub metrics_listen: Option<String>,
+    /// Unix domain socket the server also listens on.
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the socket file.
+    pub unix_socket_mode: u32,
+    /// Listen on the Unix domain socket alone, without TCP.
+    pub unix_socket_only: bool,
+    /// Certificate chain (PEM) to serve HTTPS with, plain HTTP without it.
+    pub tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of the TLS certificate.
+    pub tls_key: Option<PathBuf>,
+    /// HTTP worker threads.
+    pub workers: usize,
+    /// Blocking threads each worker may spawn for compiles, on top of the worker itself.
+    pub blocking_threads: usize,
+    /// Connections each worker accepts before it stops accepting new ones.
+    pub max_connections: usize,
+    /// How long a client may take to send the request head, zero waits forever.
+    pub client_request_timeout: Duration,
+    /// How long a closing connection may take to shut down.
+    pub client_disconnect_timeout: Duration,
+    /// How long idle connections are kept open, `None` closes them after each response.
+    pub keep_alive: Option<Duration>,
+    /// Requests each client IP may make per minute, zero disables rate limiting.
+    pub rate_limit_per_minute: usize,
+    /// Requests a client may make in a burst before the per-minute rate applies.
+    pub rate_limit_burst: usize,
+    /// Trust `forwarded_header` from any peer to name the client, since a reverse proxy sets it.
+    pub behind_proxy: bool,
+    /// Proxies whose `forwarded_header` is trusted, only theirs. Setting any implies
+    /// `behind_proxy` for requests they pass on.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Header naming the client behind the proxy, its last entry being the one the proxy
+    /// appended.
+    pub forwarded_header: HeaderName,
+    /// Only clients in these networks are served, empty serves everyone.
+    pub ip_allowlist: Vec<IpNet>,
+    /// Clients in these networks are turned away, even if the allowlist has them.
+    pub ip_denylist: Vec<IpNet>,
+    /// Bearer tokens whose requests may ask for `X-Priority: high`.
+    pub high_priority_tokens: Vec<String>,
+    /// How long the results of finished jobs are kept.
+    pub job_retention: Duration,
+    /// Bytes of job results kept at most, the oldest are dropped first beyond it.
+    pub job_results_size: usize,
+    /// How long a job's status stays queryable after its result was dropped.
+    pub job_tombstone_retention: Duration,
+    /// Revisions kept per stored project, the oldest dropped first, `None` keeping all.
+    pub project_revisions: Option<usize>,
+    /// How long revisions of stored projects are kept, the latest staying regardless.
+    pub project_revision_retention: Option<Duration>,
+    /// How long stored projects are kept after they were last changed or compiled, unless
+    /// they set their own TTL. `None` keeps them.
+    pub project_ttl: Option<Duration>,
+    /// SQLite database stored projects and jobs are kept in across restarts.
+    pub database: Option<PathBuf>,
+    /// Redis shared by all replicas for cached compiles and job results.
+    pub redis_url: Option<String>,
+    /// How long compiles stay cached in Redis.
+    pub redis_cache_max_age: Duration,
+    /// Hosts job callbacks may be sent to, like `hooks.example.com` or `*.example.com`.
+    pub callback_allowlist: Vec<String>,
+    /// Key signing callback bodies, required once the allowlist enables callbacks.
+    pub callback_secret: Option<String>,
+    /// Extra attempts after a failed callback delivery.
+    pub callback_retries: usize,
+    /// PDFs up to this many bytes are included in the callback, zero never includes them.
+    pub callback_inline_limit: usize,
+    /// Origins browsers may call from, like `https://editor.example.com`, `https://*.example.com`
+    /// or `*`. None by default, which leaves CORS off.
+    pub cors_origins: Vec<String>,
+    /// Methods cross-origin requests may use.
+    pub cors_methods: Vec<String>,
+    /// Request headers cross-origin requests may send, empty for the ones the API reads.
+    pub cors_headers: Vec<String>,
+    /// Let cross-origin requests carry cookies and authorization.
+    pub cors_credentials: bool,
+    /// How long browsers may cache a preflight answer.
+    pub cors_max_age: Duration,
+    /// PDFs up to this many bytes are sent in the last event of `/compile/events`, larger ones
+    /// are kept as a job to fetch.
+    pub events_inline_limit: usize,
+    /// How long a live preview waits for further edits before it recompiles.
+    pub preview_debounce: Duration,
+    /// Most pages a live preview renders, on top of the usual page limit.
+    pub preview_max_pages: usize,
+    /// Bytes the files of one live preview may add up to.
+    pub preview_max_size: usize,
+    /// Directory `GET /documents/{name}.pdf` compiles sources from, unset to serve none.
+    pub documents_root: Option<PathBuf>,
+    /// Fail compiles with warnings unless the request or its key says otherwise.
+    pub fail_on_warnings: bool,
+    /// Widest an image is assumed to be on the page, in millimetres, for `?max_image_ppi=`.
+    pub image_max_width: usize,
+    /// Optimize PDFs for the web unless the request says otherwise.
+    pub optimize_pdf: bool,
+    /// The qpdf binary PDFs are optimized with.
+    pub qpdf: PathBuf,
+    /// Run every stateless compile in its own worker process.
+    pub isolate_compiles: bool,
+    /// Bytes of address space an isolated compile may use.
+    pub isolation_memory_limit: u64,
+    /// Image isolated compiles run in a container of, instead of a child process.
+    pub isolation_image: Option<String>,
+    /// The docker compatible runtime the containers are started with.
+    pub isolation_runtime: PathBuf,
+    /// How long running compiles may take to finish once a shutdown started.
+    pub shutdown_grace_period: Duration,
+    /// Compile the example document at startup, so the first request finds warm caches.
+    pub warm_up: bool,
+    /// Keep reporting not ready when the warm-up compile fails.
+    pub warm_up_required: bool,
+    /// `/selftest` runs at most once per this long, for the whole server.
+    pub selftest_interval: Duration,
+    /// The bundled typst version compiles are tried again with, see `shadow`.
+    pub shadow_version: Option<String>,
+    /// Percentage of successful compiles tried again with `shadow_version`.
+    pub shadow_percent: usize,
+    /// Shadow compiles allowed to run at the same time, more are skipped.
+    pub shadow_max_concurrent: usize,
+}
+
+/// What uploads may contain unless `ALLOWED_EXTENSIONS` says otherwise: sources, images,
+/// data files and fonts.
+const DEFAULT_EXTENSIONS: [&str; 19] = [
+    "typ", "png", "jpg", "jpeg", "gif", "svg", "webp", "csv", "json", "yaml", "yml", "toml", "xml", "txt", "bib",
+    "ttf", "otf", "ttc", "otc",
+];
+
+/// The extensions `?embed_sources=all` attaches unless `EMBED_SOURCE_EXTENSIONS` says, the
+/// text sources and data.
+const EMBEDDED_EXTENSIONS: [&str; 9] = ["typ", "bib", "yml", "yaml", "json", "toml", "csv", "txt", "xml"];
+
+/// The configuration in effect, replaced as a whole when it is reloaded.
+pub struct ConfigHandle {
+    current: RwLock<Arc<Config>>,
+}
+
+/// The configuration as it was when a request arrived, so a reload halfway through
+/// the request can't mix old and new settings.
+pub struct CurrentConfig(Arc<Config>);
+
+/// Request header overriding the configured timezone for one compile.
+pub const TIMEZONE_HEADER: &str = "X-Timezone";
+
+impl Config {
+    /// Where the package store finds packages.
+    pub fn package_settings(&self) -> PackageSettings {
+        PackageSettings {
+            cache_dir: self.package_cache_dir.clone(),
+            local_dir: self.local_package_dir.clone(),
+            offline: self.offline,
+            registry_url: self.registry_url.clone(),
+            credentials: self.registry_credentials.clone(),
+            ca_cert: self.registry_ca_cert.clone(),
+        }
+    }
+
+    /// Read the command line, the config file and the environment again, as for a reload.
+    pub fn reread() -> Result<Self, Vec<String>> {
+        Self::from_args(env::args_os())
+    }
+
+    /// The config a command line of `args` gives, the program name first, with the config file
+    /// and the environment filling in as they do at startup. Tests build their servers with it.
+    pub fn from_args(args: impl IntoIterator<Item = impl Into<OsString>>) -> Result<Self, Vec<String>> {
+        let settings = Settings::parse(args.into_iter().map(Into::into)).map_err(|problem| vec![problem.to_string()])?;
+        Self::from_settings(settings)
+    }
+
+    /// Read the command line and the environment, exiting with a message if they are invalid.
+    pub fn load() -> Self {
+        let settings = Settings::parse(env::args_os()).unwrap_or_else(|problem| problem.exit());
+        let print_config = settings.print_config;
+        if print_config {
+            settings.print();
+        }
+        match Self::from_settings(settings) {
+            Ok(_) if print_config => { std::process::exit(0) }
+            Ok(config) => { config }
+            Err(problems) => {
+                eprintln!("ERROR: invalid configuration:");
+                for problem in problems {
+                    eprintln!("  {problem}");
+                }
+                std::process::exit(2);
+            }
+        }
+    }
+
+    fn from_settings(settings: Settings) -> Result<Self, Vec<String>> {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        let workers = settings.positive("WORKERS").unwrap_or(cores);
+        let config = Self {
+            font_dir: settings.path("FONT_DIR"),
+            cache_max_age: settings.number("CACHE_MAX_AGE").unwrap_or(10),
+            admin_token: settings.string("ADMIN_TOKEN"),
+            api_keys: settings.list("API_KEYS"),
+            keys: settings.path("API_KEYS_FILE")
+                .map(|path| keys::load(&path).unwrap_or_else(|problem| {
+                    settings.problem(problem);
+                    Vec::new()
+                }))
+                .unwrap_or_default(),
+            basic_auth: settings.flag("BASIC_AUTH"),
+            auth_realm: settings.string("AUTH_REALM").unwrap_or_else(|| "typstapi".into()),
+            signature_max_skew: seconds(settings.number("SIGNATURE_MAX_SKEW").unwrap_or(300)),
+            api_docs: settings.flag("API_DOCS"),
+            web_ui: settings.flag("WEB_UI"),
+            package_cache_dir: settings.path("PACKAGE_CACHE_DIR")
+                .unwrap_or_else(|| env::temp_dir().join("typst-packages")),
+            local_package_dir: settings.path("LOCAL_PACKAGE_DIR"),
+            offline: settings.flag("OFFLINE"),
+            registry_url: settings.string("REGISTRY_URL")
+                .unwrap_or_else(|| DEFAULT_REGISTRY_URL.into()),
+            registry_credentials: registry_credentials(),
+            registry_ca_cert: settings.path("REGISTRY_CA_CERT"),
+            preload_packages: settings.string("PRELOAD_PACKAGES")
+                .map(|specs| parse_package_specs(&specs, &settings))
+                .unwrap_or_default(),
+            timezone: settings.string("TIMEZONE")
+                .and_then(|zone| {
+                    let parsed = zone.parse().ok();
+                    if parsed.is_none() {
+                        settings.problem(format!("{} names an unknown timezone {zone}", describe("TIMEZONE")));
+                    }
+                    parsed
+                })
+                .unwrap_or(Tz::UTC),
+            max_pages: settings.number("MAX_PAGES").unwrap_or(1000),
+            max_pages_ceiling: settings.number("MAX_PAGES_CEILING")
+                .or_else(|| settings.number("MAX_PAGES"))
+                .unwrap_or(1000),
+            max_concurrent_compiles: settings.positive("MAX_CONCURRENT_COMPILES").unwrap_or(cores),
+            max_concurrent_compiles_per_client: settings.positive("MAX_CONCURRENT_COMPILES_PER_CLIENT"),
+            compile_queue_length: settings.number("COMPILE_QUEUE_LENGTH").unwrap_or(64),
+            compile_queue_timeout: seconds(settings.number("COMPILE_QUEUE_TIMEOUT").unwrap_or(30)),
+            compile_timeout: seconds(settings.number("COMPILE_TIMEOUT").unwrap_or(60)),
+            compile_timeout_ceiling: seconds(
+                settings.number("MAX_COMPILE_TIMEOUT")
+                    .or_else(|| settings.number("COMPILE_TIMEOUT"))
+                    .unwrap_or(60)
+            ),
+            compile_cache_size: settings.number("COMPILE_CACHE_MB").unwrap_or(256) * 1024 * 1024,
+            compile_cache_dir: settings.path("COMPILE_CACHE_DIR"),
+            compile_cache_disk_size: settings.number("COMPILE_CACHE_DISK_MB").unwrap_or(4096) as u64 * 1024 * 1024,
+            compile_cache_disk_max_age: seconds(settings.number("COMPILE_CACHE_DISK_MAX_AGE").unwrap_or(7 * 24 * 60 * 60)),
+            spool_threshold: settings.number("SPOOL_THRESHOLD_KB").unwrap_or(4 * 1024) * 1024,
+            spool_dir: settings.path("SPOOL_DIR").unwrap_or_else(env::temp_dir),
+            upload_idle_timeout: seconds(settings.number("UPLOAD_IDLE_TIMEOUT").unwrap_or(0)),
+            max_file_size: settings.positive("MAX_FILE_SIZE_KB").map(|kilobytes| kilobytes * 1024),
+            max_upload_size: settings.positive("MAX_UPLOAD_SIZE_KB").map(|kilobytes| kilobytes * 1024),
+            max_files: settings.positive("MAX_FILES"),
+            max_filename_length: settings.positive("MAX_FILENAME_LENGTH").unwrap_or(1024),
+            max_ast_size: settings.positive("MAX_AST_SIZE_KB").unwrap_or(1024) * 1024,
+            embed_extensions: Some(settings.list("EMBED_SOURCE_EXTENSIONS"))
+                .filter(|extensions| !extensions.is_empty())
+                .map(|extensions| extensions.iter().map(|extension| extension.trim_start_matches('.').to_lowercase()).collect())
+                .unwrap_or_else(|| EMBEDDED_EXTENSIONS.map(String::from).to_vec()),
+            embed_sources_max_size: settings.positive("EMBED_SOURCES_MAX_KB").unwrap_or(10240) * 1024,
+            allowed_extensions: Some(settings.list("ALLOWED_EXTENSIONS"))
+                .filter(|extensions| !extensions.is_empty())
+                .map(|extensions| extensions.iter().map(|extension| extension.trim_start_matches('.').to_lowercase()).collect())
+                .unwrap_or_else(|| DEFAULT_EXTENSIONS.map(String::from).to_vec()),
+            allow_extensionless: settings.flag("ALLOW_EXTENSIONLESS"),
+            upload_session_max_size: settings.positive("UPLOAD_SESSION_MAX_MB").unwrap_or(4096) as u64 * 1024 * 1024,
+            upload_session_ttl: seconds(settings.positive("UPLOAD_SESSION_TTL").unwrap_or(24 * 60 * 60)),
+            blob_store_size: settings.positive("BLOB_STORE_MB").unwrap_or(1024) as u64 * 1024 * 1024,
+            listen: listen_addresses(&settings),
+            log_json: match settings.string("LOG_FORMAT").as_deref() {
+                None | Some("text") => { false }
+                Some("json") => { true }
+                Some(format) => {
+                    settings.problem(format!("{} must be text or json, not {format}", describe("LOG_FORMAT")));
+                    false
+                }
+            },
+            otlp_endpoint: settings.string("OTLP_ENDPOINT"),
+            audit_log: settings.path("AUDIT_LOG"),
+            audit_log_size: settings.positive("AUDIT_LOG_MAX_MB").unwrap_or(100) as u64 * 1024 * 1024,
+            audit_log_files: settings.positive("AUDIT_LOG_FILES").unwrap_or(5),
+            audit_filenames: settings.flag("AUDIT_FILENAMES"),
+            metrics_listen: settings.string("METRICS_LISTEN"),
+            unix_socket: settings.path("UNIX_SOCKET"),
+            unix_socket_mode: settings.string("UNIX_SOCKET_MODE")
+                .and_then(|mode| {
+                    let parsed = u32::from_str_radix(mode.trim_start_matches("0o"), 8).ok().filter(|mode| *mode <= 0o777);
+                    if parsed.is_none() {
+                        settings.problem(format!("{} must be an octal mode like 660, not {mode}", describe("UNIX_SOCKET_MODE")));
+                    }
+                    parsed
+                })
+                .unwrap_or(0o660),
+            unix_socket_only: settings.flag("UNIX_SOCKET_ONLY"),
+            tls_cert: settings.path("TLS_CERT"),
+            tls_key: settings.path("TLS_KEY"),
+            workers,
+            // actix's own default, spreading 512 blocking threads over the workers.
+            blocking_threads: settings.positive("BLOCKING_THREADS").unwrap_or((512 / workers).max(1)),
+            max_connections: settings.positive("MAX_CONNECTIONS").unwrap_or(25_000),
+            // actix's own defaults.
+            client_request_timeout: seconds(settings.number("CLIENT_REQUEST_TIMEOUT").unwrap_or(5)),
+            client_disconnect_timeout: seconds(settings.number("CLIENT_DISCONNECT_TIMEOUT").unwrap_or(1)),
+            keep_alive: Some(seconds(settings.number("KEEP_ALIVE").unwrap_or(5))).filter(|keep_alive| !keep_alive.is_zero()),
+            rate_limit_per_minute: settings.number("RATE_LIMIT_PER_MINUTE").unwrap_or(0),
+            rate_limit_burst: settings.number("RATE_LIMIT_BURST")
+                .or_else(|| settings.number("RATE_LIMIT_PER_MINUTE"))
+                .unwrap_or(0),
+            behind_proxy: settings.flag("BEHIND_PROXY"),
+            trusted_proxies: networks(&settings, "TRUSTED_PROXIES"),
+            forwarded_header: forwarded_header(&settings),
+            ip_allowlist: networks(&settings, "IP_ALLOWLIST"),
+            ip_denylist: networks(&settings, "IP_DENYLIST"),
+            high_priority_tokens: settings.list("HIGH_PRIORITY_TOKENS"),
+            job_retention: seconds(settings.number("JOB_RETENTION").unwrap_or(60 * 60)),
+            job_results_size: settings.number("JOB_RESULTS_MB").unwrap_or(1024) * 1024 * 1024,
+            job_tombstone_retention: seconds(settings.number("JOB_TOMBSTONE_RETENTION").unwrap_or(24 * 60 * 60)),
+            project_revisions: Some(settings.number("PROJECT_REVISIONS").unwrap_or(100)).filter(|revisions| *revisions > 0),
+            project_revision_retention: settings.number("PROJECT_REVISION_RETENTION").filter(|retention| *retention > 0).map(seconds),
+            project_ttl: settings.number("PROJECT_TTL").filter(|ttl| *ttl > 0).map(seconds),
+            database: settings.path("DATABASE"),
+            redis_url: settings.string("REDIS_URL"),
+            redis_cache_max_age: seconds(settings.number("REDIS_CACHE_MAX_AGE").unwrap_or(24 * 60 * 60)),
+            callback_allowlist: settings.list("CALLBACK_ALLOWLIST").into_iter().map(|host| host.to_lowercase()).collect(),
+            callback_secret: settings.string("CALLBACK_SECRET"),
+            callback_retries: settings.number("CALLBACK_RETRIES").unwrap_or(3),
+            callback_inline_limit: settings.number("CALLBACK_INLINE_KB").unwrap_or(0) * 1024,
+            cors_origins: settings.list("CORS_ALLOWED_ORIGINS"),
+            cors_methods: Some(settings.list("CORS_ALLOWED_METHODS"))
+                .filter(|methods| !methods.is_empty())
+                .unwrap_or_else(|| ["GET", "POST", "PUT", "PATCH", "DELETE"].map(String::from).to_vec()),
+            cors_headers: settings.list("CORS_ALLOWED_HEADERS"),
+            cors_credentials: settings.flag("CORS_ALLOW_CREDENTIALS"),
+            cors_max_age: seconds(settings.number("CORS_MAX_AGE").unwrap_or(60 * 60)),
+            events_inline_limit: settings.number("EVENTS_INLINE_KB").unwrap_or(1024) * 1024,
+            preview_debounce: Duration::from_millis(settings.number("PREVIEW_DEBOUNCE_MS").unwrap_or(200) as u64),
+            preview_max_pages: settings.positive("PREVIEW_MAX_PAGES").unwrap_or(100),
+            preview_max_size: settings.positive("PREVIEW_MAX_SIZE_KB").unwrap_or(16 * 1024) * 1024,
+            documents_root: settings.path("DOCUMENTS_ROOT"),
+            fail_on_warnings: settings.flag("FAIL_ON_WARNINGS"),
+            image_max_width: settings.positive("IMAGE_MAX_WIDTH_MM").unwrap_or(216),
+            optimize_pdf: settings.flag("OPTIMIZE_PDF"),
+            qpdf: settings.path("QPDF_PATH").unwrap_or_else(|| PathBuf::from("qpdf")),
+            isolate_compiles: settings.flag("ISOLATE_COMPILES"),
+            isolation_memory_limit: settings.number("ISOLATION_MEMORY_MB").unwrap_or(4096) as u64 * 1024 * 1024,
+            isolation_image: settings.string("ISOLATION_IMAGE"),
+            isolation_runtime: settings.path("ISOLATION_RUNTIME").unwrap_or_else(|| PathBuf::from("docker")),
+            shutdown_grace_period: seconds(settings.number("SHUTDOWN_GRACE_PERIOD").unwrap_or(30)),
+            warm_up: settings.string("WARM_UP").is_none() || settings.flag("WARM_UP"),
+            warm_up_required: settings.flag("WARM_UP_REQUIRED"),
+            selftest_interval: seconds(settings.number("SELFTEST_INTERVAL").unwrap_or(10)),
+            shadow_version: settings.string("SHADOW_TYPST_VERSION"),
+            shadow_percent: settings.number("SHADOW_PERCENT").unwrap_or(0),
+            shadow_max_concurrent: settings.positive("SHADOW_MAX_CONCURRENT").unwrap_or(1),
+        };
+
+        config.check_combinations(&settings);
+        let problems = settings.problems();
+        match problems.is_empty() {
+            true => { Ok(config) }
+            false => { Err(problems) }
+        }
+    }
+
+    /// Report settings that are valid alone but contradict each other.
+    fn check_combinations(&self, settings: &Settings) {
+        if settings.string("MAX_PAGES").is_some() && self.max_pages > self.max_pages_ceiling {
+            settings.problem(format!(
+                "{} is {}, above {} of {}",
+                describe("MAX_PAGES"), self.max_pages, describe("MAX_PAGES_CEILING"), self.max_pages_ceiling
+            ));
+        }
+        if settings.string("COMPILE_TIMEOUT").is_some() && self.compile_timeout > self.compile_timeout_ceiling {
+            settings.problem(format!(
+                "{} is {} seconds, above {} of {}",
+                describe("COMPILE_TIMEOUT"), self.compile_timeout.as_secs(),
+                describe("MAX_COMPILE_TIMEOUT"), self.compile_timeout_ceiling.as_secs()
+            ));
+        }
+        if self.shadow_percent > 100 {
+            settings.problem(format!("{} must be from 0 to 100, not {}", describe("SHADOW_PERCENT"), self.shadow_percent));
+        }
+        if self.shadow_percent > 0 && self.shadow_version.is_none() {
+            settings.problem(format!("{} needs {}", describe("SHADOW_PERCENT"), describe("SHADOW_TYPST_VERSION")));
+        }
+        #[cfg(not(unix))]
+        if self.isolate_compiles {
+            settings.problem(format!("{} is only supported on Unix", describe("ISOLATE_COMPILES")));
+        }
+        if self.isolation_image.is_some() && !self.isolate_compiles {
+            settings.problem(format!("{} needs {}", describe("ISOLATION_IMAGE"), describe("ISOLATE_COMPILES")));
+        }
+        #[cfg(not(feature = "otel"))]
+        if self.otlp_endpoint.is_some() {
+            settings.problem(format!("{} needs a server built with the otel feature", describe("OTLP_ENDPOINT")));
+        }
+        #[cfg(not(feature = "redis"))]
+        if self.redis_url.is_some() {
+            settings.problem(format!("{} needs a server built with the redis feature", describe("REDIS_URL")));
+        }
+        if self.unix_socket_only && self.unix_socket.is_none() {
+            settings.problem(format!("{} needs {}", describe("UNIX_SOCKET_ONLY"), describe("UNIX_SOCKET")));
+        }
+        #[cfg(not(unix))]
+        if self.unix_socket.is_some() {
+            settings.problem(format!("{} is only supported on Unix", describe("UNIX_SOCKET")));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            settings.problem(format!("{} and {} must be set together", describe("TLS_CERT"), describe("TLS_KEY")));
+        }
+        if self.rate_limit_per_minute > 0 && self.rate_limit_burst == 0 {
+            settings.problem(format!("{} must be at least 1 while rate limiting is on", describe("RATE_LIMIT_BURST")));
+        }
+        if !self.callback_allowlist.is_empty() && self.callback_secret.is_none() {
+            settings.problem(format!(
+                "{} must be set when {} enables job callbacks",
+                describe("CALLBACK_SECRET"), describe("CALLBACK_ALLOWLIST")
+            ));
+        }
+        for origin in &self.cors_origins {
+            // A wildcard subdomain is checked as if it were the domain itself.
+            let concrete = origin.replacen("*.", "", 1);
+            let valid = url::Url::parse(&concrete).is_ok_and(|url| url.origin().ascii_serialization() == concrete);
+            if origin != "*" && !valid {
+                settings.problem(format!(
+                    "{} takes origins like https://editor.example.com, not {origin}", describe("CORS_ALLOWED_ORIGINS")
+                ));
+            }
+        }
+        if self.cors_credentials && self.cors_origins.iter().any(|origin| origin == "*") {
+            settings.problem(format!(
+                "{} can't be combined with * in {}, browsers refuse credentials for any origin",
+                describe("CORS_ALLOW_CREDENTIALS"), describe("CORS_ALLOWED_ORIGINS")
+            ));
+        }
+        if self.auth_realm.contains(['"', '\\']) {
+            settings.problem(format!("{} can't contain quotes or backslashes", describe("AUTH_REALM")));
+        }
+        if self.warm_up_required && !self.warm_up {
+            settings.problem(format!("{} needs the warm-up enabled", describe("WARM_UP_REQUIRED")));
+        }
+    }
+
+    /// Take over the settings that only apply at startup from `running`, naming the ones
+    /// that differed, so the configuration keeps describing what is in effect.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<String> {
+        let mut ignored = vec![];
+        keep(&mut ignored, "LISTEN", &mut self.listen, &running.listen);
+        keep(&mut ignored, "LOG_FORMAT", &mut self.log_json, &running.log_json);
+        keep(&mut ignored, "OTLP_ENDPOINT", &mut self.otlp_endpoint, &running.otlp_endpoint);
+        keep(&mut ignored, "AUDIT_LOG", &mut self.audit_log, &running.audit_log);
+        keep(&mut ignored, "AUDIT_LOG_MAX_MB", &mut self.audit_log_size, &running.audit_log_size);
+        keep(&mut ignored, "AUDIT_LOG_FILES", &mut self.audit_log_files, &running.audit_log_files);
+        keep(&mut ignored, "METRICS_LISTEN", &mut self.metrics_listen, &running.metrics_listen);
+        keep(&mut ignored, "UNIX_SOCKET", &mut self.unix_socket, &running.unix_socket);
+        keep(&mut ignored, "UNIX_SOCKET_MODE", &mut self.unix_socket_mode, &running.unix_socket_mode);
+        keep(&mut ignored, "UNIX_SOCKET_ONLY", &mut self.unix_socket_only, &running.unix_socket_only);
+        // Other certificate files are picked up, but switching between HTTP and HTTPS needs a new listener.
+        if self.tls_cert.is_some() != running.tls_cert.is_some() {
+            ignored.push(describe("TLS_CERT"));
+            self.tls_cert.clone_from(&running.tls_cert);
+            self.tls_key.clone_from(&running.tls_key);
+        }
+        keep(&mut ignored, "WORKERS", &mut self.workers, &running.workers);
+        keep(&mut ignored, "BLOCKING_THREADS", &mut self.blocking_threads, &running.blocking_threads);
+        keep(&mut ignored, "MAX_CONNECTIONS", &mut self.max_connections, &running.max_connections);
+        keep(&mut ignored, "CLIENT_REQUEST_TIMEOUT", &mut self.client_request_timeout, &running.client_request_timeout);
+        keep(&mut ignored, "CLIENT_DISCONNECT_TIMEOUT", &mut self.client_disconnect_timeout, &running.client_disconnect_timeout);
+        keep(&mut ignored, "KEEP_ALIVE", &mut self.keep_alive, &running.keep_alive);
+        keep(&mut ignored, "PACKAGE_CACHE_DIR", &mut self.package_cache_dir, &running.package_cache_dir);
+        keep(&mut ignored, "LOCAL_PACKAGE_DIR", &mut self.local_package_dir, &running.local_package_dir);
+        keep(&mut ignored, "OFFLINE", &mut self.offline, &running.offline);
+        keep(&mut ignored, "REGISTRY_URL", &mut self.registry_url, &running.registry_url);
+        keep(&mut ignored, "REGISTRY_CA_CERT", &mut self.registry_ca_cert, &running.registry_ca_cert);
+        keep(&mut ignored, "COMPILE_CACHE_MB", &mut self.compile_cache_size, &running.compile_cache_size);
+        keep(&mut ignored, "COMPILE_CACHE_DIR", &mut self.compile_cache_dir, &running.compile_cache_dir);
+        keep(&mut ignored, "COMPILE_CACHE_DISK_MB", &mut self.compile_cache_disk_size, &running.compile_cache_disk_size);
+        keep(&mut ignored, "COMPILE_CACHE_DISK_MAX_AGE", &mut self.compile_cache_disk_max_age, &running.compile_cache_disk_max_age);
+        keep(&mut ignored, "BLOB_STORE_MB", &mut self.blob_store_size, &running.blob_store_size);
+        keep(&mut ignored, "JOB_RETENTION", &mut self.job_retention, &running.job_retention);
+        keep(&mut ignored, "JOB_RESULTS_MB", &mut self.job_results_size, &running.job_results_size);
+        keep(&mut ignored, "JOB_TOMBSTONE_RETENTION", &mut self.job_tombstone_retention, &running.job_tombstone_retention);
+        keep(&mut ignored, "DATABASE", &mut self.database, &running.database);
+        keep(&mut ignored, "REDIS_URL", &mut self.redis_url, &running.redis_url);
+        keep(&mut ignored, "REDIS_CACHE_MAX_AGE", &mut self.redis_cache_max_age, &running.redis_cache_max_age);
+        keep(&mut ignored, "CORS_ALLOWED_ORIGINS", &mut self.cors_origins, &running.cors_origins);
+        keep(&mut ignored, "CORS_ALLOWED_METHODS", &mut self.cors_methods, &running.cors_methods);
+        keep(&mut ignored, "CORS_ALLOWED_HEADERS", &mut self.cors_headers, &running.cors_headers);
+        keep(&mut ignored, "CORS_ALLOW_CREDENTIALS", &mut self.cors_credentials, &running.cors_credentials);
+        keep(&mut ignored, "CORS_MAX_AGE", &mut self.cors_max_age, &running.cors_max_age);
+        keep(&mut ignored, "ISOLATE_COMPILES", &mut self.isolate_compiles, &running.isolate_compiles);
+        keep(&mut ignored, "ISOLATION_MEMORY_MB", &mut self.isolation_memory_limit, &running.isolation_memory_limit);
+        keep(&mut ignored, "ISOLATION_IMAGE", &mut self.isolation_image, &running.isolation_image);
+        keep(&mut ignored, "ISOLATION_RUNTIME", &mut self.isolation_runtime, &running.isolation_runtime);
+        keep(&mut ignored, "SHUTDOWN_GRACE_PERIOD", &mut self.shutdown_grace_period, &running.shutdown_grace_period);
+        keep(&mut ignored, "WARM_UP", &mut self.warm_up, &running.warm_up);
+        keep(&mut ignored, "WARM_UP_REQUIRED", &mut self.warm_up_required, &running.warm_up_required);
+        ignored
+    }
+
+    /// The compile options of a request that tunes nothing.
+    pub fn default_options(&self) -> CompileOptions {
+        CompileOptions {
+            timezone: self.timezone,
+            max_pages: self.max_pages.min(self.max_pages_ceiling),
+            include_dependencies: false,
+            timeout: self.compile_timeout.min(self.compile_timeout_ceiling),
+            no_cache: false,
+            priority: Priority::Normal,
+            inputs: BTreeMap::new(),
+            progress: Progress::default(),
+            fail_on_warnings: self.fail_on_warnings,
+            max_image_size: None,
+            optimizer: Some(self.qpdf.clone()).filter(|_| self.optimize_pdf),
+            tenant: None,
+            now: None,
+            pdf_a: false,
+            embedding: None,
+            dry_run: false,
+        }
+    }
+
+    /// Everything a request may tune about its compile, validated against the server limits.
+    pub fn compile_options(&self, request: &HttpRequest) -> Result<CompileOptions, Error> {
+        let query = web::Query::<OptionsQuery>::from_query(request.query_string())
+            .map_err(|problem| Code::InvalidQuery.error(problem))?;
+
+        let max_pages = match query.max_pages {
+            None => { self.max_pages.min(self.max_pages_ceiling) }
+            Some(requested) if requested <= self.max_pages_ceiling => { requested }
+            Some(requested) => {
+                return Err(Code::InvalidOption.error(format!(
+                    "max_pages={requested} exceeds the server's ceiling of {}",
+                    self.max_pages_ceiling
+                )))
+            }
+        };
+
+        let timeout = match query.timeout {
+            None => { self.compile_timeout.min(self.compile_timeout_ceiling) }
+            Some(requested) if Duration::from_secs(requested) <= self.compile_timeout_ceiling => {
+                Duration::from_secs(requested)
+            }
+            Some(requested) => {
+                return Err(Code::InvalidOption.error(format!(
+                    "timeout={requested} exceeds the server's ceiling of {} seconds",
+                    self.compile_timeout_ceiling.as_secs()
+                )))
+            }
+        };
+
+        let max_image_size = match query.max_image_ppi {
+            None => { None }
+            Some(0) => { return Err(Code::InvalidOption.error("max_image_ppi must be at least 1")) }
+            Some(ppi) => { Some((ppi as f64 * self.image_max_width as f64 / 25.4).ceil().min(u32::MAX as f64) as u32) }
+        };
+
+        Ok(CompileOptions {
+            timezone: self.timezone_for(request)?,
+            max_pages,
+            include_dependencies: query.include.as_deref()
+                .is_some_and(|include| include.split(',').any(|part| part == "deps")),
+            timeout,
+            no_cache: query.no_cache.unwrap_or(false),
+            priority: self.priority_for(request)?,
+            inputs: BTreeMap::new(),
+            progress: Progress::default(),
+            fail_on_warnings: query.fail_on_warnings.unwrap_or_else(|| self.fail_on_warnings_for(request)),
+            max_image_size,
+            optimizer: Some(self.qpdf.clone()).filter(|_| query.optimize.unwrap_or(self.optimize_pdf)),
+            tenant: request.extensions().get::<Tenant>().map(|tenant| tenant.0.clone()),
+            now: query.date,
+            pdf_a: matches!(query.standard, Some(Standard::PdfA2b)),
+            embedding: None,
+            dry_run: false,
+        })
+    }
+
+    /// Whether a request that didn't say fails on warnings, by its key or else the server default.
+    fn fail_on_warnings_for(&self, request: &HttpRequest) -> bool {
+        let extensions = request.extensions();
+        let key = extensions.get::<Principal>()
+            .and_then(|principal| self.keys.iter().find(|key| key.name == principal.0));
+        key.and_then(|key| key.fail_on_warnings).unwrap_or(self.fail_on_warnings)
+    }
+
+    /// The queue priority a request asked for, if its token allows it.
+    ///
+    /// A key listing `priorities` may use only those, without the header `normal` if listed
+    /// or else its highest. Otherwise anyone may lower their priority, only callers
+    /// authenticating with one of `high_priority_tokens`, in any way `auth` accepts a key,
+    /// may raise it.
+    fn priority_for(&self, request: &HttpRequest) -> Result<Priority, Error> {
+        let allowed = {
+            let extensions = request.extensions();
+            extensions.get::<Principal>()
+                .and_then(|principal| self.keys.iter().find(|key| key.name == principal.0))
+                .and_then(|key| key.priorities.clone())
+        };
+        let requested: Option<Priority> = match request.headers().get(PRIORITY_HEADER) {
+            None => { None }
+            Some(value) => {
+                Some(value.to_str().map_err(|problem| Code::InvalidOption.error(problem))?
+                    .parse().map_err(|problem| Code::InvalidOption.error(problem))?)
+            }
+        };
+        if let Some(allowed) = allowed {
+            return match requested {
+                None if allowed.contains(&Priority::Normal) => { Ok(Priority::Normal) }
+                None => { Ok(allowed.iter().copied().min().unwrap_or_default()) }
+                Some(priority) if allowed.contains(&priority) => { Ok(priority) }
+                Some(_) => { Err(Code::PriorityNotAllowed.error("This key may not use the X-Priority it sent")) }
+            };
+        }
+        let priority = match requested {
+            None => { return Ok(Priority::Normal) }
+            Some(priority) => { priority }
+        };
+        if priority != Priority::High {
+            return Ok(priority);
+        }
+
+        // Every token is compared, so the timing doesn't tell which one matched.
+        let permitted = auth::credential(request.headers(), self).is_some_and(|given| {
+            self.high_priority_tokens.iter().fold(false, |found, allowed| auth::secrets_match(&given, allowed) | found)
+        });
+        match permitted {
+            true => { Ok(priority) }
+            false => { Err(Code::PriorityNotAllowed.error("This caller may not use X-Priority: high")) }
+        }
+    }
+
+    /// The timezone a request asked for in its header, or the configured one.
+    fn timezone_for(&self, request: &HttpRequest) -> Result<Tz, Error> {
+        let requested = match request.headers().get(TIMEZONE_HEADER) {
+            None => { return Ok(self.timezone) }
+            Some(value) => { value.to_str().map_err(|problem| Code::InvalidOption.error(problem))? }
+        };
+
+        requested.parse()
+            .map_err(|_| Code::UnknownTimezone.error(format!("Unknown timezone {requested}")))
+    }
+}
+
+/// Collect `REGISTRY_TOKEN` and `REGISTRY_USERNAME`/`REGISTRY_PASSWORD`, each optionally
+/// suffixed with `_<NAMESPACE>` to only apply to that namespace.
+fn registry_credentials() -> HashMap<String, Credentials> {
+    let mut credentials = HashMap::new();
+
+    for (key, value) in env::vars() {
+        if let Some(namespace) = key.strip_prefix("REGISTRY_TOKEN").and_then(namespace) {
+            credentials.insert(namespace, Credentials::Bearer(value));
+        } else if let Some(suffix) = key.strip_prefix("REGISTRY_USERNAME") {
+            if let Some(namespace) = namespace(suffix) {
+                let password = env::var(format!("REGISTRY_PASSWORD{suffix}")).unwrap_or_default();
+                credentials.insert(namespace, Credentials::Basic { username: value, password });
+            }
+        }
+    }
+
+    credentials
+}
+
+fn forwarded_header(settings: &Settings) -> HeaderName {
+    let name = settings.string("FORWARDED_HEADER").unwrap_or_else(|| "X-Forwarded-For".into());
+    HeaderName::try_from(name.as_str()).unwrap_or_else(|_| {
+        settings.problem(format!("{} must be a header name, not {name}", describe("FORWARDED_HEADER")));
+        HeaderName::from_static("x-forwarded-for")
+    })
+}
+
+/// Networks like `10.0.0.0/8`, a single address standing for itself.
+fn networks(settings: &Settings, name: &str) -> Vec<IpNet> {
+    settings.list(name).into_iter()
+        .filter_map(|network| {
+            let parsed = network.parse().or_else(|_| network.parse::<IpAddr>().map(IpNet::from));
+            if parsed.is_err() {
+                settings.problem(format!("{} takes addresses and networks like 10.0.0.0/8, not {network}", describe(name)));
+            }
+            parsed.ok()
+        })
+        .collect()
+}
+
+/// `LISTEN`, or else the one address `BIND_ADDR` and `PORT` make up.
+fn listen_addresses(settings: &Settings) -> Vec<String> {
+    let listen = settings.list("LISTEN");
+    for address in &listen {
+        let valid = address.rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            settings.problem(format!("{} takes addresses like 0.0.0.0:8080 or [::]:8080, not {address}", describe("LISTEN")));
+        }
+    }
+    if !listen.is_empty() {
+        return listen;
+    }
+
+    let host = settings.string("BIND_ADDR").unwrap_or_else(|| "0.0.0.0".into());
+    let port = settings.number("PORT")
+        .and_then(|port| {
+            let port = u16::try_from(port).ok();
+            if port.is_none() {
+                settings.problem(format!("{} must be at most 65535", describe("PORT")));
+            }
+            port
+        })
+        .unwrap_or(8080);
+    match host.contains(':') {
+        true => { vec![format!("[{host}]:{port}")] }
+        false => { vec![format!("{host}:{port}")] }
+    }
+}
+
+/// Parse a comma separated list like `@preview/cetz:0.2.2,@preview/tablex:0.0.8`.
+fn parse_package_specs(specs: &str, settings: &Settings) -> Vec<PackageSpec> {
+    specs.split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .filter_map(|spec| match spec.parse() {
+            Ok(spec) => { Some(spec) }
+            Err(problem) => {
+                settings.problem(format!("Invalid package {spec} in {}: {problem}", describe("PRELOAD_PACKAGES")));
+                None
+            }
+        })
+        .collect()
+}
+
+fn namespace(suffix: &str) -> Option<String> {
+    if suffix.is_empty() {
+        return Some("*".into());
+    }
+    suffix.strip_prefix('_').map(str::to_lowercase)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OptionsQuery {
+    /// Fail compiles producing more pages, up to the server's ceiling.
+    max_pages: Option<usize>,
+    /// Compile timeout in seconds.
+    timeout: Option<u64>,
+    /// Compile again even if the result is cached.
+    no_cache: Option<bool>,
+    /// Comma separated extras for the JSON response mode, like `deps`.
+    include: Option<String>,
+    /// Answer a compile with warnings with a 422 instead of the PDF.
+    fail_on_warnings: Option<bool>,
+    /// Downscale uploaded PNGs and JPEGs to about this resolution on the page.
+    max_image_ppi: Option<u32>,
+    /// Linearize and recompress the PDF for fast web view, see `OPTIMIZE_PDF`.
+    optimize: Option<bool>,
+    /// Pin the time `datetime.today()` returns and the PDF is stamped with, like
+    /// `2024-05-01T12:00:00Z`, so compiling again gives the same bytes.
+    date: Option<DateTime<Utc>>,
+    /// `pdfa-2b` exports a PDF/A-2b document for archiving, or fails with what keeps the
+    /// document from conforming. Only typst 0.12 exports it.
+    #[param(inline)]
+    standard: Option<Standard>,
+}
+
+/// A standard the PDF conforms to, see `?standard=`.
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+pub enum Standard {
+    #[serde(rename = "pdfa-2b")]
+    PdfA2b,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Config) -> Self {
+        Self { current: RwLock::new(Arc::new(config)) }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+}
+
+impl FromRequest for CurrentConfig {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let handle = request.app_data::<web::Data<ConfigHandle>>().expect("The ConfigHandle is registered");
+        ready(Ok(CurrentConfig(handle.current())))
+    }
+}
+
+impl CurrentConfig {
+    /// Keep this configuration past the request, for work it leaves running.
+    pub fn snapshot(&self) -> Arc<Config> {
+        self.0.clone()
+    }
+}
+
+impl Deref for CurrentConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.0
+    }
+}
+
+/// Reset `reloaded` to `running`, remembering `name` if they differed.
+fn keep<T: PartialEq + Clone>(ignored: &mut Vec<String>, name: &str, reloaded: &mut T, running: &T) {
+    if reloaded != running {
+        ignored.push(describe(name));
+        *reloaded = running.clone();
+    }
+}
+
+fn seconds(seconds: usize) -> Duration {
+    Duration::from_secs(seconds as u64)
+}
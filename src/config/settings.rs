@@ -0,0 +1,359 @@
+//! Where configuration values come from: a command line flag, else the `--config` file,
+//! else the environment variable, else the built-in default applied by `Config`.
+//!
+//! The file is TOML with one key per option, named like the flag with underscores, such as
+//! `max_pages = 100`. Lists may be given as arrays.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use clap::{Arg, ArgAction, Command};
+
+/// One server option, settable as `--<flag>` or through its environment variable.
+pub struct Setting {
+    pub env: &'static str,
+    pub flag: &'static str,
+    pub help: &'static str,
+    /// Given without a value on the command line, like `--offline`.
+    pub switch: bool,
+}
+
+const fn value(env: &'static str, flag: &'static str, help: &'static str) -> Setting {
+    Setting { env, flag, help, switch: false }
+}
+
+const fn switch(env: &'static str, flag: &'static str, help: &'static str) -> Setting {
+    Setting { env, flag, help, switch: true }
+}
+
+/// Every option the server reads. `REGISTRY_TOKEN_<NAMESPACE>` and the other registry
+/// credentials are environment only, since their names depend on the namespace.
+pub const SETTINGS: &[Setting] = &[
+    value("LISTEN", "listen", "Comma separated addresses to listen on, like 0.0.0.0:8080,[::]:8080 [default: --bind-addr and --port]"),
+    value("BIND_ADDR", "bind-addr", "Address to listen on without --listen [default: 0.0.0.0]"),
+    value("PORT", "port", "Port to listen on without --listen, 0 picks a free one [default: 8080]"),
+    value("LOG_FORMAT", "log-format", "text or json, RUST_LOG sets the level [default: text]"),
+    value("OTLP_ENDPOINT", "otlp-endpoint", "OTLP/HTTP collector traces are exported to, needs the otel feature"),
+    value("AUDIT_LOG", "audit-log", "File every compile request is recorded in as JSON lines, - for stdout"),
+    value("AUDIT_LOG_MAX_MB", "audit-log-max-mb", "Megabytes the audit log may grow to before it is rotated [default: 100]"),
+    value("AUDIT_LOG_FILES", "audit-log-files", "Rotated audit logs kept [default: 5]"),
+    switch("AUDIT_FILENAMES", "audit-filenames", "Record every uploaded file name in the audit log, not only the main file's"),
+    value("METRICS_LISTEN", "metrics-listen", "Internal address like 127.0.0.1:9090 serving /metrics instead of the main listeners"),
+    value("UNIX_SOCKET", "unix-socket", "Unix domain socket to listen on as well"),
+    value("UNIX_SOCKET_MODE", "unix-socket-mode", "Octal permissions of the socket file [default: 660]"),
+    switch("UNIX_SOCKET_ONLY", "unix-socket-only", "Listen on --unix-socket alone, without TCP"),
+    value("TLS_CERT", "tls-cert", "Certificate chain (PEM) to serve HTTPS with, reread on SIGHUP"),
+    value("TLS_KEY", "tls-key", "Private key (PEM) of --tls-cert"),
+    value("WORKERS", "workers", "HTTP worker threads [default: one per core]"),
+    value("BLOCKING_THREADS", "blocking-threads", "Blocking threads per worker [default: 512 divided by the workers]"),
+    value("MAX_CONNECTIONS", "max-connections", "Connections per worker [default: 25000]"),
+    value("CLIENT_REQUEST_TIMEOUT", "client-request-timeout", "Seconds a client may take to send the request head, 0 waits forever [default: 5]"),
+    value("CLIENT_DISCONNECT_TIMEOUT", "client-disconnect-timeout", "Seconds a closing connection may take to shut down [default: 1]"),
+    value("KEEP_ALIVE", "keep-alive", "Seconds idle connections are kept open, 0 closes them after each response [default: 5]"),
+    value("FONT_DIR", "font-dir", "Directory searched for fonts in addition to the system fonts"),
+    value("CACHE_MAX_AGE", "cache-max-age", "Compiles a memoized result may go unused before it is evicted [default: 10]"),
+    value("ADMIN_TOKEN", "admin-token", "Bearer token guarding the admin endpoints, which are disabled without it"),
+    value("API_KEYS", "api-keys", "Comma separated keys required on every endpoint but the probes, metrics and version"),
+    value("API_KEYS_FILE", "api-keys-file", "TOML or JSON file of named keys, each optionally disabled, expiring or limited to some endpoints"),
+    switch("BASIC_AUTH", "basic-auth", "Also accept a key as the password of HTTP Basic credentials"),
+    value("AUTH_REALM", "auth-realm", "Realm named in authentication challenges [default: typstapi]"),
+    value("SIGNATURE_MAX_SKEW", "signature-max-skew", "Seconds the timestamp of a signed request may be off [default: 300]"),
+    switch("API_DOCS", "api-docs", "Serve the OpenAPI document on /openapi.json and a Swagger UI on /docs"),
+    switch("WEB_UI", "web-ui", "Serve a page on /ui to paste typst and see what it compiles to"),
+    value("PACKAGE_CACHE_DIR", "package-cache-dir", "Where downloaded packages are unpacked [default: <temp dir>/typst-packages]"),
+    value("LOCAL_PACKAGE_DIR", "local-package-dir", "Root of the @local package namespace, laid out as <name>/<version>/"),
+    switch("OFFLINE", "offline", "Never fetch anything, such as packages, from the network"),
+    value("REGISTRY_URL", "registry-url", "Package archive URL with {namespace}, {name} and {version} placeholders [default: the typst registry]"),
+    value("REGISTRY_CA_CERT", "registry-ca-cert", "Extra root certificate (PEM) trusted for registry connections"),
+    value("PRELOAD_PACKAGES", "preload-packages", "Comma separated packages fetched at startup, like @preview/cetz:0.2.2"),
+    value("TIMEZONE", "timezone", "Zone documents see as today, unless a request overrides it [default: UTC]"),
+    value("MAX_PAGES", "max-pages", "Page limit for requests that don't ask for one [default: 1000]"),
+    value("MAX_PAGES_CEILING", "max-pages-ceiling", "Highest page limit a request may ask for [default: --max-pages]"),
+    value("MAX_CONCURRENT_COMPILES", "max-concurrent-compiles", "Compiles running at the same time [default: one per core]"),
+    value("MAX_CONCURRENT_COMPILES_PER_CLIENT", "max-concurrent-compiles-per-client", "Compiles one key or address may run at the same time, unless its key says [default: unlimited]"),
+    value("COMPILE_QUEUE_LENGTH", "compile-queue-length", "Requests waiting for a compile slot before new ones get a 503 [default: 64]"),
+    value("COMPILE_QUEUE_TIMEOUT", "compile-queue-timeout", "Seconds a request waits for a compile slot [default: 30]"),
+    value("COMPILE_TIMEOUT", "compile-timeout", "Seconds a compile may run unless the request asks otherwise [default: 60]"),
+    value("MAX_COMPILE_TIMEOUT", "max-compile-timeout", "Longest timeout in seconds a request may ask for [default: --compile-timeout]"),
+    value("COMPILE_CACHE_MB", "compile-cache-mb", "Megabytes of compiled PDFs kept in memory, 0 disables the cache [default: 256]"),
+    value("COMPILE_CACHE_DIR", "compile-cache-dir", "Directory compiled PDFs are also kept in, surviving restarts"),
+    value("COMPILE_CACHE_DISK_MB", "compile-cache-disk-mb", "Megabytes the disk cache may grow to [default: 4096]"),
+    value("COMPILE_CACHE_DISK_MAX_AGE", "compile-cache-disk-max-age", "Seconds a disk cache entry may go unused [default: one week]"),
+    value("UPLOAD_IDLE_TIMEOUT", "upload-idle-timeout", "Seconds an upload may stall before it is aborted, 0 waits forever [default: 0]"),
+    value("MAX_FILE_SIZE_KB", "max-file-size-kb", "Kilobytes an uploaded file may have [default: unlimited]"),
+    value("MAX_UPLOAD_SIZE_KB", "max-upload-size-kb", "Kilobytes an upload may have in total [default: unlimited]"),
+    value("MAX_FILES", "max-files", "Files an upload may have [default: unlimited]"),
+    value("MAX_FILENAME_LENGTH", "max-filename-length", "Bytes the path of an uploaded file may have, normalized to NFC [default: 1024]"),
+    value("MAX_AST_SIZE_KB", "max-ast-size-kb", "Kilobytes a file sent to POST /ast may have [default: 1024]"),
+    value("EMBED_SOURCE_EXTENSIONS", "embed-source-extensions", "Comma separated extensions of the files ?embed_sources=all attaches to the PDF [default: typ,bib,yml,yaml,json,toml,csv,txt,xml]"),
+    value("EMBED_SOURCES_MAX_KB", "embed-sources-max-kb", "Kilobytes the sources ?embed_sources= attaches to the PDF may have in total [default: 10240]"),
+    value("ALLOWED_EXTENSIONS", "allowed-extensions", "Comma separated extensions uploaded files may have, or * for any [default: typst sources, images, data files and fonts]"),
+    switch("ALLOW_EXTENSIONLESS", "allow-extensionless", "Accept uploaded files without an extension"),
+    value("UPLOAD_SESSION_MAX_MB", "upload-session-max-mb", "Megabytes a resumable upload may have [default: 4096]"),
+    value("UPLOAD_SESSION_TTL", "upload-session-ttl", "Seconds a resumable upload is kept after it was opened [default: 86400]"),
+    value("BLOB_STORE_MB", "blob-store-mb", "Megabytes of blobs kept for /compile/manifest, least recently used evicted [default: 1024]"),
+    value("SPOOL_THRESHOLD_KB", "spool-threshold-kb", "Uploaded files larger than this many kilobytes are spooled to disk [default: 4096]"),
+    value("SPOOL_DIR", "spool-dir", "Where large uploads are spooled [default: the temp dir]"),
+    value("RATE_LIMIT_PER_MINUTE", "rate-limit-per-minute", "Requests per minute and key, or client IP for requests without a key from the keys file, 0 disables rate limiting [default: 0]"),
+    value("RATE_LIMIT_BURST", "rate-limit-burst", "Requests a client may burst [default: --rate-limit-per-minute]"),
+    switch("BEHIND_PROXY", "behind-proxy", "Trust --forwarded-header from any peer to name the client, see --trusted-proxies"),
+    value("TRUSTED_PROXIES", "trusted-proxies", "Comma separated proxy networks whose forwarded header alone is trusted, the right-most untrusted hop counting"),
+    value("FORWARDED_HEADER", "forwarded-header", "Header the proxy names the client in, the last entry counting (Forwarded is parsed per RFC 7239) [default: X-Forwarded-For]"),
+    value("IP_ALLOWLIST", "ip-allowlist", "Comma separated addresses and networks like 10.0.0.0/8 alone served [default: everyone]"),
+    value("IP_DENYLIST", "ip-denylist", "Comma separated addresses and networks turned away, even if allowed"),
+    value("HIGH_PRIORITY_TOKENS", "high-priority-tokens", "Comma separated API keys allowed to send X-Priority: high, however they are sent"),
+    value("JOB_RETENTION", "job-retention", "Seconds finished job results are kept [default: 3600]"),
+    value("JOB_RESULTS_MB", "job-results-mb", "Megabytes of job results kept at most [default: 1024]"),
+    value("JOB_TOMBSTONE_RETENTION", "job-tombstone-retention", "Seconds a job's status is kept after its result [default: 86400]"),
+    value("PROJECT_REVISIONS", "project-revisions", "Revisions kept per stored project, 0 keeps all [default: 100]"),
+    value("PROJECT_REVISION_RETENTION", "project-revision-retention", "Seconds revisions of stored projects are kept, the latest always [default: forever]"),
+    value("PROJECT_TTL", "project-ttl", "Seconds stored projects are kept after their last change or compile [default: forever]"),
+    value("DATABASE", "database", "SQLite file projects and jobs are kept in across restarts, needs the sqlite feature"),
+    value("REDIS_URL", "redis-url", "Redis shared by replicas for cached compiles and job results"),
+    value("REDIS_CACHE_MAX_AGE", "redis-cache-max-age", "Seconds compiles stay cached in Redis [default: 86400]"),
+    value("CALLBACK_ALLOWLIST", "callback-allowlist", "Comma separated hosts job callbacks may go to, like *.example.com"),
+    value("CALLBACK_SECRET", "callback-secret", "Key signing job callbacks, required with --callback-allowlist"),
+    value("CALLBACK_RETRIES", "callback-retries", "Extra attempts after a failed callback [default: 3]"),
+    value("CALLBACK_INLINE_KB", "callback-inline-kb", "PDFs up to this many kilobytes are included in callbacks [default: 0]"),
+    value("CORS_ALLOWED_ORIGINS", "cors-allowed-origins", "Comma separated origins browsers may call from, like https://*.example.com or * [default: none, CORS off]"),
+    value("CORS_ALLOWED_METHODS", "cors-allowed-methods", "Methods cross-origin requests may use [default: GET,POST,PUT,PATCH,DELETE]"),
+    value("CORS_ALLOWED_HEADERS", "cors-allowed-headers", "Headers cross-origin requests may send [default: the ones the API reads]"),
+    switch("CORS_ALLOW_CREDENTIALS", "cors-allow-credentials", "Let cross-origin requests carry cookies and authorization"),
+    value("CORS_MAX_AGE", "cors-max-age", "Seconds browsers may cache a preflight answer [default: 3600]"),
+    value("EVENTS_INLINE_KB", "events-inline-kb", "PDFs up to this many kilobytes are sent inline by /compile/events, larger ones kept as a job [default: 1024]"),
+    value("PREVIEW_DEBOUNCE_MS", "preview-debounce-ms", "Milliseconds a live preview waits for more edits before recompiling [default: 200]"),
+    value("PREVIEW_MAX_PAGES", "preview-max-pages", "Pages a live preview renders at most [default: 100]"),
+    value("PREVIEW_MAX_SIZE_KB", "preview-max-size-kb", "Kilobytes the files of one live preview may add up to [default: 16384]"),
+    value("DOCUMENTS_ROOT", "documents-root", "Directory GET /documents/{name}.pdf compiles sources from [default: none]"),
+    value("IMAGE_MAX_WIDTH_MM", "image-max-width-mm", "Millimetres an image is assumed to span at most for max_image_ppi [default: 216]"),
+    switch("OPTIMIZE_PDF", "optimize-pdf", "Linearize and recompress PDFs with qpdf unless the request sets optimize=false"),
+    value("QPDF_PATH", "qpdf-path", "The qpdf binary PDFs are optimized with [default: qpdf]"),
+    switch("FAIL_ON_WARNINGS", "fail-on-warnings", "Answer compiles with warnings with a 422 unless the request or its key sets fail_on_warnings=false"),
+    switch("ISOLATE_COMPILES", "isolate-compiles", "Run every stateless compile in its own worker process"),
+    value("ISOLATION_MEMORY_MB", "isolation-memory-mb", "Megabytes of address space an isolated compile may use [default: 4096]"),
+    value("ISOLATION_IMAGE", "isolation-image", "Image with the typstapi binary isolated compiles run in a container of, offline, instead of a child process"),
+    value("ISOLATION_RUNTIME", "isolation-runtime", "The docker compatible runtime --isolation-image containers are started with [default: docker]"),
+    value("SHUTDOWN_GRACE_PERIOD", "shutdown-grace-period", "Seconds running compiles get to finish on SIGTERM or SIGINT [default: 30]"),
+    value("WARM_UP", "warm-up", "Compile the example document at startup, true or false [default: true]"),
+    switch("WARM_UP_REQUIRED", "warm-up-required", "Report not ready while the warm-up compile failed"),
+    value("SELFTEST_INTERVAL", "selftest-interval", "Seconds between two runs of POST /selftest, for the whole server [default: 10]"),
+    value("SHADOW_TYPST_VERSION", "shadow-typst-version", "Bundled typst version sampled compiles are tried again with in the background [default: none]"),
+    value("SHADOW_PERCENT", "shadow-percent", "Percentage of successful compiles tried again with SHADOW_TYPST_VERSION [default: 0]"),
+    value("SHADOW_MAX_CONCURRENT", "shadow-max-concurrent", "Shadow compiles running at the same time, more are skipped [default: 1]"),
+];
+
+/// Settings `--print-config` doesn't show the values of.
+const SECRETS: &[&str] = &["ADMIN_TOKEN", "API_KEYS", "CALLBACK_SECRET", "HIGH_PRIORITY_TOKENS", "REDIS_URL"];
+
+/// The values given for `SETTINGS`, collecting every invalid one instead of stopping at the first.
+pub struct Settings {
+    /// Command line values by environment variable name.
+    arguments: HashMap<&'static str, String>,
+    /// Values from the `--config` file by environment variable name.
+    file: HashMap<&'static str, String>,
+    /// Dump the settings instead of starting.
+    pub print_config: bool,
+    problems: RefCell<Vec<String>>,
+}
+
+/// Where a setting's value came from.
+#[derive(Clone, Copy)]
+enum Source {
+    Argument,
+    File,
+    Environment,
+}
+
+fn command() -> Command {
+    let mut command = Command::new("typstapi")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Compiles typst documents to PDF over HTTP")
+        .after_help("Every option can also be set in the --config file or through the environment variable \
+                     named in brackets. Flags take precedence over the file, the file over the environment.")
+        .arg(Arg::new("config").long("config").value_name("FILE")
+            .help("TOML file with options, overridden by flags and overriding the environment [env: CONFIG_FILE]"))
+        .arg(Arg::new("print-config").long("print-config").action(ArgAction::SetTrue)
+            .help("Print the effective settings and where they came from, then exit"))
+        // Set by the server when it starts a compile worker.
+        .arg(Arg::new("compile-worker").long("compile-worker").action(ArgAction::SetTrue).hide(true));
+    for setting in SETTINGS {
+        let arg = Arg::new(setting.env)
+            .long(setting.flag)
+            .help(format!("{} [env: {}]", setting.help, setting.env));
+        command = command.arg(match setting.switch {
+            true => { arg.action(ArgAction::SetTrue) }
+            false => { arg.value_name("VALUE") }
+        });
+    }
+    command
+}
+
+impl Settings {
+    /// Parse the command line, failing on unknown flags as well as for `--help` and `--version`.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, clap::Error> {
+        let matches = command().try_get_matches_from(args)?;
+        let mut arguments = HashMap::new();
+        for setting in SETTINGS {
+            let value = match setting.switch {
+                true => { matches.get_flag(setting.env).then(|| "true".to_string()) }
+                false => { matches.get_one::<String>(setting.env).cloned() }
+            };
+            if let Some(value) = value {
+                arguments.insert(setting.env, value);
+            }
+        }
+
+        let mut settings = Self {
+            arguments,
+            file: HashMap::new(),
+            print_config: matches.get_flag("print-config"),
+            problems: RefCell::default(),
+        };
+        let file = matches.get_one::<String>("config").cloned()
+            .or_else(|| env::var("CONFIG_FILE").ok().filter(|file| !file.is_empty()));
+        if let Some(file) = file {
+            settings.file = settings.read_file(&file);
+        }
+        Ok(settings)
+    }
+
+    /// Read a `--config` file, warning about keys that aren't settings.
+    fn read_file(&self, path: &str) -> HashMap<&'static str, String> {
+        let table = match fs::read_to_string(path) {
+            Ok(contents) => { contents.parse::<toml::Table>() }
+            Err(problem) => {
+                self.problem(format!("Could not read the config file {path}: {problem}"));
+                return HashMap::new();
+            }
+        };
+        let table = match table {
+            Ok(table) => { table }
+            Err(problem) => {
+                self.problem(format!("The config file {path} is not valid TOML: {problem}"));
+                return HashMap::new();
+            }
+        };
+
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            let Some(setting) = SETTINGS.iter().find(|setting| setting.flag.replace('-', "_") == key) else {
+                eprintln!("Warning: ignoring unknown key {key} in the config file {path}");
+                continue;
+            };
+            let value = match value {
+                toml::Value::String(value) => { value }
+                toml::Value::Integer(value) => { value.to_string() }
+                toml::Value::Boolean(value) => { value.to_string() }
+                toml::Value::Array(items) if items.iter().all(toml::Value::is_str) => {
+                    items.iter().filter_map(toml::Value::as_str).collect::<Vec<_>>().join(",")
+                }
+                _ => {
+                    self.problem(format!("{key} in the config file {path} must be a string, number, boolean or list of strings"));
+                    continue;
+                }
+            };
+            values.insert(setting.env, value);
+        }
+        values
+    }
+
+    /// The raw value of a setting, empty ones counting as unset.
+    pub fn string(&self, name: &str) -> Option<String> {
+        self.lookup(name).map(|(value, _)| value)
+    }
+
+    fn lookup(&self, name: &str) -> Option<(String, Source)> {
+        self.arguments.get(name).map(|value| (value.clone(), Source::Argument))
+            .or_else(|| self.file.get(name).map(|value| (value.clone(), Source::File)))
+            .or_else(|| env::var(name).ok().map(|value| (value, Source::Environment)))
+            .filter(|(value, _)| !value.is_empty())
+    }
+
+    /// Print every setting as TOML, commented with where its value came from.
+    pub fn print(&self) {
+        println!("# Precedence: command line, then config file, then environment, then default.");
+        for setting in SETTINGS {
+            let key = setting.flag.replace('-', "_");
+            let Some((value, source)) = self.lookup(setting.env) else {
+                println!("# {key} is unset: {}", setting.help);
+                continue;
+            };
+            let value = match SECRETS.contains(&setting.env) {
+                true => { "<redacted>".to_string() }
+                false => { value }
+            };
+            let source = match source {
+                Source::Argument => { format!("--{}", setting.flag) }
+                Source::File => { "config file".to_string() }
+                Source::Environment => { setting.env.to_string() }
+            };
+            println!("{key} = {}  # from {source}", toml::Value::String(value));
+        }
+    }
+
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        self.string(name).map(PathBuf::from)
+    }
+
+    pub fn number(&self, name: &str) -> Option<usize> {
+        let value = self.string(name)?;
+        match value.parse() {
+            Ok(number) => { Some(number) }
+            Err(_) => {
+                self.problem(format!("{} must be a whole number, not {value}", describe(name)));
+                None
+            }
+        }
+    }
+
+    /// A whole number that must be at least one, like a thread count.
+    pub fn positive(&self, name: &str) -> Option<usize> {
+        match self.number(name) {
+            Some(0) => {
+                self.problem(format!("{} must be at least 1", describe(name)));
+                None
+            }
+            number => { number }
+        }
+    }
+
+    /// A switch, `1`/`true` turning it on and `0`/`false` off.
+    pub fn flag(&self, name: &str) -> bool {
+        match self.string(name) {
+            None => { false }
+            Some(value) if value == "1" || value.eq_ignore_ascii_case("true") => { true }
+            Some(value) if value == "0" || value.eq_ignore_ascii_case("false") => { false }
+            Some(value) => {
+                self.problem(format!("{} must be true or false, not {value}", describe(name)));
+                false
+            }
+        }
+    }
+
+    /// A comma separated list, skipping empty entries.
+    pub fn list(&self, name: &str) -> Vec<String> {
+        self.string(name)
+            .map(|value| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn problem(&self, problem: String) {
+        self.problems.borrow_mut().push(problem);
+    }
+
+    /// Everything found invalid while reading the settings.
+    pub fn problems(self) -> Vec<String> {
+        self.problems.into_inner()
+    }
+}
+
+/// How a setting is named in messages, with both its flag and its variable.
+pub fn describe(name: &str) -> String {
+    match SETTINGS.iter().find(|setting| setting.env == name) {
+        Some(setting) => { format!("--{} ({name})", setting.flag) }
+        None => { name.to_string() }
+    }
+}
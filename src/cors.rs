@@ -0,0 +1,77 @@
+//! Cross-origin access for browser clients, off unless origins are configured.
+
+use actix_cors::Cors;
+use actix_web::http::header::{AUTHORIZATION, CONTENT_TYPE, IF_MATCH, IF_NONE_MATCH};
+use actix_web::middleware::Condition;
+use crate::attachments::EMBEDDED_HEADER;
+use crate::checksum::SHA256_HEADER;
+use crate::compilers::{VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::{Config, TIMEZONE_HEADER};
+use crate::element::ELEMENT_PAGES_HEADER;
+use crate::logging::REQUEST_ID_HEADER;
+use crate::projects::{LOCK_HEADER, OVERLAY_HEADER, REVISION_HEADER};
+use crate::slots::PRIORITY_HEADER;
+use crate::uploads::{LENGTH_HEADER, OFFSET_HEADER};
+
+/// Response headers browsers let scripts read.
+const EXPOSED_HEADERS: [&str; 17] = [
+    VERSION_HEADER,
+    WARNING_COUNT_HEADER,
+    SHA256_HEADER,
+    "Repr-Digest",
+    "Digest",
+    REQUEST_ID_HEADER,
+    OFFSET_HEADER,
+    LENGTH_HEADER,
+    OVERLAY_HEADER,
+    REVISION_HEADER,
+    EMBEDDED_HEADER,
+    ELEMENT_PAGES_HEADER,
+    "ETag",
+    "Retry-After",
+    "X-RateLimit-Limit",
+    "X-RateLimit-Remaining",
+    "X-RateLimit-Reset",
+];
+
+/// The middleware for `App::wrap`. It answers preflight requests itself, before any
+/// authentication or rate limiting.
+///
+/// Without allowed origins it is left out entirely, so requests carrying an `Origin`
+/// behave as if there were no CORS support at all.
+pub fn cors(config: &Config) -> Condition<Cors> {
+    let mut cors = Cors::default()
+        .allowed_methods(config.cors_methods.iter().map(String::as_str))
+        .expose_headers(EXPOSED_HEADERS)
+        .max_age(Some(config.cors_max_age.as_secs() as usize));
+    cors = match config.cors_headers.is_empty() {
+        true => {
+            cors.allowed_headers([AUTHORIZATION, CONTENT_TYPE, IF_MATCH, IF_NONE_MATCH])
+                .allowed_headers([VERSION_HEADER, PRIORITY_HEADER, TIMEZONE_HEADER, REQUEST_ID_HEADER, OFFSET_HEADER, LOCK_HEADER])
+        }
+        false => { cors.allowed_headers(config.cors_headers.iter().map(String::as_str)) }
+    };
+    if config.cors_credentials {
+        cors = cors.supports_credentials();
+    }
+
+    let mut patterns = vec![];
+    for origin in &config.cors_origins {
+        match origin.split_once("*.") {
+            _ if origin == "*" => { cors = cors.allow_any_origin() }
+            // Like `https://*.example.com`, any subdomain with that scheme.
+            Some((scheme, domain)) => { patterns.push((scheme.to_string(), format!(".{domain}"))) }
+            None => { cors = cors.allowed_origin(origin) }
+        }
+    }
+    if !patterns.is_empty() {
+        cors = cors.allowed_origin_fn(move |origin, _| {
+            let Ok(origin) = origin.to_str() else { return false };
+            patterns.iter().any(|(scheme, domain)| {
+                origin.strip_prefix(scheme.as_str()).is_some_and(|host| host.ends_with(domain.as_str()))
+            })
+        });
+    }
+
+    Condition::new(!config.cors_origins.is_empty(), cors)
+}
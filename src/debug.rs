@@ -0,0 +1,191 @@
+//! A diagnostic bundle for `X-Debug: 1`, to compare a compile here with one on a customer's machine.
+//!
+//! Only callers with the admin token may ask for it. The bundle describes the one compile of
+//! the request: the settings it ran with, the font faces it used by family and the families
+//! that weren't found, its dependency manifest, how long each phase took and its warnings.
+//! In the JSON response mode it is the `debug` field, otherwise the response is
+//! `multipart/mixed` with the PDF followed by the bundle as a `debug.json` attachment.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web::http::header::ContentType;
+use serde::Serialize;
+use crate::admin::require_admin;
+use crate::checksum::Checksum;
+use crate::compilers::{CompileOptions, JsonOutput, Progress, Stage, VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::Config;
+use crate::docker_world::{Compiled, Dependencies, FontFace, Warning, WarningCategory};
+use crate::metrics::Metrics;
+
+/// Request header asking for the bundle.
+pub const DEBUG_HEADER: &str = "X-Debug";
+
+/// When the compile reached each stage, see `Stage`.
+#[derive(Default)]
+struct Phases {
+    started: Option<Instant>,
+    laid_out: Option<Instant>,
+    exporting: Option<Instant>,
+}
+
+/// Records the phases of a compile that asked for the bundle.
+pub struct Recorder {
+    received: Instant,
+    phases: Arc<Mutex<Phases>>,
+}
+
+#[derive(Serialize)]
+struct Bundle<'a> {
+    typst_version: &'static str,
+    settings: Settings<'a>,
+    /// The faces used, by family.
+    fonts: BTreeMap<&'a str, Vec<&'a FontFace>>,
+    /// Warnings about families that weren't found, so typst fell back to others.
+    missing_fonts: Vec<&'a str>,
+    dependencies: &'a Dependencies,
+    timings: Timings,
+    warnings: &'a [Warning],
+}
+
+/// What shaped the compile, from the request and the server configuration.
+#[derive(Serialize)]
+struct Settings<'a> {
+    timezone: &'static str,
+    max_pages: usize,
+    timeout_seconds: u64,
+    inputs: &'a BTreeMap<String, String>,
+    fail_on_warnings: bool,
+    max_image_size: Option<u32>,
+    optimized: bool,
+    pdf_a: bool,
+    isolated: bool,
+    remote_packages: bool,
+    local_packages: bool,
+    /// Whether the result came from the compile cache, so no phase ran.
+    cache_hit: bool,
+}
+
+/// Milliseconds per phase, `null` for phases the compiler doesn't report.
+#[derive(Serialize)]
+struct Timings {
+    /// Waiting for a compile slot after the upload was read.
+    queued: Option<u64>,
+    /// Building the world and laying out the document.
+    layout: Option<u64>,
+    /// Exporting and optimizing the PDF.
+    export: Option<u64>,
+    total: u64,
+}
+
+/// Whether the request asked for the bundle, refusing it unless it carries the admin token.
+pub fn requested(request: &HttpRequest, config: &Config) -> Result<bool, Error> {
+    let asked = request.headers().get(DEBUG_HEADER).is_some_and(|value| value == "1");
+    if asked {
+        require_admin(request, config)?;
+    }
+    Ok(asked)
+}
+
+impl Recorder {
+    /// Start recording, from when the request's upload was read, through the progress of `options`.
+    pub fn start(options: &mut CompileOptions) -> Self {
+        let phases = Arc::new(Mutex::new(Phases::default()));
+        let reported = phases.clone();
+        options.progress = Progress::new(move |stage| {
+            let mut phases = reported.lock().unwrap();
+            match stage {
+                Stage::Started => { phases.started = Some(Instant::now()) }
+                Stage::Warnings(_) => { phases.laid_out = Some(Instant::now()) }
+                Stage::Exporting => { phases.exporting = Some(Instant::now()) }
+            }
+        });
+        Self { received: Instant::now(), phases }
+    }
+
+    fn timings(&self) -> Timings {
+        let finished = Instant::now();
+        let phases = self.phases.lock().unwrap();
+        let between = |from: Option<Instant>, to: Option<Instant>| {
+            from.zip(to).map(|(from, to)| to.saturating_duration_since(from).as_millis() as u64)
+        };
+        Timings {
+            queued: between(Some(self.received), phases.started),
+            layout: between(phases.started, phases.laid_out),
+            export: between(phases.exporting, Some(finished)),
+            total: finished.duration_since(self.received).as_millis() as u64,
+        }
+    }
+}
+
+/// The response `compilers::respond` would give, with the bundle added.
+pub fn respond(
+    version: &'static str,
+    compiled: Compiled,
+    options: &CompileOptions,
+    config: &Config,
+    recorder: Recorder,
+    metrics: &Metrics,
+) -> Result<HttpResponse, Error> {
+    metrics.record_warnings(&compiled.warnings);
+    let timings = recorder.timings();
+    let cache_hit = recorder.phases.lock().unwrap().started.is_none();
+
+    let mut fonts: BTreeMap<&str, Vec<&FontFace>> = BTreeMap::new();
+    for face in &compiled.dependencies.fonts {
+        fonts.entry(face.family.as_str()).or_default().push(face);
+    }
+    let bundle = Bundle {
+        typst_version: version,
+        settings: Settings {
+            timezone: options.timezone.name(),
+            max_pages: options.max_pages,
+            timeout_seconds: options.timeout.as_secs(),
+            inputs: &options.inputs,
+            fail_on_warnings: options.fail_on_warnings,
+            max_image_size: options.max_image_size,
+            optimized: options.optimizer.is_some(),
+            pdf_a: options.pdf_a,
+            isolated: config.isolate_compiles,
+            remote_packages: !config.offline,
+            local_packages: config.local_package_dir.is_some(),
+            cache_hit,
+        },
+        fonts,
+        missing_fonts: compiled.warnings.iter()
+            .filter(|warning| warning.category == WarningCategory::UnknownFont)
+            .map(|warning| warning.message.as_str())
+            .collect(),
+        dependencies: &compiled.dependencies,
+        timings,
+        warnings: &compiled.warnings,
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((VERSION_HEADER, version))
+        .insert_header((WARNING_COUNT_HEADER, compiled.warnings.len().to_string()));
+
+    if options.include_dependencies {
+        #[derive(Serialize)]
+        struct WithDebug<'a> {
+            #[serde(flatten)]
+            output: JsonOutput<'a>,
+            debug: &'a Bundle<'a>,
+        }
+        let body = serde_json::to_vec(&WithDebug { output: JsonOutput::new(&compiled), debug: &bundle }).expect("the debug bundle serializes");
+        Checksum::of(&body).insert(&mut response);
+        return Ok(response.content_type(ContentType::json()).body(body));
+    }
+
+    let boundary = format!("debug-{:032x}", rand::random::<u128>());
+    let mut body = Vec::with_capacity(compiled.pdf.len() + 4096);
+    body.extend(format!("--{boundary}\r\nContent-Type: application/pdf\r\nContent-Disposition: inline; filename=\"document.pdf\"\r\n\r\n").as_bytes());
+    body.extend(&compiled.pdf);
+    body.extend(format!("\r\n--{boundary}\r\nContent-Type: application/json\r\nContent-Disposition: attachment; filename=\"debug.json\"\r\n\r\n").as_bytes());
+    body.extend(serde_json::to_vec_pretty(&bundle).expect("the debug bundle serializes"));
+    body.extend(format!("\r\n--{boundary}--\r\n").as_bytes());
+    Checksum::of(&body).insert(&mut response);
+    Ok(response.content_type(format!("multipart/mixed; boundary={boundary}")).body(body))
+}
@@ -1,24 +1,59 @@
-use std::cell::OnceCell;
-use std::collections::HashMap;
+//! Typst worlds over uploaded files, and compiling them to PDF, SVG or PNG.
+//!
+//! A `DockerWorld` holds a document's files, a `FontDb` shared by every world and the
+//! `PackageStore` its imports are read from, put together with `DockerWorld::builder`.
+//! Build one per compile with `compile`, or keep one around and edit its files between
+//! compiles with `add_file`, `update_file`, `edit_file`, `patch_file` and `remove_file`, so
+//! typst reuses what didn't change.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use typst::World;
-use std::path::PathBuf;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use typst::{Library, World, WorldExt};
+use std::path::{Component, Path, PathBuf};
 use fontdb::{Database};
-use typst::font::{Font, FontBook, FontInfo};
-use comemo::Prehashed;
-use chrono::{DateTime, Datelike, Local, Timelike};
-use typst::diag::{EcoString, FileResult, StrResult};
-use typst::eval::{Bytes, Datetime, Library, Tracer};
-use typst::syntax::{FileId, Source, VirtualPath};
+use typst::text::{Font, FontBook, FontInfo};
+use typst::utils::LazyHash;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Timelike, Utc};
+use chrono_tz::Tz;
+use ecow::EcoString;
+use tempfile::NamedTempFile;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+use typst::diag::{FileError, FileResult, PackageError, SourceDiagnostic};
+use typst::foundations::{Bytes, Datetime, Dict, Str, Value};
+use typst::syntax::{ast, FileId, Source, Span, VirtualPath};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use crate::packages::{is_local, PackageStore};
+
+mod coverage;
+mod element;
+mod export;
+mod fingerprint;
+mod statistics;
+
+pub use self::coverage::{FaceCoverage, FamilyCoverage};
+pub use self::element::{ElementError, Region};
+pub use self::export::{CompiledDocument, ExportError, PdfExport, PngExport, SvgExport};
+pub use self::fingerprint::PageFingerprint;
+pub use self::statistics::{PageStatistics, Statistics};
 
+/// Every font face the server knows about, loaded lazily and shared by all worlds.
 pub struct FontDb {
+    book: LazyHash<FontBook>,
     fonts: Vec<LazyFont>
 }
 
 struct LazyFont {
     index: u32,
     path: PathBuf,
-    data: OnceCell<Option<Font>>,
+    data: OnceLock<Option<Font>>,
 }
 
 impl LazyFont {
@@ -32,11 +67,28 @@ impl LazyFont {
 
 impl FontDb {
     fn get(&self, index: usize) -> Option<Font> {
-        self.fonts[index].get()
+        self.fonts.get(index)?.get()
+    }
+
+    /// Font faces found, the embedded fallbacks not included.
+    pub fn count(&self) -> usize {
+        self.fonts.len()
     }
 
-    pub fn new(fontdir: Option<PathBuf>, book: &mut FontBook) -> Self {
+    /// The families of the faces found.
+    pub fn families(&self) -> impl Iterator<Item = &str> {
+        self.book.families().map(|(family, _)| family)
+    }
+
+    /// Font faces read from disk and parsed so far.
+    pub fn loaded(&self) -> usize {
+        self.fonts.iter().filter(|font| font.data.get().is_some()).count()
+    }
+
+    /// Find the fonts in `fontdir` and the system's, reading only their metadata for now.
+    pub fn new(fontdir: Option<PathBuf>) -> Self {
         let mut database = Database::new();
+        let mut book = FontBook::new();
         let mut fonts= vec![];
 
         if let Some(fontdir) = fontdir {
@@ -50,148 +102,1256 @@ impl FontDb {
                 fontdb::Source::Binary(_) => continue
             };
 
-            let info = database.with_face_data(face.id, FontInfo::new).expect("Font load snafu");
-
-            if let Some(info) = info {
+            // Faces whose file vanished since the scan are left out like unparsable ones.
+            if let Some(Some(info)) = database.with_face_data(face.id, FontInfo::new) {
                 book.push(info);
                 fonts.push(
                     LazyFont {
                         path: path.clone(),
                         index: face.index,
-                        data: OnceCell::new()
+                        data: OnceLock::new()
                     }
                 )
             }
         }
 
         Self {
+            book: LazyHash::new(book),
             fonts
         }
     }
+}
 
+/// The font database new worlds get, replaced when the font directory is reloaded.
+/// Worlds keep the database they started with.
+pub struct FontLibrary {
+    current: RwLock<Arc<FontDb>>,
+}
+
+impl FontLibrary {
+    pub fn new(fontdir: Option<PathBuf>) -> Self {
+        Self { current: RwLock::new(Arc::new(FontDb::new(fontdir))) }
+    }
 
+    /// The database to give new worlds.
+    pub fn current(&self) -> Arc<FontDb> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Scan the fonts again, which takes a while, so call it off the request threads.
+    pub fn reload(&self, fontdir: Option<PathBuf>) {
+        let fonts = Arc::new(FontDb::new(fontdir));
+        *self.current.write().unwrap() = fonts;
+    }
 }
+
+/// The files of one document and everything else a compile of it may read.
 pub struct DockerWorld {
-    fonts: FontDb,
-    book: Prehashed<FontBook>,
-    library: Prehashed<Library>,
+    fonts: Arc<FontDb>,
+    library: LazyHash<Library>,
     main: FileId,
-    now: OnceCell<DateTime<Local>>,
-    sources: HashMap<FileId, Bytes>
+    now: OnceLock<DateTime<Utc>>,
+    /// The time every compile sees instead of the clock's.
+    pinned_now: Option<DateTime<Utc>>,
+    /// Zone `datetime.today()` is evaluated in when the document gives no offset.
+    timezone: Tz,
+    /// What `sys.inputs` holds, the library being built with it.
+    inputs: Dict,
+    sources: HashMap<FileId, SourceFile>,
+    /// Where imports are read from, none means importing packages fails.
+    packages: Option<Arc<PackageStore>>,
+    package_files: Mutex<HashMap<FileId, SourceFile>>,
+    /// Files and font faces the current compile asked for.
+    accessed_files: Mutex<HashSet<FileId>>,
+    accessed_fonts: Mutex<BTreeSet<usize>>,
+    /// Whether the current compile asked for `datetime.today()`.
+    read_clock: AtomicBool,
+    /// Whether a package the current compile imports couldn't be read.
+    package_failed: AtomicBool,
+    cancellation: Cancellation,
 }
 
-fn file_id(filename: &str) -> FileId {
-    FileId::new(None, VirtualPath::new(PathBuf::from(filename)))
+/// Tells a running compile to give up.
+///
+/// typst can't be interrupted, so the flag is checked whenever it asks the world for a
+/// file or font, which it does at least once per layout iteration while validating its caches.
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An uploaded file together with its lazily loaded bytes and parsed `Source`.
+struct SourceFile {
+    data: FileData,
+    bytes: OnceLock<Bytes>,
+    source: OnceLock<Source>,
 }
 
+impl SourceFile {
+    fn new(data: FileData) -> Self {
+        Self {
+            data,
+            bytes: OnceLock::new(),
+            source: OnceLock::new()
+        }
+    }
+
+    /// The contents, read from disk on first use if they were spooled.
+    fn bytes(&self) -> FileResult<Bytes> {
+        if let Some(bytes) = self.bytes.get() {
+            return Ok(bytes.clone());
+        }
+        let loaded = self.data.load()?;
+        Ok(self.bytes.get_or_init(|| loaded).clone())
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if let Some(source) = self.source.get() {
+            return Ok(source.clone());
+        }
+        let bytes = self.bytes()?;
+        let text = decode_utf8(&bytes)?;
+        Ok(self.source.get_or_init(|| typst_timing::timed!("parse", Source::new(id, text.into()))).clone())
+    }
+
+    /// Swap in new contents, editing an already parsed source so typst can reparse incrementally.
+    fn update(&mut self, data: FileData) {
+        let bytes = data.load();
+        let text = bytes.as_ref().ok().and_then(|bytes| decode_utf8(bytes).ok());
+        match (self.source.get_mut(), text) {
+            (Some(source), Some(text)) => { replace_text(source, text) }
+            _ => { self.source = OnceLock::new() }
+        }
+        self.bytes = match bytes {
+            Ok(bytes) => { OnceLock::from(bytes) }
+            Err(_) => { OnceLock::new() }
+        };
+        self.data = data;
+    }
+
+    /// Replace each range of the text with its replacement in turn, every range counting in
+    /// the text the ones before left, editing the parsed source in place. Nothing changes
+    /// unless every edit applies.
+    fn edit(&mut self, id: FileId, edits: &[(Range<usize>, &str)]) -> Result<Bytes, EditError> {
+        let path = || id.vpath().as_rootless_path().to_string_lossy().into_owned();
+        let mut source = self.source(id).map_err(|_| EditError::NotText(path()))?;
+        for (range, replacement) in edits {
+            let text = source.text();
+            if range.start > range.end || range.end > text.len()
+                || !text.is_char_boundary(range.start) || !text.is_char_boundary(range.end) {
+                return Err(EditError::Range(path()));
+            }
+            source.edit(range.clone(), replacement);
+        }
+        let bytes = Bytes::from(source.text().as_bytes().to_vec());
+        self.data = FileData::Memory(bytes.clone());
+        self.bytes = OnceLock::from(bytes.clone());
+        self.source = OnceLock::from(source);
+        Ok(bytes)
+    }
+}
+
+/// The id of an uploaded file at `filename`, like `chapters/intro.typ`.
+///
+/// The path is normalized to NFC, so `café.png` is the same file however the accent was
+/// typed, both for the upload and for the documents referring to it.
+pub fn file_id(filename: &str) -> FileId {
+    match is_nfc(filename) {
+        true => { FileId::new(None, VirtualPath::new(PathBuf::from(filename))) }
+        false => { FileId::new(None, VirtualPath::new(PathBuf::from(filename.nfc().collect::<String>()))) }
+    }
+}
+
+/// The id `file_id` gives the file `id` refers to. Package files are read from disk as they are named.
+fn canonical(id: FileId) -> FileId {
+    if id.package().is_some() {
+        return id;
+    }
+    let path = id.vpath().as_rootless_path().to_string_lossy();
+    match is_nfc(&path) {
+        true => { id }
+        false => { file_id(&path) }
+    }
+}
+
+/// An uploaded file, at the path `name` gives it.
+#[derive(Clone)]
 pub struct DocumentFile {
     pub name: FileId,
-    pub data: Bytes
+    pub data: FileData
 }
 
 impl DocumentFile {
+    /// A file at `name`, like `main.typ`, held in memory.
     pub fn new(name: &str, data: Vec<u8>) -> Self {
         Self {
             name: file_id(name),
-            data: data.into()
+            data: FileData::Memory(data.into())
+        }
+    }
+
+    /// A file at `name` whose contents were written to `file`, read when a compile needs them.
+    pub fn spooled(name: &str, file: NamedTempFile) -> Self {
+        Self {
+            name: file_id(name),
+            data: FileData::Spooled(Arc::new(file))
+        }
+    }
+
+    /// Read `path`, relative to `root` or inside it, into a file at its path within `root`.
+    ///
+    /// Paths leaving `root`, with `..` or through a symlink, are refused.
+    pub fn from_path(root: &Path, path: &Path) -> io::Result<Self> {
+        let relative = match path.is_absolute() {
+            true => { path.strip_prefix(root).map_err(|_| outside(path))? }
+            false => { path }
+        };
+        let mut name = Vec::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => { name.push(part.to_string_lossy()) }
+                Component::CurDir => {}
+                _ => { return Err(outside(path)) }
+            }
+        }
+        if name.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is no file", path.display())));
+        }
+
+        let full = root.join(relative);
+        if !full.canonicalize()?.starts_with(root.canonicalize()?) {
+            return Err(outside(path));
+        }
+        Ok(Self::new(&name.join("/"), fs::read(&full)?))
+    }
+}
+
+fn outside(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is outside the root directory", path.display()))
+}
+
+/// Every file below `root`, at their paths within it and sorted by them, refusing trees
+/// of more than `max_size` bytes.
+///
+/// Hidden files and directories are skipped. Symlinks are read if they point inside
+/// `root`, though symlinked directories are not descended into, and refused otherwise.
+pub fn load_directory(root: &Path, max_size: u64) -> io::Result<Vec<DocumentFile>> {
+    let mut files = Vec::new();
+    let mut size = 0;
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if file_type.is_symlink() && fs::metadata(entry.path())?.is_dir() {
+                if !entry.path().canonicalize()?.starts_with(root.canonicalize()?) {
+                    return Err(outside(&entry.path()));
+                }
+                continue;
+            }
+
+            size += fs::metadata(entry.path())?.len();
+            if size > max_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} holds more than {max_size} bytes", root.display()),
+                ));
+            }
+            files.push(DocumentFile::from_path(root, &path)?);
+        }
+    }
+    files.sort_by_cached_key(|file| file.name.vpath().as_rootless_path().to_path_buf());
+    Ok(files)
+}
+
+/// Contents of an uploaded file.
+#[derive(Clone)]
+pub enum FileData {
+    Memory(Bytes),
+    /// Written to a temporary file because it was large, deleted once the last clone is dropped.
+    Spooled(Arc<NamedTempFile>),
+}
+
+impl FileData {
+    /// The contents, read from disk if they were spooled.
+    pub fn load(&self) -> FileResult<Bytes> {
+        match self {
+            FileData::Memory(bytes) => { Ok(bytes.clone()) }
+            FileData::Spooled(file) => {
+                fs::read(file.path())
+                    .map(Bytes::from)
+                    .map_err(|problem| FileError::from_io(problem, file.path()))
+            }
+        }
+    }
+
+    /// Length in bytes, without reading spooled files.
+    pub fn size(&self) -> io::Result<u64> {
+        match self {
+            FileData::Memory(bytes) => { Ok(bytes.len() as u64) }
+            FileData::Spooled(file) => { Ok(file.as_file().metadata()?.len()) }
+        }
+    }
+}
+
+/// Why a compile produced no output.
+#[derive(Debug)]
+pub enum CompileError {
+    /// typst reported errors, rendered one per line.
+    Failed(EcoString),
+    /// typst reported errors, rendered one per line, after a package the document imports
+    /// couldn't be downloaded or read.
+    PackageUnavailable(EcoString),
+    /// The document laid out to more pages than the request allows.
+    TooManyPages { pages: usize, limit: usize },
+    /// The compile was stopped through its `Cancellation`.
+    Cancelled,
+    /// The compile ran longer than the request allows.
+    TimedOut { after: Duration },
+    /// An isolated compile hit its memory or CPU limit.
+    ResourceLimit(String),
+    /// An isolated compile's worker process failed for another reason.
+    WorkerCrashed(String),
+    /// The document compiled, but the request fails compiles with warnings, rendered one per
+    /// line like errors.
+    Warnings { warnings: EcoString, count: usize },
+}
+
+impl CompileError {
+    /// A short name of the kind of failure, for metrics and logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CompileError::Failed(_) => { "failed" }
+            CompileError::PackageUnavailable(_) => { "package_unavailable" }
+            CompileError::TooManyPages { .. } => { "too_many_pages" }
+            CompileError::Cancelled => { "cancelled" }
+            CompileError::TimedOut { .. } => { "timed_out" }
+            CompileError::ResourceLimit(_) => { "resource_limit" }
+            CompileError::WorkerCrashed(_) => { "worker_crashed" }
+            CompileError::Warnings { .. } => { "warnings" }
+        }
+    }
+
+    /// The code the server answers the failure with, stable unlike the message. A code is
+    /// never given another meaning, new kinds of failures get new codes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileError::Failed(_) => { "compile_error" }
+            CompileError::PackageUnavailable(_) => { "package_unavailable" }
+            CompileError::TooManyPages { .. } => { "too_many_pages" }
+            CompileError::Cancelled => { "compile_cancelled" }
+            CompileError::TimedOut { .. } => { "timeout" }
+            CompileError::ResourceLimit(_) => { "resource_limit" }
+            CompileError::WorkerCrashed(_) => { "worker_crashed" }
+            CompileError::Warnings { .. } => { "warnings" }
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Failed(errors) | CompileError::PackageUnavailable(errors) => { write!(f, "{errors}") }
+            CompileError::TooManyPages { pages, limit } => {
+                write!(f, "The document has {pages} pages, more than the limit of {limit}")
+            }
+            CompileError::Cancelled => { write!(f, "The compile was cancelled") }
+            CompileError::TimedOut { after } => {
+                write!(f, "The compile took longer than {} seconds", after.as_secs())
+            }
+            CompileError::ResourceLimit(problem) => { write!(f, "The document exceeds the server's limits: {problem}") }
+            CompileError::WorkerCrashed(problem) => { write!(f, "The compile failed unexpectedly: {problem}") }
+            CompileError::Warnings { warnings, .. } => { write!(f, "{warnings}") }
+        }
+    }
+}
+
+/// A successfully compiled document.
+#[derive(Clone)]
+pub struct Compiled {
+    pub pdf: Vec<u8>,
+    pub pages: usize,
+    /// The `PageFingerprint` of each page, empty for results read back from where they
+    /// weren't kept.
+    pub page_hashes: Vec<String>,
+    pub warnings: Vec<Warning>,
+    pub dependencies: Dependencies,
+    /// Whether compiling the same inputs again is known to give the same document, which
+    /// rules out documents reading the clock or `@local` packages that may change on disk.
+    pub cacheable: bool,
+}
+
+/// Everything a compile read, in a stable order so clients can hash it.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Dependencies {
+    /// Uploaded files that were read, by path.
+    pub files: Vec<String>,
+    /// Imported packages, like `@preview/cetz:0.2.2`.
+    pub packages: Vec<String>,
+    pub fonts: Vec<FontFace>,
+}
+
+/// A font face a compile used.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct FontFace {
+    pub family: String,
+    pub style: String,
+    pub weight: u16,
+}
+
+/// A warning typst reported about a document that still compiled.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Warning {
+    pub category: WarningCategory,
+    /// Rendered as `path:line:column: message`.
+    pub message: EcoString,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WarningCategory {
+    UnknownFont,
+    Deprecated,
+    Other,
+}
+
+impl WarningCategory {
+    /// Tell warnings apart by the syntax they point at first, their wording second.
+    ///
+    /// `in_font_argument` says whether the warning's span lies in a `font:` argument.
+    pub fn classify(message: &str, hints: &[EcoString], in_font_argument: bool) -> Self {
+        let message = message.to_lowercase();
+        if in_font_argument || message.contains("unknown font") {
+            WarningCategory::UnknownFont
+        } else if message.contains("deprecated") || hints.iter().any(|hint| hint.contains("deprecated")) {
+            WarningCategory::Deprecated
+        } else {
+            WarningCategory::Other
+        }
+    }
+}
+
+/// Collects what a `DockerWorld` is made of, see `DockerWorld::builder`.
+#[derive(Default)]
+pub struct DockerWorldBuilder {
+    main: Option<DocumentFile>,
+    files: Vec<DocumentFile>,
+    fonts: Option<Arc<FontDb>>,
+    packages: Option<Arc<PackageStore>>,
+    timezone: Option<Tz>,
+    now: Option<DateTime<Utc>>,
+    inputs: Dict,
+}
+
+/// Why a `DockerWorldBuilder` couldn't build a world.
+#[derive(Debug)]
+pub enum BuildError {
+    /// No main file was given.
+    MissingMain,
+    /// No font database was given.
+    MissingFonts,
+    /// Two files, the main file included, have the same path.
+    DuplicateFile(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::MissingMain => { write!(f, "The world has no main file") }
+            BuildError::MissingFonts => { write!(f, "The world has no font database") }
+            BuildError::DuplicateFile(path) => { write!(f, "There are two files at {path}") }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Why a file of a `DockerWorld` couldn't be changed.
+#[derive(Debug)]
+pub enum EditError {
+    /// The world has no file at that path.
+    NotFound(String),
+    /// The main file can be updated but not removed.
+    MainFile,
+    /// Only text files can be edited by range.
+    NotText(String),
+    /// The range runs past the end of the text or splits a character.
+    Range(String),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditError::NotFound(path) => { write!(f, "There is no file at {path}") }
+            EditError::MainFile => { write!(f, "The main file can't be removed") }
+            EditError::NotText(path) => { write!(f, "{path} isn't UTF-8 text") }
+            EditError::Range(path) => { write!(f, "The range lies outside {path} or splits a character") }
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+impl From<BuildError> for CompileError {
+    fn from(problem: BuildError) -> Self {
+        CompileError::Failed(problem.to_string().into())
+    }
+}
+
+impl DockerWorldBuilder {
+    /// The file compiles start at. Required.
+    pub fn main(mut self, file: DocumentFile) -> Self {
+        self.main = Some(file);
+        self
+    }
+
+    /// Add files the main file may read.
+    pub fn files(mut self, files: impl IntoIterator<Item = DocumentFile>) -> Self {
+        self.files.extend(files);
+        self
+    }
+
+    /// The fonts documents can use, usually shared by every world. Required.
+    pub fn font_db(mut self, fonts: Arc<FontDb>) -> Self {
+        self.fonts = Some(fonts);
+        self
+    }
+
+    /// Where imported packages come from. Without one, importing a package fails.
+    pub fn packages(mut self, packages: Arc<PackageStore>) -> Self {
+        self.packages = Some(packages);
+        self
+    }
+
+    /// The zone `datetime.today()` is evaluated in without an offset, UTC by default.
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Have every compile see `now` instead of the time it runs at, for reproducible output.
+    pub fn now(mut self, now: DateTime<Utc>) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /// Values documents read from `sys.inputs`, all strings as with `typst compile --input`.
+    pub fn inputs<K: Into<String>, V: Into<String>>(mut self, inputs: impl IntoIterator<Item = (K, V)>) -> Self {
+        for (key, value) in inputs {
+            self.inputs.insert(Str::from(key.into()), Value::Str(Str::from(value.into())));
         }
+        self
+    }
+
+    pub fn build(self) -> Result<DockerWorld, BuildError> {
+        let main = self.main.ok_or(BuildError::MissingMain)?;
+        let fonts = self.fonts.ok_or(BuildError::MissingFonts)?;
+        let mut paths = HashSet::from([main.name]);
+        if let Some(duplicate) = self.files.iter().find(|file| !paths.insert(file.name)) {
+            return Err(BuildError::DuplicateFile(duplicate.name.vpath().as_rootless_path().display().to_string()));
+        }
+
+        let mut world = DockerWorld::assemble(main, self.files, fonts, self.packages, self.timezone.unwrap_or(Tz::UTC));
+        world.library = LazyHash::new(Library::builder().with_inputs(self.inputs.clone()).build());
+        world.inputs = self.inputs;
+        world.pinned_now = self.now;
+        world.now = self.now.map(OnceLock::from).unwrap_or_default();
+        Ok(world)
     }
 }
 
 impl DockerWorld {
-    pub fn new(main_document: DocumentFile, other_files: Vec<DocumentFile>,fontdir: Option<PathBuf>) -> Self {
-        let mut book = FontBook::new();
-        let fonts = FontDb::new(fontdir, &mut book);
+    /// Put a world together piece by piece, checking the pieces fit once it is built.
+    pub fn builder() -> DockerWorldBuilder {
+        DockerWorldBuilder::default()
+    }
+
+    /// A world compiling `main_document`, which may read `other_files`, later files
+    /// replacing earlier ones at the same path.
+    #[deprecated(note = "use DockerWorld::builder, which checks the files")]
+    pub fn new(
+        main_document: DocumentFile,
+        other_files: Vec<DocumentFile>,
+        fonts: Arc<FontDb>,
+        packages: Arc<PackageStore>,
+        timezone: Tz,
+    ) -> Self {
+        Self::assemble(main_document, other_files, fonts, Some(packages), timezone)
+    }
+
+    fn assemble(
+        main_document: DocumentFile,
+        other_files: Vec<DocumentFile>,
+        fonts: Arc<FontDb>,
+        packages: Option<Arc<PackageStore>>,
+        timezone: Tz,
+    ) -> Self {
         let main = main_document.name;
-        let mut sources: HashMap<FileId, Bytes> = HashMap::new();
-        sources.insert(main, main_document.data);
+        let mut sources: HashMap<FileId, SourceFile> = HashMap::new();
+        sources.insert(main, SourceFile::new(main_document.data));
         for file in other_files {
-            sources.insert(file.name, file.data);
+            sources.insert(file.name, SourceFile::new(file.data));
         }
         Self {
             main,
             fonts,
-            book: Prehashed::new(book),
-            library: Prehashed::new(typst_library::build()),
+            library: LazyHash::new(Library::default()),
+            inputs: Dict::new(),
             sources,
-            now: OnceCell::new()
+            now: OnceLock::new(),
+            pinned_now: None,
+            timezone,
+            packages,
+            package_files: Mutex::new(HashMap::new()),
+            accessed_files: Mutex::new(HashSet::new()),
+            accessed_fonts: Mutex::new(BTreeSet::new()),
+            read_clock: AtomicBool::new(false),
+            package_failed: AtomicBool::new(false),
+            cancellation: Cancellation::default(),
         }
     }
 
-    pub fn compile(&mut self) -> StrResult<Vec<u8>> {
-        let mut tracer = Tracer::default();
-        let result = typst::compile(self, &mut tracer);
+    /// Add a file to the world, or edit it in place if it is already known.
+    pub fn add_file(&mut self, file: DocumentFile) {
+        match self.sources.get_mut(&file.name) {
+            Some(existing) => { existing.update(file.data) }
+            None => { self.sources.insert(file.name, SourceFile::new(file.data)); }
+        }
+    }
 
-        match result {
-            Err(_) => { Err(EcoString::from("Something terrible has happened")) }
-            Ok(document) => {
-                Ok(
-                    typst::export::pdf(&document, None, self.now())
-                )
+    /// Replace the contents of a file the world has, like `chapters/intro.typ`, as an edit
+    /// typst can reparse incrementally.
+    pub fn update_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), EditError> {
+        let existing = self.sources.get_mut(&file_id(path)).ok_or_else(|| EditError::NotFound(path.into()))?;
+        existing.update(FileData::Memory(Bytes::from(data)));
+        Ok(())
+    }
+
+    /// Replace the bytes `range` of the text of a file the world has with `replacement`,
+    /// returning the new contents. Offsets don't count a byte order mark.
+    pub fn edit_file(&mut self, path: &str, range: Range<usize>, replacement: &str) -> Result<Bytes, EditError> {
+        self.patch_file(path, &[(range, replacement)])
+    }
+
+    /// Apply a diff to the text of a file the world has, each range counting in the text the
+    /// edits before it left, and return the new contents. Either every edit applies or none.
+    pub fn patch_file(&mut self, path: &str, edits: &[(Range<usize>, &str)]) -> Result<Bytes, EditError> {
+        let id = file_id(path);
+        let existing = self.sources.get_mut(&id).ok_or_else(|| EditError::NotFound(path.into()))?;
+        existing.edit(id, edits)
+    }
+
+    /// Drop a file, so the next compile can't read it anymore. The main file has to stay.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), EditError> {
+        let id = file_id(path);
+        if id == self.main {
+            return Err(EditError::MainFile);
+        }
+        self.sources.remove(&id).map(|_| ()).ok_or_else(|| EditError::NotFound(path.into()))
+    }
+
+    /// Change the zone `datetime.today()` is evaluated in for the next compile.
+    pub fn set_timezone(&mut self, timezone: Tz) {
+        self.timezone = timezone;
+    }
+
+    /// Change what `sys.inputs` holds for the next compile. The library is only rebuilt if
+    /// they differ from the last ones, so compiling with the same inputs stays incremental.
+    pub fn set_inputs<K: Into<String>, V: Into<String>>(&mut self, inputs: impl IntoIterator<Item = (K, V)>) {
+        let mut dict = Dict::new();
+        for (key, value) in inputs {
+            dict.insert(Str::from(key.into()), Value::Str(Str::from(value.into())));
+        }
+        if dict != self.inputs {
+            self.library = LazyHash::new(Library::builder().with_inputs(dict.clone()).build());
+            self.inputs = dict;
+        }
+    }
+
+    /// Compile with `overlay` shadowing the files at the same paths, for this compile only.
+    ///
+    /// The shadowed files are put back as they were, parsed sources included, so the next
+    /// compile without the overlay is as incremental as if it had never happened.
+    pub fn compile_overlaid(
+        &mut self,
+        overlay: Vec<DocumentFile>,
+        max_pages: usize,
+        cancellation: Cancellation,
+    ) -> Result<Compiled, CompileError> {
+        let shadowed: Vec<_> = overlay.into_iter()
+            .map(|file| (file.name, self.sources.insert(file.name, SourceFile::new(file.data))))
+            .collect();
+        let compiled = self.compile(max_pages, cancellation);
+        for (id, original) in shadowed.into_iter().rev() {
+            match original {
+                Some(original) => { self.sources.insert(id, original); }
+                None => { self.sources.remove(&id); }
             }
         }
+        compiled
+    }
+
+    /// Compile to PDF, refusing documents longer than `max_pages` and stopping early once
+    /// `cancellation` is triggered.
+    pub fn compile(&mut self, max_pages: usize, cancellation: Cancellation) -> Result<Compiled, CompileError> {
+        let document = self.typeset(max_pages, cancellation)?;
+        let pdf = tracing::info_span!("export").in_scope(|| document.to_pdf(&PdfExport::default()))?;
+        Ok(document.into_compiled(pdf))
+    }
+
+    /// Lay the document out without exporting it, so it can be exported to several formats.
+    ///
+    /// typst offers no hook into layout, so the page count can only be checked once
+    /// layout is done, but at least the export work is skipped for oversized documents.
+    pub fn typeset(&mut self, max_pages: usize, cancellation: Cancellation) -> Result<CompiledDocument<'_>, CompileError> {
+        // A reused world must not keep the timestamp of its first compile,
+        // nor local package files that may have changed since.
+        self.now = self.pinned_now.map(OnceLock::from).unwrap_or_default();
+        self.package_files.get_mut().unwrap().retain(|id, _| !id.package().is_some_and(is_local));
+        self.accessed_files.get_mut().unwrap().clear();
+        self.accessed_fonts.get_mut().unwrap().clear();
+        *self.read_clock.get_mut() = false;
+        *self.package_failed.get_mut() = false;
+        self.cancellation = cancellation;
+        let result = tracing::info_span!("typeset").in_scope(|| typst::compile(&*self));
+        if self.cancellation.is_cancelled() {
+            return Err(CompileError::Cancelled);
+        }
+        let world = &*self;
+        let warnings = result.warnings.iter().map(|warning| world.warning(warning)).collect();
+
+        let document = match result.output {
+            Err(errors) if world.package_failed.load(Ordering::Relaxed) => {
+                return Err(CompileError::PackageUnavailable(world.describe(&errors)))
+            }
+            Err(errors) => { return Err(CompileError::Failed(world.describe(&errors))) }
+            Ok(document) => { document }
+        };
+
+        if document.pages.len() > max_pages {
+            return Err(CompileError::TooManyPages { pages: document.pages.len(), limit: max_pages });
+        }
 
+        let dependencies = world.dependencies();
+        let cacheable = (world.pinned_now.is_some() || !world.read_clock.load(Ordering::Relaxed))
+            && !dependencies.packages.iter().any(|package| package.starts_with("@local/"));
+        Ok(CompiledDocument { world, document, warnings, dependencies, cacheable })
+    }
+
+    fn dependencies(&self) -> Dependencies {
+        let mut files = BTreeSet::new();
+        let mut packages = BTreeSet::new();
+        for id in self.accessed_files.lock().unwrap().iter() {
+            match id.package() {
+                None => { files.insert(id.vpath().as_rootless_path().to_string_lossy().into_owned()); }
+                Some(spec) => { packages.insert(spec.to_string()); }
+            }
+        }
+
+        let fonts: BTreeSet<_> = self.accessed_fonts.lock().unwrap().iter()
+            .filter_map(|&index| self.fonts.book.info(index))
+            .map(|info| FontFace {
+                family: info.family.clone(),
+                style: format!("{:?}", info.variant.style).to_lowercase(),
+                weight: info.variant.weight.to_number(),
+            })
+            .collect();
+
+        Dependencies {
+            files: files.into_iter().collect(),
+            packages: packages.into_iter().collect(),
+            fonts: fonts.into_iter().collect(),
+        }
+    }
+
+    fn warning(&self, diagnostic: &SourceDiagnostic) -> Warning {
+        let in_font_argument = self.in_font_argument(diagnostic.span).unwrap_or(false);
+        Warning {
+            category: WarningCategory::classify(&diagnostic.message, &diagnostic.hints, in_font_argument),
+            message: self.describe(std::slice::from_ref(diagnostic)).trim_end().into(),
+        }
+    }
+
+    /// Whether the span lies inside a `font:` named argument, like the value of an unknown font.
+    fn in_font_argument(&self, span: Span) -> Option<bool> {
+        let source = self.source(span.id()?).ok()?;
+        let mut node = source.find(span)?;
+        loop {
+            if let Some(named) = node.cast::<ast::Named>() {
+                return Some(named.name().as_str() == "font");
+            }
+            node = node.parent()?.clone();
+        }
+    }
+
+    /// Render diagnostics as one `path:line:column: message` line each.
+    fn describe(&self, diagnostics: &[SourceDiagnostic]) -> EcoString {
+        let mut description = String::new();
+        for diagnostic in diagnostics {
+            if let Some(location) = self.locate(diagnostic.span) {
+                description.push_str(&location);
+                description.push_str(": ");
+            }
+            description.push_str(&diagnostic.message);
+            description.push('\n');
+        }
+        description.into()
+    }
+
+    fn locate(&self, span: Span) -> Option<String> {
+        let id = span.id()?;
+        let source = self.source(id).ok()?;
+        let start = self.range(span)?.start;
+        let line = source.byte_to_line(start)? + 1;
+        let column = source.byte_to_column(start)? + 1;
+        let path = id.vpath().as_rootless_path().display();
+        match id.package() {
+            None => { Some(format!("{path}:{line}:{column}")) }
+            Some(spec) => { Some(format!("{spec}/{path}:{line}:{column}")) }
+        }
+    }
+
+    /// Fail file access once the compile is cancelled, so typst winds down quickly.
+    fn check_cancelled(&self) -> FileResult<()> {
+        // comemo records these failures like any other result, but the next compile
+        // revalidates them against a fresh flag and recomputes.
+        if self.cancellation.is_cancelled() {
+            return Err(FileError::Other(Some("compile cancelled".into())));
+        }
+        Ok(())
+    }
+
+    /// Look up a file, reading package files from the package cache on first access.
+    fn with_file<T>(&self, id: FileId, f: impl FnOnce(&SourceFile) -> T) -> FileResult<T> {
+        let spec = match id.package() {
+            None => {
+                return match self.sources.get(&id) {
+                    None => { Err(FileError::NotFound(id.vpath().as_rootless_path().into())) }
+                    Some(file) => { Ok(f(file)) }
+                }
+            }
+            Some(spec) => { spec }
+        };
+
+        let mut package_files = self.package_files.lock().unwrap();
+        if !package_files.contains_key(&id) {
+            let read = match self.packages.as_ref() {
+                Some(packages) => { typst_timing::timed!("read package file", packages.read(spec, id)) }
+                None => { Err(FileError::Package(PackageError::Other(Some("this world can't import packages".into())))) }
+            };
+            let data = read.inspect_err(|_| self.package_failed.store(true, Ordering::Relaxed))?;
+            package_files.insert(id, SourceFile::new(FileData::Memory(data)));
+        }
+        Ok(f(&package_files[&id]))
     }
 
     /// Get the current date and time in UTC.
     fn now(&self) -> Option<Datetime> {
-        let now = self.now.get_or_init(Local::now);
-        Datetime::from_ymd_hms(
-            now.year(),
-            now.month().try_into().ok()?,
-            now.day().try_into().ok()?,
-            now.hour().try_into().ok()?,
-            now.minute().try_into().ok()?,
-            now.second().try_into().ok()?,
-        )
+        datetime(*self.now.get_or_init(Utc::now))
     }
 }
 
+fn datetime(at: DateTime<Utc>) -> Option<Datetime> {
+    let at = at.naive_utc();
+    Datetime::from_ymd_hms(
+        at.year(),
+        at.month().try_into().ok()?,
+        at.day().try_into().ok()?,
+        at.hour().try_into().ok()?,
+        at.minute().try_into().ok()?,
+        at.second().try_into().ok()?,
+    )
+}
+
+/// Build a world and compile it once, as `DockerWorld::compile`.
+pub fn compile(world: DockerWorldBuilder, max_pages: usize, cancellation: Cancellation) -> Result<Compiled, CompileError> {
+    let mut world = tracing::info_span!("world").in_scope(|| world.build())?;
+    world.compile(max_pages, cancellation)
+}
+
 impl World for DockerWorld {
-    fn library(&self) -> &Prehashed<Library> {
+    fn library(&self) -> &LazyHash<Library> {
         &self.library
     }
 
-    fn book(&self) -> &Prehashed<FontBook> {
-        &self.book
+    fn book(&self) -> &LazyHash<FontBook> {
+        &self.fonts.book
     }
 
-    fn main(&self) -> Source {
-        self.source(self.main).unwrap()
+    fn main(&self) -> FileId {
+        self.main
     }
 
     fn source(&self, id: FileId) -> FileResult<Source> {
-        let raw_data = self.sources.get(&id).expect("No Such Source file");
-        Ok(Source::new(id, decode_utf8(&raw_data).parse().unwrap()))
+        self.check_cancelled()?;
+        let id = canonical(id);
+        self.accessed_files.lock().unwrap().insert(id);
+        self.with_file(id, |file| file.source(id))?
     }
 
     fn file(&self, id: FileId) -> FileResult<Bytes> {
-        let data = self.sources.get(&id).expect("No Such Source file");
-        Ok(data.clone())
+        self.check_cancelled()?;
+        let id = canonical(id);
+        self.accessed_files.lock().unwrap().insert(id);
+        self.with_file(id, |file| file.bytes())?
     }
 
-    fn font(&self, index: usize) -> Option<Font> { self.fonts.get(index) }
+    fn font(&self, index: usize) -> Option<Font> {
+        self.check_cancelled().ok()?;
+        self.accessed_fonts.lock().unwrap().insert(index);
+        typst_timing::timed!("load font", self.fonts.get(index))
+    }
 
     fn today(&self, offset: Option<i64>) -> Option<Datetime> {
-        let now = self.now.get_or_init(Local::now);
-
-        let naive = match offset {
-            None => { now.naive_local() }
-            Some(o) => { now.naive_utc() + chrono::Duration::hours(o) }
-        };
+        self.read_clock.store(true, Ordering::Relaxed);
+        let now = self.now.get_or_init(Utc::now);
+        let date = today_at(*now, self.timezone, offset)?;
 
         Datetime::from_ymd(
-            naive.year(),
-            naive.month().try_into().ok()?,
-            naive.day().try_into().ok()?
+            date.year(),
+            date.month().try_into().ok()?,
+            date.day().try_into().ok()?
         )
     }
 }
 
+/// The calendar date at `now`, `offset` hours east of UTC or in `timezone` without one.
+///
+/// Both cases start from the same UTC instant, so in a UTC server `today()` and
+/// `today(offset: 0)` always agree. Zones with sub-hour offsets are only reachable
+/// through `timezone`, since typst offsets are whole hours. Offsets of a day or more
+/// have no date.
+pub fn today_at(now: DateTime<Utc>, timezone: Tz, offset: Option<i64>) -> Option<NaiveDate> {
+    match offset {
+        None => { Some(now.with_timezone(&timezone).date_naive()) }
+        Some(hours) => {
+            let seconds = i32::try_from(hours.checked_mul(3600)?).ok()?;
+            let offset = FixedOffset::east_opt(seconds)?;
+            Some(now.with_timezone(&offset).date_naive())
+        }
+    }
+}
+
 /// Decode UTF-8 with an optional BOM.
-fn decode_utf8(buf: &[u8]) -> &str {
+fn decode_utf8(buf: &[u8]) -> FileResult<&str> {
     // Remove UTF-8 BOM.
-    std::str::from_utf8(
-        buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf),
-    ).expect("What the hell")
-}
\ No newline at end of file
+    std::str::from_utf8(buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf)).map_err(|_| FileError::InvalidUtf8)
+}
+
+/// Apply the smallest single edit that turns the source's text into `new`.
+fn replace_text(source: &mut Source, new: &str) {
+    let old = source.text();
+
+    let mut prefix = old.bytes().zip(new.bytes()).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+
+    let mut suffix = old[prefix..].bytes().rev()
+        .zip(new[prefix..].bytes().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let range = prefix..old.len() - suffix;
+    let replacement = new[prefix..new.len() - suffix].to_string();
+    source.edit(range, &replacement);
+}
+
+#[cfg(feature = "server")]
+mod responses {
+    use actix_web::http::StatusCode;
+    use actix_web::{HttpResponse, ResponseError};
+    use serde::Serialize;
+    use utoipa::ToSchema;
+    use super::CompileError;
+
+    /// Response body of a failure, `error` being a code that stays while the message changes.
+    #[derive(Serialize, ToSchema)]
+    pub struct ErrorOutput {
+        error: &'static str,
+        message: String,
+    }
+
+    /// Response body of a timed out compile.
+    #[derive(Serialize, ToSchema)]
+    pub struct TimeoutOutput {
+        error: &'static str,
+        message: String,
+        timeout_seconds: u64,
+    }
+
+    /// Response body of a document that compiled but failed on its warnings.
+    #[derive(Serialize, ToSchema)]
+    pub struct WarningsOutput {
+        /// Always `warnings`, telling a policy failure from errors in the document.
+        error: &'static str,
+        /// The warnings, one per line like errors.
+        message: String,
+        warnings: usize,
+    }
+
+    impl ErrorOutput {
+        pub fn new(error: &'static str, message: String) -> Self {
+            Self { error, message }
+        }
+    }
+
+    impl ResponseError for CompileError {
+        fn status_code(&self) -> StatusCode {
+            match self {
+                CompileError::Failed(_) | CompileError::PackageUnavailable(_) => { StatusCode::BAD_REQUEST }
+                CompileError::TooManyPages { .. } => { StatusCode::UNPROCESSABLE_ENTITY }
+                CompileError::Cancelled => { StatusCode::SERVICE_UNAVAILABLE }
+                CompileError::TimedOut { .. } => { StatusCode::GATEWAY_TIMEOUT }
+                CompileError::ResourceLimit(_) => { StatusCode::UNPROCESSABLE_ENTITY }
+                CompileError::WorkerCrashed(_) => { StatusCode::INTERNAL_SERVER_ERROR }
+                CompileError::Warnings { .. } => { StatusCode::UNPROCESSABLE_ENTITY }
+            }
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            match self {
+                CompileError::TimedOut { after } => {
+                    HttpResponse::build(self.status_code()).json(TimeoutOutput {
+                        error: self.code(),
+                        message: self.to_string(),
+                        timeout_seconds: after.as_secs(),
+                    })
+                }
+                CompileError::Warnings { count, .. } => {
+                    HttpResponse::build(self.status_code()).json(WarningsOutput {
+                        error: self.code(),
+                        message: self.to_string(),
+                        warnings: *count,
+                    })
+                }
+                _ => { HttpResponse::build(self.status_code()).json(ErrorOutput::new(self.code(), self.to_string())) }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+pub use self::responses::{ErrorOutput, TimeoutOutput, WarningsOutput};
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use chrono_tz::Tz;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use super::{load_directory, today_at, Cancellation, DockerWorld, DocumentFile, EditError, FontDb};
+
+    fn date(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, month, day)
+    }
+
+    #[test]
+    fn today_turns_at_midnight_utc() {
+        let before = Utc.with_ymd_and_hms(2024, 6, 1, 23, 59, 59).unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 6, 2, 0, 0, 0).unwrap();
+        assert_eq!(today_at(before, Tz::UTC, None), date(2024, 6, 1));
+        assert_eq!(today_at(after, Tz::UTC, None), date(2024, 6, 2));
+        assert_eq!(today_at(before, Tz::UTC, Some(0)), date(2024, 6, 1));
+        assert_eq!(today_at(after, Tz::UTC, Some(0)), date(2024, 6, 2));
+    }
+
+    #[test]
+    fn today_follows_the_offset() {
+        let evening = Utc.with_ymd_and_hms(2024, 6, 1, 22, 0, 0).unwrap();
+        let night = Utc.with_ymd_and_hms(2024, 6, 1, 2, 0, 0).unwrap();
+        assert_eq!(today_at(evening, Tz::UTC, Some(3)), date(2024, 6, 2));
+        assert_eq!(today_at(evening, Tz::UTC, Some(1)), date(2024, 6, 1));
+        assert_eq!(today_at(night, Tz::UTC, Some(-5)), date(2024, 5, 31));
+        assert_eq!(today_at(night, Tz::UTC, Some(-2)), date(2024, 6, 1));
+        // The offset wins over the zone.
+        assert_eq!(today_at(night, Tz::Asia__Tokyo, Some(-5)), date(2024, 5, 31));
+    }
+
+    #[test]
+    fn offsets_of_a_day_have_no_date() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(today_at(now, Tz::UTC, Some(24)), None);
+        assert_eq!(today_at(now, Tz::UTC, Some(-24)), None);
+        assert_eq!(today_at(now, Tz::UTC, Some(i64::MAX)), None);
+    }
+
+    #[test]
+    fn today_follows_daylight_saving_time_of_the_zone() {
+        let zone = Tz::America__New_York;
+        // Daylight saving time starts on 2024-03-10 at 07:00 UTC, moving the zone from -5 to -4.
+        let winter = Utc.with_ymd_and_hms(2024, 3, 10, 4, 30, 0).unwrap();
+        let summer = Utc.with_ymd_and_hms(2024, 3, 11, 4, 30, 0).unwrap();
+        assert_eq!(today_at(winter, zone, None), date(2024, 3, 9));
+        assert_eq!(today_at(summer, zone, None), date(2024, 3, 11));
+        assert_eq!(today_at(summer, zone, Some(-5)), date(2024, 3, 10));
+        // And ends on 2024-11-03 at 06:00 UTC.
+        let summer_again = Utc.with_ymd_and_hms(2024, 11, 3, 4, 30, 0).unwrap();
+        let winter_again = Utc.with_ymd_and_hms(2024, 11, 4, 4, 30, 0).unwrap();
+        assert_eq!(today_at(summer_again, zone, None), date(2024, 11, 3));
+        assert_eq!(today_at(winter_again, zone, None), date(2024, 11, 3));
+    }
+
+    /// A tree with a `root` of nested files, hidden ones and a symlink inside, next to an
+    /// `outside` directory.
+    fn tree() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("chapters/deep")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(dir.path().join("outside")).unwrap();
+        fs::write(root.join("main.typ"), "#include \"chapters/intro.typ\"").unwrap();
+        fs::write(root.join("chapters/intro.typ"), "= Intro").unwrap();
+        fs::write(root.join("chapters/deep/notes.typ"), "Notes").unwrap();
+        fs::write(root.join(".git/config"), "[core]").unwrap();
+        fs::write(root.join(".env"), "SECRET=1").unwrap();
+        fs::write(dir.path().join("outside/secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("chapters/intro.typ", root.join("inside.typ")).unwrap();
+            std::os::unix::fs::symlink("chapters", root.join("linked")).unwrap();
+        }
+        // Other systems don't allow names that aren't UTF-8.
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            fs::write(root.join(std::ffi::OsStr::from_bytes(b"caf\xe9.typ")), "Latin-1").unwrap();
+        }
+        dir
+    }
+
+    fn names(files: &[DocumentFile]) -> Vec<String> {
+        files.iter().map(|file| file.name.vpath().as_rootless_path().to_string_lossy().into_owned()).collect()
+    }
+
+    fn contents(file: &DocumentFile) -> String {
+        String::from_utf8(file.data.load().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn loads_every_visible_file_below_the_root() {
+        let dir = tree();
+        let files = load_directory(&dir.path().join("root"), 1024).unwrap();
+        // The symlinked directory isn't descended into, the name that isn't UTF-8 is read lossily.
+        let expected = match (cfg!(target_os = "linux"), cfg!(unix)) {
+            (true, _) => { vec!["caf\u{fffd}.typ", "chapters/deep/notes.typ", "chapters/intro.typ", "inside.typ", "main.typ"] }
+            (false, true) => { vec!["chapters/deep/notes.typ", "chapters/intro.typ", "inside.typ", "main.typ"] }
+            (false, false) => { vec!["chapters/deep/notes.typ", "chapters/intro.typ", "main.typ"] }
+        };
+        assert_eq!(names(&files), expected);
+        let inside = files.iter().find(|file| file.name.vpath().as_rootless_path() == Path::new("chapters/deep/notes.typ"));
+        assert_eq!(contents(inside.unwrap()), "Notes");
+    }
+
+    #[test]
+    fn refuses_trees_beyond_the_size() {
+        let dir = tree();
+        let problem = load_directory(&dir.path().join("root"), 10).err().unwrap();
+        assert_eq!(problem.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        let dir = tree();
+        let root = dir.path().join("root");
+        std::os::unix::fs::symlink("../../outside/secret.txt", root.join("chapters/escape.txt")).unwrap();
+        assert_eq!(load_directory(&root, 1024).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            DocumentFile::from_path(&root, Path::new("chapters/escape.txt")).err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied,
+        );
+        fs::remove_file(root.join("chapters/escape.txt")).unwrap();
+
+        std::os::unix::fs::symlink("../outside", root.join("elsewhere")).unwrap();
+        assert_eq!(load_directory(&root, 1024).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            DocumentFile::from_path(&root, Path::new("elsewhere/secret.txt")).err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied,
+        );
+    }
+
+    #[test]
+    fn reads_a_file_at_its_path_within_the_root() {
+        let dir = tree();
+        let root = dir.path().join("root");
+        let absolute = root.join("chapters/deep/notes.typ");
+        for path in [Path::new("chapters/deep/notes.typ"), Path::new("./chapters/deep/notes.typ"), absolute.as_path()] {
+            let file = DocumentFile::from_path(&root, path).unwrap();
+            assert_eq!(names(&[file.clone()]), ["chapters/deep/notes.typ"]);
+            assert_eq!(contents(&file), "Notes");
+        }
+    }
+
+    #[test]
+    fn refuses_paths_leaving_the_root() {
+        let dir = tree();
+        let root = dir.path().join("root");
+        let absolute = dir.path().join("outside/secret.txt");
+        let refused = [
+            (Path::new("../outside/secret.txt"), io::ErrorKind::PermissionDenied),
+            (Path::new("chapters/../../outside/secret.txt"), io::ErrorKind::PermissionDenied),
+            (absolute.as_path(), io::ErrorKind::PermissionDenied),
+            (Path::new("."), io::ErrorKind::InvalidInput),
+            (Path::new("missing.typ"), io::ErrorKind::NotFound),
+        ];
+        for (path, kind) in refused {
+            assert_eq!(DocumentFile::from_path(&root, path).err().map(|problem| problem.kind()), Some(kind), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn a_patch_applies_whole_or_not_at_all() {
+        let mut world = DockerWorld::builder()
+            .main(DocumentFile::new("main.typ", b"= Title\nSome text\n".to_vec()))
+            .font_db(Arc::new(FontDb::new(None)))
+            .build()
+            .unwrap();
+
+        let patched = world.patch_file("main.typ", &[(2..7, "Heading"), (10..14, "other")]).unwrap();
+        assert_eq!(&*patched, b"= Heading\nother text\n");
+
+        let refused = world.patch_file("main.typ", &[(0..1, "=="), (40..41, "")]);
+        assert!(matches!(refused, Err(EditError::Range(path)) if path == "main.typ"));
+        let unchanged = world.edit_file("main.typ", 0..0, "").unwrap();
+        assert_eq!(&*unchanged, b"= Heading\nother text\n");
+    }
+
+    #[test]
+    fn imports_find_a_file_however_its_accents_were_typed() {
+        let (composed, decomposed) = ("caf\u{e9}.typ", "cafe\u{301}.typ");
+        for (uploaded, imported) in [(decomposed, composed), (composed, decomposed)] {
+            let compiled = DockerWorld::builder()
+                .main(DocumentFile::new("main.typ", format!("#import \"{imported}\": answer\n#assert.eq(answer, 42)\n").into_bytes()))
+                .files([DocumentFile::new(uploaded, b"#let answer = 42\n".to_vec())])
+                .font_db(Arc::new(FontDb::new(None)))
+                .build()
+                .unwrap()
+                .compile(1, Cancellation::default());
+            assert!(compiled.is_ok(), "{uploaded:?} imported as {imported:?}");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Which characters of a text the font faces have glyphs for, from the coverage their
+//! metadata lists, so no font has to be loaded.
+//!
+//! Each distinct character counts once however often the text has it. Whitespace and
+//! control characters are left out, typst never looks them up in a font. Combining marks
+//! count like any other character, so a text with decomposed Vietnamese diacritics needs a
+//! face covering the marks.
+
+use serde::{Deserialize, Serialize};
+use typst::text::{FontInfo, FontStyle};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+use super::FontDb;
+
+/// How one family covers a text, see the module docs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct FamilyCoverage {
+    pub family: String,
+    /// The percentage of the characters the face covering most of them covers.
+    pub coverage: f64,
+    pub faces: Vec<FaceCoverage>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct FaceCoverage {
+    /// `normal`, `italic` or `oblique`.
+    pub style: String,
+    /// From 100 for thin to 900 for black.
+    pub weight: u16,
+    /// Width relative to the normal width, 1 for normal.
+    pub stretch: f64,
+    /// The percentage of the characters the face has glyphs for.
+    pub coverage: f64,
+    /// How many of the characters the face has glyphs for.
+    pub covered: usize,
+    /// The characters without a glyph, like `U+1EA1`, in code point order.
+    pub uncovered: Vec<String>,
+}
+
+impl FontDb {
+    /// How the faces of `family`, matched ignoring case, cover `text`, or `None` if there is no
+    /// such family.
+    pub fn coverage(&self, family: &str, text: &str) -> Option<FamilyCoverage> {
+        let characters = characters(text);
+        self.book.families()
+            .find(|(name, _)| name.eq_ignore_ascii_case(family))
+            .map(|(_, faces)| family_coverage(faces, &characters))
+    }
+
+    /// The `limit` families covering most of `text`, the best first.
+    pub fn best_coverage(&self, text: &str, limit: usize) -> Vec<FamilyCoverage> {
+        let characters = characters(text);
+        let mut families: Vec<_> = self.book.families()
+            .map(|(_, faces)| family_coverage(faces, &characters))
+            .collect();
+        families.sort_by(|a, b| b.coverage.total_cmp(&a.coverage).then_with(|| a.family.cmp(&b.family)));
+        families.truncate(limit);
+        families
+    }
+}
+
+/// The distinct characters of `text` a font would be asked for, in code point order.
+fn characters(text: &str) -> Vec<char> {
+    let mut characters: Vec<_> = text.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect();
+    characters.sort_unstable();
+    characters.dedup();
+    characters
+}
+
+fn family_coverage<'a>(faces: impl Iterator<Item = &'a FontInfo>, characters: &[char]) -> FamilyCoverage {
+    let faces: Vec<_> = faces.collect();
+    // The book's key is lowercase, the faces have the family as the font names it.
+    let family = faces.first().map(|info| info.family.clone()).unwrap_or_default();
+    let faces: Vec<_> = faces.into_iter().map(|info| face_coverage(info, characters)).collect();
+    FamilyCoverage {
+        family,
+        coverage: faces.iter().map(|face| face.coverage).fold(0.0, f64::max),
+        faces,
+    }
+}
+
+fn face_coverage(info: &FontInfo, characters: &[char]) -> FaceCoverage {
+    let uncovered: Vec<_> = characters.iter()
+        .filter(|&&c| !info.coverage.contains(c as u32))
+        .map(|&c| format!("U+{:04X}", c as u32))
+        .collect();
+    let coverage = match characters.len() {
+        0 => { 100.0 }
+        total => { (total - uncovered.len()) as f64 / total as f64 * 100.0 }
+    };
+    let style = match info.variant.style {
+        FontStyle::Normal => { "normal" }
+        FontStyle::Italic => { "italic" }
+        FontStyle::Oblique => { "oblique" }
+    };
+    FaceCoverage {
+        style: style.to_string(),
+        weight: info.variant.weight.to_number(),
+        stretch: info.variant.stretch.to_ratio().get(),
+        coverage,
+        covered: characters.len() - uncovered.len(),
+        uncovered,
+    }
+}
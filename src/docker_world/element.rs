@@ -0,0 +1,149 @@
+//! Finding where a labeled element ended up and rendering just that part of its pages.
+//!
+//! typst marks where each element starts and ends in the frames, so the element's region on
+//! a page is the bounding box of what was placed between its marks, text from its ascender
+//! to its descender. An element can break across pages, then it has a region on each. The
+//! crop is the page with everything outside the region and its padding cut away.
+
+use std::fmt;
+use typst::foundations::{Label, Selector};
+use typst::introspection::{Location, Tag};
+use typst::layout::{Abs, Frame, FrameItem, Page, Point, Size, Transform};
+use super::{CompiledDocument, ExportError};
+
+/// The part of a page an element covers.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    /// The page, counting from 1.
+    pub page: usize,
+    pub min: Point,
+    pub max: Point,
+}
+
+/// Why there is no element to render.
+#[derive(Debug)]
+pub enum ElementError {
+    /// No element has the label.
+    NoSuchLabel(String),
+    /// More than one element has the label.
+    AmbiguousLabel { label: String, count: usize },
+    /// The element was laid out, but nothing visible of it.
+    Invisible(String),
+}
+
+impl fmt::Display for ElementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElementError::NoSuchLabel(label) => { write!(f, "No element is labeled <{label}>") }
+            ElementError::AmbiguousLabel { label, count } => {
+                write!(f, "{count} elements are labeled <{label}>, label just one")
+            }
+            ElementError::Invisible(label) => { write!(f, "The element labeled <{label}> shows nothing") }
+        }
+    }
+}
+
+impl std::error::Error for ElementError {}
+
+/// Where the walk through the frames is, relative to the element.
+struct Walk {
+    location: Location,
+    inside: bool,
+    /// The bounds on the current page so far.
+    bounds: Option<(Point, Point)>,
+}
+
+impl CompiledDocument<'_> {
+    /// The regions of the element labeled `label`, one per page it is on, in page order.
+    pub fn element_regions(&self, label: &str) -> Result<Vec<Region>, ElementError> {
+        let elements = self.document.introspector.query(&Selector::Label(Label::new(label)));
+        let location = match elements.as_slice() {
+            [] => { return Err(ElementError::NoSuchLabel(label.to_string())) }
+            [element] => { element.location() }
+            _ => { return Err(ElementError::AmbiguousLabel { label: label.to_string(), count: elements.len() }) }
+        };
+        let Some(location) = location else {
+            return Err(ElementError::NoSuchLabel(label.to_string()));
+        };
+
+        let mut walk = Walk { location, inside: false, bounds: None };
+        let mut regions = Vec::new();
+        for (index, page) in self.document.pages.iter().enumerate() {
+            walk_frame(&page.frame, Transform::identity(), &mut walk);
+            if let Some((min, max)) = walk.bounds.take() {
+                regions.push(Region { page: index + 1, min, max });
+            }
+        }
+        match regions.is_empty() {
+            true => { Err(ElementError::Invisible(label.to_string())) }
+            false => { Ok(regions) }
+        }
+    }
+
+    /// `region` as SVG, with `padding` around it within the page.
+    pub fn region_svg(&self, region: &Region, padding: Abs) -> String {
+        typst_svg::svg(&self.crop(region, padding))
+    }
+
+    /// `region` as PNG at `ppi`, with `padding` around it within the page.
+    pub fn region_png(&self, region: &Region, padding: Abs, ppi: f32) -> Result<Vec<u8>, ExportError> {
+        let pixmap = typst_render::render(&self.crop(region, padding), ppi / 72.0);
+        pixmap.encode_png().map_err(|problem| ExportError::Png(problem.to_string()))
+    }
+
+    /// The page of `region` cropped to it, with `padding` around it within the page.
+    fn crop(&self, region: &Region, padding: Abs) -> Page {
+        let page = &self.document.pages[region.page - 1];
+        let size = page.frame.size();
+        let min = Point::new((region.min.x - padding).max(Abs::zero()), (region.min.y - padding).max(Abs::zero()));
+        let max = Point::new((region.max.x + padding).min(size.x), (region.max.y + padding).min(size.y));
+        let mut frame = page.frame.clone();
+        frame.translate(Point::new(-min.x, -min.y));
+        frame.set_size(Size::new(max.x - min.x, max.y - min.y));
+        let mut cropped = page.clone();
+        cropped.frame = frame;
+        cropped
+    }
+}
+
+fn walk_frame(frame: &Frame, transform: Transform, walk: &mut Walk) {
+    for (position, item) in frame.items() {
+        let at = |point: Point| Point::new(position.x + point.x, position.y + point.y).transform(transform);
+        match item {
+            FrameItem::Tag(Tag::Start(element)) if element.location() == Some(walk.location) => { walk.inside = true }
+            FrameItem::Tag(Tag::End(location, _)) if *location == walk.location => { walk.inside = false }
+            FrameItem::Group(group) => {
+                let inner = transform
+                    .pre_concat(Transform::translate(position.x, position.y))
+                    .pre_concat(group.transform);
+                walk_frame(&group.frame, inner, walk);
+            }
+            _ if !walk.inside => {}
+            FrameItem::Text(text) => {
+                let metrics = text.font.metrics();
+                let top = -metrics.ascender.at(text.size);
+                let bottom = -metrics.descender.at(text.size);
+                include(walk, at, Point::new(Abs::zero(), top), Point::new(text.width(), bottom));
+            }
+            FrameItem::Shape(shape, _) => {
+                let size = shape.geometry.bbox_size();
+                include(walk, at, Point::zero(), Point::new(size.x, size.y));
+            }
+            FrameItem::Image(_, size, _) => { include(walk, at, Point::zero(), Point::new(size.x, size.y)) }
+            _ => {}
+        }
+    }
+}
+
+/// Grow the bounds on the current page to hold the box from `min` to `max`, placed by `at`.
+/// All four corners count, so a rotated box is held too.
+fn include(walk: &mut Walk, at: impl Fn(Point) -> Point, min: Point, max: Point) {
+    for corner in [min, Point::new(max.x, min.y), Point::new(min.x, max.y), max].map(at) {
+        walk.bounds = Some(match walk.bounds {
+            Some((low, high)) => {
+                (Point::new(low.x.min(corner.x), low.y.min(corner.y)), Point::new(high.x.max(corner.x), high.y.max(corner.y)))
+            }
+            None => { (corner, corner) }
+        });
+    }
+}
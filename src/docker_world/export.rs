@@ -0,0 +1,126 @@
+//! Exporting a laid out document, as many times and to as many formats as needed.
+
+use std::fmt;
+use chrono::{DateTime, Utc};
+use ecow::EcoString;
+use typst::layout::Page;
+use typst::model::Document;
+use typst_pdf::{PdfOptions, PdfStandard, PdfStandards, Timestamp};
+use super::{datetime, CompileError, Compiled, Dependencies, DockerWorld, Warning};
+
+/// A document typst laid out, see `DockerWorld::typeset`.
+pub struct CompiledDocument<'a> {
+    /// Where diagnostics of the export are located.
+    pub(super) world: &'a DockerWorld,
+    pub(super) document: Document,
+    pub warnings: Vec<Warning>,
+    pub dependencies: Dependencies,
+    /// Whether compiling the same inputs again is known to give the same document.
+    pub cacheable: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct PdfExport {
+    /// Written to the metadata instead of the time the document was compiled at.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Conform to PDF/A-2b, failing with what violates it if the document can't.
+    pub pdf_a: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct SvgExport {
+    /// The page to render, counting from 1.
+    pub page: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct PngExport {
+    /// The page to render, counting from 1.
+    pub page: usize,
+    /// Pixels per inch.
+    pub ppi: f32,
+}
+
+impl Default for PngExport {
+    fn default() -> Self {
+        Self { page: 1, ppi: 144.0 }
+    }
+}
+
+/// Why a laid out document couldn't be exported.
+#[derive(Debug)]
+pub enum ExportError {
+    /// The document has no page with that number.
+    NoSuchPage { page: usize, pages: usize },
+    /// typst reported errors exporting the PDF, rendered one per line.
+    Pdf(EcoString),
+    /// The rendered page couldn't be encoded.
+    Png(String),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::NoSuchPage { page, pages } => {
+                write!(f, "The document has no page {page}, it has {pages} pages")
+            }
+            ExportError::Pdf(errors) => { write!(f, "{errors}") }
+            ExportError::Png(problem) => { write!(f, "The page could not be encoded as PNG: {problem}") }
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<ExportError> for CompileError {
+    fn from(problem: ExportError) -> Self {
+        CompileError::Failed(problem.to_string().into())
+    }
+}
+
+impl CompiledDocument<'_> {
+    pub fn pages(&self) -> usize {
+        self.document.pages.len()
+    }
+
+    pub fn to_pdf(&self, options: &PdfExport) -> Result<Vec<u8>, ExportError> {
+        let timestamp = match options.timestamp {
+            Some(timestamp) => { datetime(timestamp) }
+            None => { self.world.now() }
+        };
+        let standards = match options.pdf_a {
+            true => { PdfStandards::new(&[PdfStandard::A_2b]).expect("PDF/A-2b is a valid set of standards") }
+            false => { PdfStandards::default() }
+        };
+        let options = PdfOptions { timestamp: timestamp.map(Timestamp::new_utc), standards, ..PdfOptions::default() };
+        typst_pdf::pdf(&self.document, &options).map_err(|errors| ExportError::Pdf(self.world.describe(&errors)))
+    }
+
+    pub fn to_svg(&self, options: &SvgExport) -> Result<String, ExportError> {
+        Ok(typst_svg::svg(self.page(options.page)?))
+    }
+
+    pub fn to_png(&self, options: &PngExport) -> Result<Vec<u8>, ExportError> {
+        let pixmap = typst_render::render(self.page(options.page)?, options.ppi / 72.0);
+        pixmap.encode_png().map_err(|problem| ExportError::Png(problem.to_string()))
+    }
+
+    fn page(&self, page: usize) -> Result<&Page, ExportError> {
+        let pages = self.pages();
+        page.checked_sub(1)
+            .and_then(|index| self.document.pages.get(index))
+            .ok_or(ExportError::NoSuchPage { page, pages })
+    }
+
+    /// What `DockerWorld::compile` returns, with the PDF exported from this document.
+    pub fn into_compiled(self, pdf: Vec<u8>) -> Compiled {
+        Compiled {
+            pdf,
+            pages: self.pages(),
+            page_hashes: self.page_hashes(),
+            warnings: self.warnings,
+            dependencies: self.dependencies,
+            cacheable: self.cacheable,
+        }
+    }
+}
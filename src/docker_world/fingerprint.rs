@@ -0,0 +1,88 @@
+//! A hash of what each page shows, to compare the pages two typst versions laid out.
+//!
+//! It covers the page's size and every text run, shape and image on it, in the order typst
+//! placed them, each with where it is to a hundredth of a point, and the text of the runs.
+//! It leaves out how those are stored, so it stays the same across versions as long as the
+//! layout does, which hashing the PDF or the frames wouldn't. Colors and fonts don't count.
+
+use sha2::{Digest, Sha256};
+use typst::layout::{Frame, FrameItem, Point, Transform};
+use super::CompiledDocument;
+
+/// The hash of one page, fed by walking its frames.
+pub struct PageFingerprint {
+    hasher: Sha256,
+}
+
+impl PageFingerprint {
+    /// Start the hash of a page `width` by `height` points.
+    pub fn new(width: f64, height: f64) -> Self {
+        let mut fingerprint = Self { hasher: Sha256::new() };
+        fingerprint.numbers(b'P', &[width, height]);
+        fingerprint
+    }
+
+    /// A text run at `x`, `y` in `size` points.
+    pub fn text(&mut self, x: f64, y: f64, size: f64, text: &str) {
+        self.numbers(b'T', &[x, y, size]);
+        self.hasher.update((text.len() as u64).to_le_bytes());
+        self.hasher.update(text.as_bytes());
+    }
+
+    /// A shape whose bounding box is `width` by `height` at `x`, `y`.
+    pub fn shape(&mut self, x: f64, y: f64, width: f64, height: f64) {
+        self.numbers(b'S', &[x, y, width, height]);
+    }
+
+    /// An image `width` by `height` at `x`, `y`.
+    pub fn image(&mut self, x: f64, y: f64, width: f64, height: f64) {
+        self.numbers(b'I', &[x, y, width, height]);
+    }
+
+    /// The hash in hex.
+    pub fn finish(self) -> String {
+        self.hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn numbers(&mut self, kind: u8, points: &[f64]) {
+        self.hasher.update([kind]);
+        for point in points {
+            self.hasher.update(((point * 100.0).round() as i64).to_le_bytes());
+        }
+    }
+}
+
+impl CompiledDocument<'_> {
+    /// The `PageFingerprint` of each page, in order.
+    pub fn page_hashes(&self) -> Vec<String> {
+        self.document.pages.iter()
+            .map(|page| {
+                let size = page.frame.size();
+                let mut fingerprint = PageFingerprint::new(size.x.to_pt(), size.y.to_pt());
+                walk_frame(&page.frame, Transform::identity(), &mut fingerprint);
+                fingerprint.finish()
+            })
+            .collect()
+    }
+}
+
+fn walk_frame(frame: &Frame, transform: Transform, fingerprint: &mut PageFingerprint) {
+    for (position, item) in frame.items() {
+        let at = Point::new(position.x, position.y).transform(transform);
+        match item {
+            FrameItem::Group(group) => {
+                let inner = transform
+                    .pre_concat(Transform::translate(position.x, position.y))
+                    .pre_concat(group.transform);
+                walk_frame(&group.frame, inner, fingerprint);
+            }
+            FrameItem::Text(text) => { fingerprint.text(at.x.to_pt(), at.y.to_pt(), text.size.to_pt(), &text.text) }
+            FrameItem::Shape(shape, _) => {
+                let size = shape.geometry.bbox_size();
+                fingerprint.shape(at.x.to_pt(), at.y.to_pt(), size.x.to_pt(), size.y.to_pt());
+            }
+            FrameItem::Image(_, size, _) => { fingerprint.image(at.x.to_pt(), at.y.to_pt(), size.x.to_pt(), size.y.to_pt()) }
+            _ => {}
+        }
+    }
+}
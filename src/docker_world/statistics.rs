@@ -0,0 +1,144 @@
+//! Counting the words and characters of a laid out document, and its headings, figures and
+//! tables.
+//!
+//! Only what ended up on the pages counts, so code, comments and content that isn't shown
+//! don't. Each page's text runs are read in the order typst placed them. A run starting
+//! where the last ended on the same baseline continues it, as does a run on a later line
+//! after one ending in a hyphen, typst's or the text's own. Anything else is a word boundary.
+//!
+//! Words are what Unicode's word boundaries (UAX #29) delimit holding a letter or digit.
+//! In scripts written without spaces that makes each Chinese or Japanese ideograph and
+//! each kana a word, while Thai and other runs without boundaries count as one. Characters
+//! are extended grapheme clusters, so `é` counts once however it is encoded, and
+//! whitespace characters are those with Unicode's `White_Space` property. The counts only
+//! depend on the document, not on the server.
+
+use serde::{Deserialize, Serialize};
+use typst::foundations::{NativeElement, Value};
+use typst::layout::{Abs, Frame, FrameItem, Point, Transform};
+use typst::model::{FigureElem, HeadingElem, TableElem};
+use typst::text::TextItem;
+use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+use super::CompiledDocument;
+
+/// How far apart two runs may be and still be read as adjacent.
+const TOLERANCE: Abs = Abs::raw(0.5);
+
+/// The counts of a document, see the module docs.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Statistics {
+    pub words: usize,
+    pub characters: usize,
+    pub non_whitespace_characters: usize,
+    pub headings: usize,
+    pub figures: usize,
+    /// Figures holding a table, typst only keeps track of tables in figures.
+    pub tables: usize,
+    /// The counts of each page, in order.
+    pub pages: Vec<PageStatistics>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct PageStatistics {
+    /// Counting from 1.
+    pub page: usize,
+    pub words: usize,
+    pub characters: usize,
+    pub non_whitespace_characters: usize,
+}
+
+/// A text run where it is on the page.
+struct Run<'a> {
+    text: &'a str,
+    start: Point,
+    end: Point,
+    /// Whether it ends in a hyphen, so a word may go on on the next line.
+    hyphenated: bool,
+}
+
+impl CompiledDocument<'_> {
+    pub fn statistics(&self) -> Statistics {
+        let pages: Vec<_> = self.document.pages.iter()
+            .enumerate()
+            .map(|(index, page)| count_page(index + 1, &page.frame))
+            .collect();
+        let introspector = &self.document.introspector;
+        let figures = introspector.query(&FigureElem::elem().select());
+        let tables = figures.iter()
+            .filter(|figure| {
+                figure.get_by_name("kind").into_iter().any(|kind| {
+                    matches!(kind, Value::Func(func) if func.element() == Some(TableElem::elem()))
+                })
+            })
+            .count();
+        Statistics {
+            words: pages.iter().map(|page| page.words).sum(),
+            characters: pages.iter().map(|page| page.characters).sum(),
+            non_whitespace_characters: pages.iter().map(|page| page.non_whitespace_characters).sum(),
+            headings: introspector.query(&HeadingElem::elem().select()).len(),
+            figures: figures.len(),
+            tables,
+            pages,
+        }
+    }
+}
+
+fn count_page(page: usize, frame: &Frame) -> PageStatistics {
+    let mut runs = Vec::new();
+    collect_runs(frame, Transform::identity(), &mut runs);
+
+    let mut counts = PageStatistics { page, ..PageStatistics::default() };
+    let mut text = String::new();
+    let mut last: Option<&Run> = None;
+    for run in &runs {
+        if let Some(last) = last {
+            let adjacent = (run.start.y - last.end.y).abs() < TOLERANCE && (run.start.x - last.end.x).abs() < TOLERANCE;
+            let continued = last.hyphenated && run.start.y > last.end.y;
+            if !adjacent && !continued {
+                text.push(' ');
+            }
+        }
+        text.push_str(run.text);
+        for grapheme in run.text.graphemes(true) {
+            counts.characters += 1;
+            if !grapheme.chars().all(char::is_whitespace) {
+                counts.non_whitespace_characters += 1;
+            }
+        }
+        last = Some(run);
+    }
+    counts.words = text.unicode_words().count();
+    counts
+}
+
+/// The text runs of `frame` in the order they were placed, positioned by `transform`.
+fn collect_runs<'a>(frame: &'a Frame, transform: Transform, runs: &mut Vec<Run<'a>>) {
+    for (position, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => {
+                let inner = transform
+                    .pre_concat(Transform::translate(position.x, position.y))
+                    .pre_concat(group.transform);
+                collect_runs(&group.frame, inner, runs);
+            }
+            FrameItem::Text(text) => { runs.push(run(text, *position, transform)) }
+            _ => {}
+        }
+    }
+}
+
+fn run(text: &TextItem, position: Point, transform: Transform) -> Run<'_> {
+    // typst marks the hyphen it adds breaking a word with a glyph standing for no text.
+    let hyphenated = text.glyphs.last().is_some_and(|glyph| glyph.range.is_empty())
+        || text.text.ends_with(['-', '\u{2010}', '\u{00AD}']);
+    Run {
+        text: text.text.as_str(),
+        start: position.transform(transform),
+        end: Point::new(position.x + text.width(), position.y).transform(transform),
+        hyphenated,
+    }
+}
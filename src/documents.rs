@@ -0,0 +1,107 @@
+//! Documents kept on the server under `DOCUMENTS_ROOT`, compiled at stable URLs.
+//!
+//! `GET /documents/reports/monthly.pdf` compiles `reports/monthly.typ` with every file below
+//! the root available to it, so templates can share assets, and the PDF is always the
+//! current one. Since the sources are the server's, a document with errors is a 500.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use actix_web::http::header::{self, Header, HttpDate};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use crate::cache::CompileCache;
+use crate::compilers::{compile_blocking, respond, Compilers, JsonOutput, VersionQuery};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{load_directory, CompileError, DocumentFile, ErrorOutput, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::slots::CompileSlots;
+
+/// The document `name` and every other file below `root`, and when any of them last changed.
+fn load(root: &Path, name: &str, max_size: u64) -> io::Result<(Vec<DocumentFile>, SystemTime)> {
+    let main = DocumentFile::from_path(root, Path::new(&format!("{name}.typ")))?;
+    let mut documents = vec![main];
+    documents.extend(load_directory(root, max_size)?.into_iter().filter(|file| file.name != documents[0].name));
+
+    let mut modified = SystemTime::UNIX_EPOCH;
+    for file in &documents {
+        let path: PathBuf = root.join(file.name.vpath().as_rootless_path());
+        modified = modified.max(path.metadata()?.modified()?);
+    }
+    Ok((documents, modified))
+}
+
+#[utoipa::path(
+    tag = "compile",
+    params(("name" = String, Path, description = "Path of the document below the root, without `.typ`"), VersionQuery, OptionsQuery),
+    responses(
+        (status = 200, description = "The PDF, or with `include=deps` the PDF and what the compile read", content(
+            ([u8] = "application/octet-stream"),
+            (JsonOutput = "application/json"),
+        )),
+        (status = 304, description = "No file changed since `If-Modified-Since`"),
+        (status = 404, description = "No such document, or `DOCUMENTS_ROOT` is unset", body = ErrorOutput),
+        (status = 422, description = "With `fail_on_warnings`, the document compiled with warnings", body = WarningsOutput),
+        (status = 500, description = "The document has errors", body = ErrorOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "The compile timed out", body = TimeoutOutput),
+    ),
+)]
+#[get("/documents/{name:.+}.pdf")]
+async fn document(
+    request: HttpRequest,
+    name: web::Path<String>,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+) -> Result<HttpResponse, Error> {
+    let Some(root) = config.documents_root.clone() else {
+        return Err(Code::DocumentsDisabled.error("No documents are served"));
+    };
+    let compiler = compilers.select(&request)?;
+    let options = config.compile_options(&request)?;
+    let max_size = config.max_upload_size.map_or(u64::MAX, |max| max as u64);
+
+    let name = name.into_inner();
+    let loaded = web::block(move || load(&root, &name, max_size)).await?;
+    let (documents, modified) = match loaded {
+        Ok(loaded) => { loaded }
+        Err(problem) if problem.kind() == io::ErrorKind::NotFound => { return Err(Code::DocumentNotFound.error("No such document")) }
+        Err(problem) if problem.kind() == io::ErrorKind::PermissionDenied => { return Err(Code::DocumentNotFound.error("No such document")) }
+        Err(problem) => { return Err(Code::Internal.error(problem)) }
+    };
+
+    // HTTP dates have whole seconds, so compare at that resolution.
+    let modified = SystemTime::from(HttpDate::from(modified));
+    let unchanged = header::IfModifiedSince::parse(&request)
+        .is_ok_and(|since| SystemTime::from(since.0) >= modified);
+    if unchanged {
+        return Ok(HttpResponse::NotModified().finish());
+    }
+
+    let compiled = compile_blocking(
+        &slots,
+        &metrics,
+        &cache,
+        compiler.clone(),
+        documents,
+        options.clone(),
+        config.cache_max_age,
+    ).await?;
+    let compiled = match compiled {
+        Err(CompileError::Failed(errors) | CompileError::PackageUnavailable(errors)) => { return Err(Code::DocumentFailed.error(errors)) }
+        compiled => { compiled }
+    };
+
+    let mut response = respond(compiler.version(), compiled, &options, &metrics)?;
+    let headers = response.headers_mut();
+    headers.insert(header::LAST_MODIFIED, HttpDate::from(modified).to_string().parse().expect("HTTP dates are valid headers"));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(document);
+}
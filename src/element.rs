@@ -0,0 +1,213 @@
+//! `POST /render/element`, one labeled element of a document as SVG or PNG, cropped to it,
+//! to show a figure without its page.
+//!
+//! It takes the same upload and options as `/compile`, `label` naming the element, with or
+//! without the angle brackets, and `format`, `svg` or `png` at `ppi`. The image shows the
+//! element's bounding box and `padding` points around it, 4 by default, within its page; see
+//! `docker_world::element` for how the box is found. A label no element has is a 404, one
+//! several have a 400, and an element that shows nothing a 422.
+//!
+//! An element breaking across pages is refused with a 422 unless `split=true`, which answers
+//! a zip of its part on each page, `element-page-3.svg` and so on. Either way
+//! `X-Typst-Element-Pages` lists the pages the element is on.
+
+use actix_web::http::header;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use typst::layout::Abs;
+use utoipa::IntoParams;
+use crate::archive::ZipWriter;
+use crate::audit;
+use crate::checksum::Checksum;
+use crate::compilers::{run_compile, Compilers, Paged, VersionQuery, CURRENT_VERSION, VERSION_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{CompileError, ElementError, ErrorOutput, FontLibrary, PngExport, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::multipart::{read_submission, JsonUpload, Submission};
+use crate::openapi::{SourceForm, Upload};
+use crate::packages::PackageStore;
+use crate::pages::{Format, MAX_PPI};
+use crate::slots::CompileSlots;
+
+pub const ELEMENT_PAGES_HEADER: &str = "X-Typst-Element-Pages";
+
+/// Most points of padding a request may ask for.
+const MAX_PADDING: f64 = 144.0;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ElementQuery {
+    /// The label of the element, like `fig:overview` or `<fig:overview>`.
+    label: String,
+    /// What the element is rendered as.
+    #[param(inline)]
+    format: Format,
+    /// Points around the element, 4 by default and at most 144.
+    padding: Option<f64>,
+    /// Pixels per inch of a PNG, 144 by default and at most 300.
+    ppi: Option<f32>,
+    /// Answer a zip of the parts of an element breaking across pages instead of refusing it.
+    split: Option<bool>,
+}
+
+/// The element as the compile rendered it.
+struct Rendered {
+    /// The pages the element is on and its image on each, or why there is none.
+    images: Result<Vec<(usize, Vec<u8>)>, ElementError>,
+    pages: usize,
+}
+
+impl Paged for Rendered {
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// Render the labeled element of a document, see the module docs.
+#[utoipa::path(
+    tag = "compile",
+    params(ElementQuery, VersionQuery, OptionsQuery),
+    request_body(content(
+        (Upload = "multipart/form-data"),
+        (SourceForm = "application/x-www-form-urlencoded"),
+        (JsonUpload = "application/json"),
+        (String = "text/plain"),
+        ([u8] = "application/gzip"),
+    )),
+    responses(
+        (status = 200, description = "The element, or with `split=true` a zip of its parts if it breaks across pages", content(
+            (String = "image/svg+xml"),
+            ([u8] = "image/png"),
+            ([u8] = "application/zip"),
+        )),
+        (status = 400, description = "The upload or the options are invalid, several elements have the label, or the document has errors", body = ErrorOutput),
+        (status = 404, description = "No element has the label", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 422, description = "The element breaks across pages or shows nothing, or the document exceeded the page or resource limits", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "Laying out the document timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/render/element")]
+#[allow(clippy::too_many_arguments)]
+async fn render_element(
+    request: HttpRequest,
+    payload: web::Payload,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    if compiler.version() != CURRENT_VERSION {
+        return Err(Code::InvalidParameter.error(format!("Rendering elements needs typst {CURRENT_VERSION}")));
+    }
+    let mut options = config.compile_options(&request)?;
+    let query = web::Query::<ElementQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?
+        .into_inner();
+    let label = query.label.trim().trim_start_matches('<').trim_end_matches('>').to_string();
+    if label.is_empty() {
+        return Err(Code::InvalidParameter.error("Name the element's label"));
+    }
+    let padding = query.padding.unwrap_or(4.0);
+    if !(0.0..=MAX_PADDING).contains(&padding) {
+        return Err(Code::InvalidParameter.error(format!("padding must be from 0 to {MAX_PADDING}")));
+    }
+    let ppi = query.ppi.unwrap_or(PngExport::default().ppi);
+    if !(1.0..=MAX_PPI).contains(&ppi) {
+        return Err(Code::InvalidParameter.error(format!("ppi must be from 1 to {MAX_PPI}")));
+    }
+
+    let Submission { mut documents, inputs, .. } = read_submission(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    audit::inputs(&request, &documents);
+    let mut record = audit::detach(&request);
+    options.inputs.extend(inputs);
+    let world = options.world()
+        .main(documents.remove(0))
+        .files(documents)
+        .font_db(fonts.current())
+        .packages(packages.into_inner());
+
+    let (format, padding) = (query.format, Abs::pt(padding));
+    let (max_pages, cache_max_age) = (options.max_pages, config.cache_max_age);
+    let rendered = run_compile(&slots, &metrics, options.timeout, options.priority, format.name(), move |cancellation| {
+        let mut world = world.build()?;
+        let document = world.typeset(max_pages, cancellation)?;
+        let images = match document.element_regions(&label) {
+            Ok(regions) => {
+                let images = regions.iter()
+                    .map(|region| {
+                        let image = match format {
+                            Format::Svg => { document.region_svg(region, padding).into_bytes() }
+                            Format::Png => { document.region_png(region, padding, ppi)? }
+                        };
+                        Ok::<_, CompileError>((region.page, image))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(images)
+            }
+            Err(problem) => { Err(problem) }
+        };
+        let rendered = Rendered { images, pages: document.pages() };
+        comemo::evict(cache_max_age);
+        Ok(rendered)
+    }).await;
+    if let Some(record) = &mut record {
+        record.finished(format.name(), &rendered);
+    }
+
+    let mut images = rendered??.images.map_err(|problem| {
+        let code = match problem {
+            ElementError::NoSuchLabel(_) => { Code::LabelNotFound }
+            ElementError::AmbiguousLabel { .. } => { Code::AmbiguousLabel }
+            ElementError::Invisible(_) => { Code::ElementEmpty }
+        };
+        code.error(problem)
+    })?;
+    let pages = images.iter().map(|(page, _)| page.to_string()).collect::<Vec<_>>().join(",");
+    let split = query.split.unwrap_or(false);
+    if images.len() > 1 && !split {
+        return Err(Code::ElementSplit.error(format!("The element breaks across pages {pages}, ask for each part with split=true")));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((VERSION_HEADER, CURRENT_VERSION)).insert_header((ELEMENT_PAGES_HEADER, pages));
+    let (content_type, body) = match images.len() {
+        1 => {
+            let (_, image) = images.remove(0);
+            let content_type = match format {
+                Format::Svg => { "image/svg+xml" }
+                Format::Png => { "image/png" }
+            };
+            (content_type, image)
+        }
+        _ => {
+            let mut archive = ZipWriter::new(options.now.unwrap_or_else(Utc::now));
+            let mut zip = Vec::new();
+            for (page, image) in &images {
+                let name = format!("element-page-{page}.{}", format.name());
+                // PNGs are compressed already.
+                let entry = archive.entry(&name, image, matches!(format, Format::Svg))
+                    .ok_or_else(|| Code::Internal.error("The parts are larger than a zip holds"))?;
+                zip.extend(entry);
+            }
+            zip.extend(archive.finish());
+            response.insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"element.zip\""));
+            ("application/zip", zip)
+        }
+    };
+    Checksum::of(&body).insert(&mut response);
+    Ok(response.content_type(content_type).body(body))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(render_element);
+}
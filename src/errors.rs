@@ -0,0 +1,535 @@
+//! The codes every failure is answered with, for clients to act on without reading messages.
+//!
+//! A failure's body is JSON like `{"error": "file_too_large", "message": "..."}`, the
+//! message for people and free to change, the code stable. Some failures add fields, like
+//! `timeout_seconds` or the missing blobs. The codes are those of `Code` and, for compiles,
+//! those of `CompileError::code`: `compile_error`, `package_unavailable`, `too_many_pages`,
+//! `compile_cancelled`, `timeout`, `resource_limit`, `worker_crashed` and `warnings`.
+//!
+//! A published code is never dropped or given another meaning, new failures get new codes.
+//! Failures without one of their own, like an unknown path or a malformed query, get a
+//! code for their status such as `not_found` or `bad_request`.
+
+use std::fmt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::{Error, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use crate::docker_world::{BuildError, CompileError, ErrorOutput};
+use crate::slots::Saturated;
+
+/// What went wrong, see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    /// The server failed at something it should manage, like writing a temporary file.
+    Internal,
+    /// The multipart body couldn't be read.
+    InvalidMultipart,
+    /// A raw request body couldn't be read.
+    InvalidBody,
+    /// A JSON request body doesn't match its schema.
+    InvalidJson,
+    /// No upload data arrived for `UPLOAD_IDLE_TIMEOUT`.
+    UploadTimeout,
+    /// There is no main file, or no file at all.
+    MissingMain,
+    /// Two files have the same path, the main file included.
+    DuplicateFile,
+    /// A file's path has control characters or is longer than `MAX_FILENAME_LENGTH`.
+    InvalidFilename,
+    /// A file's extension isn't in `ALLOWED_EXTENSIONS`.
+    FileTypeNotAllowed,
+    /// A file's contents don't match its extension.
+    InvalidFileContent,
+    /// A file is larger than `MAX_FILE_SIZE_KB`.
+    FileTooLarge,
+    /// The upload is larger than `MAX_UPLOAD_SIZE_KB`, or a resumable upload than `UPLOAD_SESSION_MAX_MB`.
+    UploadTooLarge,
+    /// The upload has more than `MAX_FILES` files.
+    TooManyFiles,
+    /// The query string doesn't parse, like a number that isn't one.
+    InvalidQuery,
+    /// A query parameter or header tuning the compile is invalid or beyond the server's ceiling.
+    InvalidOption,
+    /// The requested typst version isn't bundled.
+    UnknownVersion,
+    /// The timezone header names no known timezone.
+    UnknownTimezone,
+    /// The caller may not ask for high priority.
+    PriorityNotAllowed,
+    /// A part refers to a resumable upload that doesn't exist or expired.
+    UnknownUpload,
+    /// A part refers to a resumable upload that hasn't received every byte.
+    UploadIncomplete,
+    /// No such resumable upload, or it expired.
+    UploadNotFound,
+    /// A chunk lacks `Upload-Offset`.
+    MissingUploadOffset,
+    /// A chunk's `Upload-Offset` isn't the bytes received so far.
+    UploadOffsetMismatch,
+    /// A chunk was sent to an upload that is complete.
+    UploadComplete,
+    /// Another chunk is being written to the upload.
+    UploadBusy,
+    /// A chunk runs past the size the upload was opened for.
+    UploadOverrun,
+    /// A SHA-256 isn't 64 hex digits.
+    InvalidDigest,
+    /// A blob's body doesn't hash to its path.
+    DigestMismatch,
+    /// Blobs a manifest refers to are missing, with the list of them.
+    MissingBlobs,
+    /// The callback URL is invalid or not allowed.
+    InvalidCallbackUrl,
+    /// No such job, or it expired.
+    JobNotFound,
+    /// The job hasn't succeeded, so there is no result.
+    JobNotSucceeded,
+    /// The job's result expired and was deleted.
+    JobResultExpired,
+    /// The server restarted before the job finished.
+    JobInterrupted,
+    /// No such project.
+    ProjectNotFound,
+    /// The project expired after its TTL.
+    ProjectExpired,
+    /// The project id has control characters.
+    InvalidProjectId,
+    /// The project is being compiled.
+    ProjectBusy,
+    /// The change lacks `If-Match`.
+    VersionRequired,
+    /// The project changed since the version in `If-Match`.
+    VersionMismatch,
+    /// Someone else holds the project's lock.
+    ProjectLocked,
+    /// No such file in the project.
+    FileNotFound,
+    /// An edit of a file doesn't fit it.
+    InvalidEdit,
+    /// An overlay file isn't valid base64.
+    InvalidBase64,
+    /// The project keeps no such revision.
+    RevisionNotFound,
+    /// The file to analyze can't be analyzed at the cursor.
+    AnalysisFailed,
+    /// No documents are served.
+    DocumentsDisabled,
+    /// No such served document.
+    DocumentNotFound,
+    /// A served document failed to compile.
+    DocumentFailed,
+    /// No font family of that name was found.
+    FontNotFound,
+    /// No element in the document has the label.
+    LabelNotFound,
+    /// More than one element in the document has the label.
+    AmbiguousLabel,
+    /// The element breaks across pages and the request didn't ask for each part.
+    ElementSplit,
+    /// The element shows nothing that could be rendered.
+    ElementEmpty,
+    /// The request has no API key.
+    ApiKeyRequired,
+    /// The API key is not valid.
+    InvalidApiKey,
+    /// The API key is disabled.
+    ApiKeyDisabled,
+    /// The API key expired.
+    ApiKeyExpired,
+    /// The API key may not use this endpoint.
+    EndpointNotAllowed,
+    /// The request lacks its signature or timestamp.
+    SignatureRequired,
+    /// The request's timestamp is too far off.
+    SignatureStale,
+    /// The request's signature is not valid.
+    InvalidSignature,
+    /// A signed request with a body lacks `Content-Length`.
+    LengthRequired,
+    /// The client's address is denied by the access rules.
+    ClientDenied,
+    /// The admin endpoints are disabled.
+    AdminDisabled,
+    /// The admin token is missing or wrong.
+    InvalidAdminToken,
+    /// The API docs are disabled.
+    DocsDisabled,
+    /// The web UI is disabled.
+    UiDisabled,
+    /// The package isn't in the cache.
+    PackageNotCached,
+    /// The client sent requests faster than `RATE_LIMIT` allows.
+    RateLimited,
+    /// The tenant used up a quota, with the quota and when it resets.
+    QuotaExceeded,
+    /// Every compile slot and the queue are taken.
+    ServerBusy,
+    /// The client runs as many compiles at once as it may, see `concurrency`.
+    ClientBusy,
+    /// A parameter of `/example` is out of range.
+    InvalidParameter,
+    // The codes of failures without one of their own, by their status.
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    Conflict,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// Another status of the client's fault.
+    ClientError,
+    /// Another status of the server's fault.
+    ServerError,
+}
+
+/// A failure with its code, as handlers return it.
+#[derive(Debug)]
+struct Failure {
+    code: Code,
+    message: String,
+}
+
+impl Code {
+    /// The code in the body and the status answered with it.
+    fn parts(self) -> (&'static str, StatusCode) {
+        match self {
+            Code::Internal => { ("internal_error", StatusCode::INTERNAL_SERVER_ERROR) }
+            Code::InvalidMultipart => { ("invalid_multipart", StatusCode::BAD_REQUEST) }
+            Code::InvalidBody => { ("invalid_body", StatusCode::BAD_REQUEST) }
+            Code::InvalidJson => { ("invalid_json", StatusCode::BAD_REQUEST) }
+            Code::UploadTimeout => { ("upload_timeout", StatusCode::REQUEST_TIMEOUT) }
+            Code::MissingMain => { ("missing_main", StatusCode::BAD_REQUEST) }
+            Code::DuplicateFile => { ("duplicate_file", StatusCode::BAD_REQUEST) }
+            Code::InvalidFilename => { ("invalid_filename", StatusCode::BAD_REQUEST) }
+            Code::FileTypeNotAllowed => { ("file_type_not_allowed", StatusCode::BAD_REQUEST) }
+            Code::InvalidFileContent => { ("invalid_file_content", StatusCode::BAD_REQUEST) }
+            Code::FileTooLarge => { ("file_too_large", StatusCode::PAYLOAD_TOO_LARGE) }
+            Code::UploadTooLarge => { ("upload_too_large", StatusCode::PAYLOAD_TOO_LARGE) }
+            Code::TooManyFiles => { ("too_many_files", StatusCode::PAYLOAD_TOO_LARGE) }
+            Code::InvalidQuery => { ("invalid_query", StatusCode::BAD_REQUEST) }
+            Code::InvalidOption => { ("invalid_option", StatusCode::BAD_REQUEST) }
+            Code::UnknownVersion => { ("unknown_version", StatusCode::BAD_REQUEST) }
+            Code::UnknownTimezone => { ("unknown_timezone", StatusCode::BAD_REQUEST) }
+            Code::PriorityNotAllowed => { ("priority_not_allowed", StatusCode::FORBIDDEN) }
+            Code::UnknownUpload => { ("unknown_upload", StatusCode::BAD_REQUEST) }
+            Code::UploadIncomplete => { ("upload_incomplete", StatusCode::BAD_REQUEST) }
+            Code::UploadNotFound => { ("upload_not_found", StatusCode::NOT_FOUND) }
+            Code::MissingUploadOffset => { ("missing_upload_offset", StatusCode::BAD_REQUEST) }
+            Code::UploadOffsetMismatch => { ("upload_offset_mismatch", StatusCode::CONFLICT) }
+            Code::UploadComplete => { ("upload_complete", StatusCode::CONFLICT) }
+            Code::UploadBusy => { ("upload_busy", StatusCode::CONFLICT) }
+            Code::UploadOverrun => { ("upload_overrun", StatusCode::PAYLOAD_TOO_LARGE) }
+            Code::InvalidDigest => { ("invalid_digest", StatusCode::BAD_REQUEST) }
+            Code::DigestMismatch => { ("digest_mismatch", StatusCode::BAD_REQUEST) }
+            Code::MissingBlobs => { ("missing_blobs", StatusCode::CONFLICT) }
+            Code::InvalidCallbackUrl => { ("invalid_callback_url", StatusCode::BAD_REQUEST) }
+            Code::JobNotFound => { ("job_not_found", StatusCode::NOT_FOUND) }
+            Code::JobNotSucceeded => { ("job_not_succeeded", StatusCode::CONFLICT) }
+            Code::JobResultExpired => { ("job_result_expired", StatusCode::GONE) }
+            Code::JobInterrupted => { ("job_interrupted", StatusCode::SERVICE_UNAVAILABLE) }
+            Code::ProjectNotFound => { ("project_not_found", StatusCode::NOT_FOUND) }
+            Code::ProjectExpired => { ("project_expired", StatusCode::GONE) }
+            Code::InvalidProjectId => { ("invalid_project_id", StatusCode::BAD_REQUEST) }
+            Code::ProjectBusy => { ("project_busy", StatusCode::CONFLICT) }
+            Code::VersionRequired => { ("version_required", StatusCode::PRECONDITION_REQUIRED) }
+            Code::VersionMismatch => { ("version_mismatch", StatusCode::PRECONDITION_FAILED) }
+            Code::ProjectLocked => { ("project_locked", StatusCode::LOCKED) }
+            Code::FileNotFound => { ("file_not_found", StatusCode::NOT_FOUND) }
+            Code::InvalidEdit => { ("invalid_edit", StatusCode::BAD_REQUEST) }
+            Code::InvalidBase64 => { ("invalid_base64", StatusCode::BAD_REQUEST) }
+            Code::RevisionNotFound => { ("revision_not_found", StatusCode::NOT_FOUND) }
+            Code::AnalysisFailed => { ("analysis_failed", StatusCode::BAD_REQUEST) }
+            Code::DocumentsDisabled => { ("documents_disabled", StatusCode::NOT_FOUND) }
+            Code::DocumentNotFound => { ("document_not_found", StatusCode::NOT_FOUND) }
+            Code::DocumentFailed => { ("document_failed", StatusCode::INTERNAL_SERVER_ERROR) }
+            Code::FontNotFound => { ("font_not_found", StatusCode::NOT_FOUND) }
+            Code::LabelNotFound => { ("label_not_found", StatusCode::NOT_FOUND) }
+            Code::AmbiguousLabel => { ("ambiguous_label", StatusCode::BAD_REQUEST) }
+            Code::ElementSplit => { ("element_split", StatusCode::UNPROCESSABLE_ENTITY) }
+            Code::ElementEmpty => { ("element_empty", StatusCode::UNPROCESSABLE_ENTITY) }
+            Code::ApiKeyRequired => { ("api_key_required", StatusCode::UNAUTHORIZED) }
+            Code::InvalidApiKey => { ("invalid_api_key", StatusCode::FORBIDDEN) }
+            Code::ApiKeyDisabled => { ("api_key_disabled", StatusCode::FORBIDDEN) }
+            Code::ApiKeyExpired => { ("api_key_expired", StatusCode::FORBIDDEN) }
+            Code::EndpointNotAllowed => { ("endpoint_not_allowed", StatusCode::FORBIDDEN) }
+            Code::SignatureRequired => { ("signature_required", StatusCode::UNAUTHORIZED) }
+            Code::SignatureStale => { ("signature_stale", StatusCode::UNAUTHORIZED) }
+            Code::InvalidSignature => { ("invalid_signature", StatusCode::UNAUTHORIZED) }
+            Code::LengthRequired => { ("length_required", StatusCode::LENGTH_REQUIRED) }
+            Code::ClientDenied => { ("client_denied", StatusCode::FORBIDDEN) }
+            Code::AdminDisabled => { ("admin_disabled", StatusCode::NOT_FOUND) }
+            Code::InvalidAdminToken => { ("invalid_admin_token", StatusCode::UNAUTHORIZED) }
+            Code::DocsDisabled => { ("docs_disabled", StatusCode::NOT_FOUND) }
+            Code::UiDisabled => { ("ui_disabled", StatusCode::NOT_FOUND) }
+            Code::PackageNotCached => { ("package_not_cached", StatusCode::NOT_FOUND) }
+            Code::RateLimited => { ("rate_limited", StatusCode::TOO_MANY_REQUESTS) }
+            Code::QuotaExceeded => { ("quota_exceeded", StatusCode::TOO_MANY_REQUESTS) }
+            Code::ServerBusy => { ("server_busy", StatusCode::SERVICE_UNAVAILABLE) }
+            Code::ClientBusy => { ("client_busy", StatusCode::TOO_MANY_REQUESTS) }
+            Code::InvalidParameter => { ("invalid_parameter", StatusCode::BAD_REQUEST) }
+            Code::BadRequest => { ("bad_request", StatusCode::BAD_REQUEST) }
+            Code::Unauthorized => { ("unauthorized", StatusCode::UNAUTHORIZED) }
+            Code::Forbidden => { ("forbidden", StatusCode::FORBIDDEN) }
+            Code::NotFound => { ("not_found", StatusCode::NOT_FOUND) }
+            Code::MethodNotAllowed => { ("method_not_allowed", StatusCode::METHOD_NOT_ALLOWED) }
+            Code::NotAcceptable => { ("not_acceptable", StatusCode::NOT_ACCEPTABLE) }
+            Code::RequestTimeout => { ("request_timeout", StatusCode::REQUEST_TIMEOUT) }
+            Code::Conflict => { ("conflict", StatusCode::CONFLICT) }
+            Code::PayloadTooLarge => { ("payload_too_large", StatusCode::PAYLOAD_TOO_LARGE) }
+            Code::UnsupportedMediaType => { ("unsupported_media_type", StatusCode::UNSUPPORTED_MEDIA_TYPE) }
+            Code::ClientError => { ("client_error", StatusCode::BAD_REQUEST) }
+            Code::ServerError => { ("server_error", StatusCode::INTERNAL_SERVER_ERROR) }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.parts().0
+    }
+
+    pub fn status(self) -> StatusCode {
+        self.parts().1
+    }
+
+    /// The failure to answer with, `message` telling people what went wrong.
+    pub fn error(self, message: impl fmt::Display) -> Error {
+        Failure { code: self, message: message.to_string() }.into()
+    }
+
+    /// The body of the failure, for types that answer with their own headers.
+    pub fn body(self, message: impl fmt::Display) -> ErrorOutput {
+        ErrorOutput::new(self.as_str(), message.to_string())
+    }
+
+    /// The code of a failure without one of its own, by its type or else its status.
+    fn fallback(problem: Option<&Error>, status: StatusCode) -> Self {
+        if let Some(JsonPayloadError::Deserialize(_)) = problem.and_then(Error::as_error::<JsonPayloadError>) {
+            return Code::InvalidJson;
+        }
+        if let Some(QueryPayloadError::Deserialize(_)) = problem.and_then(Error::as_error::<QueryPayloadError>) {
+            return Code::InvalidQuery;
+        }
+        if problem.and_then(Error::as_error::<Saturated>).is_some() {
+            return Code::ServerBusy;
+        }
+        match status {
+            StatusCode::BAD_REQUEST => { Code::BadRequest }
+            StatusCode::UNAUTHORIZED => { Code::Unauthorized }
+            StatusCode::FORBIDDEN => { Code::Forbidden }
+            StatusCode::NOT_FOUND => { Code::NotFound }
+            StatusCode::METHOD_NOT_ALLOWED => { Code::MethodNotAllowed }
+            StatusCode::NOT_ACCEPTABLE => { Code::NotAcceptable }
+            StatusCode::REQUEST_TIMEOUT => { Code::RequestTimeout }
+            StatusCode::CONFLICT => { Code::Conflict }
+            StatusCode::PAYLOAD_TOO_LARGE => { Code::PayloadTooLarge }
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => { Code::UnsupportedMediaType }
+            status if status.is_client_error() => { Code::ClientError }
+            _ => { Code::ServerError }
+        }
+    }
+}
+
+/// The failure of a world that couldn't be built from the uploaded files.
+pub fn unbuildable(problem: BuildError) -> Error {
+    let code = match problem {
+        BuildError::MissingMain => { Code::MissingMain }
+        BuildError::DuplicateFile(_) => { Code::DuplicateFile }
+        BuildError::MissingFonts => { Code::Internal }
+    };
+    code.error(problem)
+}
+
+/// The code of a failure, as jobs and event streams report it.
+pub fn code(problem: &Error) -> &'static str {
+    if let Some(failure) = problem.as_error::<Failure>() {
+        return failure.code.as_str();
+    }
+    match problem.as_error::<CompileError>() {
+        Some(failure) => { failure.code() }
+        None => { Code::fallback(Some(problem), problem.as_response_error().status_code()).as_str() }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for Failure {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.code.body(&self.message))
+    }
+}
+
+/// Middleware giving failures that aren't JSON yet the body of the module docs, with the
+/// code for their status, for `App::wrap_fn`.
+pub fn codes<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let call = service.call(request);
+    Box::pin(async move {
+        let response = call.await?;
+        let status = response.status();
+        let json = response.headers().get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+        if !(status.is_client_error() || status.is_server_error()) || json {
+            return Ok(response.map_into_left_body());
+        }
+
+        let problem = response.response().error();
+        let code = Code::fallback(problem, status);
+        let message = match problem {
+            Some(problem) => { problem.to_string() }
+            None => { status.canonical_reason().unwrap_or("Failed").to_string() }
+        };
+        let (request, plain) = response.into_parts();
+        let mut answer = HttpResponse::build(status).json(code.body(message));
+        for (name, value) in plain.headers() {
+            if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+                answer.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        Ok(ServiceResponse::new(request, answer).map_into_right_body())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use super::*;
+
+    /// A test per code, that it keeps its string, its status and both in its response.
+    macro_rules! codes {
+        ($($name:ident: $code:ident => $status:literal,)*) => {
+            $(
+                #[actix_web::test]
+                async fn $name() {
+                    let code = Code::$code;
+                    assert_eq!(code.as_str(), stringify!($name));
+                    assert_eq!(code.status().as_u16(), $status);
+
+                    let response = code.error("Something went wrong").error_response();
+                    assert_eq!(response.status().as_u16(), $status);
+                    let body: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+                    assert_eq!(body["error"], stringify!($name));
+                    assert_eq!(body["message"], "Something went wrong");
+                }
+            )*
+
+            #[test]
+            fn codes_are_distinct() {
+                let mut codes = vec![$(stringify!($name)),*];
+                let count = codes.len();
+                codes.sort_unstable();
+                codes.dedup();
+                assert_eq!(codes.len(), count);
+            }
+        };
+    }
+
+    codes! {
+        internal_error: Internal => 500,
+        invalid_multipart: InvalidMultipart => 400,
+        invalid_body: InvalidBody => 400,
+        invalid_json: InvalidJson => 400,
+        upload_timeout: UploadTimeout => 408,
+        missing_main: MissingMain => 400,
+        duplicate_file: DuplicateFile => 400,
+        invalid_filename: InvalidFilename => 400,
+        file_type_not_allowed: FileTypeNotAllowed => 400,
+        invalid_file_content: InvalidFileContent => 400,
+        file_too_large: FileTooLarge => 413,
+        upload_too_large: UploadTooLarge => 413,
+        too_many_files: TooManyFiles => 413,
+        invalid_query: InvalidQuery => 400,
+        invalid_option: InvalidOption => 400,
+        unknown_version: UnknownVersion => 400,
+        unknown_timezone: UnknownTimezone => 400,
+        priority_not_allowed: PriorityNotAllowed => 403,
+        unknown_upload: UnknownUpload => 400,
+        upload_incomplete: UploadIncomplete => 400,
+        upload_not_found: UploadNotFound => 404,
+        missing_upload_offset: MissingUploadOffset => 400,
+        upload_offset_mismatch: UploadOffsetMismatch => 409,
+        upload_complete: UploadComplete => 409,
+        upload_busy: UploadBusy => 409,
+        upload_overrun: UploadOverrun => 413,
+        invalid_digest: InvalidDigest => 400,
+        digest_mismatch: DigestMismatch => 400,
+        missing_blobs: MissingBlobs => 409,
+        invalid_callback_url: InvalidCallbackUrl => 400,
+        job_not_found: JobNotFound => 404,
+        job_not_succeeded: JobNotSucceeded => 409,
+        job_result_expired: JobResultExpired => 410,
+        job_interrupted: JobInterrupted => 503,
+        project_not_found: ProjectNotFound => 404,
+        project_expired: ProjectExpired => 410,
+        invalid_project_id: InvalidProjectId => 400,
+        project_busy: ProjectBusy => 409,
+        version_required: VersionRequired => 428,
+        version_mismatch: VersionMismatch => 412,
+        project_locked: ProjectLocked => 423,
+        file_not_found: FileNotFound => 404,
+        invalid_edit: InvalidEdit => 400,
+        invalid_base64: InvalidBase64 => 400,
+        revision_not_found: RevisionNotFound => 404,
+        analysis_failed: AnalysisFailed => 400,
+        documents_disabled: DocumentsDisabled => 404,
+        document_not_found: DocumentNotFound => 404,
+        document_failed: DocumentFailed => 500,
+        font_not_found: FontNotFound => 404,
+        label_not_found: LabelNotFound => 404,
+        ambiguous_label: AmbiguousLabel => 400,
+        element_split: ElementSplit => 422,
+        element_empty: ElementEmpty => 422,
+        api_key_required: ApiKeyRequired => 401,
+        invalid_api_key: InvalidApiKey => 403,
+        api_key_disabled: ApiKeyDisabled => 403,
+        api_key_expired: ApiKeyExpired => 403,
+        endpoint_not_allowed: EndpointNotAllowed => 403,
+        signature_required: SignatureRequired => 401,
+        signature_stale: SignatureStale => 401,
+        invalid_signature: InvalidSignature => 401,
+        length_required: LengthRequired => 411,
+        client_denied: ClientDenied => 403,
+        admin_disabled: AdminDisabled => 404,
+        invalid_admin_token: InvalidAdminToken => 401,
+        docs_disabled: DocsDisabled => 404,
+        ui_disabled: UiDisabled => 404,
+        package_not_cached: PackageNotCached => 404,
+        rate_limited: RateLimited => 429,
+        quota_exceeded: QuotaExceeded => 429,
+        server_busy: ServerBusy => 503,
+        client_busy: ClientBusy => 429,
+        invalid_parameter: InvalidParameter => 400,
+        bad_request: BadRequest => 400,
+        unauthorized: Unauthorized => 401,
+        forbidden: Forbidden => 403,
+        not_found: NotFound => 404,
+        method_not_allowed: MethodNotAllowed => 405,
+        not_acceptable: NotAcceptable => 406,
+        request_timeout: RequestTimeout => 408,
+        conflict: Conflict => 409,
+        payload_too_large: PayloadTooLarge => 413,
+        unsupported_media_type: UnsupportedMediaType => 415,
+        client_error: ClientError => 400,
+        server_error: ServerError => 500,
+    }
+}
@@ -0,0 +1,116 @@
+//! Strong `ETag`s on `/compile`, so gateways can cache its responses and revalidate them.
+//!
+//! The tag hashes what the compile cache key does, the files, `sys.inputs`, the options that
+//! shape the output, the tenant and the compiler version, with the pinned `date`, whether the
+//! response is JSON and whether warnings fail it. It is only sent for compiles known to give
+//! the same bytes again: without `date` the PDF is stamped with the time it was compiled at,
+//! and a document using `@local` packages changes with them rather than with the upload.
+//! A request whose `If-None-Match` has the tag its inputs hash to is answered with a 304
+//! without compiling.
+
+use actix_web::http::header::{EntityTag, Header, IfNoneMatch};
+use actix_web::HttpRequest;
+use crate::cache::CacheKey;
+use crate::compilers::CompileOptions;
+use crate::docker_world::{Compiled, DocumentFile};
+
+/// The tag a compile of `documents`, the main file first, would be sent with, if its output
+/// can be deterministic at all.
+pub fn of(version: &str, documents: &[DocumentFile], options: &CompileOptions) -> Option<EntityTag> {
+    options.now?;
+    let (main, files) = documents.split_first()?;
+    let key = CacheKey::new(version, main, files, options)?;
+    // Neither changes the document, so the cache key leaves them out, but both change the response.
+    let mode = match (options.include_dependencies, options.fail_on_warnings) {
+        (false, false) => { "pdf" }
+        (false, true) => { "pdf-strict" }
+        (true, false) => { "json" }
+        (true, true) => { "json-strict" }
+    };
+    Some(EntityTag::new_strong(format!("{}-{mode}", key.to_hex())))
+}
+
+/// `tag` if `compiled` is known to come out the same from the same inputs.
+pub fn deterministic(tag: Option<EntityTag>, compiled: &Compiled) -> Option<EntityTag> {
+    tag.filter(|_| compiled.cacheable)
+}
+
+/// Whether `If-None-Match` lists `tag`, so the client has the response already.
+pub fn matches(request: &HttpRequest, tag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(request) {
+        Ok(IfNoneMatch::Items(tags)) => { tags.iter().any(|listed| listed.weak_eq(tag)) }
+        Ok(IfNoneMatch::Any) | Err(_) => { false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::compilers::CompileOptions;
+    use crate::config::Config;
+    use crate::docker_world::{Compiled, Dependencies, DocumentFile};
+    use super::{deterministic, of};
+
+    fn documents(main: &[u8]) -> Vec<DocumentFile> {
+        vec![DocumentFile::new("main.typ", main.to_vec()), DocumentFile::new("data.csv", b"a,1\n".to_vec())]
+    }
+
+    /// The server's defaults with the time pinned to `at`.
+    fn pinned(at: i64) -> CompileOptions {
+        let mut options = Config::from_args(["typstapi"]).unwrap().default_options();
+        options.now = Some(Utc.timestamp_opt(at, 0).unwrap());
+        options
+    }
+
+    fn compiled(cacheable: bool) -> Compiled {
+        let dependencies = Dependencies::default();
+        Compiled { pdf: Vec::new(), pages: 1, page_hashes: Vec::new(), warnings: Vec::new(), dependencies, cacheable }
+    }
+
+    #[test]
+    fn the_same_inputs_get_the_same_tag() {
+        let tag = of("0.11.0", &documents(b"= A"), &pinned(1_700_000_000)).unwrap();
+        assert!(!tag.weak);
+        assert_eq!(of("0.11.0", &documents(b"= A"), &pinned(1_700_000_000)), Some(tag));
+    }
+
+    #[test]
+    fn any_other_input_gets_another_tag() {
+        let tag = of("0.11.0", &documents(b"= A"), &pinned(1_700_000_000)).unwrap();
+        let mut input = pinned(1_700_000_000);
+        input.inputs.insert("name".to_string(), "World".to_string());
+        let mut strict = pinned(1_700_000_000);
+        strict.fail_on_warnings = !strict.fail_on_warnings;
+        let mut json = pinned(1_700_000_000);
+        json.include_dependencies = true;
+        let mut tenant = pinned(1_700_000_000);
+        tenant.tenant = Some("finance".to_string());
+
+        let others = [
+            of("0.11.0", &documents(b"= A"), &pinned(1_700_000_001)),
+            of("0.11.0", &documents(b"= B"), &pinned(1_700_000_000)),
+            of("0.10.0", &documents(b"= A"), &pinned(1_700_000_000)),
+            of("0.11.0", &documents(b"= A"), &input),
+            of("0.11.0", &documents(b"= A"), &strict),
+            of("0.11.0", &documents(b"= A"), &json),
+            of("0.11.0", &documents(b"= A"), &tenant),
+        ];
+        for other in others {
+            assert_ne!(other.unwrap(), tag);
+        }
+    }
+
+    #[test]
+    fn a_compile_on_the_clock_gets_no_tag() {
+        let mut options = pinned(1_700_000_000);
+        options.now = None;
+        assert_eq!(of("0.11.0", &documents(b"#datetime.today().display()"), &options), None);
+    }
+
+    #[test]
+    fn a_compile_that_may_change_loses_its_tag() {
+        let tag = of("0.11.0", &documents(b"= A"), &pinned(1_700_000_000));
+        assert_eq!(deterministic(tag.clone(), &compiled(true)), tag);
+        assert_eq!(deterministic(tag, &compiled(false)), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Compiling with progress reported as server-sent events, for documents slow enough that a
+//! client wants to show how far they got.
+//!
+//! `POST /compile/events` takes the same upload and options as `/compile` and answers with a
+//! `text/event-stream`. Every event carries one line of JSON, in this order:
+//!
+//! - `received`: `{"files": 3, "bytes": 51234}` once the upload was read.
+//! - `fonts`: `{"faces": 412}` with the font faces the server knows.
+//! - `started`: `{}` once the compile got a slot, not sent for cached results.
+//! - `warning`: `{"category": "other", "message": "..."}` for each warning, once the document
+//!   was laid out.
+//! - `export`: `{}` when the PDF export started.
+//!
+//! The compiler bundled as the newest version sends all of them, isolated compiles and
+//! older versions skip `warning` and `export`. The stream ends with exactly one of
+//!
+//! - `result`: `{"version": "0.12.0", "pages": 2, "warnings": [...], "sha256": "...", "pdf": "<base64>"}`,
+//!   or with `"job"` and `"url"` naming where to fetch a PDF larger than `EVENTS_INLINE_KB`.
+//!   `sha256` is the PDF's, the same the job and the audit log keep.
+//! - `error`: `{"kind": "failed", "code": "compile_error", "message": "..."}`, the kind as in
+//!   the metrics and the code as in failed responses, or kind `error` with a `status` when the
+//!   compile didn't run, like for a full queue.
+//!
+//! Names and fields only ever get added to. A client disconnecting cancels the compile.
+
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::stream;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::AbortHandle;
+use crate::audit;
+use crate::cache::CompileCache;
+use crate::checksum::Checksum;
+use crate::compilers::{compile_blocking, Compilers, Progress, Stage, VersionQuery};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{Compiled, ErrorOutput, FontLibrary, Warning};
+use crate::errors;
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::multipart::read_documents;
+use crate::openapi::Upload;
+use crate::slots::{CompileSlots, Priority};
+
+#[derive(Serialize)]
+struct Finished<'a> {
+    version: &'static str,
+    pages: usize,
+    warnings: &'a [Warning],
+    /// Hex SHA-256 of the PDF.
+    sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pdf: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+fn event(name: &str, data: &impl Serialize) -> Bytes {
+    Bytes::from(format!("event: {name}\ndata: {}\n\n", serde_json::to_string(data).expect("events serialize")))
+}
+
+fn send(events: &UnboundedSender<Bytes>, name: &str, data: &impl Serialize) {
+    let _ = events.send(event(name, data));
+}
+
+/// Aborts the task compiling for a stream once the stream is dropped with its client.
+pub struct AbortOnDrop(pub AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Compile an upload, reporting progress as server-sent events, see the module docs.
+#[utoipa::path(
+    tag = "compile",
+    params(VersionQuery, OptionsQuery),
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The progress and outcome of the compile as server-sent events", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "The upload or the options are invalid", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+    ),
+)]
+#[post("/compile/events")]
+#[allow(clippy::too_many_arguments)]
+async fn compile_events(
+    request: HttpRequest,
+    payload: Multipart,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    fonts: web::Data<FontLibrary>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+    jobs: web::Data<Jobs>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    let mut options = config.compile_options(&request)?;
+    let documents = read_documents(&request, payload, &config).await?;
+    audit::inputs(&request, &documents);
+    let mut record = audit::detach(&request);
+
+    let (events, received) = mpsc::unbounded_channel();
+    let bytes: u64 = documents.iter().map(|file| file.data.size().unwrap_or(0)).sum();
+    send(&events, "received", &json!({ "files": documents.len(), "bytes": bytes }));
+    send(&events, "fonts", &json!({ "faces": fonts.current().count() }));
+    // Weak, so the stream ends with the final event even while a cancelled compile winds down.
+    let stages = events.downgrade();
+    options.progress = Progress::new(move |stage| {
+        let Some(stages) = stages.upgrade() else { return };
+        match stage {
+            Stage::Started => { send(&stages, "started", &json!({})) }
+            Stage::Warnings(warnings) => {
+                for warning in &warnings {
+                    send(&stages, "warning", warning);
+                }
+            }
+            Stage::Exporting => { send(&stages, "export", &json!({})) }
+        }
+    });
+
+    let (cache_max_age, inline_limit) = (config.cache_max_age, config.events_inline_limit);
+    let task = actix_web::rt::spawn(async move {
+        let (version, priority, format) = (compiler.version(), options.priority, options.output_format());
+        let owner = options.tenant.clone();
+        let compiled = compile_blocking(&slots, &metrics, &cache, compiler, documents, options, cache_max_age).await;
+        if let Some(record) = &mut record {
+            record.compiled(format, &compiled);
+        }
+        match compiled {
+            Ok(Ok(compiled)) => {
+                metrics.record_warnings(&compiled.warnings);
+                let finished = finish(&jobs, version, priority, owner, bytes, compiled, inline_limit).await;
+                let _ = events.send(finished);
+            }
+            Ok(Err(problem)) => {
+                send(&events, "error", &json!({ "kind": problem.kind(), "code": problem.code(), "message": problem.to_string() }))
+            }
+            Err(problem) => {
+                let status = problem.error_response().status().as_u16();
+                send(&events, "error", &json!({ "kind": "error", "status": status, "code": errors::code(&problem), "message": problem.to_string() }));
+            }
+        }
+    });
+
+    let body = stream::unfold((received, AbortOnDrop(task.abort_handle())), |(mut received, abort)| async move {
+        let event = received.recv().await?;
+        Some((Ok::<_, Error>(event), (received, abort)))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+/// The `result` event, keeping a PDF too large to inline as a job.
+async fn finish(
+    jobs: &web::Data<Jobs>,
+    version: &'static str,
+    priority: Priority,
+    owner: Option<String>,
+    input_size: u64,
+    compiled: Compiled,
+    inline_limit: usize,
+) -> Bytes {
+    let (pages, warnings, sha256) = (compiled.pages, compiled.warnings.clone(), Checksum::of(&compiled.pdf));
+    let mut finished = Finished { version, pages, warnings: &warnings, sha256: sha256.hex(), pdf: None, job: None, url: None };
+    match compiled.pdf.len() <= inline_limit {
+        true => { finished.pdf = Some(STANDARD.encode(&compiled.pdf)) }
+        false => {
+            let id = Jobs::keep(jobs, version, priority, owner, input_size, compiled, sha256).await;
+            finished.url = Some(format!("/jobs/{id}/result"));
+            finished.job = Some(id);
+        }
+    }
+    event("result", &finished)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(compile_events);
+}
@@ -0,0 +1,149 @@
+//! The example document, compiled with inputs from the query as a demo and smoke test, and
+//! `GET /hello/{name}`, which only greets.
+
+use std::collections::BTreeMap;
+use actix_web::http::header;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use crate::cache::CompileCache;
+use crate::checksum::Checksum;
+use crate::compilers::{compile_blocking, respond, run_compile, Compilers, JsonOutput, Paged, VersionQuery, CURRENT_VERSION, VERSION_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{DocumentFile, ErrorOutput, FontLibrary, PngExport, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+
+/// The document, built in so the server doesn't depend on its working directory.
+const EXAMPLE: &[u8] = include_bytes!("../example.typ");
+
+/// Most pages the example may be asked for.
+const MAX_PAGES: usize = 10;
+
+/// Longest name the example greets.
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExampleQuery {
+    /// Who the example greets.
+    name: Option<String>,
+    /// Pages to render, from 1 to 10.
+    pages: Option<usize>,
+    /// The page a PNG shows, counting from 1.
+    page: Option<usize>,
+}
+
+/// A page rendered as PNG.
+struct Png {
+    data: Vec<u8>,
+    pages: usize,
+}
+
+impl Paged for Png {
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// Whether the client prefers PNG, by the first output media type its `Accept` header lists.
+fn wants_png(request: &HttpRequest) -> bool {
+    let accept = request.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()).unwrap_or_default();
+    let preferred = accept.split(',')
+        .map(|media| media.split(';').next().unwrap_or_default().trim())
+        .find(|media| matches!(*media, "image/png" | "application/pdf" | "application/octet-stream" | "application/json"));
+    preferred == Some("image/png")
+}
+
+#[get("/hello/{name}")]
+async fn greet(name: web::Path<String>) -> impl Responder {
+    format!("Hello {name}!")
+}
+
+#[utoipa::path(
+    tag = "compile",
+    params(ExampleQuery, VersionQuery, OptionsQuery),
+    responses(
+        (status = 200, description = "The PDF, with `include=deps` the PDF and what the compile read, or with `Accept: image/png` one page as PNG", content(
+            ([u8] = "application/octet-stream"),
+            (JsonOutput = "application/json"),
+            ([u8] = "image/png"),
+        )),
+        (status = 400, description = "The example parameters are out of bounds", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits, or with `fail_on_warnings` compiled with warnings", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "The compile timed out", body = TimeoutOutput),
+    ),
+)]
+#[get("/hello_typst.pdf")]
+#[allow(clippy::too_many_arguments)]
+async fn typst_example(
+    request: HttpRequest,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    let mut options = config.compile_options(&request)?;
+    let query = web::Query::<ExampleQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?
+        .into_inner();
+    let name = query.name.unwrap_or_else(|| "World".into());
+    if name.chars().count() > MAX_NAME_LENGTH || name.chars().any(char::is_control) {
+        return Err(Code::InvalidParameter.error(format!("name must be up to {MAX_NAME_LENGTH} printable characters")));
+    }
+    let pages = query.pages.unwrap_or(1);
+    if !(1..=MAX_PAGES).contains(&pages) {
+        return Err(Code::InvalidParameter.error(format!("pages must be from 1 to {MAX_PAGES}")));
+    }
+    options.inputs = BTreeMap::from([("name".into(), name), ("pages".into(), pages.to_string())]);
+
+    let example = DocumentFile::new("example.typ", EXAMPLE.to_vec());
+
+    if !wants_png(&request) {
+        let compiled = compile_blocking(
+            &slots,
+            &metrics,
+            &cache,
+            compiler.clone(),
+            vec! [example],
+            options.clone(),
+            config.cache_max_age,
+        ).await?;
+        return respond(compiler.version(), compiled, &options, &metrics);
+    }
+
+    if compiler.version() != CURRENT_VERSION {
+        return Err(Code::InvalidParameter.error(format!("PNG output needs typst {CURRENT_VERSION}")));
+    }
+    let page = query.page.unwrap_or(1);
+    if !(1..=pages).contains(&page) {
+        return Err(Code::InvalidParameter.error(format!("page must be from 1 to {pages}")));
+    }
+    let world = options.world().main(example).font_db(fonts.current()).packages(packages.into_inner());
+    let (max_pages, cache_max_age) = (options.max_pages, config.cache_max_age);
+    let png = run_compile(&slots, &metrics, options.timeout, options.priority, "png", move |cancellation| {
+        let mut world = world.build()?;
+        let document = world.typeset(max_pages, cancellation)?;
+        let data = document.to_png(&PngExport { page, ..PngExport::default() })?;
+        comemo::evict(cache_max_age);
+        Ok(Png { data, pages: document.pages() })
+    }).await??;
+
+    let mut response = HttpResponse::Ok();
+    Checksum::of(&png.data).insert(&mut response);
+    Ok(response
+        .insert_header((VERSION_HEADER, CURRENT_VERSION))
+        .content_type("image/png")
+        .body(png.data))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(greet).service(typst_example);
+}
@@ -0,0 +1,298 @@
+//! `POST /compile/exports`, several outputs of a document from one compile, in a zip.
+//!
+//! It takes the same upload and options as `/compile` and `?formats=` listing the outputs,
+//! separated by commas, each a format with its options after colons, like
+//! `pdf,png:page=1:ppi=96,svg:page=2`. `pdf` takes the request's options like `/compile`
+//! does, `svg` a `page`, and `png` a `page` and `ppi`, page 1 and 144 ppi by default. The
+//! document is laid out once and exported to each in turn, into `document.pdf`, `page-1.png`
+//! and so on. Errors laying out the document are answered like for `/compile`.
+//!
+//! An export that fails, like one of a page the document doesn't have, doesn't fail the
+//! others. The zip's `manifest.json` says how each went, the file it is in or why it has
+//! none, and how long it took. `Server-Timing` has the same times, laying out first.
+
+use std::time::{Duration, Instant};
+use actix_web::http::header;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::archive::ZipWriter;
+use crate::audit;
+use crate::checksum::Checksum;
+use crate::compilers::{run_compile, CompileOptions, Compilers, Paged, VersionQuery, CURRENT_VERSION, VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{CompileError, CompiledDocument, ErrorOutput, FontLibrary, PngExport, SvgExport, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::multipart::{read_submission, JsonUpload, Submission};
+use crate::openapi::{SourceForm, Upload};
+use crate::optimize;
+use crate::packages::PackageStore;
+use crate::pages::MAX_PPI;
+use crate::slots::CompileSlots;
+
+/// Most outputs a request may ask for.
+const MAX_EXPORTS: usize = 16;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportsQuery {
+    /// The outputs, like `pdf,png:page=1:ppi=96,svg:page=2`.
+    formats: String,
+}
+
+/// An output a request asked for.
+#[derive(Clone, Copy)]
+enum Export {
+    Pdf,
+    Svg(SvgExport),
+    Png(PngExport),
+}
+
+impl Export {
+    fn format(self) -> &'static str {
+        match self {
+            Export::Pdf => { "pdf" }
+            Export::Svg(_) => { "svg" }
+            Export::Png(_) => { "png" }
+        }
+    }
+
+    /// The entry the output is written to.
+    fn file(self) -> String {
+        match self {
+            Export::Pdf => { "document.pdf".to_string() }
+            Export::Svg(svg) => { format!("page-{}.svg", svg.page) }
+            Export::Png(png) => { format!("page-{}.png", png.page) }
+        }
+    }
+}
+
+/// How the exports went, the zip's `manifest.json`.
+#[derive(Serialize, ToSchema)]
+pub struct Manifest {
+    /// Pages the document laid out to.
+    pages: usize,
+    /// How long laying the document out took.
+    typeset_milliseconds: f64,
+    /// The outputs in the order `formats` lists them.
+    exports: Vec<ExportReport>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ExportReport {
+    format: &'static str,
+    /// The entry holding the output, unless it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    /// Why the output couldn't be exported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    milliseconds: f64,
+}
+
+/// What the compile exported.
+struct Exported {
+    zip: Vec<u8>,
+    checksum: Checksum,
+    manifest: Manifest,
+    warnings: usize,
+}
+
+impl Paged for Exported {
+    fn pages(&self) -> usize {
+        self.manifest.pages
+    }
+}
+
+/// Compile an upload once to several outputs, see the module docs.
+#[utoipa::path(
+    tag = "compile",
+    params(ExportsQuery, VersionQuery, OptionsQuery),
+    request_body(content(
+        (Upload = "multipart/form-data"),
+        (SourceForm = "application/x-www-form-urlencoded"),
+        (JsonUpload = "application/json"),
+        (String = "text/plain"),
+        ([u8] = "application/gzip"),
+    )),
+    responses(
+        (status = 200, description = "A zip with a file per output that succeeded and `manifest.json` saying how each went", content_type = "application/zip", body = [u8]),
+        (status = 400, description = "The upload, the options or `formats` are invalid, or the document has errors", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 415, description = "The body is neither multipart, a form, JSON, a tar archive nor a document", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "Laying out or exporting the document timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/compile/exports")]
+#[allow(clippy::too_many_arguments)]
+async fn compile_exports(
+    request: HttpRequest,
+    payload: web::Payload,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    if compiler.version() != CURRENT_VERSION {
+        return Err(Code::InvalidParameter.error(format!("Exporting several formats needs typst {CURRENT_VERSION}")));
+    }
+    let mut options = config.compile_options(&request)?;
+    let query = web::Query::<ExportsQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let exports = parse(&query.formats)?;
+
+    let Submission { mut documents, inputs, warnings } = read_submission(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    audit::inputs(&request, &documents);
+    let mut record = audit::detach(&request);
+    options.inputs.extend(inputs);
+    let world = options.world()
+        .main(documents.remove(0))
+        .files(documents)
+        .font_db(fonts.current())
+        .packages(packages.into_inner());
+
+    let (max_pages, cache_max_age) = (options.max_pages, config.cache_max_age);
+    let export_options = options.clone();
+    let exported = run_compile(&slots, &metrics, options.timeout, options.priority, "exports", move |cancellation| {
+        let mut world = world.build()?;
+        let started = Instant::now();
+        let document = world.typeset(max_pages, cancellation.clone())?;
+        let typeset = started.elapsed();
+        let mut archive = ZipWriter::new(export_options.now.unwrap_or_else(Utc::now));
+        let mut zip = Vec::new();
+        let mut reports = Vec::new();
+        for export in &exports {
+            if cancellation.is_cancelled() {
+                return Err(CompileError::TimedOut { after: export_options.timeout });
+            }
+            let started = Instant::now();
+            let exported = render(&document, *export, &export_options);
+            let elapsed = milliseconds(started.elapsed());
+            let report = match exported {
+                Ok(data) => {
+                    let file = export.file();
+                    // PNGs are compressed already, and so are a PDF's streams.
+                    let entry = archive.entry(&file, &data, matches!(export, Export::Svg(_)))
+                        .ok_or_else(|| CompileError::Failed("The outputs are larger than a zip holds".into()))?;
+                    zip.extend(entry);
+                    ExportReport { format: export.format(), file: Some(file), error: None, milliseconds: elapsed }
+                }
+                Err(problem) => {
+                    ExportReport { format: export.format(), file: None, error: Some(problem), milliseconds: elapsed }
+                }
+            };
+            reports.push(report);
+        }
+        let manifest = Manifest { pages: document.pages(), typeset_milliseconds: milliseconds(typeset), exports: reports };
+        let json = serde_json::to_vec_pretty(&manifest).expect("the manifest serializes");
+        let entry = archive.entry(MANIFEST_ENTRY, &json, true)
+            .ok_or_else(|| CompileError::Failed("The outputs are larger than a zip holds".into()))?;
+        zip.extend(entry);
+        zip.extend(archive.finish());
+        let exported = Exported { checksum: Checksum::of(&zip), zip, manifest, warnings: document.warnings.len() };
+        comemo::evict(cache_max_age);
+        Ok(exported)
+    }).await;
+    if let Some(record) = &mut record {
+        match &exported {
+            Ok(Ok(done)) => { record.streamed("exports", &exported, done.checksum) }
+            _ => { record.finished("exports", &exported) }
+        }
+    }
+
+    let exported = exported??;
+    let mut response = HttpResponse::Ok();
+    exported.checksum.insert(&mut response);
+    Ok(response
+        .insert_header((VERSION_HEADER, CURRENT_VERSION))
+        .insert_header((WARNING_COUNT_HEADER, (exported.warnings + warnings.len()).to_string()))
+        .insert_header(("Server-Timing", server_timing(&exported.manifest)))
+        .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"exports.zip\""))
+        .content_type("application/zip")
+        .body(exported.zip))
+}
+
+/// The outputs `formats` lists.
+fn parse(formats: &str) -> Result<Vec<Export>, Error> {
+    let mut exports: Vec<Export> = Vec::new();
+    for spec in formats.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let mut parts = spec.split(':');
+        let format = parts.next().unwrap_or_default();
+        let mut export = match format {
+            "pdf" => { Export::Pdf }
+            "svg" => { Export::Svg(SvgExport { page: 1 }) }
+            "png" => { Export::Png(PngExport::default()) }
+            _ => { return Err(Code::InvalidParameter.error(format!("Unknown format {format}, use pdf, svg or png"))) }
+        };
+        for option in parts {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| Code::InvalidParameter.error(format!("{spec}: options are written key=value")))?;
+            let invalid = || Code::InvalidParameter.error(format!("{spec}: {value} is no valid {key}"));
+            match (&mut export, key) {
+                (Export::Svg(SvgExport { page }) | Export::Png(PngExport { page, .. }), "page") => {
+                    *page = value.parse().ok().filter(|&page| page > 0).ok_or_else(invalid)?;
+                }
+                (Export::Png(png), "ppi") => {
+                    png.ppi = value.parse().ok().filter(|ppi| (1.0..=MAX_PPI).contains(ppi)).ok_or_else(invalid)?;
+                }
+                _ => { return Err(Code::InvalidParameter.error(format!("{spec}: {format} has no option {key}"))) }
+            }
+        }
+        if exports.iter().any(|other| other.file() == export.file()) {
+            return Err(Code::InvalidParameter.error(format!("{} is asked for twice", export.file())));
+        }
+        exports.push(export);
+    }
+    match exports.len() {
+        0 => { Err(Code::InvalidParameter.error("Ask for at least one format")) }
+        count if count > MAX_EXPORTS => { Err(Code::InvalidParameter.error(format!("Ask for at most {MAX_EXPORTS} formats"))) }
+        _ => { Ok(exports) }
+    }
+}
+
+/// `document` as `export` asks, or why it can't be.
+fn render(document: &CompiledDocument, export: Export, options: &CompileOptions) -> Result<Vec<u8>, String> {
+    let _span = tracing::info_span!("export", format = export.format()).entered();
+    let rendered = match export {
+        Export::Pdf => {
+            document.to_pdf(&options.pdf_export()).map(|pdf| match &options.optimizer {
+                Some(qpdf) => { optimize::optimize(pdf, qpdf) }
+                None => { pdf }
+            })
+        }
+        Export::Svg(svg) => { document.to_svg(&svg).map(String::into_bytes) }
+        Export::Png(png) => { document.to_png(&png) }
+    };
+    rendered.map_err(|problem| problem.to_string())
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The times of `manifest` as `Server-Timing` has them, laying out and then each export.
+fn server_timing(manifest: &Manifest) -> String {
+    let exports = manifest.exports.iter().enumerate().map(|(index, export)| {
+        format!("{}-{};dur={:.1}", export.format, index + 1, export.milliseconds)
+    });
+    std::iter::once(format!("typeset;dur={:.1}", manifest.typeset_milliseconds))
+        .chain(exports)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(compile_exports);
+}
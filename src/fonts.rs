@@ -0,0 +1,101 @@
+//! `/fonts/coverage`, which characters of a text the server's fonts have glyphs for, to tell
+//! whether a font suits a language before a template uses it.
+//!
+//! `GET` takes the text as `text=`, `POST` a longer sample as the body. With `family=` the
+//! answer has that family's faces, each with the percentage of the text's characters it
+//! covers and those it doesn't. Without it every family is checked and the `limit` covering
+//! most of the text come first. What counts as a character is in `docker_world::coverage`.
+
+use actix_web::{get, post, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::docker_world::{ErrorOutput, FamilyCoverage, FontLibrary};
+use crate::errors::Code;
+
+/// Families answered unless `limit` says.
+const DEFAULT_LIMIT: usize = 10;
+
+/// Most families a request may ask for.
+const MAX_LIMIT: usize = 100;
+
+/// Bytes a sample sent with `POST` may have.
+const MAX_SAMPLE_SIZE: usize = 256 * 1024;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CoverageQuery {
+    /// The family to check, ignoring case, or every family if left out.
+    family: Option<String>,
+    /// The text to check for, with `GET`.
+    text: Option<String>,
+    /// Families answered without `family`, 10 by default and at most 100.
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Coverage {
+    /// The family asked for, or the best candidates, the best first.
+    families: Vec<FamilyCoverage>,
+}
+
+/// How the fonts cover `text=`, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    params(CoverageQuery),
+    responses(
+        (status = 200, description = "How the family or the best candidates cover the text", body = Coverage),
+        (status = 400, description = "`text` is missing or `limit` is out of range", body = ErrorOutput),
+        (status = 404, description = "No family of that name was found", body = ErrorOutput),
+    ),
+)]
+#[get("/fonts/coverage")]
+async fn coverage(query: web::Query<CoverageQuery>, fonts: web::Data<FontLibrary>) -> Result<HttpResponse, Error> {
+    let mut query = query.into_inner();
+    let text = query.text.take().ok_or_else(|| Code::InvalidParameter.error("Send the text to check as text="))?;
+    respond(query, text, &fonts).await
+}
+
+/// How the fonts cover the sample in the body, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    params(CoverageQuery),
+    request_body(content = String, content_type = "text/plain", description = "The text to check for"),
+    responses(
+        (status = 200, description = "How the family or the best candidates cover the text", body = Coverage),
+        (status = 400, description = "The body isn't UTF-8 or `limit` is out of range", body = ErrorOutput),
+        (status = 404, description = "No family of that name was found", body = ErrorOutput),
+        (status = 413, description = "The sample is larger than 256 KiB", body = ErrorOutput),
+    ),
+)]
+#[post("/fonts/coverage")]
+async fn sample_coverage(
+    query: web::Query<CoverageQuery>,
+    body: web::Bytes,
+    fonts: web::Data<FontLibrary>,
+) -> Result<HttpResponse, Error> {
+    if body.len() > MAX_SAMPLE_SIZE {
+        return Err(Code::FileTooLarge.error(format!("The sample is larger than {MAX_SAMPLE_SIZE} bytes")));
+    }
+    let text = String::from_utf8(body.to_vec()).map_err(|_| Code::InvalidBody.error("The sample isn't UTF-8"))?;
+    respond(query.into_inner(), text, &fonts).await
+}
+
+async fn respond(query: CoverageQuery, text: String, fonts: &FontLibrary) -> Result<HttpResponse, Error> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(Code::InvalidParameter.error(format!("limit must be from 1 to {MAX_LIMIT}")));
+    }
+    let fonts = fonts.current();
+    let families = match query.family {
+        Some(family) => {
+            let coverage = web::block(move || fonts.coverage(&family, &text).ok_or(family)).await?;
+            vec![coverage.map_err(|family| Code::FontNotFound.error(format!("No font family {family} was found")))?]
+        }
+        None => { web::block(move || fonts.best_coverage(&text, limit)).await? }
+    };
+    Ok(HttpResponse::Ok().json(Coverage { families }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(coverage).service(sample_coverage);
+}
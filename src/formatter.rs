@@ -0,0 +1,445 @@
+//! `POST /format`, a typst source file formatted consistently.
+//!
+//! The file comes as the request body or as the only part of a multipart upload. Code is
+//! spaced one way, `f(a, b: 1)`, `x + 1`, `let x = 1`, and lines inside parentheses and
+//! braces spanning several are indented two spaces past the line they opened on. Markup
+//! keeps its lines and their indentation, which nests list items, and only loses trailing
+//! whitespace, runs of spaces and blank lines beyond one. Markup lines longer than `width`
+//! are broken at a space, though never inside headings, list items and equations, nor
+//! where the next line would start a list or heading. Lines are never joined, math and raw
+//! text are left as they are.
+//!
+//! Files with syntax errors are answered unchanged with `formatted: false` and the errors.
+//! A result that would change if formatted again, or doesn't parse, is never sent: the
+//! original is, with `formatted: false` and no errors.
+
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use typst::syntax::{Source, SyntaxKind, SyntaxNode};
+use utoipa::{IntoParams, ToSchema};
+use crate::config::CurrentConfig;
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::multipart::read_source;
+use crate::openapi::Upload;
+
+const DEFAULT_WIDTH: usize = 80;
+
+const WIDTHS: std::ops::RangeInclusive<usize> = 20..=400;
+
+/// Nodes whose first leaf opens a bracket the lines inside are indented from.
+const GROUPS: [SyntaxKind; 7] = [
+    SyntaxKind::CodeBlock,
+    SyntaxKind::Args,
+    SyntaxKind::Array,
+    SyntaxKind::Dict,
+    SyntaxKind::Params,
+    SyntaxKind::Parenthesized,
+    SyntaxKind::Destructuring,
+];
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FormatQuery {
+    /// Column markup lines are broken before, 80 by default, from 20 to 400.
+    width: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Formatted {
+    /// Whether `source` is the formatted file rather than the one sent.
+    formatted: bool,
+    source: String,
+    /// The syntax errors that kept the file from being formatted, as `line:column: message`.
+    errors: Vec<String>,
+}
+
+/// Where in the tree a leaf is.
+#[derive(Clone, Copy, Default)]
+struct Context {
+    /// The innermost bracketed node, counted in the order they open.
+    group: Option<usize>,
+    /// Whether markup spaces in here may become line breaks.
+    prose: bool,
+    math: bool,
+    /// Inside raw text, copied as it is.
+    verbatim: bool,
+}
+
+/// How a leaf is spaced from its neighbours.
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Plain,
+    /// The opening bracket of the group.
+    Opens(usize),
+    /// An operator with a space on either side.
+    Spaced,
+    /// A comma or colon, right after what it follows and with a space after it.
+    Separator,
+}
+
+struct Leaf<'a> {
+    kind: SyntaxKind,
+    text: &'a str,
+    parent: SyntaxKind,
+    role: Role,
+    context: Context,
+}
+
+/// The formatted text so far.
+struct Output {
+    text: String,
+    width: usize,
+    /// Byte offsets of the spaces on the current line it may be broken at.
+    breaks: Vec<usize>,
+}
+
+/// Format a typst file, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    params(FormatQuery),
+    request_body(
+        content((String = "text/plain"), (Upload = "multipart/form-data")),
+        description = "The file as the body, or as the only part of an upload",
+    ),
+    responses(
+        (status = 200, description = "The formatted file, or the one sent if it couldn't be formatted", body = Formatted),
+        (status = 400, description = "The width is out of range or the file isn't UTF-8", body = ErrorOutput),
+        (status = 413, description = "The file is larger than `MAX_FILE_SIZE`, or more than one was uploaded", body = ErrorOutput),
+    ),
+)]
+#[post("/format")]
+async fn format(request: HttpRequest, payload: web::Payload, config: CurrentConfig) -> Result<HttpResponse, Error> {
+    let query = web::Query::<FormatQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let width = query.width.unwrap_or(DEFAULT_WIDTH);
+    if !WIDTHS.contains(&width) {
+        return Err(Code::InvalidOption.error(format!("width must be from {} to {}", WIDTHS.start(), WIDTHS.end())));
+    }
+
+    let text = read_source(&request, payload, &config).await?;
+    let formatted = web::block(move || format_source(text, width)).await?;
+    Ok(HttpResponse::Ok().json(formatted))
+}
+
+fn format_source(text: String, width: usize) -> Formatted {
+    let source = Source::detached(text);
+    let errors = source.root().errors();
+    if !errors.is_empty() {
+        let errors = errors.iter()
+            .map(|error| {
+                let start = source.range(error.span).map_or(0, |range| range.start);
+                let line = source.byte_to_line(start).unwrap_or(0) + 1;
+                let column = source.byte_to_column(start).unwrap_or(0) + 1;
+                format!("{line}:{column}: {}", error.message)
+            })
+            .collect();
+        return Formatted { formatted: false, source: source.text().to_string(), errors };
+    }
+
+    let formatted = layout(source.root(), width);
+    let again = typst::syntax::parse(&formatted);
+    if again.erroneous() || layout(&again, width) != formatted {
+        tracing::warn!("formatting a file gave a result that isn't stable, the file is sent back unchanged");
+        return Formatted { formatted: false, source: source.text().to_string(), errors: Vec::new() };
+    }
+    Formatted { formatted: true, source: formatted, errors: Vec::new() }
+}
+
+/// The text of `root` formatted, see the module docs.
+fn layout(root: &SyntaxNode, width: usize) -> String {
+    let mut leaves = Vec::new();
+    let mut groups = 0;
+    collect(root, Context { prose: true, ..Context::default() }, &mut groups, &mut leaves);
+
+    let mut out = Output { text: String::new(), width, breaks: Vec::new() };
+    let mut bases = vec![None; groups];
+    for (index, leaf) in leaves.iter().enumerate() {
+        let prev = index.checked_sub(1).map(|prev| &leaves[prev]);
+        let next = leaves.get(index + 1);
+        if leaf.context.verbatim {
+            out.push(leaf.text);
+            continue;
+        }
+        match (leaf.kind, leaf.role) {
+            (SyntaxKind::Space | SyntaxKind::Parbreak, _) => { space(&mut out, leaf, index == 0, prev, next, &bases) }
+            (SyntaxKind::LineComment, _) => { out.push(leaf.text.trim_end()) }
+            (_, Role::Opens(group)) => {
+                bases[group] = Some(out.indent().to_string());
+                out.push(leaf.text);
+            }
+            (_, Role::Spaced) => {
+                if prev.is_some_and(|prev| !is_space(prev)) {
+                    out.push(" ");
+                }
+                out.push(leaf.text);
+                if next.is_some_and(|next| !is_space(next)) {
+                    out.push(" ");
+                }
+            }
+            (_, Role::Separator) => {
+                out.push(leaf.text);
+                if next.is_some_and(|next| !is_space(next) && next.kind != SyntaxKind::RightParen) {
+                    out.push(" ");
+                }
+            }
+            (_, Role::Plain) => { out.push(leaf.text) }
+        }
+    }
+
+    let mut text = out.text;
+    text.truncate(text.trim_end().len());
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text
+}
+
+/// The leaves under `node` in the order of the text, each with where it is.
+fn collect<'a>(node: &'a SyntaxNode, mut context: Context, groups: &mut usize, leaves: &mut Vec<Leaf<'a>>) {
+    let kind = node.kind();
+    match kind {
+        SyntaxKind::Markup => { context.group = None }
+        SyntaxKind::Equation => {
+            context.math = true;
+            context.prose = false;
+        }
+        SyntaxKind::Raw => { context.verbatim = true }
+        SyntaxKind::Heading | SyntaxKind::ListItem | SyntaxKind::EnumItem | SyntaxKind::TermItem | SyntaxKind::Link => {
+            context.prose = false;
+        }
+        _ if GROUPS.contains(&kind) => {
+            context.group = Some(*groups);
+            *groups += 1;
+        }
+        _ => {}
+    }
+
+    // The operator of a binary expression is what's between its operands.
+    let operands: Vec<_> = node.children().enumerate().filter(|(_, child)| !child.kind().is_trivia()).map(|(at, _)| at).collect();
+    let (first, last) = (operands.first().copied(), operands.last().copied());
+    for (at, child) in node.children().enumerate() {
+        if child.children().next().is_some() {
+            collect(child, context, groups, leaves);
+            continue;
+        }
+        if child.text().is_empty() {
+            continue;
+        }
+        let role = match (kind, child.kind()) {
+            _ if context.math || context.verbatim => { Role::Plain }
+            (_, SyntaxKind::LeftParen | SyntaxKind::LeftBrace) if at == 0 && GROUPS.contains(&kind) => {
+                Role::Opens(context.group.expect("groups number themselves"))
+            }
+            (SyntaxKind::Binary, operator) if !operator.is_trivia() && Some(at) != first && Some(at) != last => { Role::Spaced }
+            (SyntaxKind::LetBinding | SyntaxKind::DestructAssignment | SyntaxKind::Closure, SyntaxKind::Eq | SyntaxKind::Arrow) => {
+                Role::Spaced
+            }
+            (_, SyntaxKind::Comma) | (SyntaxKind::Named | SyntaxKind::Keyed | SyntaxKind::ShowRule, SyntaxKind::Colon) => {
+                Role::Separator
+            }
+            _ => { Role::Plain }
+        };
+        leaves.push(Leaf { kind: child.kind(), text: child.text().as_str(), parent: kind, role, context });
+    }
+}
+
+/// Emit whitespace: line breaks with the indentation their position calls for, spaces
+/// collapsed to one or dropped next to brackets and separators.
+fn space(out: &mut Output, leaf: &Leaf, first: bool, prev: Option<&Leaf>, next: Option<&Leaf>, bases: &[Option<String>]) {
+    if let Some(last) = leaf.text.rfind('\n') {
+        let kept = &leaf.text[last + 1..];
+        let base = leaf.context.group.filter(|_| !leaf.context.math).and_then(|group| bases[group].as_deref());
+        let indent = match base {
+            Some(base) if next.is_some_and(closes) => { base.to_string() }
+            Some(base) => { format!("{base}  ") }
+            None => { kept.to_string() }
+        };
+        let newlines = leaf.text.matches('\n').count().min(2);
+        out.push(&format!("{}{indent}", "\n".repeat(newlines)));
+        return;
+    }
+
+    match leaf.parent {
+        _ if leaf.context.math || first => { out.push(leaf.text) }
+        SyntaxKind::Markup => {
+            match leaf.context.prose && prev.is_some_and(|prev| !wide(prev.text.chars().last())) && next.is_some_and(starts_line) {
+                true => { out.candidate() }
+                false => { out.push(" ") }
+            }
+        }
+        _ => {
+            let tight = prev.is_some_and(|prev| prev.kind == SyntaxKind::LeftParen)
+                || next.is_some_and(|next| next.kind == SyntaxKind::RightParen || next.role == Role::Separator);
+            if !tight {
+                out.push(" ");
+            }
+        }
+    }
+}
+
+fn is_space(leaf: &Leaf) -> bool {
+    matches!(leaf.kind, SyntaxKind::Space | SyntaxKind::Parbreak)
+}
+
+fn closes(leaf: &Leaf) -> bool {
+    matches!(leaf.kind, SyntaxKind::RightParen | SyntaxKind::RightBrace | SyntaxKind::RightBracket)
+}
+
+/// Whether a markup line may start with `leaf`, rather than it starting a list, an enum,
+/// a term or a heading there, or being a label attaching elsewhere.
+fn starts_line(leaf: &Leaf) -> bool {
+    let after_digits = leaf.text.trim_start_matches(|c: char| c.is_ascii_digit());
+    leaf.kind != SyntaxKind::Label
+        && !leaf.text.starts_with(['-', '+', '=', '/'])
+        && !(after_digits.len() < leaf.text.len() && after_digits.starts_with('.'))
+        && !wide(leaf.text.chars().next())
+}
+
+/// Whether `c` is CJK, where typst treats line breaks unlike spaces.
+fn wide(c: Option<char>) -> bool {
+    c.is_some_and(|c| matches!(c, '\u{2E80}'..='\u{9FFF}' | '\u{AC00}'..='\u{D7AF}' | '\u{F900}'..='\u{FAFF}' | '\u{FF00}'..='\u{FFEF}'))
+}
+
+impl Output {
+    fn line_start(&self) -> usize {
+        self.text.rfind('\n').map_or(0, |at| at + 1)
+    }
+
+    /// The leading whitespace of the current line.
+    fn indent(&self) -> &str {
+        let line = &self.text[self.line_start()..];
+        &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+    }
+
+    /// Append `text`, breaking the line at its last space that may be once it gets too long.
+    fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+        if text.contains('\n') {
+            self.breaks.clear();
+        }
+        if self.breaks.is_empty() || self.text[self.line_start()..].chars().count() <= self.width {
+            return;
+        }
+        if let Some(at) = self.breaks.pop() {
+            let indent = format!("\n{}", self.indent());
+            self.text.replace_range(at..at + 1, &indent);
+            self.breaks.clear();
+        }
+    }
+
+    /// Append a space the line may be broken at.
+    fn candidate(&mut self) {
+        self.breaks.push(self.text.len());
+        self.push(" ");
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(format);
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use typst::syntax::parse;
+    use super::{format_source, layout, DEFAULT_WIDTH};
+
+    /// Documents like those sent to `/format`, each using different parts of the language.
+    const CORPUS: [&str; 8] = [
+        "= Introduction\nSome text   with  runs of spaces.   \n\n\n\nA second paragraph.\n",
+        "#let total(a,b:1)=a+b\n#let (x,y)=(1,2)\n#total(x , b:y)\n",
+        "#set page(width:10cm,\n  height: auto)\n#show heading:it=>{\n    set text(red)\n  it\n}\n",
+        "- first\n  - nested\n- second\n+ numbered\n/ Term: its description\n",
+        "#figure(\n  image(\"logo.png\"),\n      caption: [The logo],\n)\n",
+        "Math stays $a+b = c$ and\n$ sum_(i=1)^n i=(n(n+1))/2 $\n",
+        "```rust\nfn   main() {  }\n```\n// a comment   \n#{\nlet x=(a:1,b:(2,3))\n    x.at(\"a\")\n}\n",
+        "A paragraph long enough that the formatter has to break it somewhere before the eightieth column, and then once more.\n",
+    ];
+
+    /// Lines the generated documents are built of.
+    const LINES: [&str; 14] = [
+        "= A heading",
+        "== A heading with more words than fit in a narrow line",
+        "Plain text with   several   words, running on for a while so that it wraps.",
+        "- a list item",
+        "  - a nested item",
+        "+ an enumerated item",
+        "#let f(x,y:2)=x*y+1",
+        "#f(1,y:3)",
+        "#set text(size:11pt,lang:\"en\")",
+        "#{ let a=(1,2,3); a.map(x=>x+1) }",
+        "$ x^2+y^2=z^2 $",
+        "`inline raw   text`",
+        "// comment with trailing spaces   ",
+        "",
+    ];
+
+    /// `text` without any whitespace, which formatting must not change.
+    fn visible(text: &str) -> String {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    fn formats_stably(text: &str, width: usize) -> Result<(), TestCaseError> {
+        let once = layout(&parse(text), width);
+        let reparsed = parse(&once);
+        prop_assert!(!reparsed.erroneous(), "the formatted file doesn't parse:\n{once}");
+        let twice = layout(&reparsed, width);
+        prop_assert_eq!(&twice, &once, "formatting again changed the file");
+        prop_assert_eq!(visible(&once), visible(text), "formatting changed more than whitespace");
+        Ok(())
+    }
+
+    #[test]
+    fn the_corpus_formats_stably() {
+        for text in CORPUS {
+            for width in [20, DEFAULT_WIDTH, 400] {
+                formats_stably(text, width).unwrap_or_else(|problem| panic!("{problem} for {text:?} at {width}"));
+            }
+        }
+    }
+
+    #[test]
+    fn the_corpus_is_sent_formatted() {
+        for text in CORPUS {
+            let formatted = format_source(text.to_string(), DEFAULT_WIDTH);
+            assert!(formatted.formatted, "{text:?}");
+            assert!(formatted.errors.is_empty());
+            assert_eq!(format_source(formatted.source.clone(), DEFAULT_WIDTH).source, formatted.source);
+        }
+    }
+
+    #[test]
+    fn formats_code_and_markup() {
+        let formatted = format_source(CORPUS[1].to_string(), DEFAULT_WIDTH);
+        assert_eq!(formatted.source, "#let total(a, b: 1) = a + b\n#let (x, y) = (1, 2)\n#total(x, b: y)\n");
+        let formatted = format_source(CORPUS[0].to_string(), DEFAULT_WIDTH);
+        assert_eq!(formatted.source, "= Introduction\nSome text with runs of spaces.\n\nA second paragraph.\n");
+    }
+
+    #[test]
+    fn a_file_with_errors_comes_back_unchanged() {
+        let formatted = format_source("#let x = (1,\n".to_string(), DEFAULT_WIDTH);
+        assert!(!formatted.formatted);
+        assert_eq!(formatted.source, "#let x = (1,\n");
+        assert!(formatted.errors.iter().all(|error| error.starts_with("1:") || error.starts_with("2:")));
+        assert!(!formatted.errors.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn generated_documents_format_stably(
+            lines in prop::collection::vec(prop::sample::select(LINES.to_vec()), 1..16),
+            width in 20usize..=120,
+        ) {
+            let text = lines.join("\n");
+            prop_assume!(!parse(&text).erroneous());
+            formats_stably(&text, width)?;
+        }
+
+        #[test]
+        fn the_corpus_formats_stably_at_any_width(index in 0..CORPUS.len(), width in 20usize..=400) {
+            formats_stably(CORPUS[index], width)?;
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! The address a request came from, seen through the reverse proxies in front of the server.
+//!
+//! With `TRUSTED_PROXIES` the forwarded header is only read when the socket peer is one of
+//! them, and the client is the right-most hop that isn't, since every hop to its left could
+//! have been made up by the client itself. `BEHIND_PROXY` trusts whoever connects and takes
+//! the last hop. Only the configured header is read, so a client can't pick one the proxy
+//! doesn't overwrite.
+
+use std::net::{IpAddr, SocketAddr};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::FORWARDED;
+use actix_web::{web, Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use crate::config::{Config, ConfigHandle};
+
+/// The resolved client address of a request, in its extensions.
+pub struct ClientIp(pub IpAddr);
+
+/// Why a request behind a proxy has no client address, in its extensions instead.
+pub struct Unresolved(pub String);
+
+/// The client address, `None` over the Unix socket and `Err` where a trusted proxy didn't
+/// name one.
+fn resolve(request: &ServiceRequest, config: &Config) -> Result<Option<IpAddr>, String> {
+    let Some(peer) = request.peer_addr().map(|address| address.ip()) else { return Ok(None) };
+    let trusted = |ip: &IpAddr| config.trusted_proxies.iter().any(|network| network.contains(ip));
+    let behind_proxy = match config.trusted_proxies.is_empty() {
+        true => { config.behind_proxy }
+        false => { trusted(&peer) }
+    };
+    if !behind_proxy {
+        return Ok(Some(peer));
+    }
+
+    let hops = hops(request, config);
+    if hops.is_empty() {
+        return Err(format!("the proxy {peer} sent no {} header", config.forwarded_header));
+    }
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        client = parse_hop(hop).ok_or_else(|| format!("{} names {hop:?}, which is no address", config.forwarded_header))?;
+        if config.trusted_proxies.is_empty() || !trusted(&client) {
+            break;
+        }
+    }
+    Ok(Some(client))
+}
+
+/// Every hop of the forwarded header, oldest first, across repeated header lines.
+fn hops(request: &ServiceRequest, config: &Config) -> Vec<String> {
+    let values = request.headers().get_all(&config.forwarded_header).filter_map(|value| value.to_str().ok());
+    let elements = values.flat_map(|value| value.split(',')).map(str::trim).filter(|element| !element.is_empty());
+    match config.forwarded_header == FORWARDED {
+        // RFC 7239 elements like `for=192.0.2.60;proto=https`, only `for` naming the hop.
+        true => {
+            elements
+                .map(|element| {
+                    element.split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                        .map(|(_, value)| value.trim().to_string())
+                        .unwrap_or_default()
+                })
+                .collect()
+        }
+        false => { elements.map(String::from).collect() }
+    }
+}
+
+/// A hop like `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::1` or `"[2001:db8::1]:4711"`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim_matches('"');
+    hop.parse().ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Middleware recording who a request came from for everything after it, for `App::wrap_fn`.
+pub fn identify<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    if let Some(config) = request.app_data::<web::Data<ConfigHandle>>() {
+        match resolve(&request, &config.current()) {
+            Ok(None) => {}
+            Ok(Some(ip)) => {
+                tracing::Span::current().record("client", tracing::field::display(ip));
+                request.extensions_mut().insert(ClientIp(ip));
+            }
+            Err(problem) => {
+                tracing::debug!(problem, "client address unresolved");
+                request.extensions_mut().insert(Unresolved(problem));
+            }
+        }
+    }
+    Box::pin(service.call(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use actix_web::test::TestRequest;
+    use crate::config::Config;
+    use super::resolve;
+
+    /// The client of a request from `peer` with `headers`, under a server started with `args`.
+    fn client(args: &[&str], peer: &str, headers: &[(&str, &str)]) -> Result<Option<IpAddr>, String> {
+        let config = Config::from_args(["typstapi"].iter().chain(args)).unwrap();
+        let mut request = TestRequest::default().peer_addr(format!("{peer}:4711").parse().unwrap());
+        for &header in headers {
+            request = request.append_header(header);
+        }
+        resolve(&request.to_srv_request(), &config)
+    }
+
+    fn ip(address: &str) -> Result<Option<IpAddr>, String> {
+        Ok(Some(address.parse().unwrap()))
+    }
+
+    const TRUSTED: [&str; 2] = ["--trusted-proxies", "10.0.0.0/8"];
+
+    #[test]
+    fn without_a_proxy_the_peer_is_the_client() {
+        assert_eq!(client(&[], "203.0.113.5", &[]), ip("203.0.113.5"));
+        assert_eq!(client(&[], "203.0.113.5", &[("X-Forwarded-For", "198.51.100.7")]), ip("203.0.113.5"));
+        assert_eq!(client(&[], "203.0.113.5", &[("Forwarded", "for=198.51.100.7")]), ip("203.0.113.5"));
+    }
+
+    #[test]
+    fn a_trusted_proxy_names_the_client() {
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &[("X-Forwarded-For", "198.51.100.7")]), ip("198.51.100.7"));
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &[("X-Forwarded-For", "198.51.100.7:5000")]), ip("198.51.100.7"));
+        assert!(client(&TRUSTED, "10.0.0.2", &[]).is_err());
+        assert!(client(&TRUSTED, "10.0.0.2", &[("X-Forwarded-For", "somewhere")]).is_err());
+    }
+
+    #[test]
+    fn an_untrusted_peer_cannot_name_a_client() {
+        let spoofed = [("X-Forwarded-For", "10.0.0.9")];
+        assert_eq!(client(&TRUSTED, "203.0.113.5", &spoofed), ip("203.0.113.5"));
+        assert_eq!(client(&TRUSTED, "203.0.113.5", &[("X-Forwarded-For", "198.51.100.7")]), ip("203.0.113.5"));
+    }
+
+    #[test]
+    fn hops_left_of_the_last_untrusted_one_are_ignored() {
+        // The client made up 192.0.2.1 and 10.0.0.5, the proxies appended the rest.
+        let chain = [("X-Forwarded-For", "192.0.2.1, 10.0.0.5, 198.51.100.7, 10.0.0.3")];
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &chain), ip("198.51.100.7"));
+        let lines = [("X-Forwarded-For", "192.0.2.1"), ("X-Forwarded-For", "198.51.100.7, 10.0.0.3")];
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &lines), ip("198.51.100.7"));
+        // Only proxies in the chain, the oldest one is all there is.
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &[("X-Forwarded-For", "10.0.0.5, 10.0.0.3")]), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn behind_an_untold_proxy_the_last_hop_counts() {
+        let chain = [("X-Forwarded-For", "192.0.2.1, 198.51.100.7")];
+        assert_eq!(client(&["--behind-proxy"], "203.0.113.5", &chain), ip("198.51.100.7"));
+        assert!(client(&["--behind-proxy"], "203.0.113.5", &[]).is_err());
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let args = ["--trusted-proxies", "10.0.0.0/8", "--forwarded-header", "Forwarded"];
+        let forwarded = [("Forwarded", "for=192.0.2.1, for=\"[2001:db8::1]:4711\";proto=https")];
+        assert_eq!(client(&args, "10.0.0.2", &forwarded), ip("2001:db8::1"));
+        let spoofed = [("X-Forwarded-For", "198.51.100.7")];
+        assert!(client(&args, "10.0.0.2", &spoofed).is_err());
+        assert_eq!(client(&TRUSTED, "10.0.0.2", &[("Forwarded", "for=192.0.2.1"), spoofed[0]]), ip("198.51.100.7"));
+    }
+}
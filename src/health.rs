@@ -0,0 +1,226 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use crate::cache::CompileCache;
+use crate::compilers::Compiler;
+use crate::config::{Config, CurrentConfig};
+use crate::docker_world::{Cancellation, DocumentFile, FontLibrary};
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+
+/// The example document, compiled once at startup so fonts and comemo are warm.
+const WARM_UP_DOCUMENT: &[u8] = include_bytes!("../example.typ");
+
+/// State of the startup checks the readiness probe reports on.
+pub struct Health {
+    warm_up: Mutex<WarmUp>,
+    /// Whether a failed warm-up keeps the server from reporting ready.
+    warm_up_required: bool,
+    /// Whether packages are only read from the cache, reported so it isn't mistaken for an outage.
+    offline: bool,
+    /// Set once a shutdown started, so load balancers drain the server.
+    shutting_down: AtomicBool,
+    started: Instant,
+    /// When the last `/selftest` started.
+    last_selftest: Mutex<Option<Instant>>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum WarmUp {
+    Disabled,
+    Pending,
+    Succeeded { milliseconds: u128 },
+    Failed { problem: String },
+}
+
+impl Health {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            warm_up: Mutex::new(match config.warm_up {
+                true => { WarmUp::Pending }
+                false => { WarmUp::Disabled }
+            }),
+            warm_up_required: config.warm_up_required,
+            offline: config.offline,
+            shutting_down: AtomicBool::new(false),
+            started: Instant::now(),
+            last_selftest: Mutex::new(None),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Compile the example document with `compiler`, unless warming up is disabled.
+    pub fn warm_up(&self, compiler: &dyn Compiler, config: &Config) {
+        if matches!(*self.warm_up.lock().unwrap(), WarmUp::Disabled) {
+            return;
+        }
+
+        let start = Instant::now();
+        let main = DocumentFile::new("example.typ", WARM_UP_DOCUMENT.to_vec());
+        let outcome = match compiler.compile(main, vec![], &config.default_options(), Cancellation::default()) {
+            Ok(_) => {
+                tracing::info!("Warm-up compile took {:?}", start.elapsed());
+                WarmUp::Succeeded { milliseconds: start.elapsed().as_millis() }
+            }
+            Err(problem) => {
+                tracing::error!("the warm-up compile failed, fonts or the typst library are probably broken:\n{problem}");
+                WarmUp::Failed { problem: problem.to_string() }
+            }
+        };
+        *self.warm_up.lock().unwrap() = outcome;
+    }
+
+    /// Whether the warm-up lets the server report ready.
+    pub fn warmed_up(&self) -> bool {
+        match &*self.warm_up.lock().unwrap() {
+            WarmUp::Disabled | WarmUp::Succeeded { .. } => { true }
+            WarmUp::Pending => { false }
+            WarmUp::Failed { .. } => { !self.warm_up_required }
+        }
+    }
+
+    /// Let a self-test start unless the last one started less than `interval` ago, else how
+    /// long until the next one may.
+    pub fn admit_selftest(&self, interval: Duration) -> Result<(), Duration> {
+        let mut last = self.last_selftest.lock().unwrap();
+        if let Some(wait) = last.map(|last| interval.saturating_sub(last.elapsed())).filter(|wait| !wait.is_zero()) {
+            return Err(wait);
+        }
+        *last = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    shutting_down: bool,
+    offline: bool,
+    checks: Checks,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    fonts: Check<Fonts>,
+    warm_up: Check<WarmUp>,
+    preload: Check<Preload>,
+    queue: Check<Queue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk_cache: Option<Check<CacheDir>>,
+}
+
+#[derive(Serialize)]
+struct Check<T> {
+    ok: bool,
+    #[serde(flatten)]
+    detail: T,
+}
+
+#[derive(Serialize)]
+struct Fonts {
+    count: usize,
+}
+
+/// The packages `PRELOAD_PACKAGES` has fetched at startup, ready once they are.
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum Preload {
+    Disabled,
+    Pending { packages: usize },
+    Finished { packages: usize },
+}
+
+#[derive(Serialize)]
+struct Queue {
+    running: usize,
+    queued: usize,
+    limit: usize,
+}
+
+#[derive(Serialize)]
+struct CacheDir {
+    dir: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problem: Option<String>,
+}
+
+/// 200 while the process can answer at all, without looking at anything else.
+#[utoipa::path(tag = "operations", responses((status = 200, description = "The process is alive", body = Object)))]
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "alive": true }))
+}
+
+/// 200 once the server is ready for traffic, 503 before its warm-up and the packages to
+/// preload are done, while every compile slot and the queue are taken, and while it shuts
+/// down. Never compiles anything itself.
+#[utoipa::path(
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready, with the checks that decided it", body = Object),
+        (status = 503, description = "Not ready, with the checks that decided it", body = Object),
+    ),
+)]
+#[get("/readyz")]
+async fn readyz(
+    health: web::Data<Health>,
+    fonts: web::Data<FontLibrary>,
+    slots: web::Data<CompileSlots>,
+    cache: web::Data<CompileCache>,
+    packages: web::Data<PackageStore>,
+    config: CurrentConfig,
+) -> HttpResponse {
+    let disk_cache = web::block(move || cache.check_disk()).await.ok().flatten().map(|(dir, writable)| Check {
+        ok: writable.is_ok(),
+        detail: CacheDir { dir, problem: writable.err().map(|problem| problem.to_string()) },
+    });
+    let fonts = fonts.current().count();
+    let preload = match (config.preload_packages.len(), packages.preload_finished()) {
+        (0, _) => { Preload::Disabled }
+        (packages, false) => { Preload::Pending { packages } }
+        (packages, true) => { Preload::Finished { packages } }
+    };
+    let checks = Checks {
+        fonts: Check { ok: fonts > 0, detail: Fonts { count: fonts } },
+        warm_up: Check { ok: health.warmed_up(), detail: health.warm_up.lock().unwrap().clone() },
+        preload: Check { ok: !matches!(preload, Preload::Pending { .. }), detail: preload },
+        queue: Check {
+            ok: !slots.saturated(),
+            detail: Queue { running: slots.in_use(), queued: slots.queued(), limit: slots.limit() },
+        },
+        disk_cache,
+    };
+
+    let shutting_down = health.shutting_down.load(Ordering::Relaxed);
+    let ready = !shutting_down
+        && checks.fonts.ok
+        && checks.warm_up.ok
+        && checks.preload.ok
+        && checks.queue.ok
+        && checks.disk_cache.as_ref().is_none_or(|check| check.ok);
+    let readiness = Readiness { ready, shutting_down, offline: health.offline, checks };
+    match readiness.ready {
+        true => { HttpResponse::Ok().json(readiness) }
+        false => { HttpResponse::ServiceUnavailable().json(readiness) }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz);
+    cfg.service(readyz);
+}
@@ -0,0 +1,79 @@
+//! `POST /highlight`, a typst source file as highlighted HTML.
+//!
+//! The file comes like for `/format`. The answer is a `<pre>` with each token that has a
+//! highlight wrapped in a `<span>` classed by what typst's own highlighter tags it as, like
+//! `typ-key` for keywords, `typ-str` for strings, `typ-func` for functions, `typ-math-op`
+//! for math operators, `typ-comment` and `typ-error` for what doesn't parse. Syntax errors
+//! don't fail the request, the parser recovers and the rest highlights as usual.
+//!
+//! `standalone=true` answers a whole HTML page instead, with a stylesheet for the classes.
+
+use actix_web::http::header::ContentType;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use crate::config::CurrentConfig;
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::multipart::read_source;
+use crate::openapi::Upload;
+
+const STYLESHEET: &str = "\
+pre { font-family: ui-monospace, monospace; font-size: 14px; line-height: 1.5; padding: 1em; background: #fafafa; color: #19181f; overflow-x: auto; }
+.typ-comment { color: #8a8a8a; font-style: italic; }
+.typ-punct, .typ-marker, .typ-math-delim { color: #8b41b1; }
+.typ-escape, .typ-link, .typ-label, .typ-ref { color: #1d6c76; }
+.typ-strong { font-weight: bold; }
+.typ-emph { font-style: italic; }
+.typ-link { text-decoration: underline; }
+.typ-raw, .typ-str { color: #198810; }
+.typ-heading, .typ-term { font-weight: bold; text-decoration: underline; }
+.typ-math-op, .typ-op { color: #1d6c76; }
+.typ-key { color: #d73a49; }
+.typ-num { color: #b60157; }
+.typ-func { color: #4b69c6; }
+.typ-pol { color: #8b41b1; }
+.typ-error { color: #d73a49; text-decoration: underline wavy; }
+";
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HighlightQuery {
+    /// Answer a whole HTML page with a stylesheet rather than just the `<pre>`.
+    standalone: Option<bool>,
+}
+
+/// Highlight a typst file as HTML, see the module docs.
+#[utoipa::path(
+    tag = "analysis",
+    params(HighlightQuery),
+    request_body(
+        content((String = "text/plain"), (Upload = "multipart/form-data")),
+        description = "The file as the body, or as the only part of an upload",
+    ),
+    responses(
+        (status = 200, description = "The highlighted file", content_type = "text/html", body = String),
+        (status = 400, description = "The file isn't UTF-8", body = ErrorOutput),
+        (status = 413, description = "The file is larger than `MAX_FILE_SIZE`, or more than one was uploaded", body = ErrorOutput),
+    ),
+)]
+#[post("/highlight")]
+async fn highlight(request: HttpRequest, payload: web::Payload, config: CurrentConfig) -> Result<HttpResponse, Error> {
+    let query = web::Query::<HighlightQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let text = read_source(&request, payload, &config).await?;
+    let highlighted = web::block(move || typst::syntax::highlight_html(&typst::syntax::parse(&text))).await?;
+    let body = match query.standalone.unwrap_or(false) {
+        true => {
+            format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>typst source</title>\n<style>\n{STYLESHEET}</style>\n</head>\n<body>\n{highlighted}\n</body>\n</html>\n"
+            )
+        }
+        false => { highlighted }
+    };
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(body))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(highlight);
+}
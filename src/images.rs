@@ -0,0 +1,80 @@
+//! Shrinking uploaded photos far larger than the page can show, for `?max_image_ppi=`.
+//!
+//! Where an image ends up on the page isn't known before layout, so a raster is assumed to
+//! span at most `IMAGE_MAX_WIDTH_MM` and its longest side is scaled down to that width at
+//! the requested resolution. PNGs and JPEGs are re-encoded in their own format,
+//! GIFs and SVGs are left alone, and images that fail to decode are passed through as they are.
+
+use std::io::Cursor;
+use std::path::Path;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader};
+use crate::docker_world::{DocumentFile, Warning, WarningCategory};
+
+/// JPEG quality downscaled photos are encoded with.
+const JPEG_QUALITY: u8 = 85;
+
+/// The raster format typst reads `name` as, for the ones worth re-encoding.
+fn format(name: &str) -> Option<ImageFormat> {
+    let extension = Path::new(name).extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "png" => { Some(ImageFormat::Png) }
+        "jpg" | "jpeg" => { Some(ImageFormat::Jpeg) }
+        _ => { None }
+    }
+}
+
+/// `file` with its longest side at most `max_size` pixels, and the warning saying so, or
+/// `None` if it is small enough or can't be decoded.
+fn downscale(file: &DocumentFile, max_size: u32) -> Option<(DocumentFile, Warning)> {
+    let path = file.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+    let format = format(&path)?;
+    let data = file.data.load().ok()?;
+    let mut reader = ImageReader::new(Cursor::new(&*data));
+    reader.set_format(format);
+    let (width, height) = reader.into_dimensions().ok()?;
+    if width.max(height) <= max_size {
+        return None;
+    }
+
+    let image = ImageReader::with_format(Cursor::new(&*data), format).decode().ok()?;
+    let resized = image.resize(max_size, max_size, FilterType::Lanczos3);
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY);
+            resized.to_rgb8().write_with_encoder(encoder).ok()?;
+        }
+        _ => { resized.write_to(&mut Cursor::new(&mut encoded), format).ok()?; }
+    }
+    if encoded.len() >= data.len() {
+        return None;
+    }
+
+    let warning = Warning {
+        category: WarningCategory::Other,
+        message: format!(
+            "{path}: downscaled from {width}x{height} to {}x{} pixels for max_image_ppi",
+            resized.width(),
+            resized.height(),
+        ).into(),
+    };
+    Some((DocumentFile::new(&path, encoded), warning))
+}
+
+/// The files of one compile with oversized rasters replaced by smaller copies, leaving the
+/// uploaded files as they are, and a warning for every replacement.
+pub fn downscale_all(files: Vec<DocumentFile>, max_size: u32) -> (Vec<DocumentFile>, Vec<Warning>) {
+    let mut warnings = Vec::new();
+    let files = files.into_iter()
+        .map(|file| match downscale(&file, max_size) {
+            Some((smaller, warning)) => {
+                warnings.push(warning);
+                smaller
+            }
+            None => { file }
+        })
+        .collect();
+    (files, warnings)
+}
@@ -0,0 +1,1195 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_multipart::Multipart;
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+use actix_web::http::header::ContentType;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rand::RngCore;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+use typst::diag::FileResult;
+use url::Url;
+use crate::attachments::Embedding;
+use crate::audit::{self, Record};
+use crate::cache::CompileCache;
+use crate::checksum::Checksum;
+use crate::compilers::{
+    cache_key, compile_processed, record_inputs, run_compile, strict, CompileOptions, Compiler, Compilers, Progress,
+    VersionQuery, VERSION_HEADER, WARNING_COUNT_HEADER,
+};
+use crate::admin::require_admin;
+use crate::config::{Config, ConfigHandle, CurrentConfig, OptionsQuery};
+use crate::errors::{self, Code};
+use crate::openapi::Upload;
+use crate::docker_world::{Compiled, Dependencies, DocumentFile, ErrorOutput};
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::multipart::read_documents;
+use crate::slots::{CompileSlots, Priority};
+use crate::storage::{Storage, StoredJob};
+use crate::store::SharedStore;
+use crate::uploads::owner;
+use crate::webhooks::{Delivery, Notification, Webhooks};
+
+/// Compiles submitted with `POST /jobs`.
+///
+/// Results are kept until their retention ends or the results outgrow their size limit,
+/// then the job remains as a tombstone reporting its final status for a while longer.
+/// With a shared store, jobs are queued there for any replica to take, and their status and
+/// result are published there so any replica can report them. Every change is also written
+/// to the storage to survive restarts.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    retention: Duration,
+    results_size: usize,
+    tombstone_retention: Duration,
+    shared: Option<Arc<dyn SharedStore>>,
+    storage: Arc<dyn Storage>,
+    /// Held from reading a job to writing it to the storage, so an older state of a job
+    /// never overwrites a newer one.
+    persisting: Mutex<()>,
+}
+
+struct Job {
+    status: JobStatus,
+    version: &'static str,
+    priority: Priority,
+    /// Bytes uploaded for the job.
+    input_size: u64,
+    /// The tenant that submitted the job, once callers are identified by API keys.
+    owner: Option<String>,
+    submitted: DateTime<Utc>,
+    started: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    /// The document once the job succeeded.
+    compiled: Option<Compiled>,
+    /// SHA-256 of the PDF, kept once the result expired.
+    sha256: Option<Checksum>,
+    /// Why the job failed.
+    error: Option<String>,
+    /// The code of the failure, see `errors`.
+    error_code: Option<String>,
+    /// When the job's result was dropped, leaving only its status.
+    expired: Option<DateTime<Utc>>,
+    /// The callback notified once the job finished, and how that went.
+    delivery: Option<Delivery>,
+    /// Stops the job's task, and with it the compile.
+    task: Option<AbortHandle>,
+    /// Taken off the shared queue, so it may be cancelled through the shared store.
+    taken: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => { "queued" }
+            JobStatus::Running => { "running" }
+            JobStatus::Succeeded => { "succeeded" }
+            JobStatus::Failed => { "failed" }
+            JobStatus::Cancelled => { "cancelled" }
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// Only jobs in this status, like `failed`.
+    status: Option<String>,
+    /// Only jobs of this tenant, for the admin token only. Others see their own jobs.
+    tenant: Option<String>,
+    offset: Option<usize>,
+    /// Jobs per page, 50 by default and at most 500.
+    limit: Option<usize>,
+}
+
+/// One entry of `GET /jobs`, metadata only.
+#[derive(Serialize, ToSchema)]
+pub struct JobSummary<'a> {
+    id: &'a str,
+    status: JobStatus,
+    submitted: DateTime<Utc>,
+    started: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    input_size: u64,
+    priority: Priority,
+    owner: Option<&'a str>,
+}
+
+/// A page of `GET /jobs`.
+#[derive(Serialize, ToSchema)]
+pub struct JobPage<'a> {
+    jobs: Vec<JobSummary<'a>>,
+    total: usize,
+    /// Offset of the next page, if there is one.
+    next_offset: Option<usize>,
+}
+
+/// The answer to `POST /jobs`.
+#[derive(Serialize, ToSchema)]
+pub struct Submitted {
+    id: String,
+    status: JobStatus,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobQuery {
+    /// Where to POST a notification once the job finished.
+    callback_url: Option<String>,
+}
+
+/// What `GET /jobs/{id}` reports about a job.
+#[derive(Serialize, ToSchema)]
+pub struct JobJson<'a> {
+    id: &'a str,
+    status: JobStatus,
+    typst_version: &'static str,
+    submitted: DateTime<Utc>,
+    started: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    expired: Option<DateTime<Utc>>,
+    error: Option<&'a str>,
+    /// The code of the failure, stable unlike `error`.
+    error_code: Option<&'a str>,
+    /// Hex SHA-256 of the PDF once the job succeeded, as `X-Content-SHA256` sends it.
+    sha256: Option<String>,
+    warnings: Vec<&'a str>,
+    #[schema(value_type = Option<Delivery>)]
+    callback: Option<&'a Delivery>,
+}
+
+/// The shared store's list of jobs waiting for a replica to take them.
+const QUEUE: &str = "typstapi:jobs:queue";
+
+/// How often a replica looks for jobs on the shared queue while it finds none, or has no
+/// compile slot free.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A job to run: what to compile, how, and whom to tell once it finished.
+#[derive(Clone)]
+struct Submission {
+    compiler: Arc<dyn Compiler>,
+    main: DocumentFile,
+    documents: Vec<DocumentFile>,
+    options: CompileOptions,
+    callback: Option<Url>,
+}
+
+/// What running a job takes besides the job.
+#[derive(Clone)]
+struct Services {
+    jobs: web::Data<Jobs>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+    cache: web::Data<CompileCache>,
+    webhooks: web::Data<Webhooks>,
+}
+
+/// A job on its way through the shared queue to whichever replica takes it.
+#[derive(Serialize, Deserialize)]
+struct QueuedJob {
+    id: String,
+    version: String,
+    owner: Option<String>,
+    input_size: u64,
+    submitted: DateTime<Utc>,
+    callback: Option<String>,
+    /// Paths and base64 contents of the uploads, the main file first.
+    files: Vec<(String, String)>,
+    options: QueuedOptions,
+}
+
+/// The `CompileOptions` of a queued job, apart from its progress, which nobody follows, and
+/// dry runs, which jobs don't do.
+#[derive(Serialize, Deserialize)]
+struct QueuedOptions {
+    timezone: String,
+    max_pages: usize,
+    include_dependencies: bool,
+    timeout_ms: u64,
+    no_cache: bool,
+    priority: Priority,
+    inputs: BTreeMap<String, String>,
+    fail_on_warnings: bool,
+    max_image_size: Option<u32>,
+    optimizer: Option<PathBuf>,
+    tenant: Option<String>,
+    now: Option<DateTime<Utc>>,
+    pdf_a: bool,
+    /// The qpdf binary and the paths of the attached sources.
+    embedding: Option<(PathBuf, Vec<String>)>,
+}
+
+impl QueuedJob {
+    /// The job, still without its files, see `files`.
+    fn new(id: &str, job: &Job, submission: &Submission) -> Self {
+        QueuedJob {
+            id: id.to_string(),
+            version: job.version.to_string(),
+            owner: job.owner.clone(),
+            input_size: job.input_size,
+            submitted: job.submitted,
+            callback: submission.callback.as_ref().map(|url| url.to_string()),
+            files: Vec::new(),
+            options: QueuedOptions::new(&submission.options),
+        }
+    }
+
+    /// The uploads of `submission` as a queued job carries them, blocking if they were spooled.
+    fn files(submission: &Submission) -> FileResult<Vec<(String, String)>> {
+        let mut files = Vec::new();
+        for file in std::iter::once(&submission.main).chain(&submission.documents) {
+            let path = file.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+            let data = file.data.load()?;
+            files.push((path, STANDARD.encode(&*data)));
+        }
+        Ok(files)
+    }
+
+    /// The job as this replica keeps it.
+    fn job(&self, compilers: &Compilers) -> Job {
+        let version = compilers.find(&self.version).map_or("unknown", |compiler| compiler.version());
+        let mut job = Job::queued(version, self.options.priority, self.owner.clone(), self.input_size, self.callback.clone());
+        job.submitted = self.submitted;
+        job.taken = true;
+        job
+    }
+
+    /// What to run, if this replica can run it.
+    fn submission(self, compilers: &Compilers) -> Result<Submission, Error> {
+        let compiler = compilers.find(&self.version)
+            .ok_or_else(|| Code::UnknownVersion.error(format!("The replica that took the job doesn't bundle typst {}", self.version)))?;
+        let mut files = Vec::new();
+        for (path, content) in &self.files {
+            let data = STANDARD.decode(content)
+                .map_err(|problem| Code::Internal.error(format!("The queued {path} is not base64: {problem}")))?;
+            files.push(DocumentFile::new(path, data));
+        }
+        if files.is_empty() {
+            return Err(Code::MissingMain.error("The queued job has no main file"));
+        }
+        let main = files.remove(0);
+        let callback = match &self.callback {
+            None => { None }
+            Some(url) => { Some(Url::parse(url).map_err(|problem| Code::InvalidCallbackUrl.error(format!("Invalid callback_url: {problem}")))?) }
+        };
+        let options = self.options.options()?;
+        Ok(Submission { compiler, main, documents: files, options, callback })
+    }
+}
+
+impl QueuedOptions {
+    fn new(options: &CompileOptions) -> Self {
+        QueuedOptions {
+            timezone: options.timezone.name().to_string(),
+            max_pages: options.max_pages,
+            include_dependencies: options.include_dependencies,
+            timeout_ms: options.timeout.as_millis() as u64,
+            no_cache: options.no_cache,
+            priority: options.priority,
+            inputs: options.inputs.clone(),
+            fail_on_warnings: options.fail_on_warnings,
+            max_image_size: options.max_image_size,
+            optimizer: options.optimizer.clone(),
+            tenant: options.tenant.clone(),
+            now: options.now,
+            pdf_a: options.pdf_a,
+            embedding: options.embedding.as_ref().map(|embedding| (embedding.qpdf.clone(), embedding.paths.clone())),
+        }
+    }
+
+    fn options(self) -> Result<CompileOptions, Error> {
+        let timezone: Tz = self.timezone.parse()
+            .map_err(|_| Code::UnknownTimezone.error(format!("Unknown timezone {}", self.timezone)))?;
+        Ok(CompileOptions {
+            timezone,
+            max_pages: self.max_pages,
+            include_dependencies: self.include_dependencies,
+            timeout: Duration::from_millis(self.timeout_ms),
+            no_cache: self.no_cache,
+            priority: self.priority,
+            inputs: self.inputs,
+            progress: Progress::default(),
+            fail_on_warnings: self.fail_on_warnings,
+            max_image_size: self.max_image_size,
+            optimizer: self.optimizer,
+            tenant: self.tenant,
+            now: self.now,
+            pdf_a: self.pdf_a,
+            embedding: self.embedding.map(|(qpdf, paths)| Embedding { qpdf, paths }),
+            dry_run: false,
+        })
+    }
+}
+
+impl Jobs {
+    pub fn new(config: &Config, shared: Option<Arc<dyn SharedStore>>, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            retention: config.job_retention,
+            results_size: config.job_results_size,
+            tombstone_retention: config.job_tombstone_retention,
+            shared,
+            storage,
+            persisting: Mutex::new(()),
+        }
+    }
+
+    /// Take over the jobs the storage kept from before a restart, of the bundled `versions`.
+    ///
+    /// Jobs that hadn't finished lost their uploads with the process, so they are failed.
+    pub fn restore(&self, versions: &[&'static str]) -> Result<(), String> {
+        let stored = self.storage.jobs()?;
+        let now = Utc::now();
+        let mut interrupted = Vec::new();
+        let mut jobs = self.jobs.lock().unwrap();
+        for stored in stored {
+            let mut job = Job {
+                status: stored.status,
+                version: versions.iter().find(|version| **version == stored.version).copied().unwrap_or("unknown"),
+                priority: stored.priority,
+                input_size: stored.input_size,
+                owner: stored.owner,
+                submitted: stored.submitted,
+                started: stored.started,
+                finished: stored.finished,
+                compiled: stored.pdf.map(|pdf| Compiled {
+                    pdf,
+                    pages: stored.pages.unwrap_or_default(),
+                    page_hashes: Vec::new(),
+                    warnings: stored.warnings,
+                    dependencies: Dependencies::default(),
+                    cacheable: false,
+                }),
+                sha256: stored.sha256.as_deref().and_then(Checksum::parse),
+                error: stored.error,
+                error_code: stored.error_code,
+                expired: stored.expired,
+                delivery: stored.delivery,
+                task: None,
+                taken: false,
+            };
+            if !job.status.is_finished() {
+                job.status = JobStatus::Failed;
+                job.finished = Some(now);
+                job.error = Some("The server restarted before the job finished, submit it again".into());
+                job.error_code = Some(Code::JobInterrupted.as_str().into());
+                interrupted.push(job.stored(&stored.id));
+            }
+            jobs.insert(stored.id, job);
+        }
+        let restored = jobs.len();
+        drop(jobs);
+
+        for job in &interrupted {
+            self.publish(&job.id);
+        }
+        if restored > 0 {
+            tracing::info!("restored {restored} jobs, {} of them interrupted by the restart", interrupted.len());
+        }
+        Ok(())
+    }
+
+    /// Turn jobs past their retention, or beyond the results size limit, into tombstones
+    /// and forget tombstones past theirs.
+    pub fn collect_garbage(&self) {
+        let retention = chrono::Duration::from_std(self.retention).unwrap_or(chrono::Duration::MAX);
+        let tombstone_retention = chrono::Duration::from_std(self.tombstone_retention).unwrap_or(chrono::Duration::MAX);
+        let now = Utc::now();
+        let _persisting = self.persisting.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let mut removed = Vec::new();
+        jobs.retain(|id, job| {
+            let keep = !job.expired.is_some_and(|expired| now - expired >= tombstone_retention);
+            if !keep {
+                removed.push(id.clone());
+            }
+            keep
+        });
+        let mut expired = Vec::new();
+        for (id, job) in jobs.iter_mut() {
+            if job.expired.is_none() && job.finished.is_some_and(|finished| now - finished >= retention) {
+                job.expire(now);
+                expired.push(id.clone());
+            }
+        }
+
+        let mut results: Vec<_> = jobs.iter_mut()
+            .filter_map(|(id, job)| Some((job.finished?, job.compiled.as_ref()?.pdf.len(), id, job)))
+            .collect();
+        let mut size: usize = results.iter().map(|(_, size, _, _)| size).sum();
+        results.sort_by_key(|(finished, _, _, _)| *finished);
+        for (_, result_size, id, job) in results {
+            if size <= self.results_size {
+                break;
+            }
+            size -= result_size;
+            job.expire(now);
+            expired.push(id.clone());
+        }
+
+        let expired: Vec<_> = expired.iter().filter_map(|id| Some(jobs.get(id)?.stored(id))).collect();
+        drop(jobs);
+        for id in &removed {
+            self.storage.remove_job(id);
+        }
+        for job in &expired {
+            self.storage.save_job(job);
+        }
+    }
+
+    fn insert(&self, id: String, job: Job) {
+        self.jobs.lock().unwrap().insert(id, job);
+    }
+
+    /// Keep a result compiled elsewhere, like a streamed compile's, as a finished job to
+    /// fetch it from.
+    pub async fn keep(
+        jobs: &web::Data<Jobs>,
+        version: &'static str,
+        priority: Priority,
+        owner: Option<String>,
+        input_size: u64,
+        compiled: Compiled,
+        sha256: Checksum,
+    ) -> String {
+        let id = new_id();
+        jobs.insert(id.clone(), Job::queued(version, priority, owner, input_size, None));
+        let finished = Utc::now();
+        jobs.update(&id, |job| {
+            job.status = JobStatus::Succeeded;
+            job.started = Some(finished);
+            job.finished = Some(finished);
+            job.compiled = Some(compiled);
+            job.sha256 = Some(sha256);
+        });
+        let (published, kept) = (jobs.clone(), id.clone());
+        let _ = web::block(move || published.publish(&kept)).await;
+        id
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    /// Stop a job that hasn't finished yet, leaving finished ones as they are.
+    ///
+    /// A queued job leaves the queue, a running one has its compile cancelled.
+    fn cancel(&self, id: &str, owner: &Option<String>) -> Result<(), Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get_mut(id).filter(|job| job.owner == *owner) {
+            None => { return Err(Code::JobNotFound.error("No such job, or it has expired")) }
+            Some(job) => { job }
+        };
+        if job.status.is_finished() {
+            return Ok(());
+        }
+
+        job.status = JobStatus::Cancelled;
+        job.finished = Some(Utc::now());
+        if let Some(task) = job.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    /// Write a job to the storage, blocking on it.
+    fn persist(&self, id: &str) {
+        let _persisting = self.persisting.lock().unwrap();
+        if let Ok(job) = self.with_job(id, |job| job.stored(id)) {
+            self.storage.save_job(&job);
+        }
+    }
+
+    /// Write a job to the storage and its status and result to the shared store, blocking on it.
+    fn publish(&self, id: &str) {
+        self.persist(id);
+        let Some(shared) = &self.shared else { return };
+        let Ok((key, status, pdf)) = self.with_job(id, |job| {
+            let status = serde_json::to_vec(&describe_json(id, job)).expect("Jobs serialize");
+            (shared_key(&job.owner, id), status, job.compiled.as_ref().map(|compiled| compiled.pdf.clone()))
+        }) else { return };
+
+        shared.set(&key, &status, self.retention + self.tombstone_retention);
+        if let Some(pdf) = pdf {
+            shared.set(&format!("{key}:result"), &pdf, self.retention);
+        }
+    }
+
+    /// A job of `owner` another replica published, as its status JSON and its PDF if that is still kept.
+    async fn published(
+        jobs: &web::Data<Jobs>,
+        id: &str,
+        owner: &Option<String>,
+    ) -> Option<(serde_json::Value, Option<Vec<u8>>)> {
+        let shared = jobs.shared.clone()?;
+        let key = shared_key(owner, id);
+        web::block(move || {
+            let status = serde_json::from_slice(&shared.get(&key)?).ok()?;
+            Some((status, shared.get(&format!("{key}:result"))))
+        }).await.ok()?
+    }
+
+    /// Put a job on the shared queue for any replica to take, publishing it as queued first.
+    ///
+    /// False without a shared store, or if it couldn't take the job, which then has to run here.
+    async fn enqueue(jobs: &web::Data<Jobs>, id: &str, job: &Job, submission: &Submission) -> bool {
+        let Some(shared) = jobs.shared.clone() else { return false };
+        let status = serde_json::to_vec(&describe_json(id, job)).expect("Jobs serialize");
+        let key = shared_key(&job.owner, id);
+        let ttl = jobs.retention + jobs.tombstone_retention;
+        let (mut queued, uploads) = (QueuedJob::new(id, job, submission), submission.clone());
+        let pushed = web::block(move || {
+            let Ok(files) = QueuedJob::files(&uploads) else { return false };
+            queued.files = files;
+            // Published before it is queued, so the replica taking it can't be overwritten.
+            shared.set(&key, &status, ttl);
+            shared.push(QUEUE, &serde_json::to_vec(&queued).expect("Jobs serialize"))
+        }).await;
+        pushed.unwrap_or(false)
+    }
+
+    /// The oldest job on the shared queue, and whether it was cancelled while it waited.
+    fn take(&self) -> Option<(QueuedJob, bool)> {
+        let shared = self.shared.as_ref()?;
+        let queued: QueuedJob = match serde_json::from_slice(&shared.pop(QUEUE)?) {
+            Ok(queued) => { queued }
+            Err(problem) => {
+                tracing::warn!("dropped a job from the shared queue that doesn't parse: {problem}");
+                return None;
+            }
+        };
+        let cancelled = shared.get(&cancel_key(&queued.owner, &queued.id)).is_some();
+        Some((queued, cancelled))
+    }
+
+    /// Cancel the jobs taken off the shared queue that another replica was asked to cancel.
+    pub fn follow_cancellations(&self) {
+        let Some(shared) = &self.shared else { return };
+        let running: Vec<_> = self.jobs.lock().unwrap().iter()
+            .filter(|(_, job)| job.taken && !job.status.is_finished())
+            .map(|(id, job)| (id.clone(), job.owner.clone()))
+            .collect();
+        for (id, owner) in running {
+            if shared.get(&cancel_key(&owner, &id)).is_some() && self.cancel(&id, &owner).is_ok() {
+                self.publish(&id);
+            }
+        }
+    }
+
+    /// Cancel a job of `owner` only another replica knows, answering with its status afterwards.
+    ///
+    /// The replica running it, or the one taking it off the queue, sees the cancellation
+    /// within a few seconds.
+    async fn cancel_published(jobs: &web::Data<Jobs>, id: &str, owner: &Option<String>) -> Option<serde_json::Value> {
+        let (mut status, _) = Jobs::published(jobs, id, owner).await?;
+        if status["status"] != "queued" && status["status"] != "running" {
+            return Some(status);
+        }
+        status["status"] = JobStatus::Cancelled.name().into();
+        status["finished"] = serde_json::to_value(Utc::now()).expect("Times serialize");
+
+        let shared = jobs.shared.clone()?;
+        let (key, cancel, ttl) = (shared_key(owner, id), cancel_key(owner, id), jobs.retention + jobs.tombstone_retention);
+        let published = serde_json::to_vec(&status).expect("Jobs serialize");
+        let _ = web::block(move || {
+            shared.set(&cancel, b"1", ttl);
+            shared.set(&key, &published, ttl);
+        }).await;
+        Some(status)
+    }
+
+    fn with_job<T>(&self, id: &str, f: impl FnOnce(&Job) -> T) -> Result<T, Error> {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs.get(id) {
+            None => { Err(Code::JobNotFound.error("No such job, or it has expired")) }
+            Some(job) => { Ok(f(job)) }
+        }
+    }
+
+    /// Like `with_job`, as if the jobs of tenants other than `owner` didn't exist.
+    fn with_own_job<T>(&self, id: &str, owner: &Option<String>, f: impl FnOnce(&Job) -> T) -> Result<T, Error> {
+        self.with_job(id, |job| (job.owner == *owner).then(|| f(job)))?
+            .ok_or_else(|| Code::JobNotFound.error("No such job, or it has expired"))
+    }
+
+    /// Stop and forget the jobs of `tenant`.
+    pub fn remove_tenant(&self, tenant: &str) {
+        let _persisting = self.persisting.lock().unwrap();
+        let mut removed = Vec::new();
+        self.jobs.lock().unwrap().retain(|id, job| {
+            let keep = job.owner.as_deref() != Some(tenant);
+            if !keep {
+                if let Some(task) = job.task.take() {
+                    task.abort();
+                }
+                removed.push(id.clone());
+            }
+            keep
+        });
+        for id in &removed {
+            self.storage.remove_job(id);
+        }
+    }
+}
+
+/// Where a job is published in the shared store, apart for every tenant.
+fn shared_key(owner: &Option<String>, id: &str) -> String {
+    match owner {
+        None => { format!("typstapi:job:{id}") }
+        Some(tenant) => { format!("typstapi:tenant:{tenant}:job:{id}") }
+    }
+}
+
+/// A random job id, 32 hex digits.
+fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Where a job's cancellation is noted for the replica running it.
+fn cancel_key(owner: &Option<String>, id: &str) -> String {
+    format!("{}:cancel", shared_key(owner, id))
+}
+
+impl Job {
+    /// A job just submitted.
+    fn queued(
+        version: &'static str,
+        priority: Priority,
+        owner: Option<String>,
+        input_size: u64,
+        callback: Option<String>,
+    ) -> Self {
+        Job {
+            status: JobStatus::Queued,
+            version,
+            priority,
+            input_size,
+            owner,
+            submitted: Utc::now(),
+            started: None,
+            finished: None,
+            compiled: None,
+            sha256: None,
+            error: None,
+            error_code: None,
+            expired: None,
+            delivery: callback.map(Delivery::pending),
+            task: None,
+            taken: false,
+        }
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        self.compiled = None;
+        self.expired = Some(now);
+    }
+
+    fn stored(&self, id: &str) -> StoredJob {
+        StoredJob {
+            id: id.to_string(),
+            status: self.status,
+            version: self.version.to_string(),
+            priority: self.priority,
+            input_size: self.input_size,
+            owner: self.owner.clone(),
+            submitted: self.submitted,
+            started: self.started,
+            finished: self.finished,
+            error: self.error.clone(),
+            error_code: self.error_code.clone(),
+            sha256: self.sha256.map(|sha256| sha256.hex()),
+            expired: self.expired,
+            delivery: self.delivery.clone(),
+            pages: self.compiled.as_ref().map(|compiled| compiled.pages),
+            warnings: self.compiled.iter().flat_map(|compiled| compiled.warnings.clone()).collect(),
+            pdf: self.compiled.as_ref().map(|compiled| compiled.pdf.clone()),
+        }
+    }
+}
+
+/// Queue a compile of a `/compile` style upload and answer with its job id right away.
+///
+/// With a shared store the job goes to the replicas' shared queue for whichever has a slot
+/// free, this one runs it only if the store can't take the job.
+#[utoipa::path(
+    tag = "jobs",
+    params(VersionQuery, OptionsQuery, JobQuery),
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "The job was queued", body = Submitted),
+        (status = 400, description = "The upload or a parameter is invalid", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+    ),
+)]
+#[post("/jobs")]
+#[allow(clippy::too_many_arguments)]
+async fn submit_job(
+    request: HttpRequest,
+    payload: Multipart,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    jobs: web::Data<Jobs>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+    cache: web::Data<CompileCache>,
+    webhooks: web::Data<Webhooks>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    let options = config.compile_options(&request)?;
+    let query = web::Query::<JobQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let callback = match &query.callback_url {
+        None => { None }
+        Some(url) => { Some(webhooks.validate(url, &config)?) }
+    };
+    let mut documents = read_documents(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    audit::inputs(&request, &documents);
+    let main = documents.remove(0);
+
+    let input_size = record_inputs(std::iter::once(&main).chain(&documents));
+    let id = new_id();
+    let job = Job::queued(
+        compiler.version(),
+        options.priority,
+        options.tenant.clone(),
+        input_size,
+        callback.as_ref().map(|url| url.to_string()),
+    );
+    let submission = Submission { compiler, main, documents, options, callback };
+    let mut record = audit::detach(&request);
+    if let Some(record) = &mut record {
+        record.job(&id);
+    }
+    if Jobs::enqueue(&jobs, &id, &job, &submission).await {
+        if let Some(record) = &mut record {
+            record.queued();
+        }
+        return Ok(HttpResponse::Accepted().json(Submitted { id, status: JobStatus::Queued }));
+    }
+
+    jobs.insert(id.clone(), job);
+    let (persisted, queued) = (jobs.clone(), id.clone());
+    let _ = web::block(move || persisted.persist(&queued)).await;
+    let services = Services { jobs: jobs.clone(), slots, metrics, cache, webhooks };
+    start(services, id.clone(), submission, record, config.snapshot());
+
+    Ok(HttpResponse::Accepted().json(Submitted { id, status: JobStatus::Queued }))
+}
+
+/// Run a job in the background, stoppable through `Jobs::cancel`.
+fn start(services: Services, id: String, submission: Submission, record: Option<Record>, config: Arc<Config>) {
+    let jobs = services.jobs.clone();
+    let work = run(services, id.clone(), submission, record, config)
+        .instrument(tracing::info_span!("job", id = %id, cache_hit = tracing::field::Empty));
+
+    // Spawned under the lock, so a cancel sees either no task yet, and none is started, or
+    // the handle to abort.
+    let mut all = jobs.jobs.lock().unwrap();
+    if let Some(job) = all.get_mut(&id).filter(|job| !job.status.is_finished()) {
+        job.task = Some(actix_web::rt::spawn(work).abort_handle());
+    }
+}
+
+async fn run(services: Services, job: String, submission: Submission, record: Option<Record>, config: Arc<Config>) {
+    let Services { jobs, slots, metrics, cache, webhooks } = services;
+    let Submission { compiler, main, documents, options, callback } = submission;
+    let cache_max_age = config.cache_max_age;
+    let (format, fail_on_warnings) = (options.output_format(), options.fail_on_warnings);
+    let key = cache_key(compiler.as_ref(), &main, &documents, &options);
+    let cached = key.as_ref().and_then(|key| cache.get(key));
+    tracing::Span::current().record("cache_hit", cached.is_some());
+    let compiled = match cached {
+        Some(compiled) => { Ok(Ok(compiled)) }
+        None => {
+            let started = jobs.clone();
+            let job = job.clone();
+            run_compile(&slots, &metrics, options.timeout, options.priority, format, move |cancellation| {
+                started.update(&job, |job| {
+                    if job.status == JobStatus::Queued {
+                        job.status = JobStatus::Running;
+                        job.started = Some(Utc::now());
+                    }
+                });
+                started.publish(&job);
+                let compiled = compile_processed(compiler.as_ref(), main, documents, &options, cancellation);
+                compiler.evict(cache_max_age);
+                compiled
+            }).await
+        }
+    };
+
+    if let Some(mut record) = record {
+        record.compiled(format, &compiled);
+    }
+    if let Ok(Ok(compiled)) = &compiled {
+        metrics.record_warnings(&compiled.warnings);
+        if let Some(key) = key {
+            cache.insert(key, compiled);
+        }
+    }
+    let compiled = compiled.map(|compiled| strict(compiled, fail_on_warnings));
+    let sha256 = match &compiled {
+        Ok(Ok(compiled)) => { Some(Checksum::of(&compiled.pdf)) }
+        _ => { None }
+    };
+
+    let finished = Utc::now();
+    let mut cancelled = false;
+    jobs.update(&job, |job| {
+        // The job may have been cancelled while its compile was finishing.
+        if job.status == JobStatus::Cancelled {
+            cancelled = true;
+            return;
+        }
+        job.finished = Some(finished);
+        job.task = None;
+        match compiled {
+            Ok(Ok(compiled)) => {
+                job.status = JobStatus::Succeeded;
+                job.compiled = Some(compiled);
+                job.sha256 = sha256;
+            }
+            Ok(Err(problem)) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(problem.to_string());
+                job.error_code = Some(problem.code().into());
+            }
+            Err(problem) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(problem.to_string());
+                job.error_code = Some(errors::code(&problem).into());
+            }
+        }
+    });
+
+    let published = jobs.clone();
+    let published_job = job.clone();
+    let _ = web::block(move || published.publish(&published_job)).await;
+
+    let url = match callback {
+        Some(url) if !cancelled => { url }
+        _ => { return }
+    };
+    let notification = jobs.with_job(&job, |finished| {
+        let pdf = finished.compiled.as_ref().map(|compiled| &compiled.pdf);
+        Notification {
+            id: job.clone(),
+            status: finished.status.name(),
+            result_size: pdf.map(Vec::len),
+            error: finished.error.clone(),
+            error_code: finished.error_code.clone(),
+            sha256: finished.sha256.map(|sha256| sha256.hex()),
+            pdf: pdf.filter(|pdf| webhooks.inline(pdf, &config)).map(|pdf| STANDARD.encode(pdf)),
+        }
+    });
+    if let Ok(notification) = notification {
+        let delivery = Webhooks::deliver(webhooks, config, url, notification).await;
+        jobs.update(&job, |job| job.delivery = Some(delivery));
+        let _ = web::block(move || jobs.publish(&job)).await;
+    }
+}
+
+/// Take jobs off the shared queue while this replica has a compile slot free, until it
+/// shuts down. Without a shared store there is no queue, and this returns right away.
+#[allow(clippy::too_many_arguments)]
+pub async fn work(
+    jobs: web::Data<Jobs>,
+    compilers: web::Data<Compilers>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+    cache: web::Data<CompileCache>,
+    webhooks: web::Data<Webhooks>,
+    config: web::Data<ConfigHandle>,
+    health: web::Data<Health>,
+) {
+    if jobs.shared.is_none() {
+        return;
+    }
+    let services = Services { jobs: jobs.clone(), slots: slots.clone(), metrics, cache, webhooks };
+    while !health.shutting_down() {
+        if slots.in_use() + slots.queued() >= slots.limit() {
+            actix_web::rt::time::sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        let taking = jobs.clone();
+        let (queued, cancelled) = match web::block(move || taking.take()).await {
+            Ok(Some(taken)) => { taken }
+            _ => {
+                actix_web::rt::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let (id, mut job) = (queued.id.clone(), queued.job(&compilers));
+        let submission = match cancelled {
+            true => {
+                job.status = JobStatus::Cancelled;
+                job.finished = Some(Utc::now());
+                None
+            }
+            false => {
+                match queued.submission(&compilers) {
+                    Ok(submission) => { Some(submission) }
+                    Err(problem) => {
+                        tracing::warn!(id = %id, "cannot run a job from the shared queue: {problem}");
+                        job.status = JobStatus::Failed;
+                        job.finished = Some(Utc::now());
+                        job.error = Some(problem.to_string());
+                        job.error_code = Some(errors::code(&problem).into());
+                        None
+                    }
+                }
+            }
+        };
+        jobs.insert(id.clone(), job);
+        let (published, taken) = (jobs.clone(), id.clone());
+        let _ = web::block(move || published.publish(&taken)).await;
+        if let Some(submission) = submission {
+            start(services.clone(), id, submission, None, config.current());
+        }
+    }
+}
+
+/// The caller's jobs, or with the admin token everyone's, newest first, optionally filtered
+/// by status and owner.
+#[utoipa::path(
+    tag = "jobs",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "A page of jobs", body = JobPage),
+        (status = 401, description = "`tenant` was given without the admin token", body = ErrorOutput),
+    ),
+)]
+#[get("/jobs")]
+async fn list_jobs(
+    request: HttpRequest,
+    config: CurrentConfig,
+    jobs: web::Data<Jobs>,
+) -> Result<HttpResponse, Error> {
+    let query = web::Query::<ListQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let own = match require_admin(&request, &config) {
+        Ok(()) => { None }
+        Err(problem) if query.tenant.is_some() => { return Err(problem) }
+        Err(_) => { Some(owner(&request)) }
+    };
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(50).min(500);
+
+    let jobs = jobs.jobs.lock().unwrap();
+    let mut matching: Vec<_> = jobs.iter()
+        .filter(|(_, job)| query.status.is_none() || query.status.as_deref() == Some(job.status.name()))
+        .filter(|(_, job)| query.tenant.is_none() || job.owner == query.tenant)
+        .filter(|(_, job)| own.as_ref().is_none_or(|own| job.owner == *own))
+        .collect();
+    matching.sort_by(|(_, a), (_, b)| b.submitted.cmp(&a.submitted));
+
+    let total = matching.len();
+    let page: Vec<_> = matching.into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(id, job)| JobSummary {
+            id,
+            status: job.status,
+            submitted: job.submitted,
+            started: job.started,
+            finished: job.finished,
+            input_size: job.input_size,
+            priority: job.priority,
+            owner: job.owner.as_deref(),
+        })
+        .collect();
+    let next_offset = (offset + page.len() < total).then_some(offset + page.len());
+
+    Ok(HttpResponse::Ok().json(JobPage { jobs: page, total, next_offset }))
+}
+
+/// The status of a job, kept a while after its result expired.
+#[utoipa::path(
+    tag = "jobs",
+    params(("id" = String, Path, description = "The id `POST /jobs` answered with")),
+    responses(
+        (status = 200, description = "The job's status", body = JobJson),
+        (status = 404, description = "No such job", body = ErrorOutput),
+    ),
+)]
+#[get("/jobs/{id}")]
+async fn job_status(request: HttpRequest, id: web::Path<String>, jobs: web::Data<Jobs>) -> Result<HttpResponse, Error> {
+    let owner = owner(&request);
+    match jobs.with_own_job(&id, &owner, |job| describe(&id, job)) {
+        Ok(response) => { Ok(response) }
+        Err(missing) => {
+            let (status, _) = Jobs::published(&jobs, &id, &owner).await.ok_or(missing)?;
+            Ok(HttpResponse::Ok().json(status))
+        }
+    }
+}
+
+/// Cancel a job, answering with its status afterwards like `GET /jobs/{id}`.
+#[utoipa::path(
+    tag = "jobs",
+    params(("id" = String, Path, description = "The id `POST /jobs` answered with")),
+    responses(
+        (status = 200, description = "The job's status afterwards, unchanged if it had finished", body = JobJson),
+        (status = 404, description = "No such job", body = ErrorOutput),
+    ),
+)]
+#[delete("/jobs/{id}")]
+async fn cancel_job(request: HttpRequest, id: web::Path<String>, jobs: web::Data<Jobs>) -> Result<HttpResponse, Error> {
+    let owner = owner(&request);
+    if let Err(missing) = jobs.cancel(&id, &owner) {
+        let status = Jobs::cancel_published(&jobs, &id, &owner).await.ok_or(missing)?;
+        return Ok(HttpResponse::Ok().json(status));
+    }
+    let (persisted, cancelled) = (jobs.clone(), id.clone());
+    let _ = web::block(move || persisted.publish(&cancelled)).await;
+    jobs.with_job(&id, |job| describe(&id, job))
+}
+
+fn describe(id: &str, job: &Job) -> HttpResponse {
+    HttpResponse::Ok().json(describe_json(id, job))
+}
+
+fn describe_json<'a>(id: &'a str, job: &'a Job) -> JobJson<'a> {
+    let warnings = job.compiled.iter()
+        .flat_map(|compiled| &compiled.warnings)
+        .map(|warning| warning.message.as_str())
+        .collect();
+    JobJson {
+        id,
+        status: job.status,
+        typst_version: job.version,
+        submitted: job.submitted,
+        started: job.started,
+        finished: job.finished,
+        expired: job.expired,
+        error: job.error.as_deref(),
+        error_code: job.error_code.as_deref(),
+        sha256: job.sha256.map(|sha256| sha256.hex()),
+        warnings,
+        callback: job.delivery.as_ref(),
+    }
+}
+
+/// The PDF of a succeeded job.
+#[utoipa::path(
+    tag = "jobs",
+    params(("id" = String, Path, description = "The id `POST /jobs` answered with")),
+    responses(
+        (status = 200, description = "The PDF", body = [u8], content_type = "application/octet-stream"),
+        (status = 404, description = "No such job", body = ErrorOutput),
+        (status = 409, description = "The job has not succeeded", body = ErrorOutput),
+        (status = 410, description = "The result expired", body = ErrorOutput),
+    ),
+)]
+#[get("/jobs/{id}/result")]
+async fn job_result(request: HttpRequest, id: web::Path<String>, jobs: web::Data<Jobs>) -> Result<HttpResponse, Error> {
+    let owner = owner(&request);
+    let local = jobs.with_own_job(&id, &owner, |job| match &job.compiled {
+        None if job.expired.is_some() && job.status == JobStatus::Succeeded => {
+            Err(Code::JobResultExpired.error("The job's result has expired and was deleted, submit the job again"))
+        }
+        None => { Err(Code::JobNotSucceeded.error("The job has not succeeded, see GET /jobs/{id}")) }
+        Some(compiled) => {
+            let mut response = HttpResponse::Ok();
+            job.sha256.unwrap_or_else(|| Checksum::of(&compiled.pdf)).insert(&mut response);
+            Ok(response
+                .insert_header((VERSION_HEADER, job.version))
+                .insert_header((WARNING_COUNT_HEADER, compiled.warnings.len().to_string()))
+                .content_type(ContentType::octet_stream())
+                .body(compiled.pdf.clone()))
+        }
+    });
+    let missing = match local {
+        Ok(response) => { return response }
+        Err(missing) => { missing }
+    };
+
+    let (status, pdf) = Jobs::published(&jobs, &id, &owner).await.ok_or(missing)?;
+    let succeeded = status["status"] == "succeeded";
+    match pdf {
+        Some(pdf) if succeeded => {
+            let mut response = HttpResponse::Ok();
+            Checksum::of(&pdf).insert(&mut response);
+            Ok(response
+                .insert_header((VERSION_HEADER, status["typst_version"].as_str().unwrap_or_default()))
+                .insert_header((WARNING_COUNT_HEADER, status["warnings"].as_array().map_or(0, Vec::len).to_string()))
+                .content_type(ContentType::octet_stream())
+                .body(pdf))
+        }
+        None if succeeded => {
+            Err(Code::JobResultExpired.error("The job's result has expired and was deleted, submit the job again"))
+        }
+        _ => { Err(Code::JobNotSucceeded.error("The job has not succeeded, see GET /jobs/{id}")) }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(submit_job)
+        .service(list_jobs)
+        .service(job_status)
+        .service(cancel_job)
+        .service(job_result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_survive_the_shared_queue() {
+        let mut options = Config::from_args(["typstapi"]).unwrap().default_options();
+        options.timezone = chrono_tz::Europe::Berlin;
+        options.timeout = Duration::from_millis(1500);
+        options.priority = Priority::Low;
+        options.inputs.insert("name".into(), "Ada".into());
+        options.tenant = Some("alpha".into());
+        options.now = Some(Utc::now());
+        options.embedding = Some(Embedding { qpdf: "qpdf".into(), paths: vec!["main.typ".into()] });
+
+        let sent = serde_json::to_vec(&QueuedOptions::new(&options)).unwrap();
+        let received = serde_json::from_slice::<QueuedOptions>(&sent).unwrap().options().unwrap();
+        assert_eq!(received.timezone, options.timezone);
+        assert_eq!(received.timeout, options.timeout);
+        assert_eq!(received.priority, options.priority);
+        assert_eq!(received.inputs, options.inputs);
+        assert_eq!(received.tenant, options.tenant);
+        assert_eq!(received.now, options.now);
+        assert_eq!(received.embedding.map(|embedding| embedding.paths), Some(vec!["main.typ".to_string()]));
+        assert_eq!(
+            (received.max_pages, received.no_cache, received.fail_on_warnings, received.pdf_a),
+            (options.max_pages, options.no_cache, options.fail_on_warnings, options.pdf_a),
+        );
+    }
+}
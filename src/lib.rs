@@ -0,0 +1,153 @@
+//! Compiling typst documents to PDF, SVG or PNG, as the `typstapi` server does, without the server.
+//!
+//! Build a `FontDb` and a `PackageStore` once and share them, put worlds over uploaded
+//! files together with `DockerWorld::builder`, then compile them once with `compile` or
+//! keep a `DockerWorld` between compiles. `DockerWorld::typeset` lays a document out once
+//! for exporting it to several formats. Nothing here panics on bad input: unreadable
+//! files, fonts and packages surface as errors of the compile, and setting up the package
+//! store reports an unusable CA certificate.
+//!
+//! The default `server` feature adds the HTTP server and its dependencies, turn it off with
+//! `default-features = false` to use only this library. The server's modules are public so
+//! the binary and the integration tests build the same application, with `app::app`.
+
+pub mod docker_world;
+pub mod packages;
+
+#[cfg(feature = "server")]
+pub mod access;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod analyze;
+#[cfg(feature = "server")]
+pub mod app;
+#[cfg(feature = "server")]
+pub mod archive;
+#[cfg(feature = "server")]
+pub mod ast;
+#[cfg(feature = "server")]
+pub mod attachments;
+#[cfg(feature = "server")]
+pub mod audit;
+#[cfg(feature = "server")]
+pub mod auth;
+#[cfg(feature = "server")]
+pub mod bibliography;
+#[cfg(feature = "server")]
+pub mod blobs;
+#[cfg(feature = "server")]
+pub mod cache;
+#[cfg(feature = "server")]
+pub mod checksum;
+#[cfg(feature = "server")]
+pub mod compile_endpoint;
+#[cfg(feature = "server")]
+pub mod compilers;
+#[cfg(feature = "server")]
+pub mod concurrency;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod cors;
+#[cfg(feature = "server")]
+pub mod debug;
+#[cfg(feature = "server")]
+pub mod documents;
+#[cfg(feature = "server")]
+pub mod element;
+#[cfg(feature = "server")]
+pub mod errors;
+#[cfg(feature = "server")]
+pub mod etag;
+#[cfg(feature = "server")]
+pub mod events;
+#[cfg(feature = "server")]
+pub mod exports;
+#[cfg(feature = "server")]
+pub mod example;
+#[cfg(feature = "server")]
+pub mod fonts;
+#[cfg(feature = "server")]
+pub mod formatter;
+#[cfg(feature = "server")]
+pub mod forwarded;
+#[cfg(feature = "server")]
+pub mod health;
+#[cfg(feature = "server")]
+pub mod highlight;
+#[cfg(feature = "server")]
+pub mod images;
+#[cfg(feature = "server")]
+pub mod jobs;
+#[cfg(feature = "server")]
+pub mod limits;
+#[cfg(feature = "server")]
+pub mod listeners;
+#[cfg(feature = "server")]
+pub mod logging;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod multipart;
+#[cfg(feature = "server")]
+pub mod openapi;
+#[cfg(feature = "server")]
+pub mod optimize;
+#[cfg(feature = "server")]
+pub mod package_admin;
+#[cfg(feature = "server")]
+pub mod pages;
+#[cfg(feature = "server")]
+pub mod preview;
+#[cfg(feature = "server")]
+pub mod profile;
+#[cfg(feature = "server")]
+pub mod projects;
+#[cfg(feature = "server")]
+pub mod quotas;
+#[cfg(feature = "server")]
+pub mod rate_limit;
+#[cfg(all(feature = "server", unix))]
+pub mod reload;
+#[cfg(feature = "server")]
+pub mod revisions;
+#[cfg(feature = "server")]
+pub mod sarif;
+#[cfg(feature = "server")]
+pub mod selftest;
+#[cfg(feature = "server")]
+pub mod shadow;
+#[cfg(feature = "server")]
+pub mod shutdown;
+#[cfg(feature = "server")]
+pub mod slots;
+#[cfg(feature = "server")]
+pub mod sniff;
+#[cfg(feature = "server")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod storage;
+#[cfg(feature = "server")]
+pub mod store;
+#[cfg(all(feature = "server", unix))]
+pub mod systemd;
+#[cfg(feature = "server")]
+pub mod tls;
+#[cfg(feature = "server")]
+pub mod ui;
+#[cfg(all(feature = "server", unix))]
+pub mod unix_socket;
+#[cfg(feature = "server")]
+pub mod uploads;
+#[cfg(feature = "server")]
+pub mod version;
+#[cfg(feature = "server")]
+pub mod webhooks;
+
+pub use docker_world::{
+    compile, load_directory, BuildError, Cancellation, CompileError, Compiled, CompiledDocument, DockerWorld,
+    DockerWorldBuilder, DocumentFile, EditError, ElementError, ExportError, FaceCoverage, FamilyCoverage, FontDb,
+    FontLibrary, PageFingerprint, PageStatistics, PdfExport, PngExport, Region, Statistics, SvgExport,
+};
+pub use packages::{PackageSettings, PackageStore};
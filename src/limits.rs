@@ -0,0 +1,74 @@
+//! The limits requests are held to, so clients can check uploads before sending them.
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::config::{Config, CurrentConfig};
+
+/// What `GET /limits` reports, read from the same configuration the checks use.
+#[derive(Serialize, ToSchema)]
+pub struct Limits {
+    /// Bytes an uploaded file may have, `null` for no limit.
+    max_file_size: Option<usize>,
+    /// Bytes an upload may have in total, `null` for no limit.
+    max_upload_size: Option<usize>,
+    /// Files an upload may have, `null` for no limit.
+    max_files: Option<usize>,
+    /// Bytes the path of an uploaded file may have, normalized to NFC.
+    max_filename_length: usize,
+    /// Bytes a file sent to `/ast` may have.
+    max_ast_size: usize,
+    /// Bytes the sources `?embed_sources=` attaches may have in total.
+    embed_sources_max_size: usize,
+    /// Extensions uploaded files may have, `*` for any.
+    allowed_extensions: Vec<String>,
+    /// Whether uploaded files may have no extension.
+    allow_extensionless: bool,
+    /// Seconds a compile may run unless the request asks otherwise.
+    compile_timeout_seconds: u64,
+    /// Longest `?timeout=` a request may ask for.
+    max_compile_timeout_seconds: u64,
+    /// Pages a document may have unless the request asks otherwise.
+    max_pages: usize,
+    /// Highest `?max_pages=` a request may ask for.
+    max_pages_ceiling: usize,
+    #[schema(value_type = Vec<String>)]
+    output_formats: &'static [&'static str],
+    /// Whether `@preview` and other registry packages can be imported.
+    remote_packages: bool,
+    /// Whether `@local` packages can be imported.
+    local_packages: bool,
+}
+
+impl Limits {
+    fn of(config: &Config) -> Self {
+        let defaults = config.default_options();
+        Self {
+            max_file_size: config.max_file_size,
+            max_upload_size: config.max_upload_size,
+            max_files: config.max_files,
+            max_filename_length: config.max_filename_length,
+            max_ast_size: config.max_ast_size,
+            embed_sources_max_size: config.embed_sources_max_size,
+            allowed_extensions: config.allowed_extensions.clone(),
+            allow_extensionless: config.allow_extensionless,
+            compile_timeout_seconds: defaults.timeout.as_secs(),
+            max_compile_timeout_seconds: config.compile_timeout_ceiling.as_secs(),
+            max_pages: defaults.max_pages,
+            max_pages_ceiling: config.max_pages_ceiling,
+            output_formats: &["pdf"],
+            remote_packages: !config.offline,
+            local_packages: config.local_package_dir.is_some(),
+        }
+    }
+}
+
+#[utoipa::path(tag = "compile", responses((status = 200, description = "The limits requests are held to", body = Limits)))]
+#[get("/limits")]
+async fn limits(config: CurrentConfig) -> HttpResponse {
+    HttpResponse::Ok().json(Limits::of(&config))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(limits);
+}
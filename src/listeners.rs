@@ -0,0 +1,27 @@
+//! TCP listeners for the configured addresses.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Same as actix's own listeners.
+const BACKLOG: i32 = 2048;
+
+/// Listen on every address `address` resolves to.
+pub fn bind(address: &str) -> io::Result<Vec<TcpListener>> {
+    address.to_socket_addrs()?.map(listener).collect()
+}
+
+fn listener(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    // Otherwise `[::]:8080` also claims IPv4 on most systems and `0.0.0.0:8080` can't bind next to it.
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
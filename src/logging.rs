@@ -0,0 +1,117 @@
+//! Structured logs through `tracing`, with a span per request carrying its id.
+//!
+//! The id is taken from an incoming `X-Request-Id` or generated, and echoed on every
+//! response, errors included, so users can quote it.
+
+#[cfg(feature = "otel")]
+mod telemetry;
+
+use std::time::Instant;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+use crate::config::Config;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Incoming ids longer than this are replaced, so clients can't bloat the logs.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Log to stderr, which also keeps stdout free for the compile worker protocol, and
+/// export spans when `OTLP_ENDPOINT` is set.
+///
+/// `RUST_LOG` filters like `info,typstapi=debug` apply, the default is `info`. Fails if the
+/// exporter can't be set up, before anything is logged.
+pub fn init(config: &Config) -> Result<(), String> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let format = match config.log_json {
+        true => { fmt::layer().json().with_writer(std::io::stderr).boxed() }
+        false => { fmt::layer().with_writer(std::io::stderr).boxed() }
+    };
+    let logs = tracing_subscriber::registry().with(filter).with(format);
+
+    #[cfg(feature = "otel")]
+    {
+        let telemetry = match config.otlp_endpoint.as_deref() {
+            Some(endpoint) => {
+                let layer = telemetry::layer(endpoint)
+                    .map_err(|problem| format!("cannot export traces to {endpoint}: {problem}"))?;
+                Some(layer)
+            }
+            None => { None }
+        };
+        logs.with(telemetry).init();
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        if let Some(endpoint) = &config.otlp_endpoint {
+            return Err(format!("OTLP_ENDPOINT is set to {endpoint}, but this server was built without the otel feature"));
+        }
+        logs.init();
+    }
+    Ok(())
+}
+
+/// Export what is left before the process exits.
+pub fn flush() {
+    #[cfg(feature = "otel")]
+    telemetry::flush();
+}
+
+/// Middleware running each request in a span named after its id, for `App::wrap_fn`.
+pub fn trace<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.path(),
+        client = Empty,
+        principal = Empty,
+        files = Empty,
+        input_bytes = Empty,
+        cache_hit = Empty,
+    );
+    #[cfg(feature = "otel")]
+    telemetry::adopt(&span, request.headers());
+    let http_request = request.request().clone();
+    let started = Instant::now();
+    let call = span.in_scope(|| service.call(request));
+
+    Box::pin(async move {
+        let mut response = match call.instrument(span.clone()).await {
+            Ok(response) => { response.map_into_left_body() }
+            Err(error) => { ServiceResponse::from_err(error, http_request).map_into_right_body() }
+        };
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+        }
+
+        let status = response.status().as_u16();
+        let milliseconds = started.elapsed().as_millis() as u64;
+        span.in_scope(|| match response.status().is_server_error() {
+            true => { tracing::error!(status, milliseconds, "request failed") }
+            false => { tracing::info!(status, milliseconds, "request finished") }
+        });
+        Ok(response)
+    })
+}
@@ -0,0 +1,56 @@
+//! Exporting spans over OTLP, continuing the traces callers pass in `traceparent`.
+//!
+//! Spans are batched and sent in the background, so an unreachable collector only loses
+//! the spans, never delays requests.
+
+use actix_web::http::header::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::Tracer;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The layer sending spans to the collector at `endpoint`.
+pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(Resource::new([
+            KeyValue::new("service.name", "typstapi"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(runtime::TokioCurrentThread)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Make `span` part of the trace named by the request's `traceparent`, if it has one.
+pub fn adopt(span: &Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&Headers(headers)));
+    span.set_parent(parent);
+}
+
+/// Send the spans still waiting in the batch.
+pub fn flush() {
+    global::shutdown_tracer_provider();
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
@@ -1,74 +1,237 @@
-mod docker_world;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{App, HttpServer};
+use typstapi::app::{self, AppState};
+use typstapi::config::Config;
+use typstapi::tls::Certificates;
+use typstapi::{compilers, jobs, listeners, logging, metrics, shutdown};
+#[cfg(unix)]
+use typstapi::{reload, systemd, unix_socket};
 
-use std::fs::read;
-use actix_multipart::{Multipart};
-use actix_web::{get, web, App, HttpServer, Responder, error, post};
-use futures_util::StreamExt;
-use crate::docker_world::{DockerWorld, DocumentFile};
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    #[cfg(unix)]
+    if std::env::args().any(|arg| arg == compilers::isolated::WORKER_FLAG) {
+        return compilers::isolated::run_worker();
+    }
 
-#[get("/hello/{name}")]
-async fn greet(name: web::Path<String>) -> impl Responder {
-    format!("Hello {name}!")
-}
+    let config = Config::load();
+    if let Err(problem) = logging::init(&config) {
+        // Nothing can be logged without the subscriber.
+        eprintln!("ERROR: {problem}");
+        std::process::exit(1);
+    }
+    let certificates = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            match Certificates::load(cert, key) {
+                Ok(certificates) => { Some(Arc::new(certificates)) }
+                Err(problem) => {
+                    tracing::error!("cannot serve HTTPS: {problem}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => { None }
+    };
+    let state = AppState::new(config).unwrap_or_else(|problem| {
+        tracing::error!("{problem}");
+        std::process::exit(1);
+    });
+    let config = state.config.current();
+    state.build.log();
+
+    let (warm_up_health, warm_up_compilers, warm_up_config) = (state.health.clone(), state.compilers.clone(), config.clone());
+    let warmed_up =
+        std::thread::spawn(move || warm_up_health.warm_up(warm_up_compilers.newest().as_ref(), &warm_up_config));
 
-#[get("/hello_typst.pdf")]
-async fn typst_example() -> impl Responder {
+    let preload_packages = state.packages.clone();
+    let preload_config = config.clone();
+    std::thread::spawn(move || preload_packages.preload(&preload_config.preload_packages));
 
-    let example = DocumentFile::new(
-        "example.typ",
-        read("example.typ").expect("Failed at file reading")
-        );
+    let collected_uploads = state.uploads.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60));
+        collected_uploads.collect_garbage();
+    });
+    let collected_jobs = state.jobs.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60));
+        collected_jobs.collect_garbage();
+    });
+    let cancelled_jobs = state.jobs.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(2));
+        cancelled_jobs.follow_cancellations();
+    });
+    actix_web::rt::spawn(jobs::work(
+        state.jobs.clone(),
+        state.compilers.clone(),
+        state.slots.clone(),
+        state.metrics.clone(),
+        state.cache.clone(),
+        state.webhooks.clone(),
+        state.config.clone(),
+        state.health.clone(),
+    ));
+    let (pruned_projects, pruning_config) = (state.projects.clone(), state.config.clone());
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60 * 60));
+        pruned_projects.prune_revisions(&pruning_config.current());
+    });
+    let (expired_projects, expiry_config, expiry_metrics) =
+        (state.projects.clone(), state.config.clone(), state.metrics.clone());
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(5 * 60));
+        expired_projects.expire(&expiry_config.current(), &expiry_metrics);
+    });
+    let measured_packages = state.packages.clone();
+    std::thread::spawn(move || loop {
+        measured_packages.measure();
+        std::thread::sleep(Duration::from_secs(10 * 60));
+    });
+    let pruned_cache = state.cache.clone();
+    std::thread::spawn(move || loop {
+        pruned_cache.prune();
+        std::thread::sleep(Duration::from_secs(10 * 60));
+    });
 
-    let compiled = DockerWorld::new(example,vec! [], None).compile();
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload::on_hangup(reload::Reloadable {
+        config: state.config.clone(),
+        fonts: state.fonts.clone(),
+        slots: state.slots.clone(),
+        rate_limiter: state.rate_limiter.clone(),
+        certificates: certificates.clone(),
+    }));
 
-    match compiled {
-        Ok(data) => { Ok(data) }
-        Err(error) => { Err(error::ErrorBadRequest(error)) }
+    if let Some(address) = &config.metrics_listen {
+        let (metrics, slots, fonts, cache, quotas) =
+            (state.metrics.clone(), state.slots.clone(), state.fonts.clone(), state.cache.clone(), state.quotas.clone());
+        let internal = HttpServer::new(move || {
+            App::new()
+                .app_data(metrics.clone())
+                .app_data(slots.clone())
+                .app_data(fonts.clone())
+                .app_data(cache.clone())
+                .app_data(quotas.clone())
+                .configure(metrics::configure)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(address.as_str());
+        match internal {
+            Ok(internal) => {
+                tracing::info!("Serving /metrics on http://{address}");
+                actix_web::rt::spawn(internal.run());
+            }
+            Err(problem) => {
+                tracing::error!("could not bind {address} for metrics: {problem}");
+                std::process::exit(1);
+            }
+        }
     }
-}
 
-#[post("/compile")]
-async fn typst_compile(mut payload: Multipart) -> impl Responder {
-    let mut documents = vec![];
+    let (workers, blocking_threads, max_connections) =
+        (config.workers, config.blocking_threads, config.max_connections);
+    let (draining_health, draining_slots, ready_health) = (state.health.clone(), state.slots.clone(), state.health.clone());
+    tracing::info!(
+        "Starting {workers} workers with up to {blocking_threads} blocking threads \
+         and {max_connections} connections each"
+    );
 
-    while let Some(item) = payload.next().await {
-        let mut data= vec![];
-        let filename: String;
+    let server = HttpServer::new(move || app::app(state.clone()))
+        .workers(workers)
+        .worker_max_blocking_threads(blocking_threads)
+        .max_connections(max_connections)
+        .client_request_timeout(config.client_request_timeout)
+        .client_disconnect_timeout(config.client_disconnect_timeout)
+        .keep_alive(config.keep_alive)
+        .shutdown_timeout(config.shutdown_grace_period.as_secs())
+        .disable_signals();
+    #[cfg(unix)]
+    let server = server.on_connect(unix_socket::on_connect);
 
-        match item {
-            Err(problem) => { return Err(error::ErrorBadRequest(problem)) }
-            Ok(mut field) => {
-                filename = field.name().into();
-                while let Some(chunk) = field.next().await {
-                    match chunk {
-                        Ok(bytes) => {
-                            data.extend::<Vec<u8>>(bytes.into());
-                        }
-                        Err(_) => {}
-                    }
+    let scheme = match certificates.is_some() {
+        true => { "https" }
+        false => { "http" }
+    };
+    let mut server = server;
+    let mut socket_activated = false;
+    #[cfg(unix)]
+    for socket in systemd::activated_sockets().unwrap_or_else(|problem| {
+        tracing::error!("could not take over the sockets from systemd: {problem}");
+        std::process::exit(1);
+    }) {
+        socket_activated = true;
+        let bound = match socket {
+            systemd::Activated::Tcp(listener) => {
+                match &certificates {
+                    Some(certificates) => { server.listen_rustls_0_21(listener, certificates.clone().server_config()) }
+                    None => { server.listen(listener) }
                 }
             }
-        }
-        documents.push(DocumentFile::new(filename.as_str(), data));
+            systemd::Activated::Unix(listener) => { server.listen_uds(listener) }
+        };
+        server = bound.unwrap_or_else(|problem| {
+            tracing::error!("could not listen on a socket from systemd: {problem}");
+            std::process::exit(1);
+        });
+    }
+    if socket_activated {
+        tracing::info!("Listening on the sockets systemd passed, ignoring LISTEN and UNIX_SOCKET");
+    }
+    for address in config.listen.iter().filter(|_| !config.unix_socket_only && !socket_activated) {
+        let bound = listeners::bind(address).and_then(|listeners| {
+            listeners.into_iter().try_fold(server, |server, listener| match &certificates {
+                Some(certificates) => { server.listen_rustls_0_21(listener, certificates.clone().server_config()) }
+                None => { server.listen(listener) }
+            })
+        });
+        server = bound.unwrap_or_else(|problem| {
+            tracing::error!("could not bind {address}: {problem}");
+            std::process::exit(1);
+        });
+    }
+    // With port 0 the OS picked the port, so this is the only place to learn it.
+    for address in server.addrs() {
+        tracing::info!("Listening on {scheme}://{address}");
     }
 
-    let compiled = DockerWorld::new(documents.remove(0),documents, None).compile();
+    #[cfg(unix)]
+    if let Some(path) = config.unix_socket.as_ref().filter(|_| !socket_activated) {
+        let bound = unix_socket::remove_stale(path)
+            .and_then(|()| server.bind_uds(path))
+            .and_then(|bound| unix_socket::set_mode(path, config.unix_socket_mode).map(|()| bound));
+        server = bound.unwrap_or_else(|problem| {
+            tracing::error!("could not listen on {}: {problem}", path.display());
+            std::process::exit(1);
+        });
+        tracing::info!("Listening on unix:{}", path.display());
+    }
 
-    match compiled {
-        Ok(data) => { Ok(data) }
-        Err(error) => { Err(error::ErrorBadRequest(error)) }
+    #[cfg(unix)]
+    {
+        std::thread::spawn(move || {
+            let _ = warmed_up.join();
+            systemd::ready(&ready_health);
+        });
+        actix_web::rt::spawn(systemd::watchdog());
     }
-}
+    #[cfg(not(unix))]
+    let _ = (warmed_up, ready_health);
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new()
-            .service(greet)
-            .service(typst_example)
-            .service(typst_compile)
-    })
-    .bind(("127.0.0.1", 80)).expect("Could not bind")
-    .run()
-    .await
+    let server = server.run();
+    actix_web::rt::spawn(shutdown::on_signal(
+        server.handle(),
+        draining_health,
+        draining_slots,
+        config.shutdown_grace_period,
+    ));
+    let stopped = server.await;
+    logging::flush();
+    #[cfg(unix)]
+    if let Some(path) = config.unix_socket.as_ref().filter(|_| !socket_activated) {
+        let _ = std::fs::remove_file(path);
+    }
+    stopped
 }
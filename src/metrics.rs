@@ -0,0 +1,297 @@
+//! Counters accumulated since startup, exposed in the Prometheus text format on `/metrics`.
+//!
+//! Labels only take values from small fixed sets, like route patterns, status codes and the
+//! names in the keys file, so the number of series doesn't grow with the requests.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::{get, web, Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use crate::auth::{Principal, Tenant};
+use crate::cache::CompileCache;
+use crate::docker_world::{CompileError, FontLibrary, Warning, WarningCategory};
+use crate::quotas::Quotas;
+use crate::slots::CompileSlots;
+
+/// Upper bounds in seconds of the compile duration buckets.
+const DURATION_BUCKETS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Methods counted under their own name, any other is counted as `other`.
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+#[derive(Default)]
+pub struct Metrics {
+    pub compiles_with_warnings: AtomicU64,
+    pub unknown_font_warnings: AtomicU64,
+    pub deprecated_warnings: AtomicU64,
+    pub other_warnings: AtomicU64,
+    /// Compiles whose client went away before they finished, which aren't errors.
+    pub abandoned_compiles: AtomicU64,
+    pub compiles: AtomicU64,
+    /// Compiles that ended in an error, timeouts included.
+    pub failed_compiles: AtomicU64,
+    pub timed_out_compiles: AtomicU64,
+    /// Stored projects removed when their TTL ran out, and the bytes of files and revisions that freed.
+    pub expired_projects: AtomicU64,
+    pub expired_project_bytes: AtomicU64,
+    /// Compile durations by outcome and output format.
+    compile_durations: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+    /// Responses by route pattern, method, status code, the key's name and its tenant.
+    requests: Mutex<BTreeMap<(String, &'static str, u16, Option<(String, String)>), u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Shadow compiles by outcome, and what the mismatching ones differed in, see `shadow`.
+    shadow_compiles: Mutex<BTreeMap<&'static str, u64>>,
+    shadow_differences: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn record_abandoned(&self) {
+        self.abandoned_compiles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_warnings(&self, warnings: &[Warning]) {
+        if warnings.is_empty() {
+            return;
+        }
+
+        self.compiles_with_warnings.fetch_add(1, Ordering::Relaxed);
+        for warning in warnings {
+            let counter = match warning.category {
+                WarningCategory::UnknownFont => { &self.unknown_font_warnings }
+                WarningCategory::Deprecated => { &self.deprecated_warnings }
+                WarningCategory::Other => { &self.other_warnings }
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a compile that ran for `duration` into the histogram of its outcome.
+    pub fn record_compile<T>(&self, format: &'static str, outcome: &Result<T, CompileError>, duration: Duration) {
+        self.compiles.fetch_add(1, Ordering::Relaxed);
+        let outcome = match outcome {
+            Ok(_) => { "success" }
+            Err(problem) => {
+                self.failed_compiles.fetch_add(1, Ordering::Relaxed);
+                if let CompileError::TimedOut { .. } = problem {
+                    self.timed_out_compiles.fetch_add(1, Ordering::Relaxed);
+                }
+                problem.kind()
+            }
+        };
+        let seconds = duration.as_secs_f64();
+        let mut durations = self.compile_durations.lock().unwrap();
+        let histogram = durations.entry((outcome, format)).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn record_request(
+        &self,
+        route: String,
+        method: &'static str,
+        status: u16,
+        key: Option<(String, String)>,
+        bytes_in: u64,
+        bytes_out: u64,
+    ) {
+        *self.requests.lock().unwrap().entry((route, method, status, key)).or_default() += 1;
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    /// Count a shadow compile ending in `outcome`, which differed from its request's in `differences`.
+    pub fn record_shadow(&self, outcome: &'static str, differences: &[&'static str]) {
+        *self.shadow_compiles.lock().unwrap().entry(outcome).or_default() += 1;
+        let mut counts = self.shadow_differences.lock().unwrap();
+        for difference in differences {
+            *counts.entry(*difference).or_default() += 1;
+        }
+    }
+
+    /// Responses to requests authenticated with each key from the keys file.
+    pub fn requests_by_key(&self) -> BTreeMap<String, u64> {
+        let mut by_key = BTreeMap::new();
+        for ((_, _, _, key), count) in self.requests.lock().unwrap().iter() {
+            if let Some((key, _)) = key {
+                *by_key.entry(key.clone()).or_default() += count;
+            }
+        }
+        by_key
+    }
+
+    fn render(&self, slots: &CompileSlots, fonts: &FontLibrary, cache: &CompileCache, quotas: &Quotas) -> String {
+        let mut out = String::new();
+
+        family(&mut out, "typstapi_compile_duration_seconds", "histogram", "How long compiles ran");
+        for ((outcome, format), histogram) in self.compile_durations.lock().unwrap().iter() {
+            let labels = format!("outcome=\"{outcome}\",format=\"{format}\"");
+            for (count, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                let _ = writeln!(out, "typstapi_compile_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}");
+            }
+            let _ = writeln!(out, "typstapi_compile_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "typstapi_compile_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "typstapi_compile_duration_seconds_count{{{labels}}} {}", histogram.count);
+        }
+
+        family(&mut out, "typstapi_requests_total", "counter", "Responses by route, method, status, key and tenant");
+        for ((route, method, status, key), count) in self.requests.lock().unwrap().iter() {
+            let key = key.as_ref()
+                .map(|(key, tenant)| format!(",key=\"{}\",tenant=\"{}\"", escape(key), escape(tenant)))
+                .unwrap_or_default();
+            let _ = writeln!(out, "typstapi_requests_total{{route=\"{route}\",method=\"{method}\",status=\"{status}\"{key}}} {count}");
+        }
+        counter(&mut out, "typstapi_received_bytes_total", "Request body bytes received", self.bytes_in.load(Ordering::Relaxed));
+        counter(&mut out, "typstapi_sent_bytes_total", "Response body bytes sent", self.bytes_out.load(Ordering::Relaxed));
+
+        gauge(&mut out, "typstapi_compiles_in_flight", "Compiles holding a slot", slots.in_use() as u64);
+        gauge(&mut out, "typstapi_compile_queue_depth", "Requests waiting for a compile slot", slots.queued() as u64);
+        gauge(&mut out, "typstapi_compile_slots", "Compiles allowed to run at the same time", slots.limit() as u64);
+
+        let fonts = fonts.current();
+        gauge(&mut out, "typstapi_fonts", "Font faces found", fonts.count() as u64);
+        gauge(&mut out, "typstapi_fonts_loaded", "Font faces read and parsed so far", fonts.loaded() as u64);
+
+        let quotas = quotas.measure();
+        family(&mut out, "typstapi_quota_used", "gauge", "Usage of each tenant's quotas in the current hour or day");
+        for (tenant, measured) in &quotas {
+            for (quota, measured) in measured {
+                let _ = writeln!(out, "typstapi_quota_used{{tenant=\"{}\",quota=\"{quota}\"}} {}", escape(tenant), measured.used);
+            }
+        }
+        family(&mut out, "typstapi_quota_limit", "gauge", "Limits of each tenant's quotas");
+        for (tenant, measured) in &quotas {
+            for (quota, limit) in measured.iter().filter_map(|(quota, measured)| Some((quota, measured.limit?))) {
+                let _ = writeln!(out, "typstapi_quota_limit{{tenant=\"{}\",quota=\"{quota}\"}} {limit}", escape(tenant));
+            }
+        }
+
+        let (hits, misses) = cache.hits_and_misses();
+        counter(&mut out, "typstapi_compile_cache_hits_total", "Compiles answered from the cache", hits);
+        counter(&mut out, "typstapi_compile_cache_misses_total", "Compiles not found in the cache", misses);
+        gauge(&mut out, "typstapi_compile_cache_memory_bytes", "Bytes of PDFs cached in memory", cache.memory_size() as u64);
+
+        family(&mut out, "typstapi_warnings_total", "counter", "Warnings typst reported by category");
+        for (category, total) in [
+            ("unknown_font", &self.unknown_font_warnings),
+            ("deprecated", &self.deprecated_warnings),
+            ("other", &self.other_warnings),
+        ] {
+            let _ = writeln!(out, "typstapi_warnings_total{{category=\"{category}\"}} {}", total.load(Ordering::Relaxed));
+        }
+        counter(&mut out, "typstapi_compiles_with_warnings_total", "Compiles with at least one warning",
+            self.compiles_with_warnings.load(Ordering::Relaxed));
+        counter(&mut out, "typstapi_abandoned_compiles_total", "Compiles whose client went away first",
+            self.abandoned_compiles.load(Ordering::Relaxed));
+        counter(&mut out, "typstapi_expired_projects_total", "Stored projects removed when their TTL ran out",
+            self.expired_projects.load(Ordering::Relaxed));
+        counter(&mut out, "typstapi_expired_project_bytes_total", "Bytes of files and revisions of expired projects",
+            self.expired_project_bytes.load(Ordering::Relaxed));
+
+        family(&mut out, "typstapi_shadow_compiles_total", "counter", "Shadow compiles by outcome");
+        for (outcome, count) in self.shadow_compiles.lock().unwrap().iter() {
+            let _ = writeln!(out, "typstapi_shadow_compiles_total{{outcome=\"{outcome}\"}} {count}");
+        }
+        family(&mut out, "typstapi_shadow_differences_total", "counter", "What mismatching shadow compiles differed in");
+        for (difference, count) in self.shadow_differences.lock().unwrap().iter() {
+            let _ = writeln!(out, "typstapi_shadow_differences_total{{difference=\"{difference}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Escape a label value that comes from configuration rather than the code.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    family(out, name, "counter", help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    family(out, name, "gauge", help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Middleware counting requests and their bytes, for `App::wrap_fn`.
+pub fn track<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let metrics = request.app_data::<web::Data<Metrics>>().cloned();
+    let bytes_in = request.headers().get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let call = service.call(request);
+    Box::pin(async move {
+        let response = call.await?;
+        if let Some(metrics) = metrics {
+            // Unmatched paths and unusual methods share one label, so scanners can't add series.
+            let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".into());
+            let method = METHODS.into_iter()
+                .find(|method| *method == response.request().method().as_str())
+                .unwrap_or("other");
+            let bytes_out = match response.response().body().size() {
+                BodySize::Sized(size) => { size }
+                BodySize::None | BodySize::Stream => { 0 }
+            };
+            let extensions = response.request().extensions();
+            let key = extensions.get::<Principal>().zip(extensions.get::<Tenant>())
+                .map(|(principal, tenant)| (principal.0.clone(), tenant.0.clone()));
+            drop(extensions);
+            metrics.record_request(route, method, response.status().as_u16(), key, bytes_in, bytes_out);
+        }
+        Ok(response)
+    })
+}
+
+#[utoipa::path(
+    tag = "operations",
+    responses((status = 200, description = "Prometheus text format", body = String, content_type = "text/plain; version=0.0.4")),
+)]
+#[get("/metrics")]
+async fn metrics(
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    fonts: web::Data<FontLibrary>,
+    cache: web::Data<CompileCache>,
+    quotas: web::Data<Quotas>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&slots, &fonts, &cache, &quotas))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
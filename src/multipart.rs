@@ -0,0 +1,595 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+use std::time::Duration;
+use actix_multipart::Multipart;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, Error, HttpRequest};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use flate2::read::GzDecoder;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+use unicode_normalization::UnicodeNormalization;
+use url::form_urlencoded;
+use utoipa::ToSchema;
+use crate::config::Config;
+use crate::docker_world::{file_id, DocumentFile, Warning, WarningCategory};
+use crate::errors::Code;
+use crate::sniff;
+use crate::uploads::{self, Uploads, REFERENCE_TYPE};
+
+/// Longest upload id a part referring to one may hold.
+const MAX_REFERENCE_LENGTH: usize = 64;
+
+/// What the `source` field of a form, or a raw body, is compiled as.
+const FORM_MAIN: &str = "main.typ";
+
+/// Prefix of the form fields setting `sys.inputs`, as in `input.name=World`.
+const INPUT_PREFIX: &str = "input.";
+
+/// The first bytes of a gzip stream, telling a `.tar.gz` from a plain tar.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The body of a `/compile` request, however it was encoded.
+pub struct Submission {
+    /// The main file first.
+    pub documents: Vec<DocumentFile>,
+    /// Values for `sys.inputs`.
+    pub inputs: BTreeMap<String, String>,
+    /// About what the body had that was ignored.
+    pub warnings: Vec<Warning>,
+}
+
+/// How a request body is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    /// A part per file, see `read_documents`.
+    Multipart,
+    /// A single file in the `source` field of a form, see `read_form`.
+    Form,
+    /// A `JsonUpload`, see `read_json`.
+    Json,
+    /// The main document itself, see `read_raw`.
+    Raw,
+    /// A tar of the files, gzipped or not, see `read_archive`.
+    Archive,
+}
+
+impl Encoding {
+    /// The encoding `content_type` names, `None` for those that aren't read.
+    fn of(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "multipart/form-data" => { Some(Encoding::Multipart) }
+            "application/x-www-form-urlencoded" => { Some(Encoding::Form) }
+            "application/json" => { Some(Encoding::Json) }
+            "text/plain" | "text/vnd.typst" | "application/octet-stream" => { Some(Encoding::Raw) }
+            "application/x-tar" | "application/gzip" | "application/x-gzip" | "application/x-gtar" => { Some(Encoding::Archive) }
+            _ => { None }
+        }
+    }
+}
+
+/// Read a `/compile` body in whichever encoding its `Content-Type` names, every limit of
+/// `read_documents` applying.
+pub async fn read_submission(request: &HttpRequest, payload: web::Payload, config: &Config) -> Result<Submission, Error> {
+    let content_type = request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    match Encoding::of(content_type) {
+        Some(Encoding::Multipart) => {
+            let documents = read_documents(request, Multipart::new(request.headers(), payload), config).await?;
+            Ok(Submission { documents, inputs: BTreeMap::new(), warnings: Vec::new() })
+        }
+        Some(Encoding::Form) => { read_form(payload, config).await }
+        Some(Encoding::Json) => { read_json(payload, config).await }
+        Some(Encoding::Raw) => { read_raw(payload, config).await }
+        Some(Encoding::Archive) => { read_archive(payload, config).await }
+        None => {
+            Err(Code::UnsupportedMediaType.error(
+                "Send the files as multipart/form-data, application/json or a tar archive, or the document's \
+                 source as application/x-www-form-urlencoded or text/plain",
+            ))
+        }
+    }
+}
+
+/// The files of a compile as JSON, for clients that can't build multipart bodies.
+#[derive(Deserialize, ToSchema)]
+pub struct JsonUpload {
+    /// The main file first.
+    files: Vec<JsonFile>,
+    /// Values the document reads from `sys.inputs`.
+    #[serde(default)]
+    inputs: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct JsonFile {
+    path: String,
+    content: String,
+    /// Whether `content` is base64, for binary files.
+    #[serde(default)]
+    base64: bool,
+}
+
+/// Read a `JsonUpload`.
+///
+/// The size limits apply to the files once decoded, the body may be twice as large since
+/// base64 takes four bytes for every three.
+async fn read_json(payload: web::Payload, config: &Config) -> Result<Submission, Error> {
+    let max_body = config.max_upload_size.map(|max| max.saturating_mul(2));
+    let body = read_body(payload, max_body, Code::UploadTooLarge, config).await?;
+    let upload: JsonUpload = serde_json::from_slice(&body).map_err(|problem| Code::InvalidJson.error(problem))?;
+    if upload.files.is_empty() {
+        return Err(Code::MissingMain.error("Send at least the main file"));
+    }
+    let documents = decode_files(upload.files, config)?;
+    Ok(Submission { documents, inputs: upload.inputs, warnings: Vec::new() })
+}
+
+/// The `DocumentFile`s of files sent as JSON, every limit of `read_documents` applying.
+pub fn decode_files(files: Vec<JsonFile>, config: &Config) -> Result<Vec<DocumentFile>, Error> {
+    if let Some(max) = config.max_files.filter(|max| files.len() > *max) {
+        return Err(Code::TooManyFiles.error(format!("An upload may have at most {max} files")));
+    }
+    let mut documents = Vec::with_capacity(files.len());
+    let mut total = 0;
+    for file in files {
+        valid_name(&file.path, config.max_filename_length).map_err(|problem| Code::InvalidFilename.error(problem))?;
+        allowed(&file.path, &config.allowed_extensions, config.allow_extensionless).map_err(|problem| Code::FileTypeNotAllowed.error(problem))?;
+        let data = match file.base64 {
+            true => {
+                STANDARD.decode(&file.content)
+                    .map_err(|problem| Code::InvalidBase64.error(format!("{} is not valid base64: {problem}", file.path)))?
+            }
+            false => { file.content.into_bytes() }
+        };
+        total += data.len();
+        if let Some(max) = config.max_file_size.filter(|max| data.len() > *max) {
+            return Err(Code::FileTooLarge.error(format!("{} is larger than {max} bytes", file.path)));
+        }
+        if let Some(max) = config.max_upload_size.filter(|max| total > *max) {
+            return Err(Code::UploadTooLarge.error(format!("The upload is larger than {max} bytes")));
+        }
+        let document = DocumentFile::new(&file.path, data);
+        check_contents(&file.path, &document)?;
+        documents.push(document);
+    }
+    Ok(documents)
+}
+
+/// Read a body that is the main document's source, which has to be UTF-8.
+async fn read_raw(payload: web::Payload, config: &Config) -> Result<Submission, Error> {
+    let body = read_body(payload, config.max_file_size, Code::FileTooLarge, config).await?;
+    if std::str::from_utf8(&body).is_err() {
+        return Err(Code::InvalidBody.error("The document isn't UTF-8"));
+    }
+    Ok(Submission { documents: vec![DocumentFile::new(FORM_MAIN, body)], inputs: BTreeMap::new(), warnings: Vec::new() })
+}
+
+/// Read a tar archive of the files, gzipped or not, with the main file at `main.typ`.
+///
+/// Every limit of `read_documents` applies to the unpacked files, the archive itself may be
+/// as large as the upload. Only plain files and directories may be in it, the paths of the
+/// files staying inside the archive.
+async fn read_archive(payload: web::Payload, config: &Config) -> Result<Submission, Error> {
+    let body = read_body(payload, config.max_upload_size, Code::UploadTooLarge, config).await?;
+    let unreadable = |problem: std::io::Error| Code::InvalidBody.error(format!("The archive can't be read: {problem}"));
+    let reader: Box<dyn Read> = match body.starts_with(&GZIP_MAGIC) {
+        true => { Box::new(GzDecoder::new(body.as_slice())) }
+        false => { Box::new(body.as_slice()) }
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut documents: Vec<DocumentFile> = Vec::new();
+    let mut total = 0;
+    for entry in archive.entries().map_err(unreadable)? {
+        let mut entry = entry.map_err(unreadable)?;
+        let path = archive_path(&entry.path().map_err(unreadable)?)
+            .map_err(|problem| Code::InvalidFilename.error(problem))?;
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => { continue }
+            kind if kind.is_pax_global_extensions() => { continue }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {}
+            _ => { return Err(Code::InvalidFileContent.error(format!("{path} is no plain file, which an archive may only hold"))) }
+        }
+        if path.is_empty() {
+            return Err(Code::InvalidFilename.error("A file in the archive has no name"));
+        }
+        if let Some(max) = config.max_files.filter(|max| documents.len() >= *max) {
+            return Err(Code::TooManyFiles.error(format!("An upload may have at most {max} files")));
+        }
+        valid_name(&path, config.max_filename_length).map_err(|problem| Code::InvalidFilename.error(problem))?;
+        allowed(&path, &config.allowed_extensions, config.allow_extensionless).map_err(|problem| Code::FileTypeNotAllowed.error(problem))?;
+        if documents.iter().any(|document| document.name == file_id(&path)) {
+            return Err(Code::DuplicateFile.error(format!("{path} is in the archive twice")));
+        }
+
+        // The header's size can't be trusted, so no more than the limit is unpacked.
+        let mut data = Vec::new();
+        let limit = config.max_file_size.map_or(u64::MAX, |max| max as u64 + 1);
+        (&mut entry).take(limit).read_to_end(&mut data).map_err(unreadable)?;
+        total += data.len();
+        if let Some(max) = config.max_file_size.filter(|max| data.len() > *max) {
+            return Err(Code::FileTooLarge.error(format!("{path} is larger than {max} bytes")));
+        }
+        if let Some(max) = config.max_upload_size.filter(|max| total > *max) {
+            return Err(Code::UploadTooLarge.error(format!("The unpacked archive is larger than {max} bytes")));
+        }
+        let document = DocumentFile::new(&path, data);
+        check_contents(&path, &document)?;
+        documents.push(document);
+    }
+
+    let main = documents.iter().position(|document| document.name == file_id(FORM_MAIN))
+        .ok_or_else(|| Code::MissingMain.error(format!("The archive has no {FORM_MAIN} at its root")))?;
+    let main = documents.remove(main);
+    documents.insert(0, main);
+    Ok(Submission { documents, inputs: BTreeMap::new(), warnings: Vec::new() })
+}
+
+/// The upload path of an archive entry, refused if it leaves the archive.
+fn archive_path(path: &Path) -> Result<String, String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => { parts.push(part.to_string_lossy()) }
+            Component::CurDir => {}
+            _ => { return Err(format!("{} leaves the archive", path.display())) }
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// The whole body, refused with `too_large` once it grows beyond `max` bytes.
+async fn read_body(mut payload: web::Payload, max: Option<usize>, too_large: Code, config: &Config) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = next(&mut payload, config.upload_idle_timeout).await? {
+        body.extend_from_slice(&chunk.map_err(|problem| Code::InvalidBody.error(problem))?);
+        if let Some(max) = max.filter(|max| body.len() > *max) {
+            return Err(too_large.error(format!("The body is larger than {max} bytes")));
+        }
+    }
+    Ok(body)
+}
+
+/// Read a form with the document in its `source` field and `sys.inputs` in `input.*` fields,
+/// the others ignored with a warning.
+///
+/// The size limits apply to `source` once decoded, the body may be three times as large
+/// since percent-encoding takes at most three bytes for each.
+async fn read_form(payload: web::Payload, config: &Config) -> Result<Submission, Error> {
+    let max_body = config.max_upload_size.map(|max| max.saturating_mul(3));
+    let body = read_body(payload, max_body, Code::UploadTooLarge, config).await?;
+
+    let mut source = None;
+    let mut inputs = BTreeMap::new();
+    let mut warnings = Vec::new();
+    for (name, value) in form_urlencoded::parse(&body) {
+        match name.strip_prefix(INPUT_PREFIX) {
+            Some(input) if !input.is_empty() => { inputs.insert(input.to_string(), value.into_owned()); }
+            _ if name == "source" => { source = Some(value.into_owned()) }
+            _ => {
+                warnings.push(Warning {
+                    category: WarningCategory::Other,
+                    message: format!("The form field {name} was ignored, only source and {INPUT_PREFIX}* are read").into(),
+                });
+            }
+        }
+    }
+
+    let source = source.ok_or_else(|| Code::MissingMain.error("Send the document in the form's source field"))?;
+    if let Some(max) = config.max_file_size.filter(|max| source.len() > *max) {
+        return Err(Code::FileTooLarge.error(format!("{FORM_MAIN} is larger than {max} bytes")));
+    }
+    if let Some(max) = config.max_upload_size.filter(|max| source.len() > *max) {
+        return Err(Code::UploadTooLarge.error(format!("The upload is larger than {max} bytes")));
+    }
+    Ok(Submission { documents: vec![DocumentFile::new(FORM_MAIN, source.into_bytes())], inputs, warnings })
+}
+
+/// A single file sent as the body, or as the only part of a multipart upload, which has to
+/// be UTF-8.
+pub async fn read_source(request: &HttpRequest, mut payload: web::Payload, config: &Config) -> Result<String, Error> {
+    let content_type = request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let bytes = match Encoding::of(content_type) {
+        Some(Encoding::Multipart) => {
+            let mut documents = read_documents(request, Multipart::new(request.headers(), payload), config).await?;
+            if documents.len() > 1 {
+                return Err(Code::TooManyFiles.error("Upload just the one file"));
+            }
+            let document = documents.pop().ok_or_else(|| Code::MissingMain.error("Upload the file"))?;
+            document.data.load().map_err(|problem| Code::Internal.error(problem))?.to_vec()
+        }
+        _ => {
+            let mut body = Vec::new();
+            while let Some(chunk) = next(&mut payload, config.upload_idle_timeout).await? {
+                body.extend_from_slice(&chunk.map_err(|problem| Code::InvalidBody.error(problem))?);
+                if let Some(max) = config.max_file_size.filter(|max| body.len() > *max) {
+                    return Err(Code::FileTooLarge.error(format!("The file is larger than {max} bytes")));
+                }
+            }
+            body
+        }
+    };
+    String::from_utf8(bytes).map_err(|_| Code::InvalidBody.error("The file isn't UTF-8"))
+}
+
+/// Read every part of a multipart upload into a `DocumentFile` named after the part.
+///
+/// Parts growing beyond the configured spool threshold continue into a temporary file
+/// instead of memory. Those are deleted when their `DocumentFile` is dropped, which also
+/// happens if the request fails or panics halfway through.
+///
+/// An upload that stalls for the configured idle timeout is answered with a 408, so dead
+/// connections don't hold on to what they sent so far. One beyond the configured file
+/// size, upload size or file count gets a 413 as soon as it crosses the limit, and a part
+/// with an extension that isn't allowed gets a 400 before any of it is read. So does an
+/// image whose contents don't match its extension, see `sniff`.
+///
+/// Parts of type `application/vnd.typstapi.upload` stand for a finished resumable upload,
+/// see `uploads`.
+#[tracing::instrument(name = "read_multipart", skip_all)]
+pub async fn read_documents(request: &HttpRequest, mut payload: Multipart, config: &Config) -> Result<Vec<DocumentFile>, Error> {
+    let mut documents = vec![];
+    let mut total = 0;
+
+    while let Some(item) = next(&mut payload, config.upload_idle_timeout).await? {
+        let mut data = vec![];
+        let mut spooled: Option<NamedTempFile> = None;
+        let mut size = 0;
+        let filename: String;
+
+        match item {
+            Err(problem) => { return Err(Code::InvalidMultipart.error(problem)) }
+            Ok(mut field) => {
+                if let Some(max) = config.max_files.filter(|max| documents.len() >= *max) {
+                    return Err(Code::TooManyFiles.error(format!("An upload may have at most {max} files")));
+                }
+                filename = field.name().into();
+                valid_name(&filename, config.max_filename_length).map_err(|problem| Code::InvalidFilename.error(problem))?;
+                allowed(&filename, &config.allowed_extensions, config.allow_extensionless).map_err(|problem| Code::FileTypeNotAllowed.error(problem))?;
+                if field.content_type().is_some_and(|mime| mime.essence_str() == REFERENCE_TYPE) {
+                    let mut id = vec![];
+                    while let Some(chunk) = next(&mut field, config.upload_idle_timeout).await? {
+                        id.extend_from_slice(&chunk.map_err(|problem| Code::InvalidMultipart.error(problem))?);
+                        if id.len() > MAX_REFERENCE_LENGTH {
+                            return Err(Code::UnknownUpload.error(format!("{filename} must hold just the id of an upload")));
+                        }
+                    }
+                    let uploads = request.app_data::<web::Data<Uploads>>().expect("The Uploads are registered");
+                    let owner = uploads::owner(request);
+                    let document = uploads.file(String::from_utf8_lossy(&id).trim(), &filename, owner.as_deref())?;
+                    check_contents(&filename, &document)?;
+                    documents.push(document);
+                    continue;
+                }
+                while let Some(chunk) = next(&mut field, config.upload_idle_timeout).await? {
+                    match chunk {
+                        Ok(bytes) => {
+                            size += bytes.len();
+                            total += bytes.len();
+                            if let Some(max) = config.max_file_size.filter(|max| size > *max) {
+                                return Err(Code::FileTooLarge.error(format!("{filename} is larger than {max} bytes")));
+                            }
+                            if let Some(max) = config.max_upload_size.filter(|max| total > *max) {
+                                return Err(Code::UploadTooLarge.error(format!("The upload is larger than {max} bytes")));
+                            }
+                            if spooled.is_none() && data.len() + bytes.len() > config.spool_threshold {
+                                let mut file = NamedTempFile::new_in(&config.spool_dir)
+                                    .map_err(|problem| Code::Internal.error(problem))?;
+                                file.write_all(&data).map_err(|problem| Code::Internal.error(problem))?;
+                                data = vec![];
+                                spooled = Some(file);
+                            }
+                            match &mut spooled {
+                                Some(file) => { file.write_all(&bytes).map_err(|problem| Code::Internal.error(problem))?; }
+                                None => { data.extend::<Vec<u8>>(bytes.into()); }
+                            }
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+
+        let document = match spooled {
+            Some(file) => { DocumentFile::spooled(filename.as_str(), file) }
+            None => { DocumentFile::new(filename.as_str(), data) }
+        };
+        check_contents(&filename, &document)?;
+        documents.push(document);
+    }
+
+    Ok(documents)
+}
+
+/// Refuse an image whose contents don't match its name, see `sniff`.
+fn check_contents(filename: &str, document: &DocumentFile) -> Result<(), Error> {
+    if sniff::applies(filename) {
+        let data = document.data.load().map_err(|problem| Code::Internal.error(problem))?;
+        sniff::check(filename, &data).map_err(|problem| Code::InvalidFileContent.error(problem))?;
+    }
+    Ok(())
+}
+
+/// Whether `name` may be the path of an uploaded file: without control characters, and no
+/// longer than `max_length` bytes once normalized like `docker_world::file_id` does.
+pub fn valid_name(name: &str, max_length: usize) -> Result<(), String> {
+    if name.chars().any(char::is_control) {
+        return Err(format!("{} has control characters, which file names may not have", name.escape_debug()));
+    }
+    if name.nfc().map(char::len_utf8).sum::<usize>() > max_length {
+        return Err(format!("A file name is longer than {max_length} bytes"));
+    }
+    Ok(())
+}
+
+/// Whether a file named `name` may be uploaded, by its extension, see `Config::allowed_extensions`.
+pub fn allowed(name: &str, extensions: &[String], extensionless: bool) -> Result<(), String> {
+    let extension = Path::new(name).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension {
+        None if extensionless => { Ok(()) }
+        None => { Err(format!("{name} has no extension, which uploads may not do")) }
+        Some(extension) if extensions.iter().any(|allowed| *allowed == extension || allowed == "*") => { Ok(()) }
+        Some(extension) => { Err(format!("{name} has the extension .{extension}, which uploads may not have")) }
+    }
+}
+
+/// The next item of `stream`, or an error once nothing arrived for `idle`, unless it is zero.
+pub async fn next<S: Stream + Unpin>(stream: &mut S, idle: Duration) -> Result<Option<S::Item>, Error> {
+    if idle.is_zero() {
+        return Ok(stream.next().await);
+    }
+    tokio::time::timeout(idle, stream.next()).await
+        .map_err(|_| Code::UploadTimeout.error(format!("No upload data arrived for {} seconds", idle.as_secs())))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::{test, web, Error, FromRequest};
+    use crate::config::Config;
+    use crate::errors;
+    use super::{read_submission, Encoding, Submission};
+
+    async fn submit(content_type: &str, body: impl Into<Vec<u8>>) -> Result<Submission, Error> {
+        let config = Config::from_args(["typstapi", "--max-file-size-kb", "1"]).unwrap();
+        let (request, mut payload) = test::TestRequest::post()
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body.into())
+            .to_http_parts();
+        let payload = web::Payload::from_request(&request, &mut payload).await.unwrap();
+        read_submission(&request, payload, &config).await
+    }
+
+    /// The paths and contents of the files submitted.
+    fn files(submission: &Submission) -> Vec<(String, Vec<u8>)> {
+        submission.documents.iter()
+            .map(|file| {
+                let path = file.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+                (path, file.data.load().unwrap().to_vec())
+            })
+            .collect()
+    }
+
+    /// A tar of `files`, gzipped if `gzip`, with their paths written as they are.
+    fn archive(files: &[(&str, &[u8])], gzip: bool) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+        match gzip {
+            true => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                std::io::Write::write_all(&mut encoder, &tar).unwrap();
+                encoder.finish().unwrap()
+            }
+            false => { tar }
+        }
+    }
+
+    fn code(result: Result<Submission, Error>) -> &'static str {
+        errors::code(&result.err().expect("the submission is refused"))
+    }
+
+    #[test]
+    fn the_content_type_picks_the_encoding() {
+        let cases = [
+            ("multipart/form-data; boundary=x", Some(Encoding::Multipart)),
+            ("application/x-www-form-urlencoded", Some(Encoding::Form)),
+            ("Application/JSON; charset=utf-8", Some(Encoding::Json)),
+            ("text/plain; charset=utf-8", Some(Encoding::Raw)),
+            ("text/vnd.typst", Some(Encoding::Raw)),
+            ("application/octet-stream", Some(Encoding::Raw)),
+            ("application/gzip", Some(Encoding::Archive)),
+            ("application/x-tar", Some(Encoding::Archive)),
+            ("application/pdf", None),
+            ("", None),
+        ];
+        for (content_type, encoding) in cases {
+            assert_eq!(Encoding::of(content_type), encoding, "{content_type}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn reads_a_multipart_upload() {
+        let body = "--x\r\nContent-Disposition: form-data; name=\"main.typ\"; filename=\"main.typ\"\r\n\r\n= A\r\n\
+                    --x\r\nContent-Disposition: form-data; name=\"data.csv\"; filename=\"data.csv\"\r\n\r\na,1\r\n--x--\r\n";
+        let submission = submit("multipart/form-data; boundary=x", body).await.unwrap();
+        assert_eq!(files(&submission), [("main.typ".to_string(), b"= A".to_vec()), ("data.csv".to_string(), b"a,1".to_vec())]);
+        assert!(submission.inputs.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn reads_a_form() {
+        let submission = submit("application/x-www-form-urlencoded", "source=%3D+A&input.name=World&submit=Go").await.unwrap();
+        assert_eq!(files(&submission), [("main.typ".to_string(), b"= A".to_vec())]);
+        assert_eq!(submission.inputs["name"], "World");
+        assert_eq!(submission.warnings.len(), 1);
+
+        assert_eq!(code(submit("application/x-www-form-urlencoded", "input.name=World").await), "missing_main");
+    }
+
+    #[actix_web::test]
+    async fn reads_json() {
+        let body = r#"{"files": [{"path": "main.typ", "content": "= A"}, {"path": "data.csv", "content": "YSwx", "base64": true}], "inputs": {"name": "World"}}"#;
+        let submission = submit("application/json", body).await.unwrap();
+        assert_eq!(files(&submission), [("main.typ".to_string(), b"= A".to_vec()), ("data.csv".to_string(), b"a,1".to_vec())]);
+        assert_eq!(submission.inputs["name"], "World");
+
+        let refused = [
+            (r#"{"files": []}"#.to_string(), "missing_main"),
+            (r#"{"files": [{"path": "main.typ"}]}"#.to_string(), "invalid_json"),
+            (r#"{"files": [{"path": "main.typ", "content": "%", "base64": true}]}"#.to_string(), "invalid_base64"),
+            (r#"{"files": [{"path": "tool.exe", "content": "MZ"}]}"#.to_string(), "file_type_not_allowed"),
+            (format!(r#"{{"files": [{{"path": "main.typ", "content": "{}"}}]}}"#, "a".repeat(2048)), "file_too_large"),
+        ];
+        for (body, expected) in refused {
+            assert_eq!(code(submit("application/json", body.clone()).await), expected, "{body}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn reads_a_raw_document() {
+        let submission = submit("text/plain; charset=utf-8", "= A").await.unwrap();
+        assert_eq!(files(&submission), [("main.typ".to_string(), b"= A".to_vec())]);
+        assert!(submission.inputs.is_empty());
+
+        assert_eq!(code(submit("text/plain", b"\xff\xfe".to_vec()).await), "invalid_body");
+        assert_eq!(code(submit("text/plain", vec![b'a'; 2048]).await), "file_too_large");
+    }
+
+    #[actix_web::test]
+    async fn reads_an_archive() {
+        let packed: [(&str, &[u8]); 2] = [("./data/rows.csv", b"a,1"), ("main.typ", b"= A")];
+        for gzip in [true, false] {
+            let submission = submit("application/gzip", archive(&packed, gzip)).await.unwrap();
+            assert_eq!(files(&submission), [("main.typ".to_string(), b"= A".to_vec()), ("data/rows.csv".to_string(), b"a,1".to_vec())]);
+        }
+
+        let refused: [(&[(&str, &[u8])], &str); 5] = [
+            (&[("chapter.typ", b"= A")], "missing_main"),
+            (&[("main.typ", b"= A"), ("../escape.typ", b"= B")], "invalid_filename"),
+            (&[("main.typ", b"= A"), ("run.sh", b"rm")], "file_type_not_allowed"),
+            (&[("main.typ", b"= A"), ("main.typ", b"= B")], "duplicate_file"),
+            (&[("main.typ", &[b'a'; 2048])], "file_too_large"),
+        ];
+        for (packed, expected) in refused {
+            assert_eq!(code(submit("application/x-tar", archive(packed, true)).await), expected, "{:?}", packed[packed.len() - 1].0);
+        }
+        assert_eq!(code(submit("application/gzip", b"not an archive".to_vec()).await), "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn refuses_other_bodies() {
+        assert_eq!(code(submit("application/pdf", "%PDF-").await), "unsupported_media_type");
+    }
+}
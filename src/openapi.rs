@@ -0,0 +1,254 @@
+//! The OpenAPI document, generated from the handlers, and a Swagger UI to browse it.
+//!
+//! Both are off unless `API_DOCS` is set, since they tell anyone who asks what the server can do.
+
+use actix_web::{get, web, Error, HttpResponse};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{KnownFormat, ObjectBuilder, RefOr, Schema, SchemaFormat, SchemaType};
+use utoipa::{Modify, OpenApi, ToSchema};
+use crate::auth::API_KEY_HEADER;
+use crate::config::{Config, CurrentConfig};
+use crate::errors::Code;
+
+/// A multipart upload with one part per file, named after its path.
+// Only ever named in the document, requests are read part by part instead.
+#[allow(dead_code)]
+pub struct Upload;
+
+impl<'s> ToSchema<'s> for Upload {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let file = ObjectBuilder::new()
+            .schema_type(SchemaType::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)));
+        let upload = ObjectBuilder::new()
+            .description(Some("One part per file, named after its path like `chapters/intro.typ`. The first part is the main document."))
+            .additional_properties(Some(file));
+        ("Upload", upload.into())
+    }
+}
+
+/// A single-file compile sent as an HTML form, the value of `source` as the main document.
+// Only ever named in the document, the body is read by `multipart::read_submission`.
+#[allow(dead_code)]
+pub struct SourceForm;
+
+impl<'s> ToSchema<'s> for SourceForm {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let text = ObjectBuilder::new().schema_type(SchemaType::String);
+        let form = ObjectBuilder::new()
+            .description(Some("`source` is the main document. Fields named `input.<key>` set `sys.inputs.<key>`, other fields are ignored with a warning."))
+            .property("source", text.clone())
+            .required("source")
+            .additional_properties(Some(text));
+        ("SourceForm", form.into())
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "typstapi", description = "Compiles typst documents to PDF."),
+    paths(
+        crate::compile_endpoint::typst_compile,
+        crate::example::typst_example,
+        crate::events::compile_events,
+        crate::pages::compile_pages,
+        crate::exports::compile_exports,
+        crate::element::render_element,
+        crate::stats::stats,
+        crate::documents::document,
+        crate::analyze::analyze,
+        crate::ast::ast,
+        crate::formatter::format,
+        crate::highlight::highlight,
+        crate::fonts::coverage,
+        crate::fonts::sample_coverage,
+        crate::bibliography::preflight,
+        crate::preview::preview,
+        crate::limits::limits,
+        crate::jobs::submit_job,
+        crate::jobs::list_jobs,
+        crate::jobs::job_status,
+        crate::jobs::cancel_job,
+        crate::jobs::job_result,
+        crate::uploads::create_upload,
+        crate::uploads::append_chunk,
+        crate::uploads::upload_offset,
+        crate::blobs::negotiate,
+        crate::blobs::upload_blob,
+        crate::blobs::compile_manifest,
+        crate::projects::list_projects,
+        crate::projects::get_project,
+        crate::projects::update_project,
+        crate::projects::delete_project,
+        crate::projects::lock_project,
+        crate::projects::unlock_project,
+        crate::projects::upload_project,
+        crate::projects::patch_file,
+        crate::projects::list_revisions,
+        crate::projects::compile_project,
+        crate::package_admin::list_packages,
+        crate::package_admin::purge_package,
+        crate::package_admin::purge_packages,
+        crate::admin::evict_cache,
+        crate::admin::stats,
+        crate::admin::delete_tenant,
+        crate::quotas::usage,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::selftest::selftest,
+        crate::metrics::metrics,
+        crate::version::version,
+    ),
+    components(schemas(
+        Upload,
+        SourceForm,
+        crate::multipart::JsonUpload,
+        crate::multipart::JsonFile,
+        crate::selftest::SelfTest,
+        crate::selftest::StageOutcome,
+        crate::compilers::JsonOutput,
+        crate::compile_endpoint::DryRunOutput,
+        crate::docker_world::ErrorOutput,
+        crate::docker_world::TimeoutOutput,
+        crate::docker_world::WarningsOutput,
+        crate::docker_world::Dependencies,
+        crate::docker_world::FontFace,
+        crate::docker_world::Statistics,
+        crate::docker_world::PageStatistics,
+        crate::exports::Manifest,
+        crate::exports::ExportReport,
+        crate::limits::Limits,
+        crate::analyze::Analysis,
+        crate::analyze::Completions,
+        crate::analyze::CompletionJson,
+        crate::analyze::TooltipJson,
+        crate::analyze::DefinitionJson,
+        crate::ast::Tree,
+        crate::ast::Node,
+        crate::ast::SyntaxMessage,
+        crate::formatter::Formatted,
+        crate::fonts::Coverage,
+        crate::docker_world::FamilyCoverage,
+        crate::docker_world::FaceCoverage,
+        crate::bibliography::Preflight,
+        crate::bibliography::FileReport,
+        crate::bibliography::ParseProblem,
+        crate::bibliography::Incomplete,
+        crate::bibliography::Duplicate,
+        crate::bibliography::Place,
+        crate::jobs::JobStatus,
+        crate::jobs::JobSummary,
+        crate::jobs::JobPage,
+        crate::jobs::JobJson,
+        crate::jobs::Submitted,
+        crate::uploads::NewUpload,
+        crate::uploads::UploadCreated,
+        crate::projects::ProjectJson,
+        crate::projects::ProjectPage,
+        crate::projects::ProjectSettings,
+        crate::projects::LockRequest,
+        crate::projects::LockJson,
+        crate::projects::RangeEdit,
+        crate::projects::Overlay,
+        crate::revisions::RevisionJson,
+        crate::revisions::RevisionFile,
+        crate::blobs::Manifest,
+        crate::blobs::ManifestEntry,
+        crate::blobs::Missing,
+        crate::blobs::MissingBlobs,
+        crate::webhooks::Delivery,
+        crate::webhooks::DeliveryAttempt,
+        crate::webhooks::DeliveryState,
+        crate::slots::Priority,
+        crate::packages::CachedPackage,
+        crate::admin::Stats,
+        crate::admin::Compiles,
+        crate::admin::Queue,
+        crate::admin::ResultCache,
+        crate::admin::Fonts,
+        crate::admin::PackageCache,
+        crate::concurrency::ClientUsage,
+        crate::quotas::Exceeded,
+        crate::quotas::Measured,
+        crate::version::Build,
+    )),
+    modifiers(&Schemes),
+    tags(
+        (name = "compile", description = "Compiling right away"),
+        (name = "jobs", description = "Compiling in the background"),
+        (name = "uploads", description = "Resumable uploads of large files, referred to by later uploads"),
+        (name = "projects", description = "Long-lived projects compiled again after each change"),
+        (name = "analysis", description = "Looking into sources and fonts without compiling"),
+        (name = "admin", description = "Guarded by the admin token"),
+        (name = "operations", description = "Probes, metrics and build information"),
+    ),
+)]
+struct ApiDoc;
+
+/// The ways to authenticate, named by the operations that need one.
+struct Schemes;
+
+impl Modify for Schemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))));
+        components.add_security_scheme("bearer", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        components.add_security_scheme("admin_token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+/// The document as this configuration serves it, requiring a key where the server does.
+fn document(config: &Config) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    if config.api_keys.is_empty() && config.keys.is_empty() {
+        return openapi;
+    }
+    let mut schemes = vec!["api_key", "bearer"];
+    if config.basic_auth {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("basic", SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)));
+        schemes.push("basic");
+    }
+    let no_scopes: [&str; 0] = [];
+    openapi.security = Some(schemes.into_iter().map(|scheme| SecurityRequirement::new(scheme, no_scopes)).collect());
+    openapi
+}
+
+fn require_docs(config: &Config) -> Result<(), Error> {
+    match config.api_docs {
+        true => { Ok(()) }
+        false => { Err(Code::DocsDisabled.error("API docs are disabled")) }
+    }
+}
+
+#[get("/openapi.json")]
+async fn openapi_json(config: CurrentConfig) -> Result<HttpResponse, Error> {
+    require_docs(&config)?;
+    Ok(HttpResponse::Ok().json(document(&config)))
+}
+
+/// Swagger UI from its CDN, pointed at `/openapi.json`.
+#[get("/docs")]
+async fn docs(config: CurrentConfig) -> Result<HttpResponse, Error> {
+    require_docs(&config)?;
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(SWAGGER_UI))
+}
+
+const SWAGGER_UI: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>typstapi</title>
+  <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="docs"></div>
+  <script src="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#docs" });</script>
+</body>
+</html>
+"#;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_json).service(docs);
+}
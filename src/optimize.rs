@@ -0,0 +1,56 @@
+//! Rewriting PDFs for fast web view with qpdf, for `?optimize=true` and `OPTIMIZE_PDF`.
+//!
+//! qpdf recompresses the streams, packs objects into object streams and linearizes the file,
+//! so browsers show the first page before the rest arrived. Its output is only served if
+//! `qpdf --check` accepts it and it is no larger, otherwise the PDF is served as typst wrote it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// `pdf` optimized with the `qpdf` binary, or as it was if that failed or didn't shrink it.
+pub fn optimize(pdf: Vec<u8>, qpdf: &Path) -> Vec<u8> {
+    let span = tracing::info_span!("optimize", input_bytes = pdf.len(), output_bytes = tracing::field::Empty);
+    let _entered = span.enter();
+    match rewrite(&pdf, qpdf) {
+        Ok(optimized) if optimized.len() <= pdf.len() => {
+            span.record("output_bytes", optimized.len());
+            optimized
+        }
+        Ok(optimized) => {
+            tracing::debug!("optimizing grew the PDF to {} bytes, serving it unoptimized", optimized.len());
+            pdf
+        }
+        Err(problem) => {
+            tracing::warn!("could not optimize the PDF, serving it unoptimized: {problem}");
+            pdf
+        }
+    }
+}
+
+fn rewrite(pdf: &[u8], qpdf: &Path) -> io::Result<Vec<u8>> {
+    let dir = tempfile::tempdir()?;
+    let (input, output) = (dir.path().join("input.pdf"), dir.path().join("output.pdf"));
+    fs::write(&input, pdf)?;
+    succeeded(Command::new(qpdf)
+        .args(["--linearize", "--object-streams=generate", "--recompress-flate", "--compression-level=9"])
+        .arg(&input)
+        .arg(&output)
+        .stdin(Stdio::null())
+        .output()?)?;
+    // What qpdf wrote must parse again, so a bug there can't serve a broken file.
+    succeeded(Command::new(qpdf).arg("--check").arg(&output).stdin(Stdio::null()).output()?)?;
+    fs::read(&output)
+}
+
+/// Treat qpdf's warnings as failures too, they mean it had to repair something.
+pub fn succeeded(output: Output) -> io::Result<()> {
+    match output.status.success() {
+        true => { Ok(()) }
+        false => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(io::Error::other(format!("qpdf exited with {}: {}", output.status, stderr.trim())))
+        }
+    }
+}
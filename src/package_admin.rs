@@ -0,0 +1,83 @@
+//! Admin endpoints over the package cache.
+
+use actix_web::{delete, get, web, Error, HttpRequest, HttpResponse};
+use crate::admin::require_admin;
+use crate::config::CurrentConfig;
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::packages::{CachedPackage, PackageStore};
+
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "The cached packages", body = [CachedPackage]),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[get("/packages")]
+async fn list_packages(
+    request: HttpRequest,
+    config: CurrentConfig,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    let list = packages.list().map_err(|problem| Code::Internal.error(problem))?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "admin",
+    params(
+        ("namespace" = String, Path, description = "Like `preview`"),
+        ("name" = String, Path),
+        ("version" = String, Path, description = "Like `0.2.2`"),
+    ),
+    responses(
+        (status = 204, description = "The package was removed from the cache"),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+        (status = 404, description = "The package is not cached", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[delete("/packages/{namespace}/{name}/{version}")]
+async fn purge_package(
+    request: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    config: CurrentConfig,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    let (namespace, name, version) = path.into_inner();
+
+    match packages.purge(&namespace, &name, &version) {
+        Ok(true) => { Ok(HttpResponse::NoContent().finish()) }
+        Ok(false) => { Err(Code::PackageNotCached.error("Package is not cached")) }
+        Err(problem) => { Err(Code::Internal.error(problem)) }
+    }
+}
+
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 204, description = "The package cache was emptied"),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[delete("/packages")]
+async fn purge_packages(
+    request: HttpRequest,
+    config: CurrentConfig,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    packages.purge_all().map_err(|problem| Code::Internal.error(problem))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_packages)
+        .service(purge_package)
+        .service(purge_packages);
+}
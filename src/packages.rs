@@ -0,0 +1,418 @@
+//! Typst packages for worlds to import, from a registry through an on-disk cache.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+use sha2::{Digest, Sha256};
+use ecow::EcoString;
+use typst::diag::{FileError, FileResult, PackageError, PackageResult};
+use typst::foundations::Bytes;
+use typst::syntax::FileId;
+use typst::syntax::package::PackageSpec;
+
+/// The registry typst itself downloads packages from.
+pub const DEFAULT_REGISTRY_URL: &str = "https://packages.typst.org/{namespace}/{name}-{version}.tar.gz";
+
+/// Where a `PackageStore` finds packages.
+pub struct PackageSettings {
+    /// Directory registry packages are unpacked into.
+    pub cache_dir: PathBuf,
+    /// Root of the `@local` namespace, laid out as `<root>/<name>/<version>/`.
+    pub local_dir: Option<PathBuf>,
+    /// Never download, serve only what is already in the cache.
+    pub offline: bool,
+    /// Archive URL with `{namespace}`, `{name}` and `{version}` placeholders.
+    pub registry_url: String,
+    /// Credentials sent to the registry keyed by namespace, `*` applies to all others.
+    pub credentials: HashMap<String, Credentials>,
+    /// Extra root certificate (PEM) trusted for registry connections.
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl PackageSettings {
+    /// Download from the typst registry into `cache_dir`, without `@local` packages.
+    pub fn new(cache_dir: PathBuf) -> Self {
+        Self {
+            cache_dir,
+            local_dir: None,
+            offline: false,
+            registry_url: DEFAULT_REGISTRY_URL.into(),
+            credentials: HashMap::new(),
+            ca_cert: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Credentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    /// Value for the `Authorization` header.
+    pub fn authorization(&self) -> String {
+        match self {
+            Credentials::Bearer(token) => { format!("Bearer {token}") }
+            Credentials::Basic { username, password } => {
+                format!("Basic {}", STANDARD.encode(format!("{username}:{password}")))
+            }
+        }
+    }
+}
+
+/// Unpacked typst packages on disk, downloaded from the registry on first use.
+///
+/// Packages in the `@local` namespace are instead served from `local_dir`, laid out as
+/// `<local_dir>/<name>/<version>/` like the typst CLI's local package directory.
+pub struct PackageStore {
+    cache_dir: PathBuf,
+    local_dir: Option<PathBuf>,
+    /// Never download, serve only what is already in the cache.
+    offline: bool,
+    registry_url: String,
+    credentials: HashMap<String, Credentials>,
+    agent: ureq::Agent,
+    downloads: Mutex<HashMap<PackageSpec, Arc<Mutex<()>>>>,
+    /// Held for reading while files are read out of the cache, and for writing by purges.
+    purge: RwLock<()>,
+    last_used: Mutex<HashMap<PathBuf, SystemTime>>,
+    preloaded: AtomicBool,
+    /// Bytes in the cache directory as of the last `measure`.
+    measured_size: AtomicU64,
+}
+
+/// A package unpacked in the cache, as listed by `GET /packages`.
+#[derive(Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct CachedPackage {
+    namespace: String,
+    name: String,
+    version: String,
+    size: u64,
+    last_used: DateTime<Utc>,
+}
+
+impl PackageStore {
+    /// Fails only when the extra root certificate can't be read or used.
+    pub fn new(settings: PackageSettings) -> io::Result<Self> {
+        Ok(Self {
+            agent: agent(settings.ca_cert.as_deref())?,
+            cache_dir: settings.cache_dir,
+            local_dir: settings.local_dir,
+            offline: settings.offline,
+            registry_url: settings.registry_url,
+            credentials: settings.credentials,
+            downloads: Mutex::new(HashMap::new()),
+            purge: RwLock::new(()),
+            last_used: Mutex::new(HashMap::new()),
+            preloaded: AtomicBool::new(false),
+            measured_size: AtomicU64::new(0),
+        })
+    }
+
+    /// Make sure the given packages are in the cache, downloading the missing ones.
+    ///
+    /// Failures are only logged, a package that couldn't be preloaded is simply
+    /// downloaded again by the first compile importing it.
+    pub fn preload(&self, specs: &[PackageSpec]) {
+        for spec in specs {
+            let _guard = self.purge.read().unwrap();
+            match self.prepare(spec) {
+                Ok(_) => { tracing::info!("Preloaded package {spec}") }
+                Err(problem) => { tracing::warn!("could not preload package {spec}: {problem}") }
+            }
+        }
+        self.preloaded.store(true, Ordering::Release);
+    }
+
+    /// Whether `preload` has run to completion.
+    pub fn preload_finished(&self) -> bool {
+        self.preloaded.load(Ordering::Acquire)
+    }
+
+    /// Read a file of the package `id` belongs to.
+    pub fn read(&self, spec: &PackageSpec, id: FileId) -> FileResult<Bytes> {
+        let _guard = self.purge.read().unwrap();
+        let root = self.prepare(spec).map_err(FileError::Package)?;
+        let path = id.vpath().resolve(&root).ok_or(FileError::AccessDenied)?;
+        let data = fs::read(&path).map_err(|problem| FileError::from_io(problem, &path))?;
+        Ok(data.into())
+    }
+
+    /// Directory holding the unpacked package, downloading it first if it isn't cached.
+    pub fn prepare(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        if is_local(spec) {
+            return self.prepare_local(spec);
+        }
+
+        let dir = self.cache_dir
+            .join(spec.namespace.as_str())
+            .join(spec.name.as_str())
+            .join(spec.version.to_string());
+
+        self.last_used.lock().unwrap().insert(dir.clone(), SystemTime::now());
+        if dir.exists() {
+            return Ok(dir);
+        }
+
+        if self.offline {
+            return Err(PackageError::Other(Some(
+                format!("package {spec} not in cache and offline mode is enabled").into()
+            )));
+        }
+
+        // Only one compile may download a given package, the others wait for it.
+        let lock = self.downloads.lock().unwrap().entry(spec.clone()).or_default().clone();
+        let guard = lock.lock().unwrap();
+        let downloaded = match dir.exists() {
+            true => { Ok(()) }
+            false => { self.download(spec, &dir) }
+        };
+        drop(guard);
+
+        // Forget the lock once nobody else waits on it, clones are only taken under the map's lock.
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads.get(spec).is_some_and(|entry| Arc::ptr_eq(entry, &lock) && Arc::strong_count(&lock) == 2) {
+            downloads.remove(spec);
+        }
+        downloaded.map(|()| dir)
+    }
+
+    /// Fetch a package archive from the registry and unpack it into `dir`.
+    fn download(&self, spec: &PackageSpec, dir: &Path) -> PackageResult<()> {
+        let url = self.registry_url
+            .replace("{namespace}", &spec.namespace)
+            .replace("{name}", &spec.name)
+            .replace("{version}", &spec.version.to_string());
+
+        let mut request = self.agent.get(&url);
+        let credentials = self.credentials.get(spec.namespace.as_str())
+            .or_else(|| self.credentials.get("*"));
+        if let Some(credentials) = credentials {
+            request = request.set("Authorization", &credentials.authorization());
+        }
+
+        let response = match request.call() {
+            Ok(response) => { response }
+            Err(ureq::Error::Status(404, _)) => { return Err(PackageError::NotFound(spec.clone())) }
+            Err(problem) => { return Err(PackageError::NetworkFailed(message(problem))) }
+        };
+
+        // Artifactory style mirrors announce the archive's hash, check it when they do.
+        let expected = response.header("X-Checksum-Sha256").map(str::to_ascii_lowercase);
+        let mut data = vec![];
+        response.into_reader().read_to_end(&mut data)
+            .map_err(|problem| PackageError::NetworkFailed(message(problem)))?;
+        if let Some(expected) = expected {
+            let actual = format!("{:x}", Sha256::digest(&data));
+            if actual != expected {
+                return Err(PackageError::MalformedArchive(Some(
+                    format!("checksum mismatch, expected {expected} but got {actual}").into()
+                )));
+            }
+        }
+
+        unpack(&data, dir)
+    }
+
+    /// Add up the size of the cache directory, which `size` then reports.
+    pub fn measure(&self) {
+        let size = match self.cache_dir.exists() {
+            true => { directory_size(&self.cache_dir) }
+            false => { Ok(0) }
+        };
+        match size {
+            Ok(size) => { self.measured_size.store(size, Ordering::Relaxed) }
+            Err(problem) => { tracing::warn!("could not measure the package cache: {problem}") }
+        }
+    }
+
+    /// Bytes in the cache directory, as of the last `measure`.
+    pub fn size(&self) -> u64 {
+        self.measured_size.load(Ordering::Relaxed)
+    }
+
+    /// Every package currently unpacked in the cache.
+    pub fn list(&self) -> io::Result<Vec<CachedPackage>> {
+        let last_used = self.last_used.lock().unwrap();
+        let mut packages = vec![];
+
+        for namespace in subdirectories(&self.cache_dir)? {
+            for name in subdirectories(&namespace)? {
+                for version in subdirectories(&name)? {
+                    let used = match last_used.get(&version) {
+                        Some(time) => { *time }
+                        None => { fs::metadata(&version)?.modified()? }
+                    };
+                    packages.push(CachedPackage {
+                        namespace: file_name(&namespace),
+                        name: file_name(&name),
+                        version: file_name(&version),
+                        size: directory_size(&version)?,
+                        last_used: used.into(),
+                    });
+                }
+            }
+        }
+
+        Ok(packages)
+    }
+
+    /// Remove one package version from the cache, returning whether it was there.
+    ///
+    /// Waits for in-flight reads out of the cache to finish first.
+    pub fn purge(&self, namespace: &str, name: &str, version: &str) -> io::Result<bool> {
+        if ![namespace, name, version].iter().all(|part| is_plain_component(part)) {
+            return Ok(false);
+        }
+
+        let _guard = self.purge.write().unwrap();
+        let dir = self.cache_dir.join(namespace).join(name).join(version);
+        if !dir.is_dir() {
+            return Ok(false);
+        }
+        fs::remove_dir_all(&dir)?;
+        self.last_used.lock().unwrap().remove(&dir);
+        Ok(true)
+    }
+
+    /// Empty the whole cache.
+    pub fn purge_all(&self) -> io::Result<()> {
+        let _guard = self.purge.write().unwrap();
+        for namespace in subdirectories(&self.cache_dir)? {
+            fs::remove_dir_all(namespace)?;
+        }
+        self.last_used.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn prepare_local(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        let root = match &self.local_dir {
+            None => {
+                return Err(PackageError::Other(Some(
+                    format!("cannot import {spec}, no local package directory is configured").into()
+                )))
+            }
+            Some(root) => { root }
+        };
+
+        let dir = root.join(spec.name.as_str()).join(spec.version.to_string());
+        if !dir.is_dir() {
+            return Err(PackageError::Other(Some(
+                format!("package {spec} not found, looked in {}", dir.display()).into()
+            )));
+        }
+        Ok(dir)
+    }
+}
+
+/// Whether the package lives in the `@local` namespace, which is read fresh on every compile.
+pub fn is_local(spec: &PackageSpec) -> bool {
+    spec.namespace.as_str() == "local"
+}
+
+/// Unpack a gzipped tarball into `dir`.
+///
+/// The archive is unpacked into a staging directory next to `dir` and renamed into
+/// place, so a half-extracted package is never visible in the cache.
+fn unpack(archive: &[u8], dir: &Path) -> PackageResult<()> {
+    let parent = dir.parent()
+        .ok_or_else(|| PackageError::Other(Some("the package cache has no parent directory".into())))?;
+    fs::create_dir_all(parent).map_err(|problem| PackageError::Other(message(problem)))?;
+    let staging = tempfile::Builder::new()
+        .prefix(".download-")
+        .tempdir_in(parent)
+        .map_err(|problem| PackageError::Other(message(problem)))?;
+
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    archive.unpack(staging.path())
+        .map_err(|problem| PackageError::MalformedArchive(message(problem)))?;
+
+    let staging = staging.into_path();
+    if let Err(problem) = fs::rename(&staging, dir) {
+        let _ = fs::remove_dir_all(&staging);
+        // Another process may have finished the same download first.
+        if !dir.exists() {
+            return Err(PackageError::Other(message(problem)));
+        }
+    }
+
+    Ok(())
+}
+
+/// HTTP agent for the registry, trusting an extra root certificate when one is configured.
+fn agent(ca_cert: Option<&Path>) -> io::Result<ureq::Agent> {
+    let mut builder = ureq::AgentBuilder::new();
+
+    if let Some(path) = ca_cert {
+        let problem = |kind: io::ErrorKind, problem: &dyn Display| {
+            io::Error::new(kind, format!("registry CA certificate {}: {problem}", path.display()))
+        };
+        let pem = fs::read(path).map_err(|read| problem(read.kind(), &read))?;
+        let certificate = native_tls::Certificate::from_pem(&pem)
+            .map_err(|invalid| problem(io::ErrorKind::InvalidData, &invalid))?;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(certificate)
+            .build()
+            .map_err(|invalid| problem(io::ErrorKind::InvalidData, &invalid))?;
+        builder = builder.tls_connector(Arc::new(connector));
+    }
+
+    Ok(builder.build())
+}
+
+fn message(problem: impl Display) -> Option<EcoString> {
+    Some(problem.to_string().into())
+}
+
+/// Directories directly inside `dir`, skipping hidden ones like download staging areas.
+fn subdirectories(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut dirs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn directory_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => { directory_size(&entry.path())? }
+            false => { metadata.len() }
+        };
+    }
+    Ok(size)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn is_plain_component(part: &str) -> bool {
+    let mut components = Path::new(part).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
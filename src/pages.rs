@@ -0,0 +1,266 @@
+//! Every page of a document as SVG or PNG, in a zip streamed while the pages are rendered.
+//!
+//! `POST /compile/pages?format=svg` takes the same upload and options as `/compile`. Once the
+//! document is laid out the response starts, and each page is sent as `page-001.svg` and so
+//! on as soon as it was rendered, so only about one page is held at a time. Errors laying out
+//! the document are answered like for `/compile`.
+//!
+//! The status is sent by the time a page fails to render or the compile times out, so the
+//! archive then ends after the pages so far with an `error.txt` saying why, and is still a
+//! valid zip. A client disconnecting cancels the rendering.
+//!
+//! The zip is hashed as it goes out, and its SHA-256 is in the audit log, see `checksum`.
+
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures_util::stream;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use utoipa::{IntoParams, ToSchema};
+use crate::archive::ZipWriter;
+use crate::audit::{self, Record};
+use crate::checksum::Checksum;
+use crate::compilers::{run_compile, Compilers, Paged, VersionQuery, CURRENT_VERSION, VERSION_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{Cancellation, CompileError, CompiledDocument, ErrorOutput, FontLibrary, PngExport, SvgExport, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::events::AbortOnDrop;
+use crate::metrics::Metrics;
+use crate::multipart::read_documents;
+use crate::openapi::Upload;
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+
+/// Highest resolution PNG pages may be asked for, keeping a rendered page to some 40 MB.
+pub const MAX_PPI: f32 = 300.0;
+
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Svg,
+    Png,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PagesQuery {
+    /// What each page is rendered as.
+    #[param(inline)]
+    format: Format,
+    /// Pixels per inch of PNG pages, 144 by default and at most 300.
+    ppi: Option<f32>,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Svg => { "svg" }
+            Format::Png => { "png" }
+        }
+    }
+}
+
+/// The pages a streamed compile rendered.
+struct Streamed {
+    pages: usize,
+}
+
+impl Paged for Streamed {
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// What the response body holds while the pages go out.
+struct Streaming {
+    received: mpsc::Receiver<Bytes>,
+    task: JoinHandle<Result<Result<Streamed, CompileError>, Error>>,
+    /// Dropped with the body, so a client going away cancels the rendering.
+    _abort: AbortOnDrop,
+    format: Format,
+    /// Over every chunk sent so far.
+    hasher: Sha256,
+    record: Option<Record>,
+}
+
+impl Streaming {
+    /// Note how the compile went once the last chunk went out.
+    async fn finish(self) {
+        let outcome = self.task.await.unwrap_or_else(|problem| Err(Code::Internal.error(problem)));
+        if let Some(mut record) = self.record {
+            record.streamed(self.format.name(), &outcome, Checksum::from(self.hasher));
+        }
+    }
+}
+
+/// Why the pages stopped before the last.
+enum Stopped {
+    /// The client went away, nobody reads the rest.
+    Disconnected,
+    /// The archive ends with an error entry saying this.
+    Failed(String),
+}
+
+/// Compile an upload to a zip of its pages, see the module docs.
+#[utoipa::path(
+    tag = "compile",
+    params(PagesQuery, VersionQuery, OptionsQuery),
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "A zip with a file per page, ending with `error.txt` if rendering stopped early", content_type = "application/zip", body = [u8]),
+        (status = 400, description = "The upload or the options are invalid, or the document has errors", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "Laying out the document timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/compile/pages")]
+#[allow(clippy::too_many_arguments)]
+async fn compile_pages(
+    request: HttpRequest,
+    payload: Multipart,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    if compiler.version() != CURRENT_VERSION {
+        return Err(Code::InvalidParameter.error(format!("SVG and PNG output need typst {CURRENT_VERSION}")));
+    }
+    let options = config.compile_options(&request)?;
+    let query = web::Query::<PagesQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?
+        .into_inner();
+    let ppi = query.ppi.unwrap_or(PngExport::default().ppi);
+    if !(1.0..=MAX_PPI).contains(&ppi) {
+        return Err(Code::InvalidParameter.error(format!("ppi must be from 1 to {MAX_PPI}")));
+    }
+
+    let mut documents = read_documents(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    audit::inputs(&request, &documents);
+    let mut record = audit::detach(&request);
+    let world = options.world()
+        .main(documents.remove(0))
+        .files(documents)
+        .font_db(fonts.current())
+        .packages(packages.into_inner());
+
+    // Bounded, so rendering waits for a slow client instead of piling up pages.
+    let (chunks, received) = mpsc::channel(1);
+    let (laid_out, typeset) = oneshot::channel();
+    let format = query.format;
+    let (max_pages, cache_max_age, timeout, priority) = (options.max_pages, config.cache_max_age, options.timeout, options.priority);
+    let task = actix_web::rt::spawn(async move {
+        run_compile(&slots, &metrics, timeout, priority, format.name(), move |cancellation| {
+            let mut world = world.build()?;
+            let document = world.typeset(max_pages, cancellation.clone())?;
+            let pages = document.pages();
+            let streamed = match laid_out.send(()) {
+                Ok(()) => { stream_pages(&document, format, ppi, &cancellation, &chunks) }
+                Err(()) => { Ok(()) }
+            };
+            comemo::evict(cache_max_age);
+            streamed.map(|()| Streamed { pages })
+        }).await
+    });
+    let abort = AbortOnDrop(task.abort_handle());
+
+    if typeset.await.is_err() {
+        // The compile ended before the document was laid out.
+        let outcome = task.await.unwrap_or_else(|problem| Err(Code::Internal.error(problem)));
+        if let Some(record) = &mut record {
+            record.finished(format.name(), &outcome);
+        }
+        return match outcome {
+            Ok(Err(problem)) => { Err(problem.into()) }
+            Err(problem) => { Err(problem) }
+            Ok(Ok(_)) => { Err(Code::Internal.error("The compile stopped without an outcome")) }
+        };
+    }
+
+    let streaming = Streaming { received, task, _abort: abort, format, hasher: Sha256::new(), record };
+    let body = stream::unfold(streaming, |mut streaming| async move {
+        match streaming.received.recv().await {
+            Some(chunk) => {
+                streaming.hasher.update(&chunk);
+                Some((Ok::<_, Error>(chunk), streaming))
+            }
+            None => {
+                streaming.finish().await;
+                None
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, CURRENT_VERSION))
+        .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"pages.zip\""))
+        .content_type("application/zip")
+        .streaming(body))
+}
+
+/// Render the pages of `document` into a zip, sending each entry to `chunks` once it is written.
+///
+/// Fails with what the error entry says if the archive ended early.
+fn stream_pages(
+    document: &CompiledDocument,
+    format: Format,
+    ppi: f32,
+    cancellation: &Cancellation,
+    chunks: &mpsc::Sender<Bytes>,
+) -> Result<(), CompileError> {
+    let mut archive = ZipWriter::new(Utc::now());
+    let (tail, streamed) = match write_pages(&mut archive, document, format, ppi, cancellation, chunks) {
+        Ok(()) => { (archive.finish(), Ok(())) }
+        Err(Stopped::Disconnected) => { return Ok(()) }
+        Err(Stopped::Failed(message)) => {
+            tracing::warn!(problem = message.as_str(), "rendering pages failed, the archive ends early");
+            (archive.fail(&message), Err(CompileError::Failed(message.into())))
+        }
+    };
+    let _ = chunks.blocking_send(Bytes::from(tail));
+    streamed
+}
+
+fn write_pages(
+    archive: &mut ZipWriter,
+    document: &CompiledDocument,
+    format: Format,
+    ppi: f32,
+    cancellation: &Cancellation,
+    chunks: &mpsc::Sender<Bytes>,
+) -> Result<(), Stopped> {
+    let pages = document.pages();
+    let digits = pages.to_string().len().max(3);
+    for page in 1..=pages {
+        if cancellation.is_cancelled() {
+            return Err(Stopped::Failed(format!("The compile timed out after {} of {pages} pages", page - 1)));
+        }
+        let rendered = match format {
+            Format::Svg => { document.to_svg(&SvgExport { page }).map(String::into_bytes) }
+            Format::Png => { document.to_png(&PngExport { page, ppi }) }
+        };
+        let data = rendered.map_err(|problem| Stopped::Failed(format!("Page {page} could not be rendered: {problem}")))?;
+        let name = format!("page-{page:0digits$}.{}", format.name());
+        // PNGs are compressed already.
+        let entry = archive.entry(&name, &data, matches!(format, Format::Svg))
+            .ok_or_else(|| Stopped::Failed(format!("The archive reached the 4 GiB a zip holds after {} of {pages} pages", page - 1)))?;
+        chunks.blocking_send(Bytes::from(entry)).map_err(|_| Stopped::Disconnected)?;
+    }
+    Ok(())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(compile_pages);
+}
@@ -0,0 +1,332 @@
+//! Live preview over a WebSocket, recompiling a world kept for the connection as it is edited.
+//!
+//! Every message is a JSON text frame. The client starts with the whole project,
+//! `{"type": "open", "files": [{"path": "main.typ", "content": "..."}]}` with the main file
+//! first, then sends `{"type": "edit", "path": ..., "content": ...}` to add or replace a file
+//! and `{"type": "remove", "path": ...}`. Binary files set `"base64": true`. A text file can
+//! instead be changed by a diff, `{"type": "patch", "path": ..., "changes": [{"offset": 2, "length": 5, "replacement": "..."}]}`,
+//! offsets counting UTF-8 bytes in the text the changes before left, which applies whole or
+//! not at all.
+//!
+//! Once no edit arrived for `PREVIEW_DEBOUNCE_MS`, the document is compiled again and the
+//! server answers `{"type": "pages", "pages": 3, "changed": [{"page": 2, "svg": "..."}], "warnings": [...]}`,
+//! with only the pages whose SVG differs from the one sent last, or
+//! `{"type": "diagnostics", "kind": "failed", "code": "compile_error", "message": "..."}`. Messages it can't apply are
+//! answered with `{"type": "error", "message": ...}`. The world is dropped with the connection.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use crate::compilers::{run_compile, CompileOptions, Paged};
+use crate::config::CurrentConfig;
+use crate::docker_world::{DockerWorld, DocumentFile, ErrorOutput, FontLibrary, SvgExport, Warning};
+use crate::metrics::Metrics;
+use crate::multipart::{allowed, valid_name};
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+use crate::sniff;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Incoming {
+    Open { files: Vec<IncomingFile> },
+    Edit(IncomingFile),
+    Remove { path: String },
+    Patch { path: String, changes: Vec<Change> },
+}
+
+/// Replace `length` bytes of a text file from `offset` on.
+#[derive(Deserialize)]
+struct Change {
+    offset: usize,
+    length: usize,
+    replacement: String,
+}
+
+#[derive(Deserialize)]
+struct IncomingFile {
+    path: String,
+    content: String,
+    #[serde(default)]
+    base64: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Outgoing {
+    Pages { pages: usize, changed: Vec<ChangedPage>, warnings: Vec<Warning> },
+    Diagnostics { kind: &'static str, code: &'static str, message: String },
+    Error { message: String },
+}
+
+#[derive(Serialize)]
+struct ChangedPage {
+    /// Counting from 1.
+    page: usize,
+    svg: String,
+}
+
+/// Every page of a compile as SVG.
+struct Rendered {
+    pages: Vec<String>,
+    warnings: Vec<Warning>,
+}
+
+impl Paged for Rendered {
+    fn pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// One connection's world and what it was last sent.
+struct Preview {
+    world: Option<Arc<Mutex<DockerWorld>>>,
+    /// Bytes of every file, to hold the connection to `PREVIEW_MAX_SIZE_KB`.
+    sizes: HashMap<String, usize>,
+    /// Hashes of the SVG of every page sent, so unchanged pages aren't sent again.
+    sent: Vec<u64>,
+    options: CompileOptions,
+    max_pages: usize,
+    max_size: usize,
+    max_files: Option<usize>,
+    allowed_extensions: Vec<String>,
+    allow_extensionless: bool,
+    max_filename_length: usize,
+    debounce: Duration,
+    cache_max_age: usize,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+}
+
+impl Preview {
+    fn apply(&mut self, message: &str) -> Result<(), String> {
+        let message: Incoming = serde_json::from_str(message).map_err(|problem| format!("Unreadable message: {problem}"))?;
+        match (message, self.world.clone()) {
+            (Incoming::Open { files }, None) => {
+                let opened = self.open(files);
+                if opened.is_err() {
+                    self.sizes.clear();
+                }
+                opened
+            }
+            (Incoming::Open { .. }, Some(_)) => { Err("The preview is already open".into()) }
+            (_, None) => { Err("Open the preview with the project first".into()) }
+            (Incoming::Edit(file), Some(world)) => {
+                let document = self.admit(file)?;
+                world.lock().unwrap().add_file(document);
+                Ok(())
+            }
+            (Incoming::Remove { path }, Some(world)) => {
+                world.lock().unwrap().remove_file(&path).map_err(|problem| problem.to_string())?;
+                self.sizes.remove(&path);
+                Ok(())
+            }
+            (Incoming::Patch { path, changes }, Some(world)) => {
+                let size = self.sizes.get(&path).copied().unwrap_or_default();
+                self.fits(&path, changes.iter().fold(size, |size, change| size.saturating_sub(change.length) + change.replacement.len()))?;
+                let mut edits = Vec::with_capacity(changes.len());
+                for change in &changes {
+                    let end = change.offset.checked_add(change.length).ok_or_else(|| format!("A change of {path} is too long"))?;
+                    edits.push((change.offset..end, change.replacement.as_str()));
+                }
+                let text = world.lock().unwrap().patch_file(&path, &edits).map_err(|problem| problem.to_string())?;
+                self.sizes.insert(path, text.len());
+                Ok(())
+            }
+        }
+    }
+
+    fn open(&mut self, files: Vec<IncomingFile>) -> Result<(), String> {
+        let mut documents = files.into_iter().map(|file| self.admit(file)).collect::<Result<Vec<_>, _>>()?;
+        if documents.is_empty() {
+            return Err("Open the preview with at least the main file".into());
+        }
+        let main = documents.remove(0);
+        let world = self.options.world()
+            .main(main)
+            .files(documents)
+            .font_db(self.fonts.current())
+            .packages(self.packages.clone().into_inner())
+            .build()
+            .map_err(|problem| problem.to_string())?;
+        self.world = Some(Arc::new(Mutex::new(world)));
+        Ok(())
+    }
+
+    /// Decode a file, as long as the connection stays within its budget.
+    fn admit(&mut self, file: IncomingFile) -> Result<DocumentFile, String> {
+        valid_name(&file.path, self.max_filename_length)?;
+        allowed(&file.path, &self.allowed_extensions, self.allow_extensionless)?;
+        let data = match file.base64 {
+            true => { STANDARD.decode(&file.content).map_err(|problem| format!("{} is not valid base64: {problem}", file.path))? }
+            false => { file.content.into_bytes() }
+        };
+        sniff::check(&file.path, &data)?;
+        self.fits(&file.path, data.len())?;
+        if let Some(max) = self.max_files.filter(|max| !self.sizes.contains_key(&file.path) && self.sizes.len() >= *max) {
+            return Err(format!("The preview may have at most {max} files"));
+        }
+        self.sizes.insert(file.path.clone(), data.len());
+        Ok(DocumentFile::new(&file.path, data))
+    }
+
+    /// Whether the files still fit `PREVIEW_MAX_SIZE_KB` with the one at `path` at `size` bytes.
+    fn fits(&self, path: &str, size: usize) -> Result<(), String> {
+        let others: usize = self.sizes.iter().filter(|(other, _)| *other != path).map(|(_, size)| size).sum();
+        match others + size > self.max_size {
+            true => { Err(format!("The preview's files may add up to {} bytes at most", self.max_size)) }
+            false => { Ok(()) }
+        }
+    }
+
+    /// Compile again and say what changed, nothing before the project was opened.
+    async fn compile(&mut self) -> Option<Outgoing> {
+        let world = self.world.clone()?;
+        let (max_pages, cache_max_age) = (self.max_pages, self.cache_max_age);
+        let (timeout, priority) = (self.options.timeout, self.options.priority);
+        let rendered = run_compile(&self.slots, &self.metrics, timeout, priority, "svg", move |cancellation| {
+            let mut world = world.lock().unwrap();
+            let rendered = world.typeset(max_pages, cancellation).and_then(|document| {
+                let pages = (1..=document.pages())
+                    .map(|page| document.to_svg(&SvgExport { page }))
+                    .collect::<Result<_, _>>()?;
+                Ok(Rendered { pages, warnings: document.warnings })
+            });
+            comemo::evict(cache_max_age);
+            rendered
+        }).await;
+
+        Some(match rendered {
+            Err(problem) => { Outgoing::Error { message: problem.to_string() } }
+            Ok(Err(problem)) => { Outgoing::Diagnostics { kind: problem.kind(), code: problem.code(), message: problem.to_string() } }
+            Ok(Ok(rendered)) => {
+                let hashes: Vec<u64> = rendered.pages.iter().map(String::as_str).map(hash).collect();
+                let changed = rendered.pages.into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| self.sent.get(*index) != Some(&hashes[*index]))
+                    .map(|(index, svg)| ChangedPage { page: index + 1, svg })
+                    .collect();
+                self.sent = hashes;
+                Outgoing::Pages { pages: self.sent.len(), changed, warnings: rendered.warnings }
+            }
+        })
+    }
+}
+
+fn hash(svg: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    svg.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn send(session: &mut Session, message: &Outgoing) -> Result<(), actix_ws::Closed> {
+    session.text(serde_json::to_string(message).expect("preview messages serialize")).await
+}
+
+/// Apply messages until the client is gone, compiling once they paused for the debounce.
+async fn serve(mut preview: Preview, mut session: Session, mut stream: MessageStream) {
+    let mut edited = false;
+    loop {
+        let message = match edited {
+            false => { stream.next().await }
+            true => {
+                match tokio::time::timeout(preview.debounce, stream.next()).await {
+                    Ok(message) => { message }
+                    Err(_) => {
+                        edited = false;
+                        let Some(outgoing) = preview.compile().await else { continue };
+                        match send(&mut session, &outgoing).await {
+                            Ok(()) => { continue }
+                            Err(_) => { break }
+                        }
+                    }
+                }
+            }
+        };
+
+        let sent = match message {
+            None | Some(Ok(Message::Close(_))) => { break }
+            Some(Err(problem)) => {
+                tracing::info!("live preview closed: {problem}");
+                break;
+            }
+            Some(Ok(Message::Ping(bytes))) => { session.pong(&bytes).await }
+            Some(Ok(Message::Pong(_) | Message::Nop)) => { Ok(()) }
+            Some(Ok(Message::Text(text))) => {
+                match preview.apply(&text) {
+                    Ok(()) => {
+                        edited = true;
+                        Ok(())
+                    }
+                    Err(message) => { send(&mut session, &Outgoing::Error { message }).await }
+                }
+            }
+            Some(Ok(Message::Binary(_) | Message::Continuation(_))) => {
+                send(&mut session, &Outgoing::Error { message: "Send each message as one JSON text frame".into() }).await
+            }
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+    let _ = session.close(None).await;
+}
+
+/// Open a live preview, taking the same query options as `/compile`.
+#[utoipa::path(
+    tag = "compile",
+    params(crate::config::OptionsQuery),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol described in the module docs"),
+        (status = 400, description = "Not a WebSocket handshake, or the options are invalid", body = ErrorOutput),
+    ),
+)]
+#[get("/ws/preview")]
+async fn preview(
+    request: HttpRequest,
+    body: web::Payload,
+    config: CurrentConfig,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let options = config.compile_options(&request)?;
+    let (response, session, stream) = actix_ws::handle(&request, body)?;
+    // Leaves room for escaping and base64 around a project that just fits.
+    let stream = stream.max_frame_size(config.preview_max_size.saturating_mul(2));
+    let preview = Preview {
+        world: None,
+        sizes: HashMap::new(),
+        sent: Vec::new(),
+        max_pages: options.max_pages.min(config.preview_max_pages),
+        options,
+        max_size: config.preview_max_size,
+        max_files: config.max_files,
+        allowed_extensions: config.allowed_extensions.clone(),
+        allow_extensionless: config.allow_extensionless,
+        max_filename_length: config.max_filename_length,
+        debounce: config.preview_debounce,
+        cache_max_age: config.cache_max_age,
+        fonts,
+        packages,
+        slots,
+        metrics,
+    };
+    actix_web::rt::spawn(serve(preview, session, stream));
+    Ok(response)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(preview);
+}
@@ -0,0 +1,146 @@
+//! A timeline of one compile for `?profile=true`, to find out why a document is slower than
+//! its siblings.
+//!
+//! Only callers with the admin token may ask for it. While a profiled compile runs, typst
+//! records its own spans, evaluating each module, the layout passes and the export, and the
+//! world adds parsing files, reading package files and loading fonts. They are answered as
+//! a chrome://tracing event array, which Perfetto opens, or in the JSON response mode as the
+//! `profile` field. Events are named after what ran and point at a file and line at most,
+//! never at document content.
+//!
+//! Without the flag typst records nothing, checking a single flag per span. The recording
+//! is process wide, so profiled compiles run one at a time, and compiles running meanwhile
+//! without the flag show up in the profile too, their locations left unresolved. Only the
+//! compiler in this process records spans, so the flag refuses isolated and older compiles.
+//! The compile cache is skipped, but typst's memoization is not, so a document compiled
+//! moments ago only shows what typst computes again.
+
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use actix_web::http::header::ContentType;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{Mutex, MutexGuard};
+use typst::syntax::{FileId, Source, Span};
+use utoipa::IntoParams;
+use crate::admin::require_admin;
+use crate::compilers::{JsonOutput, CURRENT_VERSION, VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::Config;
+use crate::docker_world::{Compiled, DocumentFile};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+
+/// Held by the profiled compile running, see the module docs.
+static PROFILING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProfileQuery {
+    /// Answer with a chrome://tracing profile of the compile instead of the PDF. Needs the admin token.
+    profile: Option<bool>,
+}
+
+/// Records the spans of a compile that asked for a profile.
+pub struct Profiler {
+    _exclusive: MutexGuard<'static, ()>,
+    /// The uploaded sources, parsed like the compile parses them, to find the lines spans point at.
+    sources: HashMap<FileId, Source>,
+}
+
+/// Whether the request asked for a profile, refusing it unless it carries the admin token and
+/// `version` is compiled in this process.
+pub fn requested(request: &HttpRequest, config: &Config, version: &str) -> Result<bool, Error> {
+    let query = web::Query::<ProfileQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let asked = query.profile.unwrap_or(false);
+    if asked {
+        require_admin(request, config)?;
+        if config.isolate_compiles || version != CURRENT_VERSION {
+            return Err(Code::InvalidParameter.error(format!("Only typst {CURRENT_VERSION} compiled in the server's process can be profiled")));
+        }
+    }
+    Ok(asked)
+}
+
+impl Profiler {
+    /// Start recording once no other profiled compile runs.
+    pub async fn start(documents: &[DocumentFile]) -> Self {
+        let exclusive = PROFILING.lock().await;
+        let sources = documents.iter()
+            .filter_map(|document| {
+                let bytes = document.data.load().ok()?;
+                let text = std::str::from_utf8(bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&bytes)).ok()?;
+                Some((document.name, Source::new(document.name, text.into())))
+            })
+            .collect();
+        typst_timing::clear();
+        typst_timing::enable();
+        Self { _exclusive: exclusive, sources }
+    }
+
+    /// Stop recording, returning the events since `start`.
+    pub fn finish(self) -> Value {
+        typst_timing::disable();
+        let mut trace = Vec::new();
+        let exported = typst_timing::export_json(&mut trace, |span| self.locate(span));
+        typst_timing::clear();
+        match exported.map(|()| serde_json::from_slice(&trace)) {
+            Ok(Ok(events)) => { events }
+            Ok(Err(problem)) => {
+                tracing::warn!("the compile profile isn't JSON: {problem}");
+                Value::Array(Vec::new())
+            }
+            Err(problem) => {
+                tracing::warn!("the compile profile could not be exported: {problem}");
+                Value::Array(Vec::new())
+            }
+        }
+    }
+
+    /// The path and line `span` points at, the path alone for files that weren't uploaded.
+    fn locate(&self, span: NonZeroU64) -> (String, u32) {
+        let span = Span::from_raw(span);
+        let Some(id) = span.id() else {
+            return (String::new(), 0);
+        };
+        let path = id.vpath().as_rootless_path().display();
+        let path = match id.package() {
+            None => { path.to_string() }
+            Some(spec) => { format!("{spec}/{path}") }
+        };
+        let line = self.sources.get(&id)
+            .and_then(|source| source.byte_to_line(source.range(span)?.start))
+            .map_or(0, |line| line as u32 + 1);
+        (path, line)
+    }
+}
+
+/// The response `compilers::respond` would give with the profile instead of the PDF, or
+/// added to the JSON.
+pub fn respond(
+    version: &'static str,
+    compiled: Compiled,
+    include_dependencies: bool,
+    profile: Value,
+    metrics: &Metrics,
+) -> Result<HttpResponse, Error> {
+    metrics.record_warnings(&compiled.warnings);
+    let body = match include_dependencies {
+        true => {
+            #[derive(Serialize)]
+            struct WithProfile<'a> {
+                #[serde(flatten)]
+                output: JsonOutput<'a>,
+                profile: Value,
+            }
+            serde_json::to_vec(&WithProfile { output: JsonOutput::new(&compiled), profile })
+        }
+        false => { serde_json::to_vec(&profile) }
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, version))
+        .insert_header((WARNING_COUNT_HEADER, compiled.warnings.len().to_string()))
+        .content_type(ContentType::json())
+        .body(body.expect("the profile serializes")))
+}
@@ -0,0 +1,1149 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_multipart::Multipart;
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_MATCH};
+use actix_web::{delete, get, patch, post, put, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::{IntoParams, ToSchema};
+use crate::admin::require_admin;
+use crate::compilers::{respond, run_compile, strict, JsonOutput, CURRENT_VERSION};
+use crate::config::{Config, CurrentConfig, OptionsQuery};
+use crate::docker_world::{DockerWorld, DocumentFile, EditError, ErrorOutput, FontLibrary, WarningsOutput};
+use crate::errors::{unbuildable, Code};
+use crate::metrics::Metrics;
+use crate::multipart::{allowed, decode_files, next, read_documents, valid_name, JsonFile};
+use crate::{audit, optimize, sniff};
+use crate::openapi::Upload;
+use crate::packages::PackageStore;
+use crate::revisions::{History, RevisionFile, RevisionJson};
+use crate::slots::CompileSlots;
+use crate::storage::{ProjectInfo, Storage, StoredFile};
+use crate::uploads::owner;
+
+/// Content type of a `PATCH` body replacing a range of a text file, see `RangeEdit`.
+pub const RANGE_TYPE: &str = "application/vnd.typstapi.range+json";
+
+/// Response header listing the overlay files a project compile used, as `path=sha256`.
+pub const OVERLAY_HEADER: &str = "X-Typst-Overlay";
+
+/// Response header with the revision a change created or a compile used.
+pub const REVISION_HEADER: &str = "X-Project-Revision";
+
+/// Request header with the token of the project's lock, for changes while it is locked.
+pub const LOCK_HEADER: &str = "X-Project-Lock";
+
+/// Seconds a lock is held for unless the client asks otherwise, and at most.
+const LOCK_TTL: u64 = 30;
+const LOCK_TTL_MAX: u64 = 300;
+
+/// Days an expired project is answered with 410 rather than 404.
+const TOMBSTONE_DAYS: i64 = 30;
+
+/// Long-lived worlds keyed by tenant and project id, see `scoped`, so successive compiles
+/// share typst's caches.
+///
+/// Their files are also written to the storage, and the worlds rebuilt from it at startup.
+/// Every tenant has projects of its own, the ids of others' unknown to it. A project is
+/// removed once its TTL passed without a change or compile, see `ProjectInfo::ttl`.
+pub struct Projects {
+    worlds: Mutex<HashMap<String, Arc<Entry>>>,
+    /// When the projects removed for their TTL expired, by key.
+    expired: Mutex<HashMap<String, DateTime<Utc>>>,
+    storage: Arc<dyn Storage>,
+}
+
+struct Entry {
+    /// The id the client chose.
+    id: String,
+    project: Mutex<Project>,
+    /// What listings show, kept apart so they don't wait for the project's compiles.
+    about: Mutex<About>,
+    /// Compiles of the project queued or running.
+    compiling: AtomicUsize,
+}
+
+struct About {
+    info: ProjectInfo,
+    /// The files of the latest revision.
+    files: Vec<RevisionFile>,
+    /// Drawn anew with every change to the files, and sent as the `ETag`.
+    version: u64,
+    lock: Option<Lock>,
+}
+
+/// An advisory lock, see `lock_project`.
+struct Lock {
+    token: String,
+    expires: DateTime<Utc>,
+}
+
+/// What a change of a project is conditional on, from the headers of its request.
+struct Preconditions {
+    if_match: Option<String>,
+    lock: Option<String>,
+}
+
+/// Counts a compile of a project for as long as it is alive.
+struct Compiling(Arc<Entry>);
+
+struct Project {
+    world: DockerWorld,
+    history: History,
+}
+
+/// Replace `length` bytes of a text file from `offset` on, offsets counting UTF-8 bytes.
+#[derive(Deserialize, ToSchema)]
+pub struct RangeEdit {
+    offset: usize,
+    length: usize,
+    replacement: String,
+}
+
+/// Files and inputs for one compile of a project, the files shadowing the project's own.
+#[derive(Deserialize, ToSchema)]
+pub struct Overlay {
+    #[serde(default)]
+    files: Vec<JsonFile>,
+    /// Values the document reads from `sys.inputs`.
+    #[serde(default)]
+    inputs: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RevisionQuery {
+    /// Compile the project as it was at this revision rather than as it is.
+    revision: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadQuery {
+    /// A name to list the project by.
+    name: Option<String>,
+    /// Seconds the project is kept after its last change or compile rather than
+    /// `PROJECT_TTL`, 0 keeping it for ever.
+    ttl_seconds: Option<u64>,
+}
+
+/// What `PATCH /projects/{id}` changes, fields left out staying as they are.
+#[derive(Deserialize, ToSchema)]
+pub struct ProjectSettings {
+    name: Option<String>,
+    /// As in `PUT /projects/{id}`.
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProjectListQuery {
+    /// Only projects of this tenant, for the admin token only. Others see their own projects.
+    owner: Option<String>,
+    /// Only projects whose name starts with this.
+    prefix: Option<String>,
+    offset: Option<usize>,
+    /// Projects per page, 50 by default and at most 500.
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteQuery {
+    /// Delete the project even while it is being compiled.
+    #[serde(default)]
+    force: bool,
+}
+
+/// What `GET /projects` and `GET /projects/{id}` report about a project.
+#[derive(Serialize, ToSchema)]
+pub struct ProjectJson {
+    id: String,
+    name: Option<String>,
+    owner: Option<String>,
+    file_count: usize,
+    /// Bytes of all files together.
+    total_bytes: u64,
+    created: DateTime<Utc>,
+    /// When the files last changed.
+    updated: DateTime<Utc>,
+    compiled: Option<DateTime<Utc>>,
+    /// The project's own TTL, if it set one.
+    ttl_seconds: Option<u64>,
+    /// When the project will be removed unless it is changed or compiled before.
+    expires: Option<DateTime<Utc>>,
+    /// The version changes must name in `If-Match`, as in the `ETag` header.
+    etag: String,
+    /// Every file, only from `GET /projects/{id}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<RevisionFile>>,
+}
+
+/// A page of `GET /projects`.
+#[derive(Serialize, ToSchema)]
+pub struct ProjectPage {
+    projects: Vec<ProjectJson>,
+    total: usize,
+    /// Offset of the next page, if there is one.
+    next_offset: Option<usize>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PatchQuery {
+    /// Add the file if the project has none at the path, rather than answering 404.
+    #[serde(default)]
+    create: bool,
+}
+
+/// Ask for `POST /projects/{id}/lock`.
+#[derive(Deserialize, ToSchema)]
+pub struct LockRequest {
+    /// Seconds to hold the lock for, 30 by default and at most 300.
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LockJson {
+    /// Send in `X-Project-Lock` with every change while holding the lock.
+    token: String,
+    expires: DateTime<Utc>,
+}
+
+impl Project {
+    fn new(world: DockerWorld, history: History) -> Self {
+        Self { world, history }
+    }
+}
+
+impl About {
+    fn etag(&self) -> String {
+        format!("\"{:016x}\"", self.version)
+    }
+
+    /// The lock, unless it expired.
+    fn held(&self) -> Option<&Lock> {
+        self.lock.as_ref().filter(|lock| lock.expires > Utc::now())
+    }
+}
+
+fn locked(lock: &Lock) -> Error {
+    Code::ProjectLocked.error(format!("The project is locked until {}", lock.expires.to_rfc3339()))
+}
+
+impl Preconditions {
+    fn of(request: &HttpRequest) -> Self {
+        let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        Self { if_match: header(IF_MATCH.as_str()), lock: header(LOCK_HEADER) }
+    }
+
+    /// Refuse a change unless the caller holds the project's lock, if it is locked, and
+    /// `If-Match` names the current version or is `*`.
+    fn check(&self, about: &About) -> Result<(), Error> {
+        if let Some(lock) = about.held().filter(|lock| self.lock.as_deref() != Some(lock.token.as_str())) {
+            return Err(locked(lock));
+        }
+        let etag = about.etag();
+        match self.if_match.as_deref().map(str::trim) {
+            None => {
+                Err(Code::VersionRequired.error(format!(
+                    "Send the project's version {etag} in If-Match, or * to change it regardless"
+                )))
+            }
+            Some("*") => { Ok(()) }
+            Some(tags) if tags.split(',').any(|tag| tag.trim() == etag) => { Ok(()) }
+            Some(_) => { Err(Code::VersionMismatch.error(format!("The project is at {etag} now"))) }
+        }
+    }
+}
+
+/// The key of a project in `Projects` and the storage, the id prefixed with the tenant if any.
+///
+/// Tenants never have control characters, and project ids are refused with them.
+fn scoped(owner: &Option<String>, id: &str) -> String {
+    match owner {
+        None => { id.to_string() }
+        Some(tenant) => { format!("{tenant}\u{1f}{id}") }
+    }
+}
+
+impl Entry {
+    fn new(id: &str, project: Project, info: ProjectInfo) -> Self {
+        let files = project.history.current();
+        Self {
+            id: id.to_string(),
+            project: Mutex::new(project),
+            about: Mutex::new(About { info, files, version: rand::random(), lock: None }),
+            compiling: AtomicUsize::new(0),
+        }
+    }
+
+    fn owned_by(&self, owner: &Option<String>) -> bool {
+        self.about.lock().unwrap().info.owner == *owner
+    }
+
+    /// When the project expires, `None` if it is kept for ever.
+    fn expires(&self, config: &Config) -> Option<DateTime<Utc>> {
+        expires(&self.about.lock().unwrap().info, config)
+    }
+
+    fn json(&self, with_files: bool, config: &Config) -> ProjectJson {
+        let about = self.about.lock().unwrap();
+        ProjectJson {
+            id: self.id.clone(),
+            name: about.info.name.clone(),
+            owner: about.info.owner.clone(),
+            file_count: about.files.len(),
+            total_bytes: about.files.iter().map(|file| file.size).sum(),
+            created: about.info.created,
+            updated: about.info.updated,
+            compiled: about.info.compiled,
+            ttl_seconds: about.info.ttl.map(|ttl| ttl.as_secs()),
+            expires: expires(&about.info, config),
+            etag: about.etag(),
+            files: with_files.then(|| about.files.clone()),
+        }
+    }
+}
+
+fn expires(info: &ProjectInfo, config: &Config) -> Option<DateTime<Utc>> {
+    let ttl = info.ttl.or(config.project_ttl).filter(|ttl| !ttl.is_zero())?;
+    let used = info.compiled.map_or(info.updated, |compiled| compiled.max(info.updated));
+    Some(used + chrono::Duration::from_std(ttl).ok()?)
+}
+
+fn gone(at: DateTime<Utc>) -> Error {
+    Code::ProjectExpired.error(format!("The project expired at {}", at.to_rfc3339()))
+}
+
+impl Compiling {
+    fn start(entry: Arc<Entry>) -> Self {
+        entry.compiling.fetch_add(1, Ordering::Relaxed);
+        Self(entry)
+    }
+}
+
+impl Drop for Compiling {
+    fn drop(&mut self) {
+        self.0.compiling.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Projects {
+    /// The projects the storage kept from before a restart.
+    pub fn restore(
+        storage: Arc<dyn Storage>,
+        fonts: &FontLibrary,
+        packages: Arc<PackageStore>,
+        config: &Config,
+    ) -> Result<Self, String> {
+        let mut worlds = HashMap::new();
+        for project in storage.projects()? {
+            let documents: Vec<_> = project.files.into_iter().map(StoredFile::document).collect();
+            let Some((main, files)) = documents.split_first() else { continue };
+            let world = DockerWorld::builder()
+                .main(main.clone())
+                .files(files.iter().cloned())
+                .font_db(fonts.current())
+                .packages(packages.clone())
+                .timezone(config.timezone)
+                .build()
+                .map_err(|problem| format!("cannot restore the project {}: {problem}", project.id))?;
+            let history = History::restore(project.revisions, project.contents);
+            let id = project.id.split_once('\u{1f}').map_or(project.id.as_str(), |(_, id)| id);
+            let entry = Entry::new(id, Project::new(world, history), project.info);
+            // Projects stored before there were revisions start their history as they are.
+            if entry.project.lock().unwrap().history.latest() == 0 {
+                record(&*storage, &project.id, &entry, &mut entry.project.lock().unwrap(), &documents, config);
+            }
+            worlds.insert(project.id, Arc::new(entry));
+        }
+        if !worlds.is_empty() {
+            tracing::info!("restored {} projects", worlds.len());
+        }
+        Ok(Self { worlds: Mutex::new(worlds), expired: Mutex::default(), storage })
+    }
+
+    /// Drop the revisions past their retention, also of projects that didn't change since.
+    pub fn prune_revisions(&self, config: &Config) {
+        let projects: Vec<_> = self.worlds.lock().unwrap().iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        for (key, entry) in projects {
+            let pruned = entry.project.lock().unwrap().history.prune(config.project_revisions, config.project_revision_retention);
+            if let Some(first) = pruned {
+                self.storage.prune_revisions(&key, first);
+            }
+        }
+    }
+
+    /// Remove the projects whose TTL passed, unless they are being compiled.
+    pub fn expire(&self, config: &Config, metrics: &Metrics) {
+        let now = Utc::now();
+        let removed: Vec<_> = {
+            let mut worlds = self.worlds.lock().unwrap();
+            let due: Vec<_> = worlds.iter()
+                .filter(|(_, entry)| entry.compiling.load(Ordering::Relaxed) == 0)
+                .filter_map(|(key, entry)| Some((key.clone(), entry.expires(config).filter(|at| *at <= now)?)))
+                .collect();
+            due.into_iter()
+                .filter_map(|(key, at)| Some((worlds.remove(&key)?, key, at)))
+                .collect()
+        };
+
+        let mut expired = self.expired.lock().unwrap();
+        expired.retain(|_, at| *at + chrono::Duration::days(TOMBSTONE_DAYS) > now);
+        if removed.is_empty() {
+            return;
+        }
+        let mut bytes = 0;
+        for (entry, key, at) in &removed {
+            bytes += entry.project.lock().unwrap().history.size();
+            self.storage.remove_project(key);
+            expired.insert(key.clone(), *at);
+        }
+        metrics.expired_projects.fetch_add(removed.len() as u64, Ordering::Relaxed);
+        metrics.expired_project_bytes.fetch_add(bytes, Ordering::Relaxed);
+        tracing::info!("removed {} expired projects, reclaiming {bytes} bytes", removed.len());
+    }
+
+    /// Drop every project of `tenant`, with what the storage has of them.
+    pub fn remove_tenant(&self, tenant: &str) {
+        let prefix = scoped(&Some(tenant.to_string()), "");
+        let removed: Vec<String> = {
+            let mut worlds = self.worlds.lock().unwrap();
+            let keys: Vec<_> = worlds.keys().filter(|key| key.starts_with(&prefix)).cloned().collect();
+            keys.into_iter().filter(|key| worlds.remove(key).is_some()).collect()
+        };
+        self.expired.lock().unwrap().retain(|key, _| !key.starts_with(&prefix));
+        for key in &removed {
+            self.storage.remove_project(key);
+        }
+    }
+
+    /// The project `id` of `owner`, answering 410 once it expired.
+    fn get(&self, id: &str, owner: &Option<String>, config: &Config) -> Result<Arc<Entry>, Error> {
+        let key = scoped(owner, id);
+        let entry = self.worlds.lock().unwrap().get(&key).cloned();
+        if let Some(entry) = entry {
+            return match entry.expires(config) {
+                Some(at) if at <= Utc::now() => { Err(gone(at)) }
+                _ => { Ok(entry) }
+            };
+        }
+        match self.expired.lock().unwrap().get(&key) {
+            Some(at) => { Err(gone(*at)) }
+            None => { Err(Code::ProjectNotFound.error("No such project")) }
+        }
+    }
+
+    /// Write uploaded files to the storage, the first being the main file of a new project.
+    async fn save(projects: &web::Data<Projects>, key: &str, documents: &[DocumentFile]) {
+        let (projects, key, documents) = (projects.clone(), key.to_string(), documents.to_vec());
+        let _ = web::block(move || {
+            let files: Vec<_> = documents.iter().filter_map(StoredFile::of).collect();
+            projects.storage.save_files(&key, &files);
+        }).await;
+    }
+}
+
+/// Note a change of `changed` to a project: a new version, and a revision written through to
+/// the storage, dropping those the retention no longer keeps.
+fn record(
+    storage: &dyn Storage,
+    key: &str,
+    entry: &Entry,
+    project: &mut Project,
+    changed: &[DocumentFile],
+    config: &Config,
+) {
+    match project.history.record(changed) {
+        Ok((revision, contents)) => { storage.save_revision(key, &revision, &contents) }
+        Err(problem) => { tracing::error!("could not record a revision of the project {}: {problem}", entry.id) }
+    }
+    if let Some(first) = project.history.prune(config.project_revisions, config.project_revision_retention) {
+        storage.prune_revisions(key, first);
+    }
+
+    let mut about = entry.about.lock().unwrap();
+    about.version = rand::random();
+    about.info.updated = Utc::now();
+    about.files = project.history.current();
+    storage.save_info(key, &about.info);
+}
+
+/// The response to a change, naming the version and the revision it created.
+fn changed(mut response: HttpResponseBuilder, entry: &Entry, project: &Project) -> HttpResponse {
+    response
+        .insert_header((ETAG, entry.about.lock().unwrap().etag()))
+        .insert_header((REVISION_HEADER, project.history.latest().to_string()))
+        .finish()
+}
+
+/// Create a project from a multipart upload, or apply the uploaded files to an existing one.
+///
+/// As with `/compile`, the first part of a new project is its main document. Changing an
+/// existing project needs `If-Match`, see `patch_file`.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path, description = "Chosen by the client"),
+        ("If-Match" = Option<String>, Header, description = "The `ETag` the change is based on, or `*`, for existing projects"),
+        ("X-Project-Lock" = Option<String>, Header, description = "The lock's token while holding it"),
+        UploadQuery,
+    ),
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The project was created, its version in `ETag`"),
+        (status = 204, description = "The files were applied to the project, its new version in `ETag`"),
+        (status = 400, description = "The upload or the id is invalid", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 412, description = "The project changed since the version in `If-Match`", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the project's lock", body = ErrorOutput),
+        (status = 428, description = "`If-Match` is missing", body = ErrorOutput),
+    ),
+)]
+#[put("/projects/{id}")]
+#[allow(clippy::too_many_arguments)]
+async fn upload_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    projects: web::Data<Projects>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    config: CurrentConfig,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if id.chars().any(char::is_control) {
+        return Err(Code::InvalidProjectId.error("A project id may not have control characters"));
+    }
+    let documents = read_documents(&request, payload, &config).await?;
+    let owner = owner(&request);
+    let key = scoped(&owner, &id);
+    let UploadQuery { name, ttl_seconds } = query.into_inner();
+    let ttl = ttl_seconds.map(Duration::from_secs);
+
+    let existing = projects.worlds.lock().unwrap().get(&key).cloned();
+    if let Some(entry) = existing {
+        if let Some(at) = entry.expires(&config).filter(|at| *at <= Utc::now()) {
+            return Err(gone(at));
+        }
+        let preconditions = Preconditions::of(&request);
+        let (stored, config) = (projects.clone(), config.snapshot());
+        return web::block(move || {
+            let mut project = entry.project.lock().unwrap();
+            preconditions.check(&entry.about.lock().unwrap())?;
+            let files: Vec<_> = documents.iter().filter_map(StoredFile::of).collect();
+            stored.storage.save_files(&key, &files);
+            for document in &documents {
+                project.world.add_file(document.clone());
+            }
+            let mut about = entry.about.lock().unwrap();
+            if name.is_some() {
+                about.info.name = name;
+            }
+            if ttl.is_some() {
+                about.info.ttl = ttl;
+            }
+            drop(about);
+            record(&*stored.storage, &key, &entry, &mut project, &documents, &config);
+            Ok(changed(HttpResponse::NoContent(), &entry, &project))
+        }).await?;
+    }
+
+    let Some((main, files)) = documents.split_first() else {
+        return Err(Code::MissingMain.error("A new project needs at least a main document"));
+    };
+    let world = DockerWorld::builder()
+        .main(main.clone())
+        .files(files.iter().cloned())
+        .font_db(fonts.current())
+        .packages(packages.into_inner())
+        .timezone(config.timezone)
+        .build()
+        .map_err(unbuildable)?;
+    Projects::save(&projects, &key, &documents).await;
+    let (stored, config) = (projects.clone(), config.snapshot());
+    Ok(web::block(move || {
+        let now = Utc::now();
+        let info = ProjectInfo { owner, name, created: now, updated: now, compiled: None, ttl };
+        let entry = Entry::new(&id, Project::new(world, History::default()), info);
+        let response = {
+            let mut project = entry.project.lock().unwrap();
+            record(&*stored.storage, &key, &entry, &mut project, &documents, &config);
+            changed(HttpResponse::Created(), &entry, &project)
+        };
+        stored.expired.lock().unwrap().remove(&key);
+        stored.worlds.lock().unwrap().insert(key, Arc::new(entry));
+        response
+    }).await?)
+}
+
+/// Change one file of a project, either sending all of its new contents or, with the
+/// content type `application/vnd.typstapi.range+json`, a `RangeEdit` of a text file.
+///
+/// Every change of a project names the version it is based on in `If-Match`, the `ETag` of
+/// the last change or read seen, so a change made in between is reported rather than
+/// overwritten. `*` changes the project whatever its version. While the project is locked,
+/// only the lock's holder may change it, see `lock_project`.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path),
+        ("path" = String, Path, description = "The file's path in the project, like `chapters/intro.typ`"),
+        ("If-Match" = String, Header, description = "The `ETag` the change is based on, or `*`"),
+        ("X-Project-Lock" = Option<String>, Header, description = "The lock's token while holding it"),
+        PatchQuery,
+    ),
+    request_body(content(
+        ([u8] = "application/octet-stream"),
+        (RangeEdit = "application/vnd.typstapi.range+json"),
+    )),
+    responses(
+        (status = 201, description = "The file was added, the project's new version in `ETag`"),
+        (status = 204, description = "The file was changed, the project's new version in `ETag`"),
+        (status = 400, description = "The file or the range is invalid", body = ErrorOutput),
+        (status = 404, description = "No such project, or no such file without `create`", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 412, description = "The project changed since the version in `If-Match`", body = ErrorOutput),
+        (status = 413, description = "The file is larger than `MAX_FILE_SIZE_KB`", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the project's lock", body = ErrorOutput),
+        (status = 428, description = "`If-Match` is missing", body = ErrorOutput),
+    ),
+)]
+#[patch("/projects/{id}/files/{path:.+}")]
+async fn patch_file(
+    request: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<PatchQuery>,
+    mut payload: web::Payload,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let (id, path) = path.into_inner();
+    valid_name(&path, config.max_filename_length).map_err(|problem| Code::InvalidFilename.error(problem))?;
+    allowed(&path, &config.allowed_extensions, config.allow_extensionless).map_err(|problem| Code::FileTypeNotAllowed.error(problem))?;
+    let owner = owner(&request);
+    let key = scoped(&owner, &id);
+    let entry = projects.get(&id, &owner, &config)?;
+
+    let mut body = Vec::new();
+    while let Some(chunk) = next(&mut payload, config.upload_idle_timeout).await? {
+        body.extend_from_slice(&chunk.map_err(|problem| Code::InvalidBody.error(problem))?);
+        if let Some(max) = config.max_file_size.filter(|max| body.len() > *max) {
+            return Err(Code::FileTooLarge.error(format!("A file may have at most {max} bytes")));
+        }
+    }
+    let range = request.headers().get(CONTENT_TYPE).is_some_and(|value| value == RANGE_TYPE);
+    let edit = match range {
+        true => { Some(serde_json::from_slice::<RangeEdit>(&body).map_err(|problem| Code::InvalidJson.error(problem))?) }
+        false => {
+            if sniff::applies(&path) {
+                sniff::check(&path, &body).map_err(|problem| Code::InvalidFileContent.error(problem))?;
+            }
+            None
+        }
+    };
+    let preconditions = Preconditions::of(&request);
+
+    // Blocking, since a compile of the project holds it, and written to the storage under
+    // the lock, so concurrent changes reach it in the order they were made.
+    let (create, stored, config) = (query.create, projects.clone(), config.snapshot());
+    web::block(move || {
+        let mut project = entry.project.lock().unwrap();
+        preconditions.check(&entry.about.lock().unwrap())?;
+
+        let (document, added) = match edit {
+            Some(edit) => {
+                let end = edit.offset.checked_add(edit.length).ok_or_else(|| Code::InvalidEdit.error("The range is too long"))?;
+                let text = project.world.edit_file(&path, edit.offset..end, &edit.replacement).map_err(edit_error)?;
+                (DocumentFile::new(&path, text.to_vec()), false)
+            }
+            None => {
+                match project.world.update_file(&path, body.clone()) {
+                    Ok(()) => { (DocumentFile::new(&path, body), false) }
+                    Err(EditError::NotFound(_)) if create => {
+                        let document = DocumentFile::new(&path, body);
+                        project.world.add_file(document.clone());
+                        (document, true)
+                    }
+                    Err(problem) => { return Err(edit_error(problem)) }
+                }
+            }
+        };
+        if let Some(file) = StoredFile::of(&document) {
+            stored.storage.save_files(&key, &[file]);
+        }
+        record(&*stored.storage, &key, &entry, &mut project, &[document], &config);
+
+        let response = match added {
+            true => { HttpResponse::Created() }
+            false => { HttpResponse::NoContent() }
+        };
+        Ok(changed(response, &entry, &project))
+    }).await?
+}
+
+fn edit_error(problem: EditError) -> Error {
+    match problem {
+        EditError::NotFound(_) => { Code::FileNotFound.error(problem) }
+        _ => { Code::InvalidEdit.error(problem) }
+    }
+}
+
+/// The overlay a compile request carries, from a multipart upload like `/compile` takes or
+/// an `Overlay`. A request without a body has none.
+async fn read_overlay(
+    request: &HttpRequest,
+    mut payload: web::Payload,
+    config: &Config,
+) -> Result<(Vec<DocumentFile>, BTreeMap<String, String>), Error> {
+    let content_type = request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    if content_type.starts_with("multipart/form-data") {
+        let documents = read_documents(request, Multipart::new(request.headers(), payload), config).await?;
+        return Ok((documents, BTreeMap::new()));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = next(&mut payload, config.upload_idle_timeout).await? {
+        body.extend_from_slice(&chunk.map_err(|problem| Code::InvalidBody.error(problem))?);
+        if let Some(max) = config.max_upload_size.filter(|max| body.len() > *max) {
+            return Err(Code::UploadTooLarge.error(format!("The upload is larger than {max} bytes")));
+        }
+    }
+    if body.is_empty() {
+        return Ok((vec![], BTreeMap::new()));
+    }
+    let overlay: Overlay = serde_json::from_slice(&body).map_err(|problem| Code::InvalidJson.error(problem))?;
+    let documents = decode_files(overlay.files, config)?;
+    Ok((documents, overlay.inputs))
+}
+
+/// The value of `X-Typst-Overlay`, escaping what a header can't hold.
+fn overlay_header(overlay: &[DocumentFile]) -> HeaderValue {
+    let listed: Vec<String> = overlay.iter()
+        .map(|file| {
+            let path = file.name.vpath().as_rootless_path().to_string_lossy().into_owned();
+            let path: String = path.bytes()
+                .map(|byte| match byte.is_ascii_graphic() && byte != b',' && byte != b'%' {
+                    true => { (byte as char).to_string() }
+                    false => { format!("%{byte:02X}") }
+                })
+                .collect();
+            let digest: String = file.data.load()
+                .map(|data| Sha256::digest(&*data).iter().map(|byte| format!("{byte:02x}")).collect())
+                .unwrap_or_default();
+            format!("{path}={digest}")
+        })
+        .collect();
+    HeaderValue::from_str(&listed.join(", ")).expect("the overlay is escaped")
+}
+
+/// List the revisions a project keeps, oldest first.
+#[utoipa::path(
+    tag = "projects",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "The revisions with their files, the project's version in `ETag`", body = [RevisionJson]),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+    ),
+)]
+#[get("/projects/{id}/revisions")]
+async fn list_revisions(
+    request: HttpRequest,
+    id: web::Path<String>,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    // Blocking, since a compile of the project holds it.
+    let (revisions, etag) = web::block(move || {
+        let project = entry.project.lock().unwrap();
+        (project.history.list(), entry.about.lock().unwrap().etag())
+    }).await?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, etag)).json(revisions))
+}
+
+/// Compile a project, optionally with an overlay of files for this compile only, or as it
+/// was at an earlier revision.
+///
+/// The overlay, sent as a multipart upload or an `Overlay`, shadows the project's files at
+/// the same paths and is never stored. Its files and their SHA-256 are listed in `X-Typst-Overlay`.
+/// An earlier revision is compiled in a world of its own, leaving the project's untouched.
+#[utoipa::path(
+    tag = "projects",
+    params(("id" = String, Path), RevisionQuery, OptionsQuery),
+    request_body(content(
+        (Upload = "multipart/form-data"),
+        (Overlay = "application/json"),
+    )),
+    responses(
+        (status = 200, description = "The PDF, or with `include=deps` the PDF and what the compile read", content(
+            ([u8] = "application/octet-stream"),
+            (JsonOutput = "application/json"),
+        )),
+        (status = 400, description = "The overlay is invalid or the document has errors", body = ErrorOutput),
+        (status = 404, description = "No such project, or it keeps no such revision", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 413, description = "The overlay exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits, or with `fail_on_warnings` compiled with warnings", body = WarningsOutput),
+    ),
+)]
+#[post("/projects/{id}/compile")]
+#[allow(clippy::too_many_arguments)]
+async fn compile_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<RevisionQuery>,
+    projects: web::Data<Projects>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    config: CurrentConfig,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let mut options = config.compile_options(&request)?;
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    let compiling = Compiling::start(entry.clone());
+    let (overlay, inputs) = read_overlay(&request, payload, &config).await?;
+    options.inputs.extend(inputs);
+    let header = (!overlay.is_empty()).then(|| overlay_header(&overlay));
+
+    let historical = match query.revision {
+        None => { None }
+        Some(number) => {
+            let entry = entry.clone();
+            let found = web::block(move || {
+                let project = entry.project.lock().unwrap();
+                (project.history.latest(), project.history.files(number))
+            }).await?;
+            match found {
+                (latest, _) if latest == number => { None }
+                (_, Some(files)) => { Some((number, files)) }
+                (_, None) => {
+                    return Err(Code::RevisionNotFound.error(format!(
+                        "The project keeps no revision {number}, GET /projects/{id}/revisions lists those it does"
+                    )))
+                }
+            }
+        }
+    };
+    let historical = match historical {
+        None => { None }
+        Some((number, files)) => {
+            let (main, files) = files.split_first().expect("a revision has the main file");
+            let world = options.world()
+                .main(main.clone())
+                .files(files.iter().cloned())
+                .font_db(fonts.current())
+                .packages(packages.into_inner())
+                .build()
+                .map_err(|problem| Code::Internal.error(problem))?;
+            Some((number, world))
+        }
+    };
+
+    let timezone = options.timezone;
+    let max_pages = options.max_pages;
+    let cache_max_age = config.cache_max_age;
+    let format = options.output_format();
+    let optimizer = options.optimizer.clone();
+    let inputs = options.inputs.clone();
+    let revision = Arc::new(AtomicU64::new(0));
+    let compiled_revision = revision.clone();
+    let stored = projects.clone();
+    let compiled = run_compile(&slots, &metrics, options.timeout, options.priority, format, move |cancellation| {
+        let mut compiled = match historical {
+            Some((number, mut world)) => {
+                compiled_revision.store(number, Ordering::Relaxed);
+                world.compile_overlaid(overlay, max_pages, cancellation)
+            }
+            None => {
+                let mut project = entry.project.lock().unwrap();
+                compiled_revision.store(project.history.latest(), Ordering::Relaxed);
+                let world = &mut project.world;
+                world.set_timezone(timezone);
+                world.set_inputs(inputs);
+                world.compile_overlaid(overlay, max_pages, cancellation)
+            }
+        };
+        comemo::evict(cache_max_age);
+        if let (Ok(compiled), Some(qpdf)) = (&mut compiled, &optimizer) {
+            compiled.pdf = optimize::optimize(std::mem::take(&mut compiled.pdf), qpdf);
+        }
+        let mut about = entry.about.lock().unwrap();
+        about.info.compiled = Some(Utc::now());
+        stored.storage.save_info(&scoped(&about.info.owner, &entry.id), &about.info);
+        drop(about);
+        compiled
+    }).await;
+    drop(compiling);
+    audit::compiled(&request, format, &compiled);
+    let compiled = strict(compiled?, options.fail_on_warnings);
+
+    let mut response = respond(CURRENT_VERSION, compiled, &options, &metrics)?;
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static("x-project-revision"), revision.load(Ordering::Relaxed).into());
+    if let Some(header) = header {
+        headers.insert(HeaderName::from_static("x-typst-overlay"), header);
+    }
+    Ok(response)
+}
+
+/// List the projects of the caller's tenant, most recently changed first.
+#[utoipa::path(
+    tag = "projects",
+    params(ProjectListQuery),
+    responses(
+        (status = 200, description = "A page of projects, without their files", body = ProjectPage),
+        (status = 400, description = "The query is invalid", body = ErrorOutput),
+        (status = 401, description = "`owner` was given without the admin token", body = ErrorOutput),
+    ),
+)]
+#[get("/projects")]
+async fn list_projects(
+    request: HttpRequest,
+    config: CurrentConfig,
+    projects: web::Data<Projects>,
+) -> Result<HttpResponse, Error> {
+    let query = web::Query::<ProjectListQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    let owner = match &query.owner {
+        Some(owner) => {
+            require_admin(&request, &config)?;
+            Some(owner.clone())
+        }
+        None => { owner(&request) }
+    };
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(50).min(500);
+
+    let entries: Vec<_> = projects.worlds.lock().unwrap().iter()
+        .map(|(_, entry)| entry.clone())
+        .collect();
+    let mut matching: Vec<ProjectJson> = entries.iter()
+        .filter(|entry| entry.owned_by(&owner))
+        .map(|entry| entry.json(false, &config))
+        .filter(|project| project.expires.is_none_or(|at| at > Utc::now()))
+        .filter(|project| match &query.prefix {
+            None => { true }
+            Some(prefix) => { project.name.as_deref().is_some_and(|name| name.starts_with(prefix.as_str())) }
+        })
+        .collect();
+    matching.sort_by(|a, b| b.updated.cmp(&a.updated).then_with(|| a.id.cmp(&b.id)));
+
+    let total = matching.len();
+    let page: Vec<_> = matching.into_iter().skip(offset).take(limit).collect();
+    let next_offset = (offset + page.len() < total).then_some(offset + page.len());
+
+    Ok(HttpResponse::Ok().json(ProjectPage { projects: page, total, next_offset }))
+}
+
+/// A project with every file of its latest revision.
+#[utoipa::path(
+    tag = "projects",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "The project and its files", body = ProjectJson),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+    ),
+)]
+#[get("/projects/{id}")]
+async fn get_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    let json = entry.json(true, &config);
+    Ok(HttpResponse::Ok().insert_header((ETAG, json.etag.clone())).json(json))
+}
+
+/// Rename a project or change its TTL, see `ProjectSettings`. Needs `If-Match` like every
+/// change, see `patch_file`, though the version stays as it is.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path),
+        ("If-Match" = String, Header, description = "The `ETag` the change is based on, or `*`"),
+        ("X-Project-Lock" = Option<String>, Header, description = "The lock's token while holding it"),
+    ),
+    request_body = ProjectSettings,
+    responses(
+        (status = 200, description = "The project as it is now", body = ProjectJson),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 412, description = "The project changed since the version in `If-Match`", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the project's lock", body = ErrorOutput),
+        (status = 428, description = "`If-Match` is missing", body = ErrorOutput),
+    ),
+)]
+#[patch("/projects/{id}")]
+async fn update_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    settings: web::Json<ProjectSettings>,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    let ProjectSettings { name, ttl_seconds } = settings.into_inner();
+    let preconditions = Preconditions::of(&request);
+    let (stored, config) = (projects.clone(), config.snapshot());
+    web::block(move || {
+        let mut about = entry.about.lock().unwrap();
+        preconditions.check(&about)?;
+        if name.is_some() {
+            about.info.name = name;
+        }
+        if let Some(ttl) = ttl_seconds {
+            about.info.ttl = Some(Duration::from_secs(ttl));
+        }
+        stored.storage.save_info(&scoped(&about.info.owner, &id), &about.info);
+        drop(about);
+        Ok(HttpResponse::Ok().json(entry.json(false, &config)))
+    }).await?
+}
+
+/// Delete a project with its files and revisions, dropping its world and the sources typst
+/// cached in it.
+///
+/// A compile already running when it is forced finishes, from the files it had.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path),
+        ("If-Match" = String, Header, description = "The `ETag` the deletion is based on, or `*`"),
+        ("X-Project-Lock" = Option<String>, Header, description = "The lock's token while holding it"),
+        DeleteQuery,
+    ),
+    responses(
+        (status = 204, description = "The project is deleted"),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 409, description = "The project is being compiled, wait or delete it with `force`", body = ErrorOutput),
+        (status = 412, description = "The project changed since the version in `If-Match`", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the project's lock", body = ErrorOutput),
+        (status = 428, description = "`If-Match` is missing", body = ErrorOutput),
+    ),
+)]
+#[delete("/projects/{id}")]
+async fn delete_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+    projects: web::Data<Projects>,
+) -> Result<HttpResponse, Error> {
+    let key = scoped(&owner(&request), &id);
+    {
+        let mut worlds = projects.worlds.lock().unwrap();
+        let entry = worlds.get(&key).ok_or_else(|| Code::ProjectNotFound.error("No such project"))?;
+        Preconditions::of(&request).check(&entry.about.lock().unwrap())?;
+        if !query.force && entry.compiling.load(Ordering::Relaxed) > 0 {
+            return Err(Code::ProjectBusy.error("The project is being compiled, wait or delete it with ?force=true"));
+        }
+        worlds.remove(&key);
+    }
+    projects.expired.lock().unwrap().remove(&key);
+    let stored = projects.clone();
+    web::block(move || stored.storage.remove_project(&key)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lock a project against changes by others, or renew the lock held.
+///
+/// The lock is advisory for reads and compiles, which go on, but every change by someone
+/// without its token, sent in `X-Project-Lock`, is refused with 423 until it expires or is
+/// released. Posting again with the token renews it for another TTL. A lock that expired is
+/// free: the next client to post takes it with a new token, and the old one stops working,
+/// so a client that died holding the lock holds up the others for at most its TTL. Locks are
+/// kept in memory and released when the server restarts.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path),
+        ("X-Project-Lock" = Option<String>, Header, description = "The token of the lock to renew"),
+    ),
+    request_body = LockRequest,
+    responses(
+        (status = 200, description = "The lock was renewed", body = LockJson),
+        (status = 201, description = "The lock was taken", body = LockJson),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the lock, until the time in the body", body = ErrorOutput),
+    ),
+)]
+#[post("/projects/{id}/lock")]
+async fn lock_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<LockRequest>,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    let held = Preconditions::of(&request).lock;
+    let ttl = body.ttl_seconds.unwrap_or(LOCK_TTL).clamp(1, LOCK_TTL_MAX);
+    let expires = Utc::now() + chrono::Duration::seconds(ttl as i64);
+
+    let mut about = entry.about.lock().unwrap();
+    let (token, mut response) = match about.held() {
+        Some(lock) if held.as_deref() == Some(lock.token.as_str()) => { (lock.token.clone(), HttpResponse::Ok()) }
+        Some(lock) => { return Err(locked(lock)) }
+        None => { (format!("{:032x}", rand::random::<u128>()), HttpResponse::Created()) }
+    };
+    about.lock = Some(Lock { token: token.clone(), expires });
+    Ok(response.json(LockJson { token, expires }))
+}
+
+/// Release the lock of a project, if the token in `X-Project-Lock` is its.
+#[utoipa::path(
+    tag = "projects",
+    params(
+        ("id" = String, Path),
+        ("X-Project-Lock" = String, Header, description = "The lock's token"),
+    ),
+    responses(
+        (status = 204, description = "The project is unlocked, it also is if the lock had expired"),
+        (status = 404, description = "No such project", body = ErrorOutput),
+        (status = 410, description = "The project expired, the time is in the body", body = ErrorOutput),
+        (status = 423, description = "Someone else holds the lock", body = ErrorOutput),
+    ),
+)]
+#[delete("/projects/{id}/lock")]
+async fn unlock_project(
+    request: HttpRequest,
+    id: web::Path<String>,
+    projects: web::Data<Projects>,
+    config: CurrentConfig,
+) -> Result<HttpResponse, Error> {
+    let entry = projects.get(&id, &owner(&request), &config)?;
+    let held = Preconditions::of(&request).lock;
+    let mut about = entry.about.lock().unwrap();
+    if let Some(lock) = about.held().filter(|lock| held.as_deref() != Some(lock.token.as_str())) {
+        return Err(locked(lock));
+    }
+    about.lock = None;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_projects)
+        .service(get_project)
+        .service(update_project)
+        .service(delete_project)
+        .service(lock_project)
+        .service(unlock_project)
+        .service(upload_project)
+        .service(patch_file)
+        .service(list_revisions)
+        .service(compile_project);
+}
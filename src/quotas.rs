@@ -0,0 +1,273 @@
+//! Compiles and output bytes each tenant may use per hour and day.
+//!
+//! The keys of a tenant share its usage, each held to the limits the keys file gives it.
+//! Windows are fixed UTC hours and days. Every compile request let through counts, failed
+//! ones too, since they took the same resources. With a shared store the counters are
+//! added up across replicas after each request, so a replica may let a few requests past
+//! the limit before it learns of the others' usage.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{get, web, Error, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::admin::require_admin;
+use crate::auth::keys::Limits;
+use crate::auth::{Principal, Tenant};
+use crate::config::{ConfigHandle, CurrentConfig};
+use crate::docker_world::ErrorOutput;
+use crate::errors::Code;
+use crate::store::SharedStore;
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+/// Usage per tenant, kept from the first request of each tenant on.
+pub struct Quotas {
+    usage: Mutex<HashMap<String, Usage>>,
+    shared: Option<Arc<dyn SharedStore>>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    /// The limits of the key of the tenant's latest request.
+    limits: Limits,
+    hour: Window,
+    day: Window,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Window {
+    /// Unix time the window started at.
+    start: i64,
+    compiles: u64,
+    output_bytes: u64,
+}
+
+/// A used up quota, answered with a 429.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Exceeded {
+    error: &'static str,
+    quota: &'static str,
+    limit: u64,
+    used: u64,
+    reset: DateTime<Utc>,
+}
+
+/// Usage of one quota, as the admin endpoint and the metrics report it.
+#[derive(Serialize, ToSchema)]
+pub struct Measured {
+    pub used: u64,
+    pub limit: Option<u64>,
+    pub reset: DateTime<Utc>,
+}
+
+impl Window {
+    /// This window, emptied if the current one of `length` seconds started since.
+    fn current(&mut self, now: i64, length: i64) -> &mut Self {
+        let start = now - now.rem_euclid(length);
+        if self.start != start {
+            *self = Window { start, ..Window::default() };
+        }
+        self
+    }
+
+    fn reset(&self, length: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.start + length, 0).single().unwrap_or_default()
+    }
+}
+
+impl Usage {
+    /// Every quota with its usage and limit, in a fixed order.
+    fn measure(&mut self, now: i64) -> [(&'static str, Measured); 3] {
+        let (hour, day) = (*self.hour.current(now, HOUR), *self.day.current(now, DAY));
+        [
+            ("compiles_per_hour", Measured { used: hour.compiles, limit: self.limits.compiles_per_hour, reset: hour.reset(HOUR) }),
+            ("compiles_per_day", Measured { used: day.compiles, limit: self.limits.compiles_per_day, reset: day.reset(DAY) }),
+            ("output_bytes_per_day", Measured { used: day.output_bytes, limit: self.limits.output_bytes_per_day, reset: day.reset(DAY) }),
+        ]
+    }
+}
+
+impl Quotas {
+    pub fn new(shared: Option<Arc<dyn SharedStore>>) -> Self {
+        Self { usage: Mutex::new(HashMap::new()), shared }
+    }
+
+    /// Count a compile for `name`, unless one of its quotas is used up.
+    fn admit(&self, name: &str, limits: Limits) -> Result<(), Exceeded> {
+        let now = Utc::now().timestamp();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(name.to_string()).or_default();
+        usage.limits = limits;
+        for (quota, measured) in usage.measure(now) {
+            if let Some(limit) = measured.limit.filter(|limit| measured.used >= *limit) {
+                return Err(Exceeded { error: Code::QuotaExceeded.as_str(), quota, limit, used: measured.used, reset: measured.reset });
+            }
+        }
+        usage.hour.compiles += 1;
+        usage.day.compiles += 1;
+        Ok(())
+    }
+
+    fn record_output(&self, name: &str, bytes: u64) {
+        let now = Utc::now().timestamp();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(name.to_string()).or_default();
+        usage.hour.current(now, HOUR).output_bytes += bytes;
+        usage.day.current(now, DAY).output_bytes += bytes;
+    }
+
+    /// Add one request's usage to the shared store and adopt the totals of all replicas,
+    /// blocking on the store.
+    fn share(&self, name: &str, compiles: u64, output_bytes: u64) {
+        let Some(shared) = &self.shared else { return };
+        let now = Utc::now().timestamp();
+        for length in [HOUR, DAY] {
+            let start = now - now.rem_euclid(length);
+            let prefix = format!("typstapi:quota:{name}:{length}:{start}");
+            let ttl = Duration::from_secs((start + length - now) as u64);
+            let compiles = shared.add(&format!("{prefix}:compiles"), compiles, ttl);
+            let output_bytes = shared.add(&format!("{prefix}:output_bytes"), output_bytes, ttl);
+
+            let mut usage = self.usage.lock().unwrap();
+            let Some(usage) = usage.get_mut(name) else { return };
+            let window = match length {
+                HOUR => { usage.hour.current(now, HOUR) }
+                _ => { usage.day.current(now, DAY) }
+            };
+            window.compiles = window.compiles.max(compiles.unwrap_or(0));
+            window.output_bytes = window.output_bytes.max(output_bytes.unwrap_or(0));
+        }
+    }
+
+    /// Forget the usage of `tenant`.
+    pub fn remove_tenant(&self, tenant: &str) {
+        self.usage.lock().unwrap().remove(tenant);
+    }
+
+    /// Usage of every tenant that made a request so far, by tenant and quota.
+    pub fn measure(&self) -> Vec<(String, [(&'static str, Measured); 3])> {
+        let now = Utc::now().timestamp();
+        let mut usage = self.usage.lock().unwrap();
+        let mut measured: Vec<_> = usage.iter_mut().map(|(name, usage)| (name.clone(), usage.measure(now))).collect();
+        measured.sort_by(|a, b| a.0.cmp(&b.0));
+        measured
+    }
+}
+
+pub fn starts_compile(request: &ServiceRequest) -> bool {
+    let path = request.path();
+    request.method() == Method::POST
+        && (matches!(path, "/compile" | "/compile/events" | "/compile/pages" | "/compile/exports" | "/render/element" | "/stats" | "/jobs")
+            || (path.starts_with("/projects/") && path.ends_with("/compile")))
+}
+
+/// Job results count as output, but downloading them doesn't need a compile.
+fn downloads_result(request: &ServiceRequest) -> bool {
+    request.method() == Method::GET && request.path().starts_with("/jobs/") && request.path().ends_with("/result")
+}
+
+/// Middleware enforcing the quotas of the tenant of the key a request authenticated with, for `App::wrap_fn`.
+pub fn enforce<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let (compiles, downloads) = (starts_compile(&request), downloads_result(&request));
+    let quotas = request.app_data::<web::Data<Quotas>>().cloned();
+    let key = request.extensions().get::<Principal>().map(|principal| principal.0.clone());
+    let name = request.extensions().get::<Tenant>().map(|tenant| tenant.0.clone());
+    let (Some(quotas), Some(key), Some(name), true) = (quotas, key, name, compiles || downloads) else {
+        let call = service.call(request);
+        return Box::pin(async move { Ok(call.await?.map_into_left_body()) });
+    };
+
+    if compiles {
+        let limits = request.app_data::<web::Data<ConfigHandle>>()
+            .and_then(|config| config.current().keys.iter().find(|issued| issued.name == key).map(|issued| issued.limits))
+            .unwrap_or_default();
+        if let Err(exceeded) = quotas.admit(&name, limits) {
+            tracing::info!(quota = exceeded.quota, limit = exceeded.limit, "quota exceeded");
+            let response = request.error_response(exceeded).map_into_right_body();
+            return Box::pin(async { Ok(response) });
+        }
+    }
+
+    let call = service.call(request);
+    Box::pin(async move {
+        let response = call.await?;
+        let output_bytes = match (response.status().is_success(), response.response().body().size()) {
+            (true, BodySize::Sized(size)) => { size }
+            _ => { 0 }
+        };
+        quotas.record_output(&name, output_bytes);
+        if quotas.shared.is_some() {
+            actix_web::rt::spawn(async move {
+                let _ = web::block(move || quotas.share(&name, compiles as u64, output_bytes)).await;
+            });
+        }
+        Ok(response.map_into_left_body())
+    })
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The tenant used {} of its {} {}, it resets at {}", self.used, self.limit, self.quota, self.reset.to_rfc3339())
+    }
+}
+
+impl ResponseError for Exceeded {
+    fn status_code(&self) -> StatusCode {
+        Code::QuotaExceeded.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let retry_after = (self.reset - Utc::now()).num_seconds().max(1);
+        HttpResponse::build(self.status_code())
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .json(self)
+    }
+}
+
+/// Usage of every tenant in the keys file, including those that made no request yet.
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "Usage by tenant and quota", body = HashMap<String, HashMap<String, Measured>>),
+        (status = 401, description = "The admin token is missing or wrong", body = ErrorOutput),
+    ),
+    security(("admin_token" = [])),
+)]
+#[get("/admin/quotas")]
+async fn usage(request: HttpRequest, config: CurrentConfig, quotas: web::Data<Quotas>) -> Result<HttpResponse, Error> {
+    require_admin(&request, &config)?;
+    let now = Utc::now().timestamp();
+    let mut measured: HashMap<String, _> = quotas.measure().into_iter().collect();
+    let mut tenants: BTreeMap<&str, BTreeMap<&str, Measured>> = BTreeMap::new();
+    for key in &config.keys {
+        if tenants.contains_key(key.tenant.as_str()) {
+            continue;
+        }
+        let quotas = measured.remove(&key.tenant)
+            .unwrap_or_else(|| Usage { limits: key.limits, ..Usage::default() }.measure(now));
+        tenants.insert(key.tenant.as_str(), quotas.into_iter().collect());
+    }
+    Ok(HttpResponse::Ok().json(tenants))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(usage);
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use crate::auth::{self, Principal};
+use crate::forwarded::ClientIp;
+use crate::config::{Config, ConfigHandle};
+use crate::errors::Code;
+
+/// Probes are never throttled, so monitoring keeps working while a client is limited.
+/// `/selftest` has a limit of its own.
+const EXEMPT_PATHS: [&str; 4] = ["/healthz", "/readyz", "/metrics", "/selftest"];
+
+/// Buckets idle for this long are full again anyway and can be forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket per key or client IP.
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Tokens added per second, zero disables limiting.
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What is left of a client's bucket after letting a request through.
+#[derive(Debug)]
+struct Quota {
+    limit: u64,
+    remaining: u64,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+}
+
+#[derive(Debug)]
+struct Limited {
+    quota: Quota,
+    /// Seconds until the next token.
+    retry_after: u64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let mut state = State {
+            rate: 0.0,
+            burst: 0.0,
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        };
+        state.configure(config);
+        Self { state: Mutex::new(state) }
+    }
+
+    /// Apply reloaded limits, keeping the buckets clients have drained so far.
+    pub fn reconfigure(&self, config: &Config) {
+        self.state.lock().unwrap().configure(config);
+    }
+
+    /// Take a token from the bucket of the client that sent `request`.
+    ///
+    /// This runs before authentication, so floods of bad keys are limited too: requests go by
+    /// the keys file entry their key belongs to, and by client IP without one.
+    fn check(&self, request: &ServiceRequest) -> Result<Option<Quota>, Limited> {
+        let key = request.app_data::<web::Data<ConfigHandle>>()
+            .and_then(|config| auth::key_name(request.headers(), &config.current()));
+        let mut state = self.state.lock().unwrap();
+        let (rate, burst) = (state.rate, state.burst);
+        if rate <= 0.0 || EXEMPT_PATHS.contains(&request.path()) {
+            return Ok(None);
+        }
+        let client = match key.map(|name| format!("key:{name}")).or_else(|| client(request)) {
+            None => { return Ok(None) }
+            Some(client) => { client }
+        };
+
+        let now = Instant::now();
+        if now.duration_since(state.swept) > SWEEP_INTERVAL {
+            state.buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+            state.swept = now;
+        }
+
+        let bucket = state.buckets.entry(client).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Limited {
+                quota: Quota::new(rate, burst, 0.0),
+                retry_after: ((1.0 - bucket.tokens) / rate).ceil() as u64,
+            });
+        }
+        bucket.tokens -= 1.0;
+        Ok(Some(Quota::new(rate, burst, bucket.tokens)))
+    }
+}
+
+impl State {
+    fn configure(&mut self, config: &Config) {
+        self.rate = config.rate_limit_per_minute as f64 / 60.0;
+        self.burst = config.rate_limit_burst.max(1) as f64;
+    }
+}
+
+/// The key's name, the client address seen through trusted proxies, or the socket user.
+pub fn client(request: &ServiceRequest) -> Option<String> {
+    if let Some(principal) = request.extensions().get::<Principal>() {
+        return Some(format!("key:{}", principal.0));
+    }
+    if let Some(ClientIp(ip)) = request.extensions().get::<ClientIp>() {
+        return Some(ip.to_string());
+    }
+    #[cfg(unix)]
+    if let Some(peer) = request.conn_data::<crate::unix_socket::PeerCredential>() {
+        return Some(format!("uid:{}", peer.uid));
+    }
+    request.peer_addr().map(|address| address.ip().to_string())
+}
+
+impl Quota {
+    fn new(rate: f64, burst: f64, tokens: f64) -> Self {
+        Self {
+            limit: burst as u64,
+            remaining: tokens.floor() as u64,
+            reset: ((burst - tokens) / rate).ceil() as u64,
+        }
+    }
+
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too many requests, retry in {} seconds", self.retry_after)
+    }
+}
+
+impl ResponseError for Limited {
+    fn status_code(&self) -> StatusCode {
+        Code::RateLimited.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code())
+            .insert_header((RETRY_AFTER, self.retry_after.max(1).to_string()))
+            .json(Code::RateLimited.body(self));
+        self.quota.insert_headers(response.headers_mut());
+        response
+    }
+}
+
+/// Middleware answering clients that ran out of tokens with a 429, for `App::wrap_fn`.
+pub fn limit<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let checked = match request.app_data::<web::Data<RateLimiter>>() {
+        None => { Ok(None) }
+        Some(limiter) => { limiter.check(&request) }
+    };
+
+    match checked {
+        Err(limited) => {
+            let response = request.error_response(limited).map_into_right_body();
+            Box::pin(async { Ok(response) })
+        }
+        Ok(quota) => {
+            let call = service.call(request);
+            Box::pin(async move {
+                let mut response = call.await?;
+                if let Some(quota) = quota {
+                    quota.insert_headers(response.headers_mut());
+                }
+                Ok(response.map_into_left_body())
+            })
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Applying a changed configuration file on SIGHUP, without dropping running compiles.
+
+use std::sync::Arc;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::web;
+use crate::config::{Config, ConfigHandle};
+use crate::docker_world::FontLibrary;
+use crate::rate_limit::RateLimiter;
+use crate::slots::CompileSlots;
+use crate::tls::Certificates;
+
+/// What a reload reconfigures besides the configuration requests see.
+pub struct Reloadable {
+    pub config: web::Data<ConfigHandle>,
+    pub fonts: web::Data<FontLibrary>,
+    pub slots: web::Data<CompileSlots>,
+    pub rate_limiter: web::Data<RateLimiter>,
+    /// The certificate served when the server speaks HTTPS.
+    pub certificates: Option<Arc<Certificates>>,
+}
+
+/// Reload whenever the process gets SIGHUP.
+pub async fn on_hangup(reloadable: Reloadable) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => { hangups }
+        Err(problem) => {
+            tracing::warn!("cannot listen for SIGHUP, the configuration won't reload: {problem}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload(&reloadable).await;
+    }
+}
+
+/// Read the configuration again and apply it, keeping the old one if it is invalid.
+///
+/// Requests in flight keep the configuration they started with, new ones see the whole new one.
+async fn reload(reloadable: &Reloadable) {
+    let mut config = match web::block(Config::reread).await {
+        Ok(Ok(config)) => { config }
+        Ok(Err(problems)) => {
+            tracing::error!("not reloading, the configuration is invalid: {}", problems.join("; "));
+            return;
+        }
+        Err(problem) => {
+            tracing::error!("not reloading: {problem}");
+            return;
+        }
+    };
+
+    let running = reloadable.config.current();
+    for setting in config.keep_startup_settings(&running) {
+        tracing::warn!("{setting} changed, but only takes effect after a restart");
+    }
+    if config.font_dir != running.font_dir {
+        let (fonts, font_dir) = (reloadable.fonts.clone(), config.font_dir.clone());
+        if web::block(move || fonts.reload(font_dir)).await.is_err() {
+            tracing::error!("reloading the fonts failed, keeping the old ones");
+        }
+    }
+
+    if let (Some(certificates), Some(cert), Some(key)) = (&reloadable.certificates, &config.tls_cert, &config.tls_key) {
+        let (certificates, cert, key) = (certificates.clone(), cert.clone(), key.clone());
+        match web::block(move || certificates.reload(&cert, &key)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(problem)) => { tracing::error!("keeping the old TLS certificate: {problem}") }
+            Err(problem) => { tracing::error!("keeping the old TLS certificate: {problem}") }
+        }
+    }
+
+    reloadable.slots.reconfigure(&config);
+    reloadable.rate_limiter.reconfigure(&config);
+    reloadable.config.replace(config);
+    tracing::info!("Reloaded the configuration");
+}
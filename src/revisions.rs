@@ -0,0 +1,153 @@
+//! The revisions of stored projects, so a document can be compiled again as it was after any change.
+//!
+//! Every change to a project's files adds a revision naming each file by the SHA-256 of its
+//! contents, which are kept once per project however many revisions have them. Revisions
+//! beyond `PROJECT_REVISIONS` or older than `PROJECT_REVISION_RETENTION` are dropped, the
+//! latest always staying.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use crate::docker_world::{file_id, DocumentFile, FileData};
+use crate::storage::StoredContent;
+
+/// One state of a project's files.
+#[derive(Clone)]
+pub struct Revision {
+    /// Counting from 1, one more than the revision before.
+    pub number: u64,
+    pub created: DateTime<Utc>,
+    /// Paths with the SHA-256 of their contents, the main file first.
+    pub files: Vec<(String, [u8; 32])>,
+}
+
+/// The revisions of one project and the contents they refer to.
+#[derive(Default)]
+pub struct History {
+    revisions: Vec<Revision>,
+    contents: HashMap<[u8; 32], FileData>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RevisionJson {
+    number: u64,
+    created: DateTime<Utc>,
+    files: Vec<RevisionFile>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct RevisionFile {
+    pub path: String,
+    /// Bytes of the contents.
+    pub size: u64,
+    /// Hex SHA-256 of the contents.
+    pub sha256: String,
+}
+
+fn path(file: &DocumentFile) -> String {
+    file.name.vpath().as_rootless_path().to_string_lossy().into_owned()
+}
+
+impl History {
+    /// A history restored from the storage.
+    pub fn restore(revisions: Vec<Revision>, contents: Vec<StoredContent>) -> Self {
+        let contents = contents.into_iter()
+            .map(|content| (content.sha256, FileData::Memory(content.data.into())))
+            .collect();
+        Self { revisions, contents }
+    }
+
+    /// Bytes of the contents all kept revisions have together.
+    pub fn size(&self) -> u64 {
+        self.contents.values().map(|data| data.size().unwrap_or(0)).sum()
+    }
+
+    /// Number of the latest revision, 0 before the first.
+    pub fn latest(&self) -> u64 {
+        self.revisions.last().map(|revision| revision.number).unwrap_or(0)
+    }
+
+    /// Add a revision in which `changed` replaced or joined the files of the latest one.
+    ///
+    /// Returns it with the contents no revision had before, to write to the storage.
+    pub fn record(&mut self, changed: &[DocumentFile]) -> Result<(Revision, Vec<StoredContent>), String> {
+        let mut files = self.revisions.last().map(|revision| revision.files.clone()).unwrap_or_default();
+        let mut added = Vec::new();
+        for file in changed {
+            let data = file.data.load().map_err(|problem| format!("cannot read {}: {problem}", path(file)))?;
+            let sha256: [u8; 32] = Sha256::digest(&*data).into();
+            if !self.contents.contains_key(&sha256) {
+                self.contents.insert(sha256, FileData::Memory(data.clone()));
+                added.push(StoredContent { sha256, data: data.to_vec() });
+            }
+            let path = path(file);
+            match files.iter_mut().find(|(existing, _)| *existing == path) {
+                Some(entry) => { entry.1 = sha256 }
+                None => { files.push((path, sha256)) }
+            }
+        }
+
+        let revision = Revision { number: self.latest() + 1, created: Utc::now(), files };
+        self.revisions.push(revision.clone());
+        Ok((revision, added))
+    }
+
+    /// The files of revision `number`, the main file first, if it is kept.
+    pub fn files(&self, number: u64) -> Option<Vec<DocumentFile>> {
+        let revision = self.revisions.iter().find(|revision| revision.number == number)?;
+        revision.files.iter()
+            .map(|(path, sha256)| Some(DocumentFile { name: file_id(path), data: self.contents.get(sha256)?.clone() }))
+            .collect()
+    }
+
+    /// Drop the revisions beyond `keep` or older than `retention`, and the contents only
+    /// they had. Returns the number of the oldest revision left if any were dropped.
+    pub fn prune(&mut self, keep: Option<usize>, retention: Option<Duration>) -> Option<u64> {
+        let before = self.revisions.len();
+        let mut first = keep.map(|keep| before.saturating_sub(keep)).unwrap_or(0);
+        if let Some(cutoff) = retention.and_then(|retention| chrono::Duration::from_std(retention).ok()) {
+            let cutoff = Utc::now() - cutoff;
+            first = first.max(self.revisions.iter().take_while(|revision| revision.created < cutoff).count());
+        }
+        let first = first.min(before.saturating_sub(1));
+        if first == 0 {
+            return None;
+        }
+
+        self.revisions.drain(..first);
+        let referenced: HashSet<&[u8; 32]> = self.revisions.iter()
+            .flat_map(|revision| revision.files.iter().map(|(_, sha256)| sha256))
+            .collect();
+        self.contents.retain(|sha256, _| referenced.contains(sha256));
+        self.revisions.first().map(|revision| revision.number)
+    }
+
+    /// The kept revisions, oldest first.
+    pub fn list(&self) -> Vec<RevisionJson> {
+        self.revisions.iter()
+            .map(|revision| RevisionJson {
+                number: revision.number,
+                created: revision.created,
+                files: self.describe(revision),
+            })
+            .collect()
+    }
+
+    /// The files of the latest revision.
+    pub fn current(&self) -> Vec<RevisionFile> {
+        self.revisions.last().map(|revision| self.describe(revision)).unwrap_or_default()
+    }
+
+    fn describe(&self, revision: &Revision) -> Vec<RevisionFile> {
+        revision.files.iter()
+            .map(|(path, sha256)| RevisionFile {
+                path: path.clone(),
+                size: self.contents.get(sha256).and_then(|data| data.size().ok()).unwrap_or(0),
+                sha256: sha256.iter().map(|byte| format!("{byte:02x}")).collect(),
+            })
+            .collect()
+    }
+}
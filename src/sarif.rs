@@ -0,0 +1,312 @@
+//! The diagnostics of a compile as a SARIF 2.1.0 log, for code scanning and CI annotations.
+//!
+//! `POST /compile?diagnostics=sarif` answers with one run holding a result per error or
+//! warning instead of the PDF, whether or not the document compiled. Errors are ruled by the
+//! code the compile failed with, like `compile_error`, warnings by their category, like
+//! `warning/unknown_font`. `executionSuccessful` says whether `/compile` would have sent the
+//! PDF, so with `fail_on_warnings` it is false for documents with warnings.
+//!
+//! Every compiler reports diagnostics rendered as `path:line:column: message`, so that is
+//! where the locations are read from. Columns count characters, as the log declares.
+
+use std::collections::BTreeMap;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::compilers::VERSION_HEADER;
+use crate::docker_world::{CompileError, Compiled, WarningCategory};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+const CONTENT_TYPE: &str = "application/sarif+json";
+
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Diagnostics {
+    Sarif,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DiagnosticsQuery {
+    /// `sarif` answers with the errors and warnings as a SARIF 2.1.0 log instead of the PDF.
+    #[param(inline)]
+    diagnostics: Option<Diagnostics>,
+}
+
+#[derive(Serialize)]
+struct Log {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    version: &'static str,
+    runs: [Run; 1],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Run {
+    tool: Tool,
+    invocations: [Invocation; 1],
+    column_kind: &'static str,
+    results: Vec<SarifResult>,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    driver: Driver,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Driver {
+    name: &'static str,
+    version: String,
+    information_uri: &'static str,
+    rules: Vec<Rule>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    id: &'static str,
+    short_description: Message,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Invocation {
+    execution_successful: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: &'static str,
+    level: &'static str,
+    message: Message,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    locations: Vec<Location>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    text: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    physical_location: PhysicalLocation,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PhysicalLocation {
+    artifact_location: ArtifactLocation,
+    region: Region,
+}
+
+#[derive(Serialize)]
+struct ArtifactLocation {
+    uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    start_line: usize,
+    start_column: usize,
+}
+
+/// Whether the request asked for the SARIF log.
+pub fn requested(request: &HttpRequest) -> Result<bool, Error> {
+    let query = web::Query::<DiagnosticsQuery>::from_query(request.query_string())
+        .map_err(|problem| Code::InvalidQuery.error(problem))?;
+    Ok(matches!(query.diagnostics, Some(Diagnostics::Sarif)))
+}
+
+/// Answer with the diagnostics of `compiled` as a log, or with failures that have none.
+pub fn respond(
+    version: &str,
+    compiled: Result<Compiled, CompileError>,
+    fail_on_warnings: bool,
+    metrics: &Metrics,
+) -> Result<HttpResponse, Error> {
+    let (results, successful) = match compiled {
+        Ok(compiled) => {
+            metrics.record_warnings(&compiled.warnings);
+            let results: Vec<_> = compiled.warnings.iter()
+                .map(|warning| SarifResult::parse(warning_rule(warning.category), "warning", &warning.message))
+                .collect();
+            let successful = !fail_on_warnings || results.is_empty();
+            (results, successful)
+        }
+        Err(problem) => {
+            let errors = match &problem {
+                CompileError::Failed(errors) | CompileError::PackageUnavailable(errors) => { errors }
+                _ => { return Err(problem.into()) }
+            };
+            let results = errors.lines()
+                .map(|line| SarifResult::parse(problem.code(), "error", line))
+                .collect();
+            (results, false)
+        }
+    };
+
+    let rules: BTreeMap<_, _> = results.iter().map(|result| (result.rule_id, describe_rule(result.rule_id))).collect();
+    let driver = Driver {
+        name: "typst",
+        version: version.to_string(),
+        information_uri: "https://typst.app",
+        rules: rules.into_iter()
+            .map(|(id, description)| Rule { id, short_description: Message { text: description.to_string() } })
+            .collect(),
+    };
+    let log = Log {
+        schema: SCHEMA,
+        version: "2.1.0",
+        runs: [Run {
+            tool: Tool { driver },
+            invocations: [Invocation { execution_successful: successful }],
+            column_kind: "unicodeCodePoints",
+            results,
+        }],
+    };
+    let body = serde_json::to_vec(&log).expect("the SARIF log serializes");
+    Ok(HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, version))
+        .content_type(CONTENT_TYPE)
+        .body(body))
+}
+
+impl SarifResult {
+    /// The result for a diagnostic rendered as `path:line:column: message`, or just the message
+    /// for diagnostics without a location.
+    fn parse(rule_id: &'static str, level: &'static str, rendered: &str) -> Self {
+        let (locations, message) = match locate(rendered) {
+            Some((location, message)) => { (vec![location], message) }
+            None => { (Vec::new(), rendered) }
+        };
+        Self { rule_id, level, message: Message { text: message.trim_end().to_string() }, locations }
+    }
+}
+
+fn locate(rendered: &str) -> Option<(Location, &str)> {
+    let (location, message) = rendered.split_once(": ")?;
+    let mut parts = location.rsplitn(3, ':');
+    let start_column = parts.next()?.parse().ok()?;
+    let start_line = parts.next()?.parse().ok()?;
+    let path = parts.next().filter(|path| !path.is_empty())?;
+    let physical_location = PhysicalLocation {
+        artifact_location: ArtifactLocation { uri: uri(path) },
+        region: Region { start_line, start_column },
+    };
+    Some((Location { physical_location }, message))
+}
+
+/// `path` as a relative URI reference, percent-encoding what a path segment can't hold.
+pub fn uri(path: &str) -> String {
+    let mut uri = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'@' | b':' | b'+' => {
+                uri.push(byte as char)
+            }
+            _ => { uri.push_str(&format!("%{byte:02X}")) }
+        }
+    }
+    uri
+}
+
+fn warning_rule(category: WarningCategory) -> &'static str {
+    match category {
+        WarningCategory::UnknownFont => { "warning/unknown_font" }
+        WarningCategory::Deprecated => { "warning/deprecated" }
+        WarningCategory::Other => { "warning/other" }
+    }
+}
+
+fn describe_rule(id: &str) -> &'static str {
+    match id {
+        "compile_error" => { "The document has errors" }
+        "package_unavailable" => { "A package the document imports couldn't be downloaded or read" }
+        "warning/unknown_font" => { "The document asks for a font the server doesn't have" }
+        "warning/deprecated" => { "The document uses something typst deprecated" }
+        _ => { "typst warned about the document" }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use jsonschema::{Draft, JSONSchema};
+    use serde_json::Value;
+    use crate::docker_world::{CompileError, Compiled, Dependencies, Warning, WarningCategory};
+    use crate::metrics::Metrics;
+    use super::respond;
+
+    /// The SARIF 2.1.0 definitions of every object the log has, see the fixture's description.
+    const SCHEMA: &str = include_str!("../tests/fixtures/sarif-2.1.0-subset.schema.json");
+
+    fn compiled(warnings: Vec<Warning>) -> Compiled {
+        let dependencies = Dependencies::default();
+        Compiled { pdf: Vec::new(), pages: 1, page_hashes: Vec::new(), warnings, dependencies, cacheable: true }
+    }
+
+    /// The log for `compiled`, checked against the schema.
+    async fn log(compiled: Result<Compiled, CompileError>, fail_on_warnings: bool) -> Value {
+        let response = respond("0.11.0", compiled, fail_on_warnings, &Metrics::default()).unwrap();
+        let log: Value = serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let schema = JSONSchema::options().with_draft(Draft::Draft7).compile(&schema).unwrap();
+        if let Err(problems) = schema.validate(&log) {
+            let problems: Vec<_> = problems.map(|problem| format!("{} at {}", problem, problem.instance_path)).collect();
+            panic!("the log isn't valid SARIF: {problems:?}");
+        }
+        log
+    }
+
+    #[actix_web::test]
+    async fn a_clean_document_has_no_results() {
+        let log = log(Ok(compiled(Vec::new())), true).await;
+        let run = &log["runs"][0];
+        assert_eq!(run["results"], Value::Array(Vec::new()));
+        assert_eq!(run["tool"]["driver"]["rules"], Value::Array(Vec::new()));
+        assert_eq!(run["invocations"][0]["executionSuccessful"], true);
+    }
+
+    #[actix_web::test]
+    async fn warnings_are_located_results() {
+        let warnings = vec![
+            Warning { category: WarningCategory::UnknownFont, message: "chapters/intro.typ:3:7: unknown font family: comic".into() },
+            Warning { category: WarningCategory::Other, message: "no location here".into() },
+        ];
+        let log = log(Ok(compiled(warnings)), true).await;
+        let run = &log["runs"][0];
+        let located = &run["results"][0];
+        assert_eq!(located["ruleId"], "warning/unknown_font");
+        assert_eq!(located["level"], "warning");
+        assert_eq!(located["message"]["text"], "unknown font family: comic");
+        let location = &located["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "chapters/intro.typ");
+        assert_eq!((location["region"]["startLine"].as_u64(), location["region"]["startColumn"].as_u64()), (Some(3), Some(7)));
+        assert!(run["results"][1].get("locations").is_none());
+        assert_eq!(run["invocations"][0]["executionSuccessful"], false);
+    }
+
+    #[actix_web::test]
+    async fn errors_are_results_of_a_failed_run() {
+        let errors = CompileError::Failed("main.typ:1:2: unknown variable: x\nmy file.typ:10:1: expected expression".into());
+        let log = log(Err(errors), false).await;
+        let run = &log["runs"][0];
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result["level"] == "error" && result["ruleId"] == "compile_error"));
+        assert_eq!(results[1]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"], "my%20file.typ");
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "compile_error");
+        assert_eq!(run["invocations"][0]["executionSuccessful"], false);
+    }
+}
@@ -0,0 +1,187 @@
+//! `POST /selftest`, a deep health probe compiling a built-in document end to end.
+//!
+//! The document has text in a font other than typst's default, an SVG image read as a file
+//! and a table, and is compiled in this process stage by stage: picking the font, building
+//! the world, laying out and exporting the PDF. Each stage is timed and the response says
+//! which passed, with a 500 if any failed. The compile cache is never asked, the compile
+//! takes a slot like any other with a short timeout, and no tenant quota counts it.
+//!
+//! Probes are limited to one per `SELFTEST_INTERVAL` for the whole server rather than per
+//! client, so a misconfigured prober can't keep the compile slots busy. The other requests'
+//! rate limit doesn't apply to it.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{post, web, Error, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::compilers::{run_compile, Paged};
+use crate::config::CurrentConfig;
+use crate::docker_world::{CompileError, DocumentFile, ErrorOutput, FontLibrary, PdfExport, WarningCategory};
+use crate::errors::Code;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::packages::PackageStore;
+use crate::slots::{CompileSlots, Priority};
+
+/// How long the probe's compile may take, well below what documents get.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The family typst uses without `font:`, which the probe avoids to exercise font selection.
+const DEFAULT_FAMILY: &str = "libertinus serif";
+
+const DOCUMENT: &str = r#"#set page(width: 12cm, height: auto, margin: 1cm)
+#set text(font: sys.inputs.font)
+= Self-test
+The quick brown fox jumps over the lazy dog.
+#image("probe.svg", width: 1cm)
+#table(columns: 2, [Stage], [State], [Layout], [Passed])
+"#;
+
+const IMAGE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><rect width="10" height="10" fill="#239dad"/></svg>"##;
+
+/// How the probe went.
+#[derive(Serialize, ToSchema)]
+pub struct SelfTest {
+    /// Whether every stage passed.
+    ok: bool,
+    milliseconds: u64,
+    /// In the order they ran, stopping at the first that failed.
+    stages: Vec<StageOutcome>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StageOutcome {
+    /// `fonts`, `world`, `layout` or `export`.
+    name: &'static str,
+    ok: bool,
+    milliseconds: u64,
+    /// Why the stage failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problem: Option<String>,
+}
+
+/// The stages so far, kept outside the compile so a timeout still reports them.
+#[derive(Clone, Default)]
+struct Stages(Arc<Mutex<Vec<StageOutcome>>>);
+
+impl Stages {
+    /// Time `stage`, noting what it returned.
+    fn run<T>(&self, name: &'static str, stage: impl FnOnce() -> Result<T, String>) -> Result<T, CompileError> {
+        let started = Instant::now();
+        let outcome = stage();
+        self.0.lock().unwrap().push(StageOutcome {
+            name,
+            ok: outcome.is_ok(),
+            milliseconds: started.elapsed().as_millis() as u64,
+            problem: outcome.as_ref().err().cloned(),
+        });
+        outcome.map_err(|problem| CompileError::Failed(problem.into()))
+    }
+}
+
+/// What the probe's compile produced.
+struct Probed {
+    pages: usize,
+}
+
+impl Paged for Probed {
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// Compile the built-in document stage by stage, see the module docs.
+#[utoipa::path(
+    tag = "operations",
+    responses(
+        (status = 200, description = "Every stage passed", body = SelfTest),
+        (status = 429, description = "The last probe was less than `SELFTEST_INTERVAL` ago", body = ErrorOutput),
+        (status = 500, description = "A stage failed", body = SelfTest),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+    ),
+)]
+#[post("/selftest")]
+async fn selftest(
+    config: CurrentConfig,
+    health: web::Data<Health>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+    slots: web::Data<CompileSlots>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    if let Err(wait) = health.admit_selftest(config.selftest_interval) {
+        let message = format!("A self-test ran less than {} seconds ago", config.selftest_interval.as_secs());
+        return Ok(HttpResponse::build(Code::RateLimited.status())
+            .insert_header((RETRY_AFTER, wait.as_secs().max(1).to_string()))
+            .json(Code::RateLimited.body(message)));
+    }
+
+    let started = Instant::now();
+    let stages = Stages::default();
+    let recorded = stages.clone();
+    let options = config.default_options();
+    let fonts = fonts.current();
+    let packages = packages.into_inner();
+    let compiled = run_compile(&slots, &metrics, TIMEOUT, Priority::High, "selftest", move |cancellation| {
+        let family = recorded.run("fonts", || {
+            fonts.families()
+                .find(|family| !family.eq_ignore_ascii_case(DEFAULT_FAMILY))
+                .map(str::to_string)
+                .ok_or_else(|| "No font besides the default was found".to_string())
+        })?;
+        let mut world = recorded.run("world", || {
+            options.world()
+                .inputs([("font", family)])
+                .main(DocumentFile::new("selftest.typ", DOCUMENT.into()))
+                .files([DocumentFile::new("probe.svg", IMAGE.into())])
+                .font_db(fonts)
+                .packages(packages)
+                .build()
+                .map_err(|problem| problem.to_string())
+        })?;
+        let document = recorded.run("layout", || {
+            let document = world.typeset(options.max_pages, cancellation).map_err(|problem| problem.to_string())?;
+            match document.warnings.iter().find(|warning| warning.category == WarningCategory::UnknownFont) {
+                Some(warning) => { Err(warning.message.to_string()) }
+                None => { Ok(document) }
+            }
+        })?;
+        let pages = document.pages();
+        recorded.run("export", || {
+            let pdf = document.to_pdf(&PdfExport::default()).map_err(|problem| problem.to_string())?;
+            match pdf.starts_with(b"%PDF-") {
+                true => { Ok(()) }
+                false => { Err("The export isn't a PDF".to_string()) }
+            }
+        })?;
+        Ok(Probed { pages })
+    }).await?;
+
+    let mut stages = std::mem::take(&mut *stages.0.lock().unwrap());
+    if let Err(CompileError::TimedOut { .. }) = compiled {
+        stages.push(StageOutcome {
+            name: "timeout",
+            ok: false,
+            milliseconds: started.elapsed().as_millis() as u64,
+            problem: Some(format!("The compile took longer than {} seconds", TIMEOUT.as_secs())),
+        });
+    }
+    let report = SelfTest {
+        ok: compiled.is_ok(),
+        milliseconds: started.elapsed().as_millis() as u64,
+        stages,
+    };
+    match report.ok {
+        true => { Ok(HttpResponse::Ok().json(report)) }
+        false => {
+            tracing::error!("the self-test failed: {}", serde_json::to_string(&report).expect("the report serializes"));
+            Ok(HttpResponse::InternalServerError().json(report))
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(selftest);
+}
@@ -0,0 +1,215 @@
+//! Shadow compiles, trying another bundled typst version on real traffic before it becomes
+//! the default.
+//!
+//! With `SHADOW_TYPST_VERSION` set, `SHADOW_PERCENT` of the successful `/compile` requests
+//! made with another version are compiled again with it in the background, once their own
+//! compile finished, and the two results are compared: the page count, each page's
+//! `PageFingerprint` and the warnings. A difference, or a shadow compile that fails, is
+//! logged with the `input_sha256` the audit log has for the request, to find and reproduce
+//! it, and counted in `/metrics`. Results read back from the disk cache from before page
+//! hashes were kept are compared by the rest.
+//!
+//! Shadow compiles never change the response. They don't take compile slots and don't go
+//! through the compile cache, quotas or the audit log, and `/metrics` counts them apart from
+//! the compiles. At most `SHADOW_MAX_CONCURRENT` run at once, a request sampled while they
+//! all do is skipped, and each is cancelled after the request's timeout. Debug bundles,
+//! SARIF and profiles aren't shadowed. The version is resolved at startup, the percentage
+//! and the cap follow reloads.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use actix_web::web;
+use rand::Rng;
+use crate::audit;
+use crate::compilers::{compile_processed, CompileOptions, Compiler, Compilers, Progress};
+use crate::config::Config;
+use crate::docker_world::{Cancellation, Compiled, DocumentFile};
+use crate::metrics::Metrics;
+
+/// The compiler sampled compiles are tried again with, and how many run.
+pub struct Shadow {
+    compiler: Option<Arc<dyn Compiler>>,
+    running: Arc<AtomicUsize>,
+}
+
+/// Held while a shadow compile runs, dropping it frees its place.
+struct Running(Arc<AtomicUsize>);
+
+/// What the request's own compile produced, to compare the shadow compile with.
+struct Expected {
+    version: &'static str,
+    pages: usize,
+    page_hashes: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Shadow {
+    /// The shadow compiler `SHADOW_TYPST_VERSION` names, or why it can't be used.
+    pub fn new(config: &Config, compilers: &Compilers) -> Result<Self, String> {
+        let compiler = match &config.shadow_version {
+            None => { None }
+            Some(version) => {
+                let compiler = compilers.find(version).ok_or_else(|| {
+                    format!("SHADOW_TYPST_VERSION {version} is not bundled, this server has {}", compilers.versions().join(", "))
+                })?;
+                Some(compiler)
+            }
+        };
+        Ok(Self { compiler, running: Arc::default() })
+    }
+
+    /// Whether to try the compile of a request made with `version` again.
+    pub fn sample(&self, config: &Config, version: &str) -> bool {
+        match &self.compiler {
+            Some(compiler) if compiler.version() != version && config.shadow_percent > 0 => {
+                rand::thread_rng().gen_range(0..100) < config.shadow_percent
+            }
+            _ => { false }
+        }
+    }
+
+    /// Compile `documents` again in the background and compare the result with `primary`,
+    /// which `version` compiled with `options`, unless every shadow compile place is taken.
+    pub fn spawn(
+        &self,
+        metrics: web::Data<Metrics>,
+        config: &Config,
+        mut documents: Vec<DocumentFile>,
+        options: &CompileOptions,
+        primary: &Compiled,
+        version: &'static str,
+    ) {
+        let Some(compiler) = self.compiler.clone().filter(|_| !documents.is_empty()) else { return };
+        let Some(running) = self.admit(config.shadow_max_concurrent) else {
+            metrics.record_shadow("skipped", &[]);
+            return;
+        };
+        let expected = Expected {
+            version,
+            pages: primary.pages,
+            page_hashes: primary.page_hashes.clone(),
+            warnings: messages(primary),
+        };
+        // Neither changes the layout, the PDF itself isn't compared.
+        let mut options = options.clone();
+        options.embedding = None;
+        options.optimizer = None;
+        options.progress = Progress::default();
+        let (timeout, cache_max_age) = (options.timeout, config.cache_max_age);
+
+        let (main, shadow_version) = (documents.remove(0), compiler.version());
+        actix_web::rt::spawn(async move {
+            let input_sha256 = web::block({
+                let (main, files) = (main.clone(), documents.clone());
+                move || audit::input_sha256(&main, &files)
+            }).await.ok().flatten().unwrap_or_default();
+            let input_sha256 = input_sha256.as_str();
+            let cancellation = Cancellation::default();
+            let work = web::block({
+                let cancellation = cancellation.clone();
+                move || {
+                    let compiled = compile_processed(compiler.as_ref(), main, documents, &options, cancellation);
+                    compiler.evict(cache_max_age);
+                    drop(running);
+                    compiled
+                }
+            });
+            let compiled = match tokio::time::timeout(timeout, work).await {
+                Ok(Ok(finished)) => { finished }
+                Ok(Err(problem)) => {
+                    metrics.record_shadow("failed", &[]);
+                    tracing::warn!(
+                        input_sha256,
+                        primary_version = expected.version,
+                        shadow_version,
+                        "shadow compile failed: {problem}",
+                    );
+                    return;
+                }
+                Err(_) => {
+                    cancellation.cancel();
+                    metrics.record_shadow("timed_out", &[]);
+                    tracing::warn!(input_sha256, primary_version = expected.version, shadow_version, "shadow compile timed out");
+                    return;
+                }
+            };
+            match compiled {
+                Ok(compiled) => {
+                    let differences = differences(&expected, &compiled);
+                    match differences.is_empty() {
+                        true => { metrics.record_shadow("match", &[]) }
+                        false => {
+                            metrics.record_shadow("mismatch", &differences);
+                            tracing::warn!(
+                                input_sha256,
+                                primary_version = expected.version,
+                                shadow_version,
+                                differences = differences.join(",").as_str(),
+                                pages = expected.pages,
+                                shadow_pages = compiled.pages,
+                                first_differing_page = first_differing_page(&expected, &compiled),
+                                "shadow compile differs",
+                            );
+                        }
+                    }
+                }
+                Err(problem) => {
+                    metrics.record_shadow("failed", &[]);
+                    tracing::warn!(
+                        input_sha256,
+                        primary_version = expected.version,
+                        shadow_version,
+                        outcome = problem.kind(),
+                        "shadow compile failed: {problem}",
+                    );
+                }
+            }
+        });
+    }
+
+    /// A place for one more shadow compile, unless `limit` run already.
+    fn admit(&self, limit: usize) -> Option<Running> {
+        self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| (running < limit).then_some(running + 1))
+            .ok()
+            .map(|_| Running(self.running.clone()))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The warnings of `compiled`, sorted, since the versions may report them in another order.
+fn messages(compiled: &Compiled) -> Vec<String> {
+    let mut messages: Vec<_> = compiled.warnings.iter().map(|warning| warning.message.to_string()).collect();
+    messages.sort();
+    messages
+}
+
+/// What `compiled` differs from `expected` in, nothing if they match.
+fn differences(expected: &Expected, compiled: &Compiled) -> Vec<&'static str> {
+    let mut differences = Vec::new();
+    if expected.pages != compiled.pages {
+        differences.push("pages");
+    }
+    let hashed = !expected.page_hashes.is_empty() && !compiled.page_hashes.is_empty();
+    if hashed && expected.page_hashes != compiled.page_hashes {
+        differences.push("page_hashes");
+    }
+    if expected.warnings != messages(compiled) {
+        differences.push("warnings");
+    }
+    differences
+}
+
+/// The first page, counting from 1, whose hash differs, or past the shorter document.
+fn first_differing_page(expected: &Expected, compiled: &Compiled) -> Option<usize> {
+    if expected.page_hashes.is_empty() || compiled.page_hashes.is_empty() {
+        return None;
+    }
+    let same = expected.page_hashes.iter().zip(&compiled.page_hashes).take_while(|(left, right)| left == right).count();
+    (same < expected.page_hashes.len().max(compiled.page_hashes.len())).then_some(same + 1)
+}
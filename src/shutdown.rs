@@ -0,0 +1,58 @@
+//! Draining running compiles before exiting on SIGTERM or SIGINT.
+
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+use futures_util::future::{select, Either};
+use crate::health::Health;
+use crate::slots::CompileSlots;
+
+/// How often draining checks whether the compiles are done.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait for a termination signal, then stop accepting connections and report not ready
+/// while the compiles running or queued, jobs included, get up to `grace` to finish.
+/// Whatever still runs after that is cancelled.
+pub async fn on_signal(server: ServerHandle, health: web::Data<Health>, slots: web::Data<CompileSlots>, grace: Duration) {
+    let signal = terminated().await;
+    tracing::info!("Got {signal}, finishing running compiles for up to {grace:?}");
+    #[cfg(unix)]
+    crate::systemd::notify("STOPPING=1");
+    health.shut_down();
+    server.pause().await;
+
+    let deadline = Instant::now() + grace;
+    while slots.in_use() + slots.queued() > 0 && Instant::now() < deadline {
+        actix_web::rt::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let unfinished = slots.in_use() + slots.queued();
+    if unfinished > 0 {
+        tracing::error!("the grace period ran out, cancelling {unfinished} running or queued compiles");
+    }
+    server.stop(unfinished == 0).await;
+}
+
+#[cfg(unix)]
+async fn terminated() -> &'static str {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => { terminate }
+        Err(problem) => {
+            tracing::warn!("cannot listen for SIGTERM, only SIGINT shuts down gracefully: {problem}");
+            let _ = actix_web::rt::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    match select(pin!(terminate.recv()), pin!(actix_web::rt::signal::ctrl_c())).await {
+        Either::Left(_) => { "SIGTERM" }
+        Either::Right(_) => { "SIGINT" }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> &'static str {
+    let _ = actix_web::rt::signal::ctrl_c().await;
+    "Ctrl-C"
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use actix_web::http::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::sync::oneshot;
+use crate::config::Config;
+use crate::errors::Code;
+
+/// Every this many hand-overs a slot goes to the longest waiting request regardless of its
+/// priority, so a steady stream of high priority requests can't starve the others.
+const STARVATION_GUARD: u64 = 4;
+
+/// Request header choosing where a compile queues, `high`, `normal` or `low`.
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+/// Bounds how many compiles run at once, since every one of them can take hundreds of megabytes.
+///
+/// Waiting requests get free slots by priority, then in arrival order.
+pub struct CompileSlots {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    queue_length: usize,
+    queue_timeout: Duration,
+    in_use: usize,
+    /// Waiting requests, highest priority and oldest first.
+    waiting: BTreeMap<(Priority, u64), oneshot::Sender<()>>,
+    arrivals: u64,
+    hand_overs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Held for as long as one compile runs, dropping it passes the slot on.
+pub struct CompileSlot {
+    shared: Arc<Shared>,
+}
+
+/// No slot became free in time, or too many requests were already waiting.
+#[derive(Debug)]
+pub struct Saturated {
+    retry_after: Duration,
+}
+
+impl CompileSlots {
+    pub fn new(config: &Config) -> Self {
+        let state = State {
+            limit: config.max_concurrent_compiles,
+            queue_length: config.compile_queue_length,
+            queue_timeout: config.compile_queue_timeout,
+            in_use: 0,
+            waiting: BTreeMap::new(),
+            arrivals: 0,
+            hand_overs: 0,
+        };
+        Self { shared: Arc::new(Shared { state: Mutex::new(state) }) }
+    }
+
+    /// Apply reloaded limits. Running compiles beyond a lowered limit finish, and their
+    /// slots go unused until the count is below it.
+    pub fn reconfigure(&self, config: &Config) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.limit = config.max_concurrent_compiles;
+        state.queue_length = config.compile_queue_length;
+        state.queue_timeout = config.compile_queue_timeout;
+        self.shared.hand_out(&mut state);
+    }
+
+    /// Wait for a free slot, giving up when the queue is full or the wait exceeds the queue timeout.
+    pub async fn acquire(&self, priority: Priority) -> Result<CompileSlot, Saturated> {
+        let (mut waiting, queue_timeout) = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.in_use < state.limit && state.waiting.is_empty() {
+                state.in_use += 1;
+                return Ok(CompileSlot { shared: self.shared.clone() });
+            }
+            if state.waiting.len() >= state.queue_length {
+                return Err(Saturated { retry_after: state.queue_timeout });
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let key = (priority, state.arrivals);
+            state.arrivals += 1;
+            state.waiting.insert(key, sender);
+            (Waiting { shared: self.shared.clone(), key, receiver, granted: false }, state.queue_timeout)
+        };
+
+        match tokio::time::timeout(queue_timeout, &mut waiting.receiver).await {
+            Ok(Ok(())) => {
+                waiting.granted = true;
+                Ok(CompileSlot { shared: self.shared.clone() })
+            }
+            _ => { Err(Saturated { retry_after: queue_timeout }) }
+        }
+    }
+
+    /// Requests currently waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.shared.state.lock().unwrap().waiting.len()
+    }
+
+    /// Slots currently taken by running compiles.
+    pub fn in_use(&self) -> usize {
+        self.shared.state.lock().unwrap().in_use
+    }
+
+    pub fn limit(&self) -> usize {
+        self.shared.state.lock().unwrap().limit
+    }
+
+    /// Whether every slot is taken and the queue is full, so new compiles are turned away.
+    pub fn saturated(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.in_use >= state.limit && state.waiting.len() >= state.queue_length
+    }
+}
+
+impl Shared {
+    /// Free a slot and pass it on.
+    fn release(&self, state: &mut State) {
+        state.in_use -= 1;
+        self.hand_out(state);
+    }
+
+    /// Give free slots to waiting requests while there are both.
+    fn hand_out(&self, state: &mut State) {
+        while state.in_use < state.limit {
+            // Only slots a waiter takes count, not those freed while nobody waits.
+            let next = match (state.hand_overs + 1) % STARVATION_GUARD == 0 {
+                true => { state.waiting.keys().min_by_key(|(_, arrival)| *arrival).copied() }
+                false => { state.waiting.keys().next().copied() }
+            };
+            let sender = match next.and_then(|key| state.waiting.remove(&key)) {
+                None => { return }
+                Some(sender) => { sender }
+            };
+            // A waiter that gave up has dropped its receiver, the slot goes to the next one.
+            if sender.send(()).is_ok() {
+                state.in_use += 1;
+                state.hand_overs += 1;
+            }
+        }
+    }
+}
+
+impl Drop for CompileSlot {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        self.shared.release(&mut state);
+    }
+}
+
+/// A place in the queue, given up when the waiting request times out or is dropped.
+struct Waiting {
+    shared: Arc<Shared>,
+    key: (Priority, u64),
+    receiver: oneshot::Receiver<()>,
+    granted: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        // Hand-overs happen under the lock, so a missing entry means the slot was already sent.
+        if state.waiting.remove(&self.key).is_none() && self.receiver.try_recv().is_ok() {
+            self.shared.release(&mut state);
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "high" => { Ok(Priority::High) }
+            "normal" => { Ok(Priority::Normal) }
+            "low" => { Ok(Priority::Low) }
+            _ => { Err(format!("Unknown priority {value}, use high, normal or low")) }
+        }
+    }
+}
+
+impl fmt::Display for Saturated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The server is busy with other compiles, retry later")
+    }
+}
+
+impl ResponseError for Saturated {
+    fn status_code(&self) -> StatusCode {
+        Code::ServerBusy.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header((RETRY_AFTER, self.retry_after.as_secs().max(1).to_string()))
+            .json(Code::ServerBusy.body(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+    use crate::config::Config;
+    use super::{CompileSlots, Priority};
+
+    fn queue(slots: &CompileSlots, priority: Priority) -> oneshot::Receiver<()> {
+        let mut state = slots.shared.state.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let key = (priority, state.arrivals);
+        state.arrivals += 1;
+        state.waiting.insert(key, sender);
+        receiver
+    }
+
+    /// Free the one slot, which is taken, and let it be handed over.
+    fn free(slots: &CompileSlots) {
+        let mut state = slots.shared.state.lock().unwrap();
+        state.in_use = 1;
+        slots.shared.release(&mut state);
+    }
+
+    #[test]
+    fn every_fourth_hand_over_goes_to_the_longest_waiting() {
+        let slots = CompileSlots::new(&Config::from_args(["typstapi", "--max-concurrent-compiles", "1"]).unwrap());
+        // Slots freed while nobody waits aren't handed over.
+        for _ in 0..3 {
+            free(&slots);
+        }
+        assert_eq!(slots.shared.state.lock().unwrap().hand_overs, 0);
+
+        let mut low = queue(&slots, Priority::Low);
+        let mut high: Vec<_> = (0..4).map(|_| queue(&slots, Priority::High)).collect();
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            free(&slots);
+            if low.try_recv().is_ok() {
+                order.push("low".to_string());
+            }
+            for (at, receiver) in high.iter_mut().enumerate() {
+                if receiver.try_recv().is_ok() {
+                    order.push(format!("high {at}"));
+                }
+            }
+        }
+        assert_eq!(order, ["high 0", "high 1", "high 2", "low", "high 3"]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Checking that uploaded images are what their extension claims, before typst trips over
+//! them deep inside layout.
+//!
+//! Rasters are told apart by their magic bytes, SVGs by parsing as XML with an `<svg>` root.
+
+use std::path::Path;
+
+/// The image type the extension of `name` claims, if any.
+fn claimed(name: &str) -> Option<&'static str> {
+    let extension = Path::new(name).extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "png" => { Some("PNG") }
+        "jpg" | "jpeg" => { Some("JPEG") }
+        "gif" => { Some("GIF") }
+        "svg" => { Some("SVG") }
+        _ => { None }
+    }
+}
+
+/// What `data` looks like by its first bytes.
+fn detected(data: &[u8]) -> &'static str {
+    let start = String::from_utf8_lossy(&data[..data.len().min(256)]).trim_start().to_lowercase();
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "PNG"
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "JPEG"
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        "GIF"
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        "WebP"
+    } else if data.starts_with(b"%PDF-") {
+        "PDF"
+    } else if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "HTML"
+    } else if start.starts_with('<') {
+        "XML"
+    } else if data.is_empty() {
+        "empty"
+    } else {
+        "unknown data"
+    }
+}
+
+/// Whether `data` is a well-formed SVG document.
+fn is_svg(data: &[u8]) -> Result<(), String> {
+    let text = std::str::from_utf8(data).map_err(|_| "not UTF-8".to_string())?;
+    let options = roxmltree::ParsingOptions { allow_dtd: true, ..roxmltree::ParsingOptions::default() };
+    let document = roxmltree::Document::parse_with_options(text, options).map_err(|problem| format!("malformed XML: {problem}"))?;
+    match document.root_element().tag_name().name() {
+        "svg" => { Ok(()) }
+        root => { Err(format!("XML with a <{root}> root")) }
+    }
+}
+
+/// Refuse a file named as an image whose contents are something else, naming both types.
+pub fn check(name: &str, data: &[u8]) -> Result<(), String> {
+    let Some(claimed) = claimed(name) else { return Ok(()) };
+    let found = match claimed {
+        "SVG" => {
+            match is_svg(data) {
+                Ok(()) => { return Ok(()) }
+                Err(problem) => {
+                    match detected(data) {
+                        "XML" | "unknown data" => { problem }
+                        found => { found.to_string() }
+                    }
+                }
+            }
+        }
+        _ => {
+            match detected(data) {
+                found if found == claimed => { return Ok(()) }
+                found => { found.to_string() }
+            }
+        }
+    };
+    Err(format!("{name} is named as {claimed} but is {found}"))
+}
+
+/// Whether `check` needs to look at the contents of `name`.
+pub fn applies(name: &str) -> bool {
+    claimed(name).is_some()
+}
@@ -0,0 +1,106 @@
+//! `POST /stats`, counting the words and characters of a document, overall and per page,
+//! and its headings, figures and tables.
+//!
+//! It takes the same upload and options as `/compile` and lays the document out like it,
+//! answering the counts instead of the PDF. They are taken from the pages, so what typst
+//! didn't show doesn't count, see `docker_world::statistics` for the rules. Errors are
+//! answered like for `/compile`.
+
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use crate::audit;
+use crate::compilers::{run_compile, Compilers, Paged, VersionQuery, CURRENT_VERSION, VERSION_HEADER, WARNING_COUNT_HEADER};
+use crate::config::{CurrentConfig, OptionsQuery};
+use crate::docker_world::{ErrorOutput, FontLibrary, Statistics, TimeoutOutput, WarningsOutput};
+use crate::errors::Code;
+use crate::metrics::Metrics;
+use crate::multipart::{read_submission, JsonUpload, Submission};
+use crate::openapi::{SourceForm, Upload};
+use crate::packages::PackageStore;
+use crate::slots::CompileSlots;
+
+/// What the compile counted.
+struct Counted {
+    statistics: Statistics,
+    warnings: usize,
+}
+
+impl Paged for Counted {
+    fn pages(&self) -> usize {
+        self.statistics.pages.len()
+    }
+}
+
+/// Count the words, characters and elements of a document, see the module docs.
+#[utoipa::path(
+    tag = "compile",
+    params(VersionQuery, OptionsQuery),
+    request_body(content(
+        (Upload = "multipart/form-data"),
+        (SourceForm = "application/x-www-form-urlencoded"),
+        (JsonUpload = "application/json"),
+        (String = "text/plain"),
+        ([u8] = "application/gzip"),
+    )),
+    responses(
+        (status = 200, description = "The counts", body = Statistics),
+        (status = 400, description = "The upload or the options are invalid, or the document has errors", body = ErrorOutput),
+        (status = 413, description = "The upload exceeds a limit `GET /limits` reports", body = ErrorOutput),
+        (status = 415, description = "The body is neither multipart, a form, JSON, a tar archive nor a document", body = ErrorOutput),
+        (status = 422, description = "The document exceeded the page or resource limits", body = WarningsOutput),
+        (status = 503, description = "Every compile slot and the queue are taken", body = ErrorOutput),
+        (status = 504, description = "Laying out the document timed out", body = TimeoutOutput),
+    ),
+)]
+#[post("/stats")]
+#[allow(clippy::too_many_arguments)]
+async fn stats(
+    request: HttpRequest,
+    payload: web::Payload,
+    config: CurrentConfig,
+    compilers: web::Data<Compilers>,
+    metrics: web::Data<Metrics>,
+    slots: web::Data<CompileSlots>,
+    fonts: web::Data<FontLibrary>,
+    packages: web::Data<PackageStore>,
+) -> Result<HttpResponse, Error> {
+    let compiler = compilers.select(&request)?;
+    if compiler.version() != CURRENT_VERSION {
+        return Err(Code::InvalidParameter.error(format!("Counting needs typst {CURRENT_VERSION}")));
+    }
+    let mut options = config.compile_options(&request)?;
+
+    let Submission { mut documents, inputs, warnings } = read_submission(&request, payload, &config).await?;
+    if documents.is_empty() {
+        return Err(Code::MissingMain.error("Upload at least the main file"));
+    }
+    audit::inputs(&request, &documents);
+    let mut record = audit::detach(&request);
+    options.inputs.extend(inputs);
+    let world = options.world()
+        .main(documents.remove(0))
+        .files(documents)
+        .font_db(fonts.current())
+        .packages(packages.into_inner());
+
+    let (max_pages, cache_max_age) = (options.max_pages, config.cache_max_age);
+    let counted = run_compile(&slots, &metrics, options.timeout, options.priority, "stats", move |cancellation| {
+        let mut world = world.build()?;
+        let document = world.typeset(max_pages, cancellation)?;
+        let counted = Counted { statistics: document.statistics(), warnings: document.warnings.len() };
+        comemo::evict(cache_max_age);
+        Ok(counted)
+    }).await;
+    if let Some(record) = &mut record {
+        record.finished("stats", &counted);
+    }
+
+    let counted = counted??;
+    Ok(HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, CURRENT_VERSION))
+        .insert_header((WARNING_COUNT_HEADER, (counted.warnings + warnings.len()).to_string()))
+        .json(counted.statistics))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(stats);
+}
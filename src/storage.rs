@@ -0,0 +1,164 @@
+//! Durable storage for stored projects and jobs, so they survive a restart.
+//!
+//! The in-process project and job lists stay authoritative while the server runs, every
+//! change is written through to the storage and it is only read back at startup.
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+use crate::docker_world::{DocumentFile, Warning};
+use crate::jobs::JobStatus;
+use crate::revisions::Revision;
+use crate::slots::Priority;
+use crate::webhooks::Delivery;
+
+/// Where projects and jobs are kept. Writes swallow and log their failures, since the
+/// running server doesn't depend on them, but reading at startup fails loudly.
+pub trait Storage: Send + Sync {
+    /// Every stored project, files in upload order and the main file first.
+    fn projects(&self) -> Result<Vec<StoredProject>, String>;
+
+    /// Add files to a project or replace them, creating it with the first as its main file.
+    fn save_files(&self, project: &str, files: &[StoredFile]);
+
+    /// Add a revision of a project with the contents it is the first to have.
+    fn save_revision(&self, project: &str, revision: &Revision, contents: &[StoredContent]);
+
+    /// Drop a project's revisions before `first`, and the contents none of the rest has.
+    fn prune_revisions(&self, project: &str, first: u64);
+
+    /// Update what a project saved before is known by.
+    fn save_info(&self, project: &str, info: &ProjectInfo);
+
+    /// Drop a project with its files and revisions.
+    fn remove_project(&self, project: &str);
+
+    /// Every stored job, including tombstones.
+    fn jobs(&self) -> Result<Vec<StoredJob>, String>;
+
+    fn save_job(&self, job: &StoredJob);
+
+    fn remove_job(&self, id: &str);
+}
+
+pub struct StoredProject {
+    pub id: String,
+    pub info: ProjectInfo,
+    pub files: Vec<StoredFile>,
+    /// Oldest first.
+    pub revisions: Vec<Revision>,
+    pub contents: Vec<StoredContent>,
+}
+
+/// What a project is known by besides its files.
+#[derive(Clone)]
+pub struct ProjectInfo {
+    /// The tenant of the key that created it, `None` for the shared keys and open servers.
+    pub owner: Option<String>,
+    pub name: Option<String>,
+    pub created: DateTime<Utc>,
+    /// When its files last changed.
+    pub updated: DateTime<Utc>,
+    /// When it was last compiled.
+    pub compiled: Option<DateTime<Utc>>,
+    /// How long it is kept after it was last changed or compiled, `None` for `PROJECT_TTL`
+    /// and zero for ever.
+    pub ttl: Option<Duration>,
+}
+
+/// Contents some revision of a project has.
+pub struct StoredContent {
+    pub sha256: [u8; 32],
+    pub data: Vec<u8>,
+}
+
+pub struct StoredFile {
+    /// Path in the project, like `chapters/intro.typ`.
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+impl StoredFile {
+    /// The contents of an uploaded file, `None` if a spooled file can't be read back.
+    pub fn of(file: &DocumentFile) -> Option<Self> {
+        Some(Self {
+            path: file.name.vpath().as_rootless_path().to_string_lossy().into_owned(),
+            data: file.data.load().ok()?.to_vec(),
+        })
+    }
+
+    pub fn document(self) -> DocumentFile {
+        DocumentFile::new(&self.path, self.data)
+    }
+}
+
+/// A job's record, its PDF stored apart from the rest.
+#[derive(Serialize, Deserialize)]
+pub struct StoredJob {
+    pub id: String,
+    pub status: JobStatus,
+    pub version: String,
+    pub priority: Priority,
+    pub input_size: u64,
+    pub owner: Option<String>,
+    pub submitted: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub error_code: Option<String>,
+    /// Hex SHA-256 of the PDF.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub expired: Option<DateTime<Utc>>,
+    pub delivery: Option<Delivery>,
+    pub pages: Option<usize>,
+    pub warnings: Vec<Warning>,
+    #[serde(skip)]
+    pub pdf: Option<Vec<u8>>,
+}
+
+/// Keeps nothing beyond what the process holds, when no `DATABASE` is configured.
+pub struct Memory;
+
+impl Storage for Memory {
+    fn projects(&self) -> Result<Vec<StoredProject>, String> {
+        Ok(Vec::new())
+    }
+
+    fn save_files(&self, _project: &str, _files: &[StoredFile]) {}
+
+    fn save_revision(&self, _project: &str, _revision: &Revision, _contents: &[StoredContent]) {}
+
+    fn prune_revisions(&self, _project: &str, _first: u64) {}
+
+    fn save_info(&self, _project: &str, _info: &ProjectInfo) {}
+
+    fn remove_project(&self, _project: &str) {}
+
+    fn jobs(&self) -> Result<Vec<StoredJob>, String> {
+        Ok(Vec::new())
+    }
+
+    fn save_job(&self, _job: &StoredJob) {}
+
+    fn remove_job(&self, _id: &str) {}
+}
+
+/// The storage `DATABASE` names, migrated to the current schema, or the memory otherwise.
+pub fn open(config: &Config) -> Result<Arc<dyn Storage>, String> {
+    let Some(path) = &config.database else { return Ok(Arc::new(Memory)) };
+    #[cfg(feature = "sqlite")]
+    {
+        Ok(Arc::new(sqlite::Sqlite::open(path)?))
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        Err(format!("DATABASE is set to {}, but this server was built without the sqlite feature", path.display()))
+    }
+}
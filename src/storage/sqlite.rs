@@ -0,0 +1,346 @@
+//! Projects and jobs in a SQLite file, one connection shared by every thread that writes.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use crate::revisions::Revision;
+use super::{ProjectInfo, Storage, StoredContent, StoredFile, StoredJob, StoredProject};
+
+/// How long a write waits for another process holding the database, like a backup.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schema changes in order, the database's `user_version` counting those it has.
+///
+/// Only ever append, a migration that shipped stays as it is.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE projects (
+        id TEXT PRIMARY KEY,
+        created TEXT NOT NULL
+    );
+    CREATE TABLE project_files (
+        project TEXT NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (project, path)
+    );
+    CREATE TABLE jobs (
+        id TEXT PRIMARY KEY,
+        status TEXT NOT NULL,
+        submitted TEXT NOT NULL,
+        record TEXT NOT NULL,
+        result BLOB
+    );",
+    "CREATE TABLE project_revisions (
+        project TEXT NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+        number INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        PRIMARY KEY (project, number)
+    );
+    CREATE TABLE revision_files (
+        project TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        sha256 BLOB NOT NULL,
+        PRIMARY KEY (project, number, path),
+        FOREIGN KEY (project, number) REFERENCES project_revisions (project, number) ON DELETE CASCADE
+    );
+    CREATE TABLE project_contents (
+        project TEXT NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+        sha256 BLOB NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (project, sha256)
+    );",
+    "ALTER TABLE projects ADD COLUMN owner TEXT;
+    ALTER TABLE projects ADD COLUMN name TEXT;
+    ALTER TABLE projects ADD COLUMN updated TEXT;
+    ALTER TABLE projects ADD COLUMN compiled TEXT;",
+    "ALTER TABLE projects ADD COLUMN ttl INTEGER;",
+    // Projects of a tenant are keyed by the tenant and their id, separated by U+001F.
+    "INSERT INTO projects (id, created, owner, name, updated, compiled, ttl)
+        SELECT owner || char(31) || id, created, owner, name, updated, compiled, ttl FROM projects
+        WHERE owner IS NOT NULL AND instr(id, char(31)) = 0;
+    INSERT INTO project_revisions (project, number, created)
+        SELECT p.owner || char(31) || r.project, r.number, r.created
+        FROM project_revisions r JOIN projects p ON p.id = r.project
+        WHERE p.owner IS NOT NULL AND instr(p.id, char(31)) = 0;
+    UPDATE revision_files
+        SET project = (SELECT owner FROM projects WHERE id = revision_files.project) || char(31) || project
+        WHERE project IN (SELECT id FROM projects WHERE owner IS NOT NULL AND instr(id, char(31)) = 0);
+    UPDATE project_files
+        SET project = (SELECT owner FROM projects WHERE id = project_files.project) || char(31) || project
+        WHERE project IN (SELECT id FROM projects WHERE owner IS NOT NULL AND instr(id, char(31)) = 0);
+    UPDATE project_contents
+        SET project = (SELECT owner FROM projects WHERE id = project_contents.project) || char(31) || project
+        WHERE project IN (SELECT id FROM projects WHERE owner IS NOT NULL AND instr(id, char(31)) = 0);
+    DELETE FROM projects WHERE owner IS NOT NULL AND instr(id, char(31)) = 0;",
+];
+
+pub struct Sqlite {
+    connection: Mutex<Connection>,
+}
+
+impl Sqlite {
+    /// Open the database, creating it if needed, check it for damage and migrate it.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let problem = |problem: rusqlite::Error| format!("cannot use the database {}: {problem}", path.display());
+        let mut connection = Connection::open(path).map_err(problem)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(problem)?;
+        // Also where a file that isn't a database is noticed.
+        let check: String = connection.query_row("PRAGMA quick_check", [], |row| row.get(0)).map_err(problem)?;
+        if check != "ok" {
+            return Err(format!("the database {} is damaged: {check}", path.display()));
+        }
+        connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).map_err(problem)?;
+        connection.pragma_update(None, "foreign_keys", true).map_err(problem)?;
+        migrate(&mut connection, path)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    /// Run `f` on the connection, logging a failure.
+    fn write(&self, what: &str, f: impl FnOnce(&mut Connection) -> rusqlite::Result<()>) {
+        if let Err(problem) = f(&mut self.connection.lock().unwrap()) {
+            tracing::error!("could not save {what} to the database: {problem}");
+        }
+    }
+}
+
+fn migrate(connection: &mut Connection, path: &Path) -> Result<(), String> {
+    let problem = |problem: rusqlite::Error| format!("cannot migrate the database {}: {problem}", path.display());
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(problem)?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "the database {} has schema version {version}, newer than this server's {}",
+            path.display(),
+            MIGRATIONS.len(),
+        ));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction().map_err(problem)?;
+        transaction.execute_batch(migration).map_err(problem)?;
+        transaction.pragma_update(None, "user_version", index + 1).map_err(problem)?;
+        transaction.commit().map_err(problem)?;
+        tracing::info!("migrated the database to schema version {}", index + 1);
+    }
+    Ok(())
+}
+
+impl Storage for Sqlite {
+    fn projects(&self) -> Result<Vec<StoredProject>, String> {
+        let connection = self.connection.lock().unwrap();
+        let rows: rusqlite::Result<Vec<(String, String, Vec<u8>)>> = connection
+            .prepare("SELECT project, path, data FROM project_files ORDER BY project, position")
+            .and_then(|mut statement| {
+                statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect()
+            });
+        let files = rows.map_err(|problem| format!("cannot read the stored projects: {problem}"))?;
+
+        let problem = |problem: rusqlite::Error| format!("cannot read the stored project revisions: {problem}");
+        let mut revisions: HashMap<String, Vec<Revision>> = HashMap::new();
+        let rows: Vec<(String, i64, String, Option<String>, Option<Vec<u8>>)> = connection
+            .prepare(
+                "SELECT r.project, r.number, r.created, f.path, f.sha256 FROM project_revisions r
+                 LEFT JOIN revision_files f ON f.project = r.project AND f.number = r.number
+                 ORDER BY r.project, r.number, f.position",
+            )
+            .and_then(|mut statement| {
+                statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?.collect()
+            })
+            .map_err(problem)?;
+        for (project, number, created, path, sha256) in rows {
+            let list = revisions.entry(project).or_default();
+            if list.last().map(|revision| revision.number) != Some(number as u64) {
+                let created = DateTime::parse_from_rfc3339(&created)
+                    .map_err(|problem| format!("a stored project revision is unreadable: {problem}"))?;
+                list.push(Revision { number: number as u64, created: created.with_timezone(&Utc), files: vec![] });
+            }
+            if let (Some(path), Some(sha256)) = (path, sha256) {
+                let sha256 = sha256.try_into().map_err(|_| "a stored project revision has a malformed hash".to_string())?;
+                list.last_mut().expect("a revision was just pushed").files.push((path, sha256));
+            }
+        }
+        let mut contents: HashMap<String, Vec<StoredContent>> = HashMap::new();
+        let rows: Vec<(String, Vec<u8>, Vec<u8>)> = connection
+            .prepare("SELECT project, sha256, data FROM project_contents")
+            .and_then(|mut statement| {
+                statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect()
+            })
+            .map_err(problem)?;
+        for (project, sha256, data) in rows {
+            let sha256 = sha256.try_into().map_err(|_| "stored project contents have a malformed hash".to_string())?;
+            contents.entry(project).or_default().push(StoredContent { sha256, data });
+        }
+
+        let mut infos: HashMap<String, ProjectInfo> = HashMap::new();
+        type Row = (String, String, Option<String>, Option<String>, Option<String>, Option<String>, Option<i64>);
+        let rows: Vec<Row> = connection
+            .prepare("SELECT id, created, owner, name, updated, compiled, ttl FROM projects")
+            .and_then(|mut statement| {
+                statement.query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+                })?.collect()
+            })
+            .map_err(|problem| format!("cannot read the stored projects: {problem}"))?;
+        let time = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|problem| format!("a stored project has an unreadable time: {problem}"))
+        };
+        for (id, created, owner, name, updated, compiled, ttl) in rows {
+            let created = time(&created)?;
+            let info = ProjectInfo {
+                owner,
+                name,
+                created,
+                updated: updated.as_deref().map(time).transpose()?.unwrap_or(created),
+                compiled: compiled.as_deref().map(time).transpose()?,
+                ttl: ttl.map(|ttl| Duration::from_secs(ttl.max(0) as u64)),
+            };
+            infos.insert(id, info);
+        }
+
+        let mut projects: Vec<StoredProject> = Vec::new();
+        for (id, path, data) in files {
+            match projects.last_mut() {
+                Some(project) if project.id == id => { project.files.push(StoredFile { path, data }) }
+                _ => {
+                    let now = Utc::now();
+                    projects.push(StoredProject {
+                        info: infos.remove(&id)
+                            .unwrap_or(ProjectInfo { owner: None, name: None, created: now, updated: now, compiled: None, ttl: None }),
+                        revisions: revisions.remove(&id).unwrap_or_default(),
+                        contents: contents.remove(&id).unwrap_or_default(),
+                        id,
+                        files: vec![StoredFile { path, data }],
+                    })
+                }
+            }
+        }
+        Ok(projects)
+    }
+
+    fn save_files(&self, project: &str, files: &[StoredFile]) {
+        self.write(&format!("project {project}"), |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO projects (id, created) VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING",
+                params![project, Utc::now().to_rfc3339()],
+            )?;
+            for file in files {
+                transaction.execute(
+                    "INSERT INTO project_files (project, path, position, data)
+                     VALUES (?1, ?2, (SELECT COALESCE(MAX(position) + 1, 0) FROM project_files WHERE project = ?1), ?3)
+                     ON CONFLICT (project, path) DO UPDATE SET data = excluded.data",
+                    params![project, file.path, file.data],
+                )?;
+            }
+            transaction.commit()
+        });
+    }
+
+    fn save_revision(&self, project: &str, revision: &Revision, contents: &[StoredContent]) {
+        self.write(&format!("revision {} of project {project}", revision.number), |connection| {
+            let transaction = connection.transaction()?;
+            for content in contents {
+                transaction.execute(
+                    "INSERT INTO project_contents (project, sha256, data) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (project, sha256) DO NOTHING",
+                    params![project, &content.sha256[..], content.data],
+                )?;
+            }
+            transaction.execute(
+                "INSERT INTO project_revisions (project, number, created) VALUES (?1, ?2, ?3)",
+                params![project, revision.number as i64, revision.created.to_rfc3339()],
+            )?;
+            for (position, (path, sha256)) in revision.files.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO revision_files (project, number, path, position, sha256) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![project, revision.number as i64, path, position as i64, &sha256[..]],
+                )?;
+            }
+            transaction.commit()
+        });
+    }
+
+    fn prune_revisions(&self, project: &str, first: u64) {
+        self.write(&format!("revisions of project {project}"), |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM project_revisions WHERE project = ?1 AND number < ?2",
+                params![project, first as i64],
+            )?;
+            transaction.execute(
+                "DELETE FROM project_contents WHERE project = ?1
+                 AND sha256 NOT IN (SELECT sha256 FROM revision_files WHERE project = ?1)",
+                params![project],
+            )?;
+            transaction.commit()
+        });
+    }
+
+    fn save_info(&self, project: &str, info: &ProjectInfo) {
+        self.write(&format!("project {project}"), |connection| {
+            connection.execute(
+                "UPDATE projects SET owner = ?2, name = ?3, updated = ?4, compiled = ?5, ttl = ?6 WHERE id = ?1",
+                params![
+                    project,
+                    info.owner,
+                    info.name,
+                    info.updated.to_rfc3339(),
+                    info.compiled.map(|compiled| compiled.to_rfc3339()),
+                    info.ttl.map(|ttl| ttl.as_secs() as i64),
+                ],
+            )?;
+            Ok(())
+        });
+    }
+
+    fn remove_project(&self, project: &str) {
+        self.write(&format!("project {project}"), |connection| {
+            connection.execute("DELETE FROM projects WHERE id = ?1", params![project])?;
+            Ok(())
+        });
+    }
+
+    fn jobs(&self) -> Result<Vec<StoredJob>, String> {
+        let connection = self.connection.lock().unwrap();
+        let rows: rusqlite::Result<Vec<(String, String, Option<Vec<u8>>)>> = connection
+            .prepare("SELECT id, record, result FROM jobs")
+            .and_then(|mut statement| {
+                statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect()
+            });
+        let rows = rows.map_err(|problem| format!("cannot read the stored jobs: {problem}"))?;
+
+        rows.into_iter()
+            .map(|(id, record, pdf)| {
+                let job: StoredJob = serde_json::from_str(&record)
+                    .map_err(|problem| format!("the stored job {id} is unreadable: {problem}"))?;
+                Ok(StoredJob { pdf, ..job })
+            })
+            .collect()
+    }
+
+    fn save_job(&self, job: &StoredJob) {
+        let record = serde_json::to_string(job).expect("Jobs serialize");
+        let submitted = job.submitted.to_rfc3339();
+        self.write(&format!("job {}", job.id), |connection| {
+            connection.execute(
+                "INSERT INTO jobs (id, status, submitted, record, result) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (id) DO UPDATE SET status = excluded.status, record = excluded.record, result = excluded.result",
+                params![job.id, job.status.name(), submitted, record, job.pdf],
+            )?;
+            Ok(())
+        });
+    }
+
+    fn remove_job(&self, id: &str) {
+        self.write(&format!("job {id}"), |connection| {
+            connection.execute("DELETE FROM jobs WHERE id = ?1", params![id])?;
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,135 @@
+//! Storage shared between replicas, so any of them can serve cached compiles, and take and
+//! report any job. The in-process cache and job list stay authoritative, the shared store is
+//! consulted when they miss.
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::Config;
+
+/// Key-value storage with expiry. Implementations swallow and log their failures, treating
+/// them as misses, so an unreachable store only costs the sharing.
+pub trait SharedStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    fn set(&self, key: &str, value: &[u8], ttl: Duration);
+
+    /// Add `amount` to the counter at `key`, expiring it `ttl` from now, and return the new total.
+    fn add(&self, key: &str, amount: u64, ttl: Duration) -> Option<u64>;
+
+    /// Append `value` to the queue at `key`, false if the store couldn't take it.
+    fn push(&self, key: &str, value: &[u8]) -> bool;
+
+    /// Take the oldest entry of the queue at `key`, without waiting for one.
+    fn pop(&self, key: &str) -> Option<Vec<u8>>;
+}
+
+/// The store configured with `REDIS_URL`, if any. Only a URL that can't name a store is an
+/// error, one that doesn't answer yet is retried on use.
+pub fn open(config: &Config) -> Result<Option<Arc<dyn SharedStore>>, String> {
+    let Some(url) = config.redis_url.as_ref() else { return Ok(None) };
+    #[cfg(feature = "redis")]
+    {
+        let store = redis_store::Redis::new(url).map_err(|problem| format!("invalid REDIS_URL: {problem}"))?;
+        Ok(Some(Arc::new(store)))
+    }
+    #[cfg(not(feature = "redis"))]
+    {
+        Err(format!("REDIS_URL is set to {url}, but this server was built without the redis feature"))
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use redis::Commands;
+    use super::SharedStore;
+
+    /// How long to wait for Redis before treating a request as a miss.
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// After a failed connection attempt, Redis is left alone for this long.
+    const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+    pub struct Redis {
+        client: redis::Client,
+        connection: Mutex<Connection>,
+    }
+
+    enum Connection {
+        Open(redis::Connection),
+        Closed { retry_at: Instant },
+    }
+
+    impl Redis {
+        pub fn new(url: &str) -> redis::RedisResult<Self> {
+            Ok(Self {
+                client: redis::Client::open(url)?,
+                connection: Mutex::new(Connection::Closed { retry_at: Instant::now() }),
+            })
+        }
+
+        /// Run `f` on the connection, reconnecting if needed, and close it on failure.
+        fn with_connection<T>(&self, f: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>) -> Option<T> {
+            let mut connection = self.connection.lock().unwrap();
+            if let Connection::Closed { retry_at } = *connection {
+                if Instant::now() < retry_at {
+                    return None;
+                }
+                match self.connect() {
+                    Ok(open) => { *connection = Connection::Open(open) }
+                    Err(problem) => {
+                        tracing::warn!("could not connect to Redis, continuing without it: {problem}");
+                        *connection = Connection::Closed { retry_at: Instant::now() + RECONNECT_DELAY };
+                        return None;
+                    }
+                }
+            }
+
+            let Connection::Open(open) = &mut *connection else { return None };
+            match f(open) {
+                Ok(value) => { Some(value) }
+                Err(problem) => {
+                    tracing::warn!("Redis request failed: {problem}");
+                    *connection = Connection::Closed { retry_at: Instant::now() };
+                    None
+                }
+            }
+        }
+
+        fn connect(&self) -> redis::RedisResult<redis::Connection> {
+            let connection = self.client.get_connection_with_timeout(TIMEOUT)?;
+            connection.set_read_timeout(Some(TIMEOUT))?;
+            connection.set_write_timeout(Some(TIMEOUT))?;
+            Ok(connection)
+        }
+    }
+
+    impl SharedStore for Redis {
+        fn get(&self, key: &str) -> Option<Vec<u8>> {
+            self.with_connection(|connection| connection.get::<_, Option<Vec<u8>>>(key)).flatten()
+        }
+
+        fn set(&self, key: &str, value: &[u8], ttl: Duration) {
+            let seconds = ttl.as_secs().max(1) as usize;
+            self.with_connection(|connection| connection.set_ex::<_, _, ()>(key, value, seconds));
+        }
+
+        fn add(&self, key: &str, amount: u64, ttl: Duration) -> Option<u64> {
+            let seconds = ttl.as_secs().max(1) as usize;
+            self.with_connection(|connection| {
+                redis::pipe().incr(key, amount).expire(key, seconds).ignore()
+                    .query::<(u64,)>(connection)
+                    .map(|(total,)| total)
+            })
+        }
+
+        fn push(&self, key: &str, value: &[u8]) -> bool {
+            self.with_connection(|connection| connection.rpush::<_, _, ()>(key, value)).is_some()
+        }
+
+        fn pop(&self, key: &str) -> Option<Vec<u8>> {
+            self.with_connection(|connection| connection.lpop::<_, Option<Vec<u8>>>(key, None)).flatten()
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Telling systemd when the server is ready or stopping, and taking over the sockets it bound.
+//!
+//! All of it is a no-op outside of systemd, which announces itself through the environment.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+use socket2::Socket;
+use crate::health::Health;
+
+/// The first file descriptor systemd passes sockets from.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket systemd bound for the server.
+pub enum Activated {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Send `state`, like `READY=1`, if systemd started the server with `Type=notify`.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else { return };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &address);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(problem) = sent {
+        tracing::warn!("could not notify systemd of {state}: {problem}");
+    }
+}
+
+/// Report ready, unless a required warm-up failed, which leaves the unit to time out starting.
+pub fn ready(health: &Health) {
+    match health.warmed_up() {
+        true => { notify("READY=1") }
+        false => { notify("STATUS=The warm-up compile failed") }
+    }
+}
+
+/// Keep pinging systemd's watchdog at half its timeout, if the unit enables it for this process.
+///
+/// The pings come from the main event loop, so a stuck loop gets the server restarted.
+pub async fn watchdog() {
+    let for_us = env::var("WATCHDOG_PID").ok().is_none_or(|pid| pid == std::process::id().to_string());
+    let timeout = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse().ok()).filter(|_| for_us);
+    let Some(timeout) = timeout.map(Duration::from_micros) else { return };
+    loop {
+        notify("WATCHDOG=1");
+        actix_web::rt::time::sleep(timeout / 2).await;
+    }
+}
+
+/// The sockets systemd passed with socket activation, none if it didn't.
+pub fn activated_sockets() -> io::Result<Vec<Activated>> {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = match env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()) {
+        Some(count) if for_us => { count }
+        _ => { return Ok(vec![]) }
+    };
+    // Isolated compile workers are spawned with this environment and must not claim the sockets.
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).map(|fd| {
+        // SAFETY: systemd passed these descriptors to this process alone and nothing else here
+        // took them. Marking them close-on-exec likewise keeps them from the compile workers.
+        let socket = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            Socket::from_raw_fd(fd)
+        };
+        socket.set_nonblocking(true)?;
+        match socket.local_addr()?.as_socket() {
+            Some(_) => { Ok(Activated::Tcp(socket.into())) }
+            None => { Ok(Activated::Unix(socket.into())) }
+        }
+    }).collect()
+}
@@ -0,0 +1,123 @@
+//! Serving HTTPS directly, with a certificate that can be replaced while the server runs.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+
+/// The certificate handed to every new TLS connection. Replacing it leaves open
+/// connections alone.
+pub struct Certificates {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+/// Why a certificate and key couldn't be used.
+#[derive(Debug)]
+pub enum TlsError {
+    NotFound(PathBuf),
+    Unreadable(PathBuf, io::Error),
+    NotPem(PathBuf, String),
+    UnsupportedKey(PathBuf),
+    KeyMismatch { certificate: PathBuf, key: PathBuf },
+}
+
+impl Certificates {
+    pub fn load(certificate: &Path, key: &Path) -> Result<Self, TlsError> {
+        Ok(Self { current: RwLock::new(Arc::new(certified_key(certificate, key)?)) })
+    }
+
+    /// Read the files again, keeping the current certificate if they are unusable.
+    pub fn reload(&self, certificate: &Path, key: &Path) -> Result<(), TlsError> {
+        let reloaded = certified_key(certificate, key)?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        Ok(())
+    }
+
+    pub fn server_config(self: Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn certified_key(certificate: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let chain: Vec<_> = read_pem(certificate)?.into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => { Some(Certificate(der)) }
+            _ => { None }
+        })
+        .collect();
+    if chain.is_empty() {
+        return Err(TlsError::NotPem(certificate.to_path_buf(), "it contains no CERTIFICATE section".into()));
+    }
+
+    let private_key = read_pem(key)?.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => { Some(PrivateKey(der)) }
+            _ => { None }
+        })
+        .ok_or_else(|| TlsError::NotPem(key.to_path_buf(), "it contains no PRIVATE KEY section".into()))?;
+    let signing_key = any_supported_type(&private_key)
+        .map_err(|_| TlsError::UnsupportedKey(key.to_path_buf()))?;
+
+    if !matches(&chain[0], signing_key.as_ref()) {
+        return Err(TlsError::KeyMismatch { certificate: certificate.to_path_buf(), key: key.to_path_buf() });
+    }
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let contents = fs::read(path).map_err(|problem| match problem.kind() {
+        io::ErrorKind::NotFound => { TlsError::NotFound(path.to_path_buf()) }
+        _ => { TlsError::Unreadable(path.to_path_buf(), problem) }
+    })?;
+    rustls_pemfile::read_all(&mut contents.as_slice())
+        .map_err(|problem| TlsError::NotPem(path.to_path_buf(), problem.to_string()))
+}
+
+/// Whether the key belongs to the certificate, found by signing a message with the key
+/// and checking the signature against the certificate's public key.
+fn matches(certificate: &Certificate, key: &dyn rustls::sign::SigningKey) -> bool {
+    let schemes = [
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+    ];
+    let Ok(certificate) = webpki::EndEntityCert::try_from(certificate.0.as_slice()) else { return false };
+    let message = b"typstapi certificate check";
+    schemes.iter().any(|(scheme, algorithm)| {
+        let Some(signer) = key.choose_scheme(&[*scheme]) else { return false };
+        signer.sign(message)
+            .is_ok_and(|signature| certificate.verify_signature(algorithm, message, &signature).is_ok())
+    })
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::NotFound(path) => { write!(f, "{} does not exist", path.display()) }
+            TlsError::Unreadable(path, problem) => { write!(f, "could not read {}: {problem}", path.display()) }
+            TlsError::NotPem(path, problem) => { write!(f, "{} is not valid PEM: {problem}", path.display()) }
+            TlsError::UnsupportedKey(path) => {
+                write!(f, "{} holds a key type rustls doesn't support, use RSA, ECDSA or Ed25519", path.display())
+            }
+            TlsError::KeyMismatch { certificate, key } => {
+                write!(f, "the key in {} doesn't match the certificate in {}", key.display(), certificate.display())
+            }
+        }
+    }
+}
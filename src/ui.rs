@@ -0,0 +1,219 @@
+//! `GET /ui`, a page to paste typst and see what it compiles to, for trying the server out
+//! without writing requests by hand.
+//!
+//! The page is self-contained, it loads nothing from elsewhere. It sends the text as
+//! `main.typ` with the picked files next to it, to `/compile?include=deps` for a PDF shown
+//! in the page with the warnings, or to `/compile/pages` for a PNG or SVG of each page, and
+//! shows what a failed compile reports line by line. It is off unless `WEB_UI` is set.
+//!
+//! The page itself is open like `/docs`, the compiles it sends need a key like any. When the
+//! server answers one with a 401 the page asks for a key and sends it as `X-Api-Key`, kept
+//! until the tab is closed.
+
+use actix_web::{get, web, Error, HttpResponse};
+use crate::config::{Config, CurrentConfig};
+use crate::errors::Code;
+
+fn require_ui(config: &Config) -> Result<(), Error> {
+    match config.web_ui {
+        true => { Ok(()) }
+        false => { Err(Code::UiDisabled.error("The web UI is disabled")) }
+    }
+}
+
+#[get("/ui")]
+async fn ui(config: CurrentConfig) -> Result<HttpResponse, Error> {
+    require_ui(&config)?;
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(PAGE))
+}
+
+const PAGE: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>typstapi</title>
+  <style>
+    body { margin: 0; font: 14px/1.4 system-ui, sans-serif; display: flex; height: 100vh; }
+    #editor, #result { flex: 1; display: flex; flex-direction: column; min-width: 0; padding: 12px; gap: 8px; }
+    #editor { border-right: 1px solid #ccc; }
+    textarea { flex: 1; font: 13px/1.4 ui-monospace, monospace; resize: none; tab-size: 2; }
+    .controls { display: flex; flex-wrap: wrap; gap: 8px; align-items: center; }
+    #output { flex: 1; overflow: auto; background: #f4f4f4; }
+    #output object { width: 100%; height: 100%; border: 0; }
+    #output img { display: block; max-width: 100%; margin: 8px auto; background: white; box-shadow: 0 1px 4px #0003; }
+    #diagnostics { margin: 0; max-height: 35%; overflow: auto; white-space: pre-wrap; font: 12px/1.4 ui-monospace, monospace; }
+    #diagnostics:empty { display: none; }
+    .error { color: #b00020; }
+    .warning { color: #8a5a00; }
+    .status { color: #555; }
+  </style>
+</head>
+<body>
+  <section id="editor">
+    <textarea id="source" spellcheck="false">= Hello
+
+This is *typst*, compiled by this server.
+
+$ sum_(k=1)^n k = (n(n+1)) / 2 $
+</textarea>
+    <div class="controls">
+      <label>Assets <input id="assets" type="file" multiple></label>
+      <label>Format
+        <select id="format">
+          <option value="pdf">PDF</option>
+          <option value="png">PNG</option>
+          <option value="svg">SVG</option>
+        </select>
+      </label>
+      <button id="render">Render</button>
+      <button id="forget" hidden>Forget key</button>
+    </div>
+  </section>
+  <section id="result">
+    <div id="status" class="status">Press Render or Ctrl+Enter.</div>
+    <div id="output"></div>
+    <pre id="diagnostics"></pre>
+  </section>
+  <script>
+    "use strict";
+    const KEY = "typstapi-key";
+    const $ = (id) => document.getElementById(id);
+    let urls = [];
+
+    function headers() {
+      const key = sessionStorage.getItem(KEY);
+      $("forget").hidden = !key;
+      return key ? { "X-Api-Key": key } : {};
+    }
+
+    function body() {
+      const form = new FormData();
+      form.append("main.typ", new Blob([$("source").value], { type: "text/plain" }), "main.typ");
+      for (const file of $("assets").files) {
+        form.append(file.name, file, file.name);
+      }
+      return form;
+    }
+
+    async function send(url) {
+      let response = await fetch(url, { method: "POST", headers: headers(), body: body() });
+      if (response.status === 401) {
+        const key = prompt("This server needs an API key:");
+        if (key) {
+          sessionStorage.setItem(KEY, key.trim());
+          response = await fetch(url, { method: "POST", headers: headers(), body: body() });
+        }
+      }
+      return response;
+    }
+
+    function show(lines, kind) {
+      for (const line of lines) {
+        const span = document.createElement("span");
+        const level = /^\s*(error|warning)\b/.exec(line);
+        span.className = level ? level[1] : kind;
+        span.textContent = line + "\n";
+        $("diagnostics").append(span);
+      }
+    }
+
+    function blobUrl(data, type) {
+      const url = URL.createObjectURL(new Blob([data], { type }));
+      urls.push(url);
+      return url;
+    }
+
+    async function unzip(buffer) {
+      const view = new DataView(buffer);
+      let end = buffer.byteLength - 22;
+      while (end >= 0 && view.getUint32(end, true) !== 0x06054b50) end--;
+      if (end < 0) throw new Error("The response is no zip");
+      const entries = [];
+      let at = view.getUint32(end + 16, true);
+      for (let count = view.getUint16(end + 10, true); count > 0; count--) {
+        const method = view.getUint16(at + 10, true);
+        const size = view.getUint32(at + 20, true);
+        const [nameLength, extraLength, commentLength] = [28, 30, 32].map((field) => view.getUint16(at + field, true));
+        const offset = view.getUint32(at + 42, true);
+        const name = new TextDecoder().decode(new Uint8Array(buffer, at + 46, nameLength));
+        const start = offset + 30 + view.getUint16(offset + 26, true) + view.getUint16(offset + 28, true);
+        let data = new Blob([new Uint8Array(buffer, start, size)]);
+        if (method === 8) {
+          data = await new Response(data.stream().pipeThrough(new DecompressionStream("deflate-raw"))).blob();
+        }
+        entries.push({ name, data });
+        at += 46 + nameLength + extraLength + commentLength;
+      }
+      return entries;
+    }
+
+    async function render() {
+      const format = $("format").value;
+      $("render").disabled = true;
+      $("status").textContent = "Compiling…";
+      $("diagnostics").textContent = "";
+      const started = performance.now();
+      try {
+        const url = format === "pdf" ? "/compile?include=deps" : `/compile/pages?format=${format}`;
+        const response = await send(url);
+        const seconds = ((performance.now() - started) / 1000).toFixed(2);
+        if (!response.ok) {
+          const text = await response.text();
+          let failure;
+          try { failure = JSON.parse(text); } catch { failure = { error: response.statusText, message: text }; }
+          $("status").textContent = `${response.status} ${failure.error} after ${seconds} s`;
+          show(String(failure.message).split("\n"), "error");
+          return;
+        }
+        urls.forEach(URL.revokeObjectURL);
+        urls = [];
+        $("output").textContent = "";
+        const version = response.headers.get("X-Typst-Version");
+        if (format === "pdf") {
+          const compiled = await response.json();
+          const pdf = Uint8Array.from(atob(compiled.pdf), (char) => char.charCodeAt(0));
+          const object = document.createElement("object");
+          object.type = "application/pdf";
+          object.data = blobUrl(pdf, "application/pdf");
+          $("output").append(object);
+          show(compiled.warnings, "warning");
+        } else {
+          const type = format === "svg" ? "image/svg+xml" : "image/png";
+          for (const entry of await unzip(await response.arrayBuffer())) {
+            if (entry.name === "error.txt") {
+              show((await entry.data.text()).split("\n"), "error");
+              continue;
+            }
+            const image = document.createElement("img");
+            image.alt = entry.name;
+            image.src = blobUrl(entry.data, type);
+            $("output").append(image);
+          }
+        }
+        $("status").textContent = `Compiled with typst ${version ?? "?"} in ${seconds} s`;
+      } catch (problem) {
+        $("status").textContent = "The request failed";
+        show([String(problem)], "error");
+      } finally {
+        $("render").disabled = false;
+      }
+    }
+
+    $("render").addEventListener("click", render);
+    $("forget").addEventListener("click", () => { sessionStorage.removeItem(KEY); headers(); });
+    $("source").addEventListener("keydown", (event) => {
+      if (event.key === "Enter" && (event.ctrlKey || event.metaKey)) {
+        event.preventDefault();
+        render();
+      }
+    });
+    headers();
+  </script>
+</body>
+</html>
+"##;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ui);
+}
@@ -0,0 +1,55 @@
+//! Listening on a Unix domain socket, for a proxy on the same host.
+
+use std::any::Any;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::UnixStream;
+
+/// Who is on the other end of a socket connection, standing in for the IP of TCP clients.
+#[derive(Clone)]
+pub struct PeerCredential {
+    pub uid: u32,
+    pub pid: Option<i32>,
+}
+
+/// Remember the peer credential of socket connections, for `HttpServer::on_connect`.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<UnixStream>() else { return };
+    if let Ok(credential) = stream.peer_cred() {
+        data.insert(PeerCredential { uid: credential.uid(), pid: credential.pid() });
+    }
+}
+
+/// Delete a socket file an unclean shutdown left behind. One a server still accepts on,
+/// or anything that isn't a socket, is left alone and reported.
+pub fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => { metadata }
+        Err(problem) if problem.kind() == io::ErrorKind::NotFound => { return Ok(()) }
+        Err(problem) => { return Err(problem) }
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and isn't a socket"));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it"));
+    }
+    fs::remove_file(path)
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+impl fmt::Display for PeerCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => { write!(f, "uid {} pid {pid}", self.uid) }
+            None => { write!(f, "uid {}", self.uid) }
+        }
+    }
+}
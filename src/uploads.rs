@@ -0,0 +1,281 @@
+//! Resumable uploads, for assets too large to send in one go over a flaky connection.
+//!
+//! `POST /uploads` with `{"size": 943718400}` opens a session. Chunks are appended with
+//! `PATCH /uploads/{id}`, their `Upload-Offset` header naming where they start, which must
+//! be exactly the bytes received so far. `HEAD /uploads/{id}` reports that in
+//! `Upload-Offset`, so a client resumes where the connection broke off. A chunk cut short
+//! keeps what arrived.
+//!
+//! Once every byte arrived, a multipart upload to `/compile`, `/jobs`, `/analyze` or
+//! `/projects` can refer to it with a part of type `application/vnd.typstapi.upload`, named
+//! like any other file and holding the id, as in `-F 'video.mp4=<id>;type=application/vnd.typstapi.upload'`.
+//!
+//! Sessions belong to the tenant of the key that opened them, hold `UPLOAD_SESSION_MAX_MB` at most and are
+//! dropped `UPLOAD_SESSION_TTL` after they were opened.
+
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use actix_web::{head, patch, post, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use utoipa::ToSchema;
+use crate::auth::Tenant;
+use crate::config::{Config, CurrentConfig};
+use crate::docker_world::{file_id, DocumentFile, ErrorOutput, FileData};
+use crate::errors::Code;
+use crate::multipart::next;
+
+/// Request and response header with the byte a chunk starts at, or the bytes received.
+pub const OFFSET_HEADER: &str = "Upload-Offset";
+
+/// Response header with the size the session was opened for.
+pub const LENGTH_HEADER: &str = "Upload-Length";
+
+/// Content type of a multipart part referring to a finished upload by its id.
+pub const REFERENCE_TYPE: &str = "application/vnd.typstapi.upload";
+
+/// The sessions in progress and the finished uploads not yet expired.
+#[derive(Default)]
+pub struct Uploads {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+struct Session {
+    owner: Option<String>,
+    size: u64,
+    offset: u64,
+    expires: Instant,
+    state: State,
+}
+
+enum State {
+    /// The bytes so far, taken out while a chunk is written so a second one is turned away.
+    Receiving(Option<NamedTempFile>),
+    Finished(FileData),
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewUpload {
+    /// Bytes the upload will have.
+    size: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadCreated {
+    id: String,
+    /// Seconds until the session and the upload are dropped.
+    expires_in_seconds: u64,
+}
+
+/// Who a session and everything else stored belongs to, the key's tenant, or `None` for the
+/// shared keys and open servers.
+pub fn owner(request: &HttpRequest) -> Option<String> {
+    request.extensions().get::<Tenant>().map(|tenant| tenant.0.clone())
+}
+
+impl Uploads {
+    /// Drop the sessions of `tenant`.
+    pub fn remove_tenant(&self, tenant: &str) {
+        self.sessions.lock().unwrap().retain(|_, session| session.owner.as_deref() != Some(tenant));
+    }
+
+    /// Drop sessions past their time, deleting their files unless a compile still reads them.
+    pub fn collect_garbage(&self) {
+        let now = Instant::now();
+        self.sessions.lock().unwrap().retain(|_, session| session.expires > now);
+    }
+
+    /// The finished upload `id` as the file `name`, if `owner` may use it.
+    pub fn file(&self, id: &str, name: &str, owner: Option<&str>) -> Result<DocumentFile, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)
+            .filter(|session| session.owner.as_deref() == owner && session.expires > Instant::now())
+            .ok_or_else(|| Code::UnknownUpload.error(format!("{name} refers to no upload {id}")))?;
+        match &session.state {
+            State::Finished(data) => { Ok(DocumentFile { name: file_id(name), data: data.clone() }) }
+            State::Receiving(_) => {
+                Err(Code::UploadIncomplete.error(format!(
+                    "{name} refers to upload {id}, which has {} of {} bytes so far",
+                    session.offset, session.size,
+                )))
+            }
+        }
+    }
+
+    /// Take the file of session `id` to append at `offset`, leaving the session busy.
+    fn take(&self, id: &str, owner: Option<&str>, offset: u64) -> Result<(NamedTempFile, u64), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)
+            .filter(|session| session.owner.as_deref() == owner && session.expires > Instant::now())
+            .ok_or_else(|| Code::UploadNotFound.error("No such upload"))?;
+        if session.offset != offset {
+            return Err(Code::UploadOffsetMismatch.error(format!("The upload has {} bytes, not {offset}", session.offset)));
+        }
+        match &mut session.state {
+            State::Finished(_) => { Err(Code::UploadComplete.error("The upload is complete")) }
+            State::Receiving(file) => {
+                let file = file.take().ok_or_else(|| Code::UploadBusy.error("Another chunk is being written to the upload"))?;
+                Ok((file, session.size))
+            }
+        }
+    }
+
+    /// Hand the file of session `id` back with the bytes it has now.
+    fn give_back(&self, id: &str, file: NamedTempFile, offset: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(id) else { return };
+        session.offset = offset;
+        session.state = match offset == session.size {
+            true => { State::Finished(FileData::Spooled(Arc::new(file))) }
+            false => { State::Receiving(Some(file)) }
+        };
+    }
+}
+
+/// Open a resumable upload, see the module docs.
+#[utoipa::path(
+    tag = "uploads",
+    request_body = NewUpload,
+    responses(
+        (status = 201, description = "The session, its URL in `Location`", body = UploadCreated),
+        (status = 413, description = "The upload would exceed `UPLOAD_SESSION_MAX_MB`", body = ErrorOutput),
+    ),
+)]
+#[post("/uploads")]
+async fn create_upload(
+    request: HttpRequest,
+    body: web::Json<NewUpload>,
+    config: CurrentConfig,
+    uploads: web::Data<Uploads>,
+) -> Result<HttpResponse, Error> {
+    if body.size > config.upload_session_max_size {
+        return Err(Code::UploadTooLarge.error(format!("An upload may have at most {} bytes", config.upload_session_max_size)));
+    }
+    let file = NamedTempFile::new_in(&config.spool_dir).map_err(|problem| Code::Internal.error(problem))?;
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let id: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    uploads.collect_garbage();
+    let state = match body.size {
+        0 => { State::Finished(FileData::Spooled(Arc::new(file))) }
+        _ => { State::Receiving(Some(file)) }
+    };
+    uploads.sessions.lock().unwrap().insert(id.clone(), Session {
+        owner: owner(&request),
+        size: body.size,
+        offset: 0,
+        expires: Instant::now() + config.upload_session_ttl,
+        state,
+    });
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/uploads/{id}")))
+        .json(UploadCreated { id, expires_in_seconds: config.upload_session_ttl.as_secs() }))
+}
+
+/// Append a chunk starting at `Upload-Offset`.
+#[utoipa::path(
+    tag = "uploads",
+    params(("id" = String, Path, description = "The upload's id")),
+    request_body(content = [u8], content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The chunk was appended, the bytes received now in `Upload-Offset`"),
+        (status = 400, description = "`Upload-Offset` is missing", body = ErrorOutput),
+        (status = 404, description = "No such upload, or it expired", body = ErrorOutput),
+        (status = 409, description = "The offset isn't the bytes received so far, the upload is complete, or another chunk is being written", body = ErrorOutput),
+        (status = 413, description = "The chunk runs past the size the upload was opened for", body = ErrorOutput),
+    ),
+)]
+#[patch("/uploads/{id}")]
+async fn append_chunk(
+    request: HttpRequest,
+    id: web::Path<String>,
+    mut payload: web::Payload,
+    config: CurrentConfig,
+    uploads: web::Data<Uploads>,
+) -> Result<HttpResponse, Error> {
+    let offset: u64 = request.headers().get(OFFSET_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| Code::MissingUploadOffset.error(format!("Send the byte the chunk starts at in {OFFSET_HEADER}")))?;
+    let owner = owner(&request);
+    let (mut file, size) = uploads.take(&id, owner.as_deref(), offset)?;
+
+    let written = append(&mut file, &mut payload, offset, size, &config).await;
+    let reached = match &written {
+        Ok(reached) => { *reached }
+        Err((reached, _)) => { *reached }
+    };
+    uploads.give_back(&id, file, reached);
+    written.map_err(|(_, problem)| problem)?;
+
+    Ok(HttpResponse::NoContent().insert_header((OFFSET_HEADER, reached.to_string())).finish())
+}
+
+/// Write the chunk in `payload` from `offset` on, returning the bytes the file has after.
+/// On failure those are the bytes that made it, all but a chunk that ran past `size`.
+async fn append(
+    file: &mut NamedTempFile,
+    payload: &mut web::Payload,
+    offset: u64,
+    size: u64,
+    config: &Config,
+) -> Result<u64, (u64, Error)> {
+    let start = |problem| (offset, Code::Internal.error(problem));
+    file.as_file().set_len(offset).map_err(start)?;
+    file.seek(SeekFrom::Start(offset)).map_err(start)?;
+
+    let mut reached = offset;
+    loop {
+        let chunk = match next(payload, config.upload_idle_timeout).await {
+            Ok(Some(Ok(chunk))) => { chunk }
+            Ok(None) => { break }
+            Ok(Some(Err(problem))) => { return Err((reached, Code::InvalidBody.error(problem))) }
+            Err(problem) => { return Err((reached, problem)) }
+        };
+        if reached + chunk.len() as u64 > size {
+            let _ = file.as_file().set_len(offset);
+            return Err((offset, Code::UploadOverrun.error(format!("The upload was opened for {size} bytes"))));
+        }
+        file.write_all(&chunk).map_err(|problem| (reached, Code::Internal.error(problem)))?;
+        reached += chunk.len() as u64;
+    }
+    file.flush().map_err(|problem| (offset, Code::Internal.error(problem)))?;
+    Ok(reached)
+}
+
+/// Report the bytes received so far, to resume from.
+#[utoipa::path(
+    tag = "uploads",
+    params(("id" = String, Path, description = "The upload's id")),
+    responses(
+        (status = 200, description = "The bytes received in `Upload-Offset`, the size in `Upload-Length`"),
+        (status = 404, description = "No such upload, or it expired"),
+    ),
+)]
+#[head("/uploads/{id}")]
+async fn upload_offset(request: HttpRequest, id: web::Path<String>, uploads: web::Data<Uploads>) -> HttpResponse {
+    let owner = owner(&request);
+    let sessions = uploads.sessions.lock().unwrap();
+    let session = sessions.get(id.as_str())
+        .filter(|session| session.owner == owner && session.expires > Instant::now());
+    match session {
+        None => { HttpResponse::NotFound().finish() }
+        Some(session) => {
+            HttpResponse::Ok()
+                .insert_header((OFFSET_HEADER, session.offset.to_string()))
+                .insert_header((LENGTH_HEADER, session.size.to_string()))
+                .insert_header(("Cache-Control", "no-store"))
+                .finish()
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_upload)
+        .service(append_chunk)
+        .service(upload_offset);
+}
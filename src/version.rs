@@ -0,0 +1,86 @@
+//! What this binary is: its version, the typst versions it bundles and how it was built.
+
+use actix_web::{get, web, HttpResponse};
+use chrono::DateTime;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::compilers::Compilers;
+use crate::config::Config;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Set by the build script when it could ask git.
+const COMMIT: Option<&str> = option_env!("TYPSTAPI_GIT_COMMIT");
+const BUILD_TIMESTAMP: Option<&str> = option_env!("TYPSTAPI_BUILD_TIMESTAMP");
+
+/// Optional capabilities compiled into this binary.
+const FEATURES: &[&str] = &[
+    "packages",
+    "tls",
+    #[cfg(unix)]
+    "unix-socket",
+    #[cfg(unix)]
+    "isolation",
+    #[cfg(feature = "redis")]
+    "redis",
+    #[cfg(feature = "sqlite")]
+    "sqlite",
+    #[cfg(feature = "otel")]
+    "otel",
+];
+
+#[derive(Serialize, ToSchema)]
+pub struct Build {
+    version: &'static str,
+    /// Bundled compilers, newest first.
+    typst: Vec<&'static str>,
+    commit: Option<&'static str>,
+    built_at: Option<String>,
+    #[schema(value_type = Vec<String>)]
+    features: &'static [&'static str],
+    /// Whether packages are only read from the cache, see `OFFLINE`.
+    offline: bool,
+}
+
+impl Build {
+    pub fn new(compilers: &Compilers, config: &Config) -> Self {
+        Self {
+            version: VERSION,
+            typst: compilers.versions(),
+            commit: COMMIT,
+            built_at: BUILD_TIMESTAMP
+                .and_then(|timestamp| timestamp.parse().ok())
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+                .map(|built_at| built_at.to_rfc3339()),
+            features: FEATURES,
+            offline: config.offline,
+        }
+    }
+
+    /// The `Server` header, like `typstapi/0.1.0 typst/0.12.0`.
+    pub fn server_header(&self) -> String {
+        format!("typstapi/{} typst/{}", self.version, self.typst.join(","))
+    }
+
+    /// Printed once at startup.
+    pub fn log(&self) {
+        tracing::info!(
+            offline = self.offline,
+            "typstapi {} (commit {}, built {}) with typst {}, features: {}",
+            self.version,
+            self.commit.unwrap_or("unknown"),
+            self.built_at.as_deref().unwrap_or("at an unknown time"),
+            self.typst.join(", "),
+            self.features.join(", "),
+        );
+    }
+}
+
+#[utoipa::path(tag = "operations", responses((status = 200, description = "How this server was built", body = Build)))]
+#[get("/version")]
+async fn version(build: web::Data<Build>) -> HttpResponse {
+    HttpResponse::Ok().json(build.get_ref())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(version);
+}
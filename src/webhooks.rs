@@ -0,0 +1,251 @@
+//! Job notifications, POSTed to the `callback_url` a job was submitted with.
+//!
+//! Every body is signed in the `X-Signature` header as `t=<unix seconds>,v1=<hex>`, the hex
+//! being the HMAC-SHA256 of `<unix seconds>.<body>` under `CALLBACK_SECRET`. Receivers should
+//! compare it in constant time and reject old timestamps, so deliveries can't be replayed.
+
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{web, Error};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sha2::Sha256;
+use url::Url;
+use crate::config::Config;
+use crate::errors::Code;
+
+/// Header carrying the signature of a notification.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Sends notifications as the configuration of the job's submission says, so a reload
+/// doesn't change the rules for jobs already queued.
+pub struct Webhooks {
+    agent: ureq::Agent,
+}
+
+/// What the callback receives as JSON.
+#[derive(Clone, Serialize)]
+pub struct Notification {
+    pub id: String,
+    pub status: &'static str,
+    pub result_size: Option<usize>,
+    pub error: Option<String>,
+    pub error_code: Option<String>,
+    /// Hex SHA-256 of the PDF.
+    pub sha256: Option<String>,
+    pub pdf: Option<String>,
+}
+
+/// How delivering a job's notification went.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Delivery {
+    pub url: String,
+    pub state: DeliveryState,
+    pub attempts: Vec<DeliveryAttempt>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeliveryAttempt {
+    pub at: DateTime<Utc>,
+    /// What the callback answered, if it answered at all.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self {
+            // Redirects could lead anywhere, past the allowlist.
+            agent: ureq::AgentBuilder::new()
+                .redirects(0)
+                .timeout(Duration::from_secs(30))
+                .build(),
+        }
+    }
+}
+
+impl Webhooks {
+    /// Accept only http(s) URLs to allowlisted hosts, so jobs can't make the server
+    /// probe its internal network. `*.example.com` also matches subdomains.
+    pub fn validate(&self, url: &str, config: &Config) -> Result<Url, Error> {
+        let url = Url::parse(url).map_err(|problem| Code::InvalidCallbackUrl.error(format!("Invalid callback_url: {problem}")))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(Code::InvalidCallbackUrl.error("callback_url must be http or https"));
+        }
+
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let allowed = config.callback_allowlist.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => { host == domain || host.ends_with(&format!(".{domain}")) }
+            None => { host == *pattern }
+        });
+        match allowed {
+            true => { Ok(url) }
+            false => { Err(Code::InvalidCallbackUrl.error(format!("callback_url host {host} is not allowed"))) }
+        }
+    }
+
+    /// Whether a PDF is small enough to be included in its notification, base64 encoded.
+    pub fn inline(&self, pdf: &[u8], config: &Config) -> bool {
+        pdf.len() <= config.callback_inline_limit
+    }
+
+    /// POST `notification`, retrying network errors and 5xx responses with exponential backoff.
+    pub async fn deliver(webhooks: web::Data<Webhooks>, config: Arc<Config>, url: Url, notification: Notification) -> Delivery {
+        let mut delivery = Delivery::pending(url.to_string());
+        let body = web::Bytes::from(serde_json::to_vec(&notification).expect("Notifications serialize"));
+
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let at = Utc::now();
+            let attempt = {
+                let (webhooks, config, url, body) = (webhooks.clone(), config.clone(), url.clone(), body.clone());
+                web::block(move || webhooks.post(&url, &body, &config)).await
+            };
+
+            let (status, problem, retry) = match attempt {
+                Ok(Ok(status)) => {
+                    delivery.attempts.push(DeliveryAttempt { at, status: Some(status), error: None });
+                    delivery.state = DeliveryState::Delivered;
+                    return delivery;
+                }
+                Ok(Err(failure)) => { failure }
+                Err(problem) => { (None, problem.to_string(), false) }
+            };
+
+            delivery.attempts.push(DeliveryAttempt { at, status, error: Some(problem) });
+            if !retry || delivery.attempts.len() > config.callback_retries {
+                delivery.state = DeliveryState::Failed;
+                return delivery;
+            }
+            actix_web::rt::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    /// `t=<timestamp>,v1=<signature>` for `body`, sent now.
+    fn sign(body: &[u8], secret: &str) -> String {
+        let timestamp = Utc::now().timestamp();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC keys can have any length");
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect();
+        format!("t={timestamp},v1={signature}")
+    }
+
+    /// One delivery attempt, failing with the status, the reason and whether it is worth retrying.
+    fn post(&self, url: &Url, body: &[u8], config: &Config) -> Result<u16, (Option<u16>, String, bool)> {
+        let secret = config.callback_secret.as_deref().unwrap_or_default();
+        let request = self.agent.post(url.as_str())
+            .set("Content-Type", "application/json")
+            .set(SIGNATURE_HEADER, &Self::sign(body, secret));
+        match request.send_bytes(body) {
+            Ok(response) if response.status() < 300 => { Ok(response.status()) }
+            Ok(response) => {
+                let status = response.status();
+                Err((Some(status), format!("callback answered {status}"), false))
+            }
+            Err(ureq::Error::Status(status, _)) => {
+                Err((Some(status), format!("callback answered {status}"), status >= 500))
+            }
+            Err(problem) => { Err((None, problem.to_string(), true)) }
+        }
+    }
+}
+
+impl Delivery {
+    pub fn pending(url: String) -> Self {
+        Self { url, state: DeliveryState::Pending, attempts: vec![] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc};
+    use actix_web::web;
+    use chrono::Utc;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use url::Url;
+    use crate::config::Config;
+    use super::{DeliveryState, Notification, Webhooks};
+
+    const SECRET: &str = "callback-secret";
+
+    /// Answer as many requests as `statuses` has, with those statuses, sending on what was
+    /// received: the signature header and the body.
+    fn receiver(statuses: &'static [&'static str]) -> (Url, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let (sender, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let (mut signature, mut length) = (String::new(), 0);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(':') else { break };
+                    match name.to_ascii_lowercase().as_str() {
+                        "x-signature" => { signature = value.trim().to_string() }
+                        "content-length" => { length = value.trim().parse().unwrap() }
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender.send((signature, body)).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    /// Whether `header` is a fresh signature of `body` under `SECRET`.
+    fn signed(header: &str, body: &[u8]) -> bool {
+        let Some((timestamp, signature)) = header.strip_prefix("t=").and_then(|rest| rest.split_once(",v1=")) else { return false };
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        let expected: String = mac.finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect();
+        let age = Utc::now().timestamp() - timestamp.parse::<i64>().unwrap();
+        signature == expected && (0..60).contains(&age)
+    }
+
+    #[actix_web::test]
+    async fn a_retried_delivery_is_signed_again() {
+        let (url, received) = receiver(&["503 Service Unavailable", "204 No Content"]);
+        let config = Config::from_args(["typstapi", "--callback-allowlist", "127.0.0.1", "--callback-secret", SECRET, "--callback-retries", "1"]).unwrap();
+        let notification = Notification {
+            id: "job".to_string(),
+            status: "succeeded",
+            result_size: Some(3),
+            error: None,
+            error_code: None,
+            sha256: None,
+            pdf: None,
+        };
+
+        let delivery = Webhooks::deliver(web::Data::new(Webhooks::default()), Arc::new(config), url, notification).await;
+        assert!(delivery.state == DeliveryState::Delivered);
+        let statuses: Vec<_> = delivery.attempts.iter().map(|attempt| attempt.status).collect();
+        assert_eq!(statuses, [Some(503), Some(204)]);
+
+        let (first, retried) = (received.recv().unwrap(), received.recv().unwrap());
+        assert_eq!(first.1, retried.1);
+        assert!(signed(&first.0, &first.1), "{}", first.0);
+        assert!(signed(&retried.0, &retried.1), "{}", retried.0);
+    }
+}
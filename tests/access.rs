@@ -0,0 +1,42 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::{json, Value};
+use typstapi::app::app;
+
+/// A request for `uri` from `peer` with the key.
+fn request(uri: &str, peer: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri(uri)
+        .peer_addr(format!("{peer}:4711").parse().unwrap())
+        .insert_header(("X-Api-Key", "secret"))
+}
+
+#[actix_web::test]
+async fn clients_outside_the_lists_are_turned_away_before_anything_else() {
+    let state = common::state(&[
+        "--ip-allowlist", "10.0.0.0/8,192.0.2.7",
+        "--ip-denylist", "10.9.0.0/16",
+        "--api-keys", "secret",
+    ]);
+    let service = test::init_service(app(state)).await;
+
+    for peer in ["10.1.2.3", "192.0.2.7"] {
+        let response = test::call_service(&service, request("/limits", peer).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK, "{peer}");
+    }
+    let unauthenticated = test::TestRequest::get().uri("/limits").peer_addr("10.1.2.3:4711".parse().unwrap()).to_request();
+    let response = test::call_service(&service, unauthenticated).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Denied even where they would be allowed, on probes and with a valid key, and without saying why.
+    for (uri, peer) in [("/limits", "10.9.1.1"), ("/healthz", "10.9.1.1"), ("/limits", "192.0.2.8"), ("/healthz", "203.0.113.1")] {
+        let response = test::call_service(&service, request(uri, peer).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri} from {peer}");
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body, json!({ "error": "client_denied", "message": "Forbidden" }), "{uri} from {peer}");
+    }
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::{json, Value};
+use typstapi::app::app;
+
+const MAIN: &[u8] = b"#include \"chapter.typ\"\n#read(\"data.txt\")\n";
+const CHAPTER: &[u8] = b"= Chapter\n";
+const DATA: &[u8] = b"Some data";
+
+fn manifest(files: &[(&str, &[u8])]) -> Value {
+    let files: Vec<_> = files.iter().map(|(path, data)| json!({ "path": path, "sha256": common::sha256(data) })).collect();
+    json!({ "files": files })
+}
+
+/// The hashes of `missing`, sorted as `/blobs/negotiate` lists them.
+fn hashes(missing: &[&[u8]]) -> Value {
+    let mut hashes: Vec<_> = missing.iter().map(|data| common::sha256(data)).collect();
+    hashes.sort();
+    json!({ "missing": hashes })
+}
+
+#[actix_web::test]
+async fn a_cold_server_asks_for_everything_and_a_warm_one_for_what_changed() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let files: [(&str, &[u8]); 3] = [("main.typ", MAIN), ("chapter.typ", CHAPTER), ("data.txt", DATA)];
+
+    let negotiate = test::TestRequest::post().uri("/blobs/negotiate").set_json(manifest(&files)).to_request();
+    let missing: Value = test::call_and_read_body_json(&service, negotiate).await;
+    assert_eq!(missing, hashes(&[MAIN, CHAPTER, DATA]));
+
+    let compile = test::TestRequest::post().uri("/compile/manifest").set_json(manifest(&files)).to_request();
+    let response = test::call_service(&service, compile).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let failure: Value = test::read_body_json(response).await;
+    assert_eq!(failure["error"], "missing_blobs");
+    assert_eq!(failure["missing"], manifest(&files)["files"]);
+
+    for (_, data) in files {
+        let upload = test::TestRequest::put()
+            .uri(&format!("/blobs/{}", common::sha256(data)))
+            .set_payload(data)
+            .to_request();
+        assert_eq!(test::call_service(&service, upload).await.status(), StatusCode::NO_CONTENT);
+    }
+    let compile = test::TestRequest::post().uri("/compile/manifest").set_json(manifest(&files)).to_request();
+    let response = test::call_service(&service, compile).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(test::read_body(response).await.starts_with(b"%PDF-"));
+
+    // Warm now, only the edited chapter is asked for.
+    let edited: [(&str, &[u8]); 3] = [("main.typ", MAIN), ("chapter.typ", b"= Chapter, edited\n"), ("data.txt", DATA)];
+    let negotiate = test::TestRequest::post().uri("/blobs/negotiate").set_json(manifest(&edited)).to_request();
+    let missing: Value = test::call_and_read_body_json(&service, negotiate).await;
+    assert_eq!(missing, hashes(&[b"= Chapter, edited\n"]));
+}
+
+#[actix_web::test]
+async fn a_blob_evicted_after_negotiating_fails_the_compile_precisely() {
+    let service = test::init_service(app(common::state(&["--blob-store-mb", "1"]))).await;
+    let (first, second) = (vec![b'a'; 600 * 1024], vec![b'b'; 600 * 1024]);
+    for data in [MAIN, CHAPTER, first.as_slice()] {
+        let upload = test::TestRequest::put().uri(&format!("/blobs/{}", common::sha256(data))).set_payload(data.to_vec()).to_request();
+        assert_eq!(test::call_service(&service, upload).await.status(), StatusCode::NO_CONTENT);
+    }
+    // Negotiating uses the blobs in this order, the chapter last.
+    let files: [(&str, &[u8]); 3] = [("main.typ", MAIN), ("data.txt", &first), ("chapter.typ", CHAPTER)];
+    let negotiate = test::TestRequest::post().uri("/blobs/negotiate").set_json(manifest(&files)).to_request();
+    let missing: Value = test::call_and_read_body_json(&service, negotiate).await;
+    assert_eq!(missing, hashes(&[]));
+
+    // Another upload pushes the least recently used blobs out until it fits.
+    let upload = test::TestRequest::put().uri(&format!("/blobs/{}", common::sha256(&second))).set_payload(second.clone()).to_request();
+    assert_eq!(test::call_service(&service, upload).await.status(), StatusCode::NO_CONTENT);
+
+    let compile = test::TestRequest::post().uri("/compile/manifest").set_json(manifest(&files)).to_request();
+    let response = test::call_service(&service, compile).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let failure: Value = test::read_body_json(response).await;
+    let evicted = json!([
+        { "path": "main.typ", "sha256": common::sha256(MAIN) },
+        { "path": "data.txt", "sha256": common::sha256(&first) },
+    ]);
+    assert_eq!(failure["missing"], evicted);
+}
+
+#[actix_web::test]
+async fn refuses_a_blob_that_does_not_hash_to_its_path() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let upload = test::TestRequest::put().uri(&format!("/blobs/{}", common::sha256(MAIN))).set_payload(CHAPTER).to_request();
+    let response = test::call_service(&service, upload).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let failure: Value = test::read_body_json(response).await;
+    assert_eq!(failure["error"], "digest_mismatch");
+}
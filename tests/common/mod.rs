@@ -0,0 +1,60 @@
+//! The server the tests call, built like `main` builds it but over the fixture fonts and
+//! without network access, and requests for it.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use typstapi::app::AppState;
+use typstapi::config::Config;
+
+/// Separates the parts of the bodies `multipart` builds.
+const BOUNDARY: &str = "typstapi-test-boundary";
+
+/// The `tests/fixtures` directory.
+pub fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+/// The config of a server started with `args`, on top of the fixture fonts, offline and
+/// without the warm-up compile unless `args` ask for it.
+pub fn config(args: &[&str]) -> Config {
+    let fonts = fixtures().join("fonts");
+    let mut command_line = vec![
+        "typstapi".to_string(),
+        "--font-dir".to_string(),
+        fonts.display().to_string(),
+        "--offline".to_string(),
+    ];
+    if !args.contains(&"--warm-up") {
+        command_line.extend(["--warm-up".to_string(), "false".to_string()]);
+    }
+    command_line.extend(args.iter().map(|arg| arg.to_string()));
+    Config::from_args(command_line).unwrap_or_else(|problems| panic!("invalid test config: {problems:?}"))
+}
+
+/// The state of a server started with `args`, see `config`.
+pub fn state(args: &[&str]) -> AppState {
+    AppState::new(config(args)).unwrap_or_else(|problem| panic!("the test server can't start: {problem}"))
+}
+
+/// A `multipart/form-data` body with a part named after each file's path, the main file
+/// first, and the `Content-Type` to send it with.
+pub fn multipart(files: &[(&str, &[u8])]) -> (String, Vec<u8>) {
+    let mut body = Vec::new();
+    for (path, data) in files {
+        body.extend_from_slice(format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{path}\"; filename=\"{path}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        ).as_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+    (format!("multipart/form-data; boundary={BOUNDARY}"), body)
+}
+
+/// The hex SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,259 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{ACCEPT, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use base64::Engine;
+use serde_json::Value;
+use typstapi::app::app;
+
+const MAIN: &[u8] = b"#import \"chapters/intro.typ\": intro\n\
+    #intro\n\
+    #let rows = csv(\"data.csv\")\n\
+    #table(columns: 2, ..rows.flatten())\n";
+const INTRO: &[u8] = b"#let intro = [= Introduction\nThe numbers follow.]\n";
+const DATA: &[u8] = b"a,1\nb,2\n";
+
+/// The status of a failed request and the code its body has.
+async fn failure<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, String) {
+    let status = response.status();
+    let body: Value = test::read_body_json(response).await;
+    (status, body["error"].as_str().unwrap_or_default().to_string())
+}
+
+#[actix_web::test]
+async fn compiles_an_upload_of_several_files() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let (content_type, body) = common::multipart(&[("main.typ", MAIN), ("chapters/intro.typ", INTRO), ("data.csv", DATA)]);
+    let request = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let pdf = test::read_body(response).await;
+    assert!(pdf.starts_with(b"%PDF-"));
+}
+
+#[actix_web::test]
+async fn lists_the_files_a_compile_read() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let (content_type, body) = common::multipart(&[
+        ("main.typ", MAIN),
+        ("chapters/intro.typ", INTRO),
+        ("data.csv", DATA),
+        ("unused.typ", b"= Never read"),
+    ]);
+    let request = test::TestRequest::post()
+        .uri("/compile?include=deps")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let compiled: Value = test::call_and_read_body_json(&service, request).await;
+    let files: Vec<_> = compiled["dependencies"]["files"].as_array().unwrap().iter().filter_map(Value::as_str).collect();
+    assert_eq!(files, ["chapters/intro.typ", "data.csv", "main.typ"]);
+    assert!(compiled["pdf"].as_str().is_some_and(|pdf| !pdf.is_empty()));
+}
+
+#[actix_web::test]
+async fn compiles_the_source_of_a_form() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let request = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, "application/x-www-form-urlencoded"))
+        .set_payload("source=Hello+%23sys.inputs.name&input.name=World")
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(test::read_body(response).await.starts_with(b"%PDF-"));
+}
+
+#[actix_web::test]
+async fn reports_errors_in_the_document() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let (content_type, body) = common::multipart(&[("main.typ", b"#import \"missing.typ\": nothing\n")]);
+    let request = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(failure(response).await, (StatusCode::BAD_REQUEST, "compile_error".to_string()));
+}
+
+#[actix_web::test]
+async fn a_dry_run_reports_pages_and_warnings_without_a_pdf() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let document = "#text(font: \"No Such Font\")[Draft]\n#pagebreak()\nEnd\n";
+    let dry_run = |query: &str| {
+        test::TestRequest::post()
+            .uri(&format!("/compile?dry_run=true{query}"))
+            .insert_header((CONTENT_TYPE, "text/plain"))
+            .set_payload(document)
+            .to_request()
+    };
+
+    let checked: Value = test::call_and_read_body_json(&service, dry_run("")).await;
+    assert_eq!(checked["pages"], 2);
+    assert_eq!(checked["warnings"].as_array().map(Vec::len), Some(1));
+    assert!(checked.get("pdf").is_none());
+
+    let response = test::call_service(&service, dry_run("&fail_on_warnings=true")).await;
+    assert_eq!(failure(response).await, (StatusCode::UNPROCESSABLE_ENTITY, "warnings".to_string()));
+
+    let with_dependencies: Value = test::call_and_read_body_json(&service, dry_run("&include=deps")).await;
+    assert!(with_dependencies["dependencies"]["files"].as_array().is_some_and(|files| !files.is_empty()));
+}
+
+#[actix_web::test]
+async fn refuses_uploads_it_cannot_compile() {
+    let service = test::init_service(app(common::state(&["--max-file-size-kb", "1"]))).await;
+    let large = vec![b'a'; 2048];
+    let cases: [(&[(&str, &[u8])], StatusCode, &str); 4] = [
+        (&[], StatusCode::BAD_REQUEST, "missing_main"),
+        (&[("main.typ", b"= A"), ("main.typ", b"= B")], StatusCode::BAD_REQUEST, "duplicate_file"),
+        (&[("main.typ", b"= A"), ("tool.exe", b"MZ")], StatusCode::BAD_REQUEST, "file_type_not_allowed"),
+        (&[("main.typ", large.as_slice())], StatusCode::PAYLOAD_TOO_LARGE, "file_too_large"),
+    ];
+    for (files, status, code) in cases {
+        let (content_type, body) = common::multipart(files);
+        let request = test::TestRequest::post()
+            .uri("/compile")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(failure(response).await, (status, code.to_string()), "{files:?}");
+    }
+}
+
+#[actix_web::test]
+async fn refuses_images_that_are_not_what_their_extension_says() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let cases: [(&str, &[u8], &str); 6] = [
+        ("logo.png", b"\xFF\xD8\xFF\xE0\0\x10JFIF", "logo.png is named as PNG but is JPEG"),
+        ("photo.JPG", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "photo.JPG is named as JPEG but is PNG"),
+        ("icon.gif", b"%PDF-1.7\n", "icon.gif is named as GIF but is PDF"),
+        ("figure.svg", b"<!DOCTYPE html><html></html>", "figure.svg is named as SVG but is HTML"),
+        ("figure.svg", b"<rect width=\"1\"/>", "figure.svg is named as SVG but is XML with a <rect> root"),
+        ("empty.jpeg", b"", "empty.jpeg is named as JPEG but is empty"),
+    ];
+    for (name, data, message) in cases {
+        let (content_type, body) = common::multipart(&[("main.typ", b"= A"), (name, data)]);
+        let request = test::TestRequest::post()
+            .uri("/compile")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{name}");
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!((body["error"].as_str(), body["message"].as_str()), (Some("invalid_file_content"), Some(message)));
+    }
+}
+
+#[actix_web::test]
+async fn only_files_named_as_images_are_sniffed() {
+    let service = test::init_service(app(common::state(&["--allow-extensionless"]))).await;
+    let (content_type, body) = common::multipart(&[
+        ("main.typ", b"= A"),
+        ("logo", b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+        ("notes.txt", b"\x89PNG\r\n\x1a\n"),
+        ("figure.svg", b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+    ]);
+    let request = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn refuses_bodies_and_options_it_does_not_know() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let request = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, "application/pdf"))
+        .set_payload("%PDF-")
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(failure(response).await, (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type".to_string()));
+
+    let (content_type, body) = common::multipart(&[("main.typ", b"= A")]);
+    let request = test::TestRequest::post()
+        .uri("/compile?typst_version=0.1")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(failure(response).await, (StatusCode::BAD_REQUEST, "unknown_version".to_string()));
+}
+
+#[actix_web::test]
+async fn compiles_the_example() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let request = test::TestRequest::get().uri("/hello_typst.pdf?name=Tester&pages=2").to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(test::read_body(response).await.starts_with(b"%PDF-"));
+
+    let request = test::TestRequest::get()
+        .uri("/hello_typst.pdf?pages=2&page=2")
+        .insert_header((ACCEPT, "image/png"))
+        .to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    assert!(test::read_body(response).await.starts_with(b"\x89PNG"));
+
+    for query in ["pages=11", "pages=0", "name=%0A"] {
+        let request = test::TestRequest::get().uri(&format!("/hello_typst.pdf?{query}")).to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(failure(response).await, (StatusCode::BAD_REQUEST, "invalid_parameter".to_string()), "{query}");
+    }
+}
+
+#[actix_web::test]
+async fn greets() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let request = test::TestRequest::get().uri("/hello/Tester").to_request();
+    assert_eq!(test::call_and_read_body(&service, request).await, "Hello Tester!");
+}
+
+#[actix_web::test]
+async fn a_high_priority_token_counts_however_it_is_sent() {
+    let service = test::init_service(app(common::state(&["--high-priority-tokens", "urgent", "--basic-auth"]))).await;
+    let compile = |header: Option<(&'static str, String)>| {
+        let (content_type, body) = common::multipart(&[("main.typ", b"= Urgent\n")]);
+        let request = test::TestRequest::post()
+            .uri("/compile")
+            .insert_header((CONTENT_TYPE, content_type))
+            .insert_header(("X-Priority", "high"))
+            .set_payload(body);
+        match header {
+            Some(header) => { request.insert_header(header).to_request() }
+            None => { request.to_request() }
+        }
+    };
+
+    let permitted = [
+        ("Authorization", "Bearer urgent".to_string()),
+        ("X-Api-Key", "urgent".to_string()),
+        ("Authorization", format!("Basic {}", base64::engine::general_purpose::STANDARD.encode("client:urgent"))),
+    ];
+    for header in permitted {
+        let response = test::call_service(&service, compile(Some(header.clone()))).await;
+        assert_eq!(response.status(), StatusCode::OK, "{header:?}");
+    }
+    for header in [Some(("X-Api-Key", "urgen".to_string())), None] {
+        let response = test::call_service(&service, compile(header.clone())).await;
+        assert_eq!(failure(response).await, (StatusCode::FORBIDDEN, "priority_not_allowed".to_string()), "{header:?}");
+    }
+}
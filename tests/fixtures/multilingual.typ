@@ -0,0 +1,16 @@
+// Counted are only the words typst lays out, not this comment or the code below.
+#set page(width: 15cm, height: auto, margin: 1cm)
+#set text(font: "DejaVu Sans")
+#let hidden = [Never shown on any page]
+
+= Statistics
+
+Hello world, naïve café.
+
+東京は大きい。
+
+שלום עולם
+
+مرحبا بالعالم
+
+#figure(table(columns: 2, [a], [b]))
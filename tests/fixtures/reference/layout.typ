@@ -0,0 +1,19 @@
+// Columns, placement, shapes and page breaks.
+#set page(width: 14cm, height: 10cm, margin: 1cm, header: [Reference], footer: context counter(page).display("1 / 1", both: true))
+#set text(font: "DejaVu Sans", size: 9pt)
+
+#columns(2)[
+  #lorem(120)
+  #colbreak()
+  #lorem(40)
+]
+
+#pagebreak()
+
+#place(top + right, circle(radius: 1cm, fill: blue.lighten(60%)))
+#stack(dir: ltr, spacing: 4mm, square(size: 1cm), ellipse(width: 2cm, height: 1cm), polygon.regular(size: 1cm, vertices: 6))
+#v(1cm)
+#align(center, box(stroke: 1pt, inset: 6pt)[Centered in a box])
+#line(length: 100%)
+#outline()
+= A heading for the outline
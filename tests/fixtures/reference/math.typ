@@ -0,0 +1,14 @@
+// Inline and block equations.
+#set page(width: 12cm, height: auto, margin: 1cm)
+#set text(font: "DejaVu Sans", size: 10pt)
+#set math.equation(numbering: "(1)")
+
+The area of a circle is $A = pi r^2$, and the roots of $a x^2 + b x + c$ are
+
+$ x_(1,2) = (-b plus.minus sqrt(b^2 - 4 a c)) / (2 a) $
+
+$ sum_(k=1)^n k = (n (n + 1)) / 2 quad "and" quad integral_0^infinity e^(-x^2) dif x = sqrt(pi) / 2 $
+
+$ mat(1, 2; 3, 4) vec(x, y) = vec(5, 6) $
+
+$ f(x) = cases(x^2 &"if" x >= 0, -x &"otherwise") $
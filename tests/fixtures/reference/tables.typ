@@ -0,0 +1,23 @@
+// Tables and grids, with spans, strokes and alignment.
+#set page(width: 14cm, height: auto, margin: 1cm)
+#set text(font: "DejaVu Sans", size: 9pt)
+
+#table(
+  columns: (auto, 1fr, 1fr),
+  align: (left, center, right),
+  table.header([*Item*], [*Count*], [*Price*]),
+  [Apples], [3], [1.20],
+  [Pears], [12], [0.80],
+  table.cell(colspan: 2)[*Total*], [15.60],
+)
+
+#grid(
+  columns: 3,
+  gutter: 6pt,
+  ..range(9).map(n => rect(width: 100%, height: 1cm, fill: luma(230))[#n]),
+)
+
+#figure(
+  table(columns: 4, stroke: 0.5pt, ..range(16).map(n => [#n])),
+  caption: [A figure with a table],
+)
@@ -0,0 +1,28 @@
+// Paragraphs, headings and lists, justified and hyphenated.
+#set page(width: 12cm, height: 16cm, margin: 1.5cm, numbering: "1")
+#set text(font: "DejaVu Sans", size: 10pt, lang: "en")
+#set par(justify: true)
+#set heading(numbering: "1.1")
+
+= Introduction
+
+#lorem(80)
+
+== Lists
+
+- A first item
+- A second item, long enough to wrap onto a second line of the list
+  - With a nested item
++ Numbered
++ Items
+
+== Quotes and emphasis
+
+#quote(block: true)[#lorem(30)]
+
+Some *strong*, _emphasized_ and `raw` text, a footnote#footnote[At the bottom of the page.] and a
+#link("https://typst.app")[link].
+
+= Conclusion
+
+#lorem(200)
@@ -0,0 +1,69 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::Value;
+use typstapi::app::app;
+
+#[actix_web::test]
+async fn a_cold_server_is_ready_once_warmed_up() {
+    let state = common::state(&["--warm-up", "true", "--warm-up-required"]);
+    let (health, compilers, config) = (state.health.clone(), state.compilers.clone(), state.config.current());
+    let service = test::init_service(app(state)).await;
+
+    let response = test::call_service(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let readiness: Value = test::read_body_json(response).await;
+    assert_eq!(readiness["checks"]["warm_up"]["state"], "pending");
+    assert_eq!(readiness["checks"]["warm_up"]["ok"], false);
+
+    actix_web::web::block(move || health.warm_up(compilers.newest().as_ref(), &config)).await.unwrap();
+
+    let response = test::call_service(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let readiness: Value = test::read_body_json(response).await;
+    assert_eq!(readiness["checks"]["warm_up"]["state"], "succeeded");
+    assert!(readiness["checks"]["warm_up"]["milliseconds"].is_u64());
+}
+
+#[actix_web::test]
+async fn a_server_without_warm_up_is_ready_at_once() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let response = test::call_service(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let readiness: Value = test::read_body_json(response).await;
+    assert_eq!(readiness["checks"]["warm_up"]["state"], "disabled");
+    assert_eq!(readiness["checks"]["preload"]["state"], "disabled");
+}
+
+#[actix_web::test]
+async fn an_offline_server_says_so() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let readiness: Value = test::call_and_read_body_json(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(readiness["offline"], true);
+    let build: Value = test::call_and_read_body_json(&service, test::TestRequest::get().uri("/version").to_request()).await;
+    assert_eq!(build["offline"], true);
+}
+
+#[actix_web::test]
+async fn a_server_is_ready_once_its_packages_are_preloaded() {
+    let state = common::state(&["--preload-packages", "@preview/example:0.1.0"]);
+    let (packages, config) = (state.packages.clone(), state.config.current());
+    let service = test::init_service(app(state)).await;
+
+    let response = test::call_service(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let readiness: Value = test::read_body_json(response).await;
+    assert_eq!(readiness["checks"]["preload"]["state"], "pending");
+    assert_eq!(readiness["checks"]["preload"]["packages"], 1);
+
+    // Offline the package can't be fetched, which is logged but doesn't keep the server from serving.
+    actix_web::web::block(move || packages.preload(&config.preload_packages)).await.unwrap();
+
+    let response = test::call_service(&service, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let readiness: Value = test::read_body_json(response).await;
+    assert_eq!(readiness["checks"]["preload"]["state"], "finished");
+}
@@ -0,0 +1,154 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{CONTENT_TYPE, ETAG, IF_MATCH};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use typstapi::app::app;
+
+const LOCK: &str = "X-Project-Lock";
+
+/// The status of a failed request and the code its body has.
+async fn failure<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, String) {
+    let status = response.status();
+    let body: Value = test::read_body_json(response).await;
+    (status, body["error"].as_str().unwrap_or_default().to_string())
+}
+
+/// An upload creating the project `p1` with a main file, as `key` if any.
+fn creation(key: Option<&str>) -> test::TestRequest {
+    let (content_type, body) = common::multipart(&[("main.typ", b"= Draft\n")]);
+    let request = test::TestRequest::put().uri("/projects/p1").insert_header((CONTENT_TYPE, content_type)).set_payload(body);
+    match key {
+        Some(key) => { request.insert_header(("X-Api-Key", key.to_string())) }
+        None => { request }
+    }
+}
+
+fn etag<B>(response: &ServiceResponse<B>) -> String {
+    response.headers().get(ETAG).expect("a change answers with the new version").to_str().unwrap().to_string()
+}
+
+/// A change of the main file to `text`, based on the version `etag` if any.
+fn edit(text: &str, etag: Option<&str>) -> test::TestRequest {
+    let request = test::TestRequest::patch().uri("/projects/p1/files/main.typ").set_payload(text.to_string());
+    match etag {
+        Some(etag) => { request.insert_header((IF_MATCH, etag.to_string())) }
+        None => { request }
+    }
+}
+
+#[actix_web::test]
+async fn of_two_edits_based_on_one_version_only_the_first_lands() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let created = test::call_service(&service, creation(None).to_request()).await;
+    assert_eq!(created.status(), StatusCode::CREATED);
+    let version = etag(&created);
+
+    let response = test::call_service(&service, edit("= Mine\n", None).to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::PRECONDITION_REQUIRED, "version_required".to_string()));
+
+    let first = test::call_service(&service, edit("= Mine\n", Some(&version)).to_request()).await;
+    assert_eq!(first.status(), StatusCode::NO_CONTENT);
+    let current = etag(&first);
+    assert_ne!(current, version);
+
+    let second = test::call_service(&service, edit("= Theirs\n", Some(&version)).to_request()).await;
+    assert_eq!(failure(second).await, (StatusCode::PRECONDITION_FAILED, "version_mismatch".to_string()));
+
+    let retried = test::call_service(&service, edit("= Theirs\n", Some(&current)).to_request()).await;
+    assert_eq!(retried.status(), StatusCode::NO_CONTENT);
+    let forced = test::call_service(&service, edit("= Anyway\n", Some("*")).to_request()).await;
+    assert_eq!(forced.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_web::test]
+async fn only_the_holder_of_the_lock_changes_the_project() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    assert_eq!(test::call_service(&service, creation(None).to_request()).await.status(), StatusCode::CREATED);
+
+    let take = test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({})).to_request();
+    let response = test::call_service(&service, take).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let lock: Value = test::read_body_json(response).await;
+    let token = lock["token"].as_str().unwrap().to_string();
+
+    let response = test::call_service(&service, edit("= Intruder\n", Some("*")).to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::LOCKED, "project_locked".to_string()));
+    let request = edit("= Holder\n", Some("*")).insert_header((LOCK, token.clone())).to_request();
+    assert_eq!(test::call_service(&service, request).await.status(), StatusCode::NO_CONTENT);
+
+    let take = test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({})).to_request();
+    let response = test::call_service(&service, take).await;
+    assert_eq!(failure(response).await, (StatusCode::LOCKED, "project_locked".to_string()));
+    let release = test::TestRequest::delete().uri("/projects/p1/lock").to_request();
+    let response = test::call_service(&service, release).await;
+    assert_eq!(failure(response).await, (StatusCode::LOCKED, "project_locked".to_string()));
+
+    let renew = test::TestRequest::post().uri("/projects/p1/lock").insert_header((LOCK, token.clone())).set_json(json!({})).to_request();
+    let response = test::call_service(&service, renew).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let renewed: Value = test::read_body_json(response).await;
+    assert_eq!(renewed["token"], token.as_str());
+
+    let release = test::TestRequest::delete().uri("/projects/p1/lock").insert_header((LOCK, token)).to_request();
+    assert_eq!(test::call_service(&service, release).await.status(), StatusCode::NO_CONTENT);
+    let take = test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({})).to_request();
+    assert_eq!(test::call_service(&service, take).await.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn an_expired_lock_is_free_to_take() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    assert_eq!(test::call_service(&service, creation(None).to_request()).await.status(), StatusCode::CREATED);
+
+    let take = test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({ "ttl_seconds": 1 })).to_request();
+    let expired: Value = test::call_and_read_body_json(&service, take).await;
+    actix_web::rt::time::sleep(Duration::from_millis(1100)).await;
+
+    let response = test::call_service(&service, edit("= Unlocked\n", Some("*")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let take = test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({})).to_request();
+    let response = test::call_service(&service, take).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let lock: Value = test::read_body_json(response).await;
+    assert_ne!(lock["token"], expired["token"]);
+
+    let stale = edit("= Stale\n", Some("*")).insert_header((LOCK, expired["token"].as_str().unwrap().to_string())).to_request();
+    let response = test::call_service(&service, stale).await;
+    assert_eq!(failure(response).await, (StatusCode::LOCKED, "project_locked".to_string()));
+}
+
+#[actix_web::test]
+async fn another_tenant_does_not_see_the_project() {
+    let directory = TempDir::new().unwrap();
+    let keys = directory.path().join("keys.toml");
+    std::fs::write(&keys, "[[keys]]\nname = \"alpha\"\nkey = \"alpha-key\"\n\n[[keys]]\nname = \"beta\"\nkey = \"beta-key\"\n").unwrap();
+    let service = test::init_service(app(common::state(&["--api-keys-file", keys.to_str().unwrap()]))).await;
+    let created = test::call_service(&service, creation(Some("alpha-key")).to_request()).await;
+    assert_eq!(created.status(), StatusCode::CREATED);
+
+    let read = test::TestRequest::get().uri("/projects/p1").insert_header(("X-Api-Key", "alpha-key")).to_request();
+    assert_eq!(test::call_service(&service, read).await.status(), StatusCode::OK);
+
+    let requests = [
+        test::TestRequest::get().uri("/projects/p1"),
+        test::TestRequest::post().uri("/projects/p1/lock").set_json(json!({})),
+        edit("= Beta\n", Some("*")),
+    ];
+    for request in requests {
+        let response = test::call_service(&service, request.insert_header(("X-Api-Key", "beta-key")).to_request()).await;
+        assert_eq!(failure(response).await, (StatusCode::NOT_FOUND, "project_not_found".to_string()));
+    }
+
+    // Beta's own project of the same id is another project.
+    let created = test::call_service(&service, creation(Some("beta-key")).to_request()).await;
+    assert_eq!(created.status(), StatusCode::CREATED);
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::Value;
+use tempfile::TempDir;
+use typstapi::app::app;
+
+const PEER: &str = "203.0.113.7:4711";
+
+/// The status of a failed request and the code its body has.
+async fn failure<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, String) {
+    let status = response.status();
+    let body: Value = test::read_body_json(response).await;
+    (status, body["error"].as_str().unwrap_or_default().to_string())
+}
+
+/// A request for the limits from the test peer, with `key` if any.
+fn limits(key: Option<&str>) -> test::TestRequest {
+    let request = test::TestRequest::get().uri("/limits").peer_addr(PEER.parse().unwrap());
+    match key {
+        None => { request }
+        Some(key) => { request.insert_header(("X-Api-Key", key)) }
+    }
+}
+
+#[actix_web::test]
+async fn clients_over_the_limit_are_told_when_to_retry() {
+    let directory = TempDir::new().unwrap();
+    let keys = directory.path().join("keys.toml");
+    std::fs::write(&keys, "[[keys]]\nname = \"alpha\"\nkey = \"alpha-key\"\n\n[[keys]]\nname = \"beta\"\nkey = \"beta-key\"\n").unwrap();
+    let state = common::state(&[
+        "--api-keys-file", keys.to_str().unwrap(),
+        "--rate-limit-per-minute", "1",
+        "--rate-limit-burst", "2",
+    ]);
+    let service = test::init_service(app(state)).await;
+
+    // Rejected keys count against the client IP before authentication turns them away.
+    let response = test::call_service(&service, limits(None).to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::UNAUTHORIZED, "api_key_required".to_string()));
+    let response = test::call_service(&service, limits(Some("wrong-key")).to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::FORBIDDEN, "invalid_api_key".to_string()));
+    let response = test::call_service(&service, limits(None).to_request()).await;
+    let retry_after: u64 = response.headers()[RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!((1..=60).contains(&retry_after), "{retry_after}");
+    assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+    assert_eq!(failure(response).await, (StatusCode::TOO_MANY_REQUESTS, "rate_limited".to_string()));
+
+    // Each key has a bucket of its own, apart from its IP's.
+    for _ in 0..2 {
+        let response = test::call_service(&service, limits(Some("alpha-key")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = test::call_service(&service, limits(Some("alpha-key")).to_request()).await;
+    assert!(response.headers().contains_key(RETRY_AFTER));
+    assert_eq!(failure(response).await, (StatusCode::TOO_MANY_REQUESTS, "rate_limited".to_string()));
+    let response = test::call_service(&service, limits(Some("beta-key")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
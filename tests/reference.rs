@@ -0,0 +1,54 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use typstapi::compilers::CURRENT_VERSION;
+use typstapi::docker_world::{Cancellation, DockerWorld, DocumentFile, FontDb};
+
+/// Set to record the fingerprints of the current typst version instead of comparing with them.
+const UPDATE: &str = "UPDATE_REFERENCE";
+
+/// Every document in `tests/fixtures/reference` lays out as recorded in the `.pages` file next
+/// to it, one `PageFingerprint` per line under a line naming the typst version recorded with.
+///
+/// A typst upgrade that changes a layout fails here. Run the tests with `UPDATE_REFERENCE=1`
+/// to record the new layout, and the diff of the `.pages` files shows which pages moved. A
+/// document without a `.pages` file yet is recorded on its first run.
+#[test]
+fn the_reference_documents_lay_out_as_recorded() {
+    let fonts = Arc::new(FontDb::new(Some(common::fixtures().join("fonts"))));
+    let directory = common::fixtures().join("reference");
+    let mut documents: Vec<_> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "typ"))
+        .collect();
+    documents.sort();
+    assert!(!documents.is_empty(), "no reference documents in {}", directory.display());
+
+    let mut changed = vec![];
+    for document in documents {
+        let name = document.file_name().unwrap().to_string_lossy().into_owned();
+        let compiled = DockerWorld::builder()
+            .main(DocumentFile::new("main.typ", fs::read(&document).unwrap()))
+            .font_db(fonts.clone())
+            .build()
+            .unwrap()
+            .compile(100, Cancellation::default())
+            .unwrap_or_else(|problem| panic!("{name} doesn't compile: {problem}"));
+        let recording = format!("typst {CURRENT_VERSION}\n{}\n", compiled.page_hashes.join("\n"));
+
+        let recorded_at = document.with_extension("pages");
+        match fs::read_to_string(&recorded_at) {
+            Ok(recorded) if std::env::var_os(UPDATE).is_none() => {
+                let (before, after) = (recorded.lines().skip(1), recording.lines().skip(1));
+                if before.ne(after) {
+                    changed.push(format!("{name}, recorded with {}", recorded.lines().next().unwrap_or_default()));
+                }
+            }
+            _ => { fs::write(&recorded_at, recording).unwrap() }
+        }
+    }
+    assert!(changed.is_empty(), "the layout changed in {changed:?}, rerun with {UPDATE}=1 to record it");
+}
@@ -0,0 +1,80 @@
+#![cfg(all(feature = "server", unix))]
+
+mod common;
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Takes a while to evaluate, without reading anything that would see a cancellation.
+const SLOW: &[u8] = b"#let total = 0\n#for i in range(1000000) { total += calc.rem(i, 7) }\n#total\n";
+
+/// Kills the server if a test fails before it stopped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A port nothing listens on right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Poll `condition` until it holds, failing the test after `limit`.
+fn wait_for(what: &str, limit: Duration, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(started.elapsed() < limit, "gave up waiting for {what}");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn sigterm_lets_a_running_compile_answer() {
+    let port = free_port();
+    let fonts = common::fixtures().join("fonts");
+    let child = Command::new(env!("CARGO_BIN_EXE_typstapi"))
+        .args(["--listen", &format!("127.0.0.1:{port}"), "--offline", "--warm-up", "false"])
+        .args(["--max-concurrent-compiles", "1", "--shutdown-grace-period", "120", "--compile-timeout", "120"])
+        .arg("--font-dir")
+        .arg(&fonts)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("the server binary starts");
+    let mut server = Server(child);
+    let base = format!("http://127.0.0.1:{port}");
+    wait_for("the server to listen", Duration::from_secs(60), || ureq::get(&format!("{base}/healthz")).call().is_ok());
+
+    let compile = std::thread::spawn({
+        let url = format!("{base}/compile");
+        move || {
+            let (content_type, body) = common::multipart(&[("main.typ", SLOW)]);
+            let response = ureq::post(&url).set("Content-Type", &content_type).send_bytes(&body).expect("the compile answers");
+            let mut pdf = Vec::new();
+            response.into_reader().read_to_end(&mut pdf).unwrap();
+            pdf
+        }
+    });
+    wait_for("the compile to start", Duration::from_secs(60), || {
+        let metrics = ureq::get(&format!("{base}/metrics")).call().ok().and_then(|response| response.into_string().ok());
+        metrics.is_some_and(|metrics| metrics.lines().any(|line| line == "typstapi_compiles_in_flight 1"))
+    });
+
+    let pid = libc::pid_t::try_from(server.0.id()).unwrap();
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+
+    let pdf = compile.join().expect("the compile thread didn't panic");
+    assert!(pdf.starts_with(b"%PDF-"));
+    let mut exited = None;
+    wait_for("the server to exit", Duration::from_secs(30), || {
+        exited = server.0.try_wait().unwrap();
+        exited.is_some()
+    });
+    assert!(exited.unwrap().success());
+}
@@ -0,0 +1,87 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{CONTENT_TYPE, TRANSFER_ENCODING};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use tempfile::TempDir;
+use typstapi::app::app;
+use typstapi::auth::signature::{SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+const KEY: &str = "signer-key";
+const SECRET: &str = "signer-secret";
+const BODY: &[u8] = b"= Signed\n";
+
+/// The status of a failed request and the code its body has.
+async fn failure<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, String) {
+    let status = response.status();
+    let body: Value = test::read_body_json(response).await;
+    (status, body["error"].as_str().unwrap_or_default().to_string())
+}
+
+/// The hex signature of `body` sent at `timestamp`.
+fn sign(timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// A compile of `body` as the signing key, signed over `signed` at `timestamp`.
+fn compile(timestamp: i64, signed: &[u8], body: &[u8]) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/compile")
+        .insert_header(("X-Api-Key", KEY))
+        .insert_header((CONTENT_TYPE, "text/plain"))
+        .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+        .insert_header((SIGNATURE_HEADER, sign(timestamp, signed)))
+        .set_payload(body.to_vec())
+}
+
+#[actix_web::test]
+async fn a_key_with_a_signing_secret_must_sign_its_requests() {
+    let directory = TempDir::new().unwrap();
+    let keys = directory.path().join("keys.toml");
+    std::fs::write(&keys, format!("[[keys]]\nname = \"signer\"\nkey = \"{KEY}\"\nsigning_secret = \"{SECRET}\"\n")).unwrap();
+    let service = test::init_service(app(common::state(&["--api-keys-file", keys.to_str().unwrap()]))).await;
+    let now = chrono::Utc::now().timestamp();
+
+    let response = test::call_service(&service, compile(now, BODY, BODY).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(test::read_body(response).await.starts_with(b"%PDF-"));
+
+    // The body is complete but for its signature, so its last chunk never reaches the compile.
+    let response = test::call_service(&service, compile(now, BODY, b"= Tampered\n").to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::UNAUTHORIZED, "invalid_signature".to_string()));
+
+    for timestamp in [now - 3600, now + 3600] {
+        let response = test::call_service(&service, compile(timestamp, BODY, BODY).to_request()).await;
+        assert_eq!(failure(response).await, (StatusCode::UNAUTHORIZED, "signature_stale".to_string()), "{}", timestamp - now);
+    }
+
+    let unsigned = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header(("X-Api-Key", KEY))
+        .insert_header((CONTENT_TYPE, "text/plain"))
+        .set_payload(BODY.to_vec())
+        .to_request();
+    let response = test::call_service(&service, unsigned).await;
+    assert_eq!(failure(response).await, (StatusCode::UNAUTHORIZED, "signature_required".to_string()));
+
+    let streamed = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header(("X-Api-Key", KEY))
+        .insert_header((CONTENT_TYPE, "text/plain"))
+        .insert_header((TRANSFER_ENCODING, "chunked"))
+        .insert_header((TIMESTAMP_HEADER, now.to_string()))
+        .insert_header((SIGNATURE_HEADER, sign(now, BODY)))
+        .to_request();
+    let response = test::call_service(&service, streamed).await;
+    assert_eq!(failure(response).await, (StatusCode::LENGTH_REQUIRED, "length_required".to_string()));
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::test;
+use serde_json::{json, Value};
+use typstapi::app::app;
+
+/// The counts of `document`, laid out by `/stats`.
+async fn count(document: &[u8]) -> Value {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let (content_type, body) = common::multipart(&[("main.typ", document)]);
+    let request = test::TestRequest::post()
+        .uri("/stats")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    test::call_and_read_body_json(&service, request).await
+}
+
+#[actix_web::test]
+async fn counts_words_and_characters_across_scripts() {
+    let document = std::fs::read(common::fixtures().join("multilingual.typ")).unwrap();
+    let statistics = count(&document).await;
+    // Per line laid out: the heading, "Hello world, naïve café." with a combining diaeresis,
+    // an ideograph or kana per word in "東京は大きい。", Hebrew, Arabic and the table's cells.
+    let (words, characters, non_whitespace) = (1 + 4 + 6 + 2 + 2 + 2, 10 + 24 + 7 + 9 + 13 + 2, 10 + 21 + 7 + 8 + 12 + 2);
+    assert_eq!(statistics, json!({
+        "words": words,
+        "characters": characters,
+        "non_whitespace_characters": non_whitespace,
+        "headings": 1,
+        "figures": 1,
+        "tables": 1,
+        "pages": [{ "page": 1, "words": words, "characters": characters, "non_whitespace_characters": non_whitespace }],
+    }));
+}
+
+#[actix_web::test]
+async fn counts_each_page() {
+    let statistics = count(b"One two three\n#pagebreak()\nFour\n#pagebreak()\n").await;
+    let pages: Vec<_> = statistics["pages"].as_array().unwrap().iter().map(|page| page["words"].as_u64().unwrap()).collect();
+    assert_eq!(pages, [3, 1, 0]);
+    assert_eq!(statistics["words"], 4);
+    assert_eq!(statistics["characters"], 13 + 4);
+}
+
+#[actix_web::test]
+async fn a_word_hyphenated_across_lines_counts_once() {
+    let document = b"#set page(width: 3cm, margin: 0.2cm)\n#set text(font: \"DejaVu Sans\", hyphenate: true, lang: \"en\")\nincomprehensibilities\n";
+    let statistics = count(document).await;
+    assert_eq!(statistics["words"], 1);
+}
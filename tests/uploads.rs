@@ -0,0 +1,78 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::{Method, StatusCode};
+use actix_web::test;
+use serde_json::{json, Value};
+use typstapi::app::app;
+use typstapi::uploads::{OFFSET_HEADER, REFERENCE_TYPE};
+
+const BOUNDARY: &str = "upload-test-boundary";
+
+/// The status of a failed request and the code its body has.
+async fn failure<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, String) {
+    let status = response.status();
+    let body: Value = test::read_body_json(response).await;
+    (status, body["error"].as_str().unwrap_or_default().to_string())
+}
+
+/// A chunk of upload `id` starting at `offset`.
+fn chunk(id: &str, offset: u64, data: &[u8]) -> test::TestRequest {
+    test::TestRequest::patch()
+        .uri(&format!("/uploads/{id}"))
+        .insert_header((OFFSET_HEADER, offset.to_string()))
+        .set_payload(data.to_vec())
+}
+
+#[actix_web::test]
+async fn chunks_must_continue_exactly_where_the_upload_stands() {
+    let service = test::init_service(app(common::state(&[]))).await;
+    let open = test::TestRequest::post().uri("/uploads").set_json(json!({ "size": 10 })).to_request();
+    let created: Value = test::call_and_read_body_json(&service, open).await;
+    let id = created["id"].as_str().unwrap().to_string();
+
+    let response = test::call_service(&service, chunk(&id, 0, b"hello").to_request()).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()[OFFSET_HEADER], "5");
+
+    // A gap, an overlap and the same chunk again.
+    for (offset, data) in [(7, &b"ld"[..]), (3, &b"loworld"[..]), (0, &b"hello"[..])] {
+        let response = test::call_service(&service, chunk(&id, offset, data).to_request()).await;
+        assert_eq!(failure(response).await, (StatusCode::CONFLICT, "upload_offset_mismatch".to_string()), "{offset}");
+    }
+    let response = test::call_service(&service, chunk(&id, 5, b"world!").to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::PAYLOAD_TOO_LARGE, "upload_overrun".to_string()));
+    let unplaced = test::TestRequest::patch().uri(&format!("/uploads/{id}")).set_payload("world").to_request();
+    let response = test::call_service(&service, unplaced).await;
+    assert_eq!(failure(response).await, (StatusCode::BAD_REQUEST, "missing_upload_offset".to_string()));
+
+    let head = test::TestRequest::default().method(Method::HEAD).uri(&format!("/uploads/{id}")).to_request();
+    let response = test::call_service(&service, head).await;
+    assert_eq!(response.headers()[OFFSET_HEADER], "5");
+
+    let response = test::call_service(&service, chunk(&id, 5, b"world").to_request()).await;
+    assert_eq!(response.headers()[OFFSET_HEADER], "10");
+    let response = test::call_service(&service, chunk(&id, 5, b"world").to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::CONFLICT, "upload_offset_mismatch".to_string()));
+    let response = test::call_service(&service, chunk(&id, 10, b"!").to_request()).await;
+    assert_eq!(failure(response).await, (StatusCode::CONFLICT, "upload_complete".to_string()));
+
+    // None of the refused chunks made it into the file.
+    let body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"main.typ\"; filename=\"main.typ\"\r\n\r\n\
+         #assert.eq(read(\"data.txt\"), \"helloworld\")\r\n\
+         --{BOUNDARY}\r\nContent-Disposition: form-data; name=\"data.txt\"\r\nContent-Type: {REFERENCE_TYPE}\r\n\r\n{id}\r\n\
+         --{BOUNDARY}--\r\n"
+    );
+    let compile = test::TestRequest::post()
+        .uri("/compile")
+        .insert_header((CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}")))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&service, compile).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}